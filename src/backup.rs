@@ -0,0 +1,621 @@
+//! Online backups of `.funk` files, and restoring them to a chosen point.
+//!
+//! A backup reads the checkpoint and the [write-ahead log](storage::wal)
+//! straight from disk, without taking the database's lock, so writers,
+//! in this process or another, carry on meanwhile. What it holds is every
+//! tree, the catalog included, as of the last commit on disk. Every commit
+//! goes through the log first, so an incremental backup is the records the
+//! log gained since the backup it builds on. Every backup records the
+//! checkpoint its log started from: a save, vacuum or rekey checkpoints
+//! and starts the log afresh, and once it has started after the backup an
+//! incremental builds on, the commits in between are gone from it and only
+//! a full backup can follow.
+//!
+//! Both kinds are a [`MAGIC`] then records framed like those of the log,
+//! each checksummed:
+//!
+//! ```text
+//! header | snapshot... (full) or commit... (incremental) | end
+//! ```
+//!
+//! Backups of an encrypted file are taken with its key and sealed with it
+//! too, record by record, bound to their place; the header says which key,
+//! so restoring asks for it, and encrypts the new file with it again.
+//!
+//! Restoring replays a full backup and the incrementals after it, in order,
+//! into a new `.funk` file, checking each record as it goes, and stops
+//! at the [`Target`] transaction or time. Commits carry both in the
+//! [`CLOCK`] they write.
+use crate::codec::Cursor;
+use crate::storage::{self, checkpoint_of, decode_clock, wal, Batch, Cipher, Key, MemoryStore, Op, StorageEngine, CLOCK};
+use crate::value::FunkValue;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+pub const MAGIC: &[u8; 8] = b"FUNKBAK\0";
+const FORMAT_VERSION: u32 = 1;
+
+/// Pairs per snapshot record.
+const CHUNK: usize = 1000;
+
+const HEADER: u8 = 0;
+const SNAPSHOT: u8 = 1;
+const COMMIT: u8 = 2;
+const END: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Full,
+    Incremental,
+}
+
+/// What a backup holds, from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backup {
+    pub kind: Kind,
+    /// The log sequence number the backup builds on, 0 for a full one.
+    pub base: u64,
+    /// The log sequence number it reaches up to.
+    pub lsn: u64,
+    /// The checkpoint the database's log started from when it was taken,
+    /// which names the log an incremental backup after it reads.
+    pub checkpoint: u64,
+    /// The last transaction it holds, and when that committed.
+    pub tick: u64,
+    pub time: i64,
+    /// The key its records are sealed with, by [`Cipher::id`], if any.
+    pub key: Option<[u8; 8]>,
+}
+
+impl Backup {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![HEADER];
+        out.extend(FORMAT_VERSION.to_le_bytes());
+        out.push(match self.kind {
+            Kind::Full => 0,
+            Kind::Incremental => 1,
+        });
+        for field in [self.base, self.lsn, self.checkpoint, self.tick] {
+            out.extend(field.to_le_bytes());
+        }
+        out.extend(self.time.to_le_bytes());
+        match self.key {
+            Some(id) => {
+                out.push(1);
+                out.extend(id);
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(payload);
+        if cursor.byte()? != HEADER {
+            bail!("The first record is not a header");
+        }
+        let version = u32::from_le_bytes(cursor.take(4)?.try_into()?);
+        if version != FORMAT_VERSION {
+            bail!("Unsupported backup format version {version}");
+        }
+        let kind = match cursor.byte()? {
+            0 => Kind::Full,
+            1 => Kind::Incremental,
+            kind => bail!("Unknown kind of backup {kind}"),
+        };
+        let field = |cursor: &mut Cursor| -> Result<[u8; 8]> { Ok(cursor.take(8)?.try_into()?) };
+        let (base, lsn, checkpoint) = (field(&mut cursor)?, field(&mut cursor)?, field(&mut cursor)?);
+        let (tick, time) = (field(&mut cursor)?, field(&mut cursor)?);
+        let key = match cursor.byte()? {
+            0 => None,
+            1 => Some(field(&mut cursor)?),
+            other => bail!("Unknown encryption {other}"),
+        };
+        Ok(Self {
+            kind,
+            base: u64::from_le_bytes(base),
+            lsn: u64::from_le_bytes(lsn),
+            checkpoint: u64::from_le_bytes(checkpoint),
+            tick: u64::from_le_bytes(tick),
+            time: i64::from_le_bytes(time),
+            key,
+        })
+    }
+}
+
+/// Which transaction to restore up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    /// The last one the backups hold.
+    #[default]
+    Latest,
+    /// The one with this id, its tick.
+    Txn(u64),
+    /// The last one committed by this time, in microseconds since the
+    /// epoch.
+    Time(i64),
+}
+
+impl Target {
+    fn stops_before(self, tick: u64, time: i64) -> bool {
+        match self {
+            Self::Latest => false,
+            Self::Txn(txn) => tick > txn,
+            Self::Time(at) => time > at,
+        }
+    }
+}
+
+/// The tick and time of the commit `batch` is, if it is one.
+fn clock_of(batch: &Batch) -> Result<Option<(u64, i64)>> {
+    for op in batch.ops() {
+        if let Op::Put { tree, key, value } = op {
+            if tree == CLOCK.0 && key == CLOCK.1 {
+                return Ok(Some(decode_clock(value)?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn location_of(db: &Path) -> Result<PathBuf> {
+    let (backend, path) = storage::parse_location(&db.to_string_lossy())?;
+    if backend != storage::Backend::File {
+        bail!("Online backups are of `.funk` files, not of {backend} databases");
+    }
+    if !path.exists() {
+        bail!("There is no database at {}", db.display());
+    }
+    Ok(path)
+}
+
+/// What a sealed record is bound to: its log sequence number and its
+/// place in the backup, so that none can be moved or dropped.
+fn aad(lsn: u64, record: u64) -> Vec<u8> {
+    [lsn.to_le_bytes(), record.to_le_bytes()].concat()
+}
+
+struct Writer<W: Write> {
+    out: W,
+    cipher: Option<Cipher>,
+    records: u64,
+}
+
+impl<W: Write> Writer<W> {
+    fn new(mut out: W, header: &Backup, cipher: Option<Cipher>) -> Result<Self> {
+        out.write_all(MAGIC)?;
+        let mut writer = Self { out, cipher, records: 0 };
+        writer.record(header.lsn, &header.encode())?;
+        Ok(writer)
+    }
+
+    fn record(&mut self, lsn: u64, payload: &[u8]) -> Result<()> {
+        self.out.write_all(&wal::frame(lsn, payload))?;
+        self.records += 1;
+        Ok(())
+    }
+
+    // The tag stays in the clear, so the end record is found without the
+    // key.
+    fn batch(&mut self, lsn: u64, tag: u8, batch: &Batch) -> Result<()> {
+        let batch = match &self.cipher {
+            Some(cipher) => cipher.seal(&aad(lsn, self.records), &wal::encode_batch(batch)),
+            None => wal::encode_batch(batch),
+        };
+        self.record(lsn, &[&[tag], batch.as_slice()].concat())
+    }
+
+    fn finish(mut self, lsn: u64) -> Result<()> {
+        let count = self.records;
+        self.record(lsn, &[&[END], count.to_le_bytes().as_slice()].concat())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes everything in the database at `db` to `out`, as of the last
+/// commit on disk. An encrypted database needs its `key`, which seals the
+/// backup as well.
+pub fn full(db: &Path, key: Option<&Key>, out: impl Write) -> Result<Backup> {
+    let (store, checkpoint, lsn) = MemoryStore::read_consistent(location_of(db)?, key)?;
+    let cipher = key.map(Cipher::new);
+    let (tick, time) = match store.get(CLOCK.0, CLOCK.1)? {
+        Some(clock) => decode_clock(&clock)?,
+        None => (0, 0),
+    };
+    let backup = Backup {
+        kind: Kind::Full,
+        base: 0,
+        lsn,
+        checkpoint,
+        tick,
+        time,
+        key: cipher.as_ref().map(Cipher::id),
+    };
+    let mut writer = Writer::new(out, &backup, cipher)?;
+    for tree in store.trees()? {
+        let pairs = store.scan(&tree, (Bound::Unbounded, Bound::Unbounded))?;
+        for chunk in pairs.chunks(CHUNK) {
+            let mut batch = Batch::new();
+            for (key, value) in chunk {
+                batch.put(&tree, key.clone(), value.clone());
+            }
+            writer.batch(lsn, SNAPSHOT, &batch)?;
+        }
+    }
+    writer.finish(lsn)?;
+    Ok(backup)
+}
+
+/// Writes the commits to the database at `db` since `since` was taken to
+/// `out`, sealed with `key` like [`full`]. Fails once a checkpoint after
+/// `since` started the log afresh, dropping commits it did not hold.
+pub fn incremental(db: &Path, key: Option<&Key>, since: &Backup, out: impl Write) -> Result<Backup> {
+    let path = location_of(db)?;
+    let cipher = key.map(Cipher::new);
+    if cipher.as_ref().map(Cipher::id) != since.key {
+        bail!(
+            "The backup of transaction {} is sealed with another key than the one given, or none; take a full backup",
+            since.tick
+        );
+    }
+    let (checkpoint, records) = loop {
+        let checkpoint = checkpoint_of(&path)?;
+        let records = wal::read(&wal::path_for(&path), cipher.as_ref(), since.lsn);
+        // See `MemoryStore::read_consistent`.
+        if checkpoint_of(&path)? != checkpoint {
+            continue;
+        }
+        // A checkpoint at or before `since` only dropped what it holds.
+        if checkpoint != since.checkpoint && checkpoint > since.lsn {
+            bail!(
+                "{} was checkpointed at log record {checkpoint} since the backup of transaction {}, which reaches up to record {}, \
+                 so its log has started afresh without the commits in between; take a full backup",
+                db.display(),
+                since.tick,
+                since.lsn
+            );
+        }
+        break (checkpoint, records?);
+    };
+    let mut backup = Backup {
+        kind: Kind::Incremental,
+        base: since.lsn,
+        checkpoint,
+        ..*since
+    };
+    for (lsn, batch) in &records {
+        backup.lsn = *lsn;
+        if let Some((tick, time)) = clock_of(batch)? {
+            (backup.tick, backup.time) = (tick, time);
+        }
+    }
+    let mut writer = Writer::new(out, &backup, cipher)?;
+    for (lsn, batch) in &records {
+        writer.batch(*lsn, COMMIT, batch)?;
+    }
+    writer.finish(backup.lsn)?;
+    Ok(backup)
+}
+
+/// Reads the records of a backup one at a time, checking each.
+struct Reader<R: Read> {
+    input: R,
+    name: String,
+    records: u64,
+}
+
+impl<R: Read> Reader<R> {
+    fn new(mut input: R, name: String) -> Result<(Self, Backup)> {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic).with_context(|| format!("Reading {name}"))?;
+        if &magic != MAGIC {
+            bail!("{name} is not a FunkDB backup");
+        }
+        let mut reader = Self { input, name, records: 0 };
+        let Some((_, payload)) = reader.next()? else {
+            bail!("{} ends before its header", reader.name);
+        };
+        let backup = Backup::decode(&payload).with_context(|| format!("Reading {}", reader.name))?;
+        Ok((reader, backup))
+    }
+
+    /// The next record, or `None` at the end record.
+    fn next(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        let name = &self.name;
+        let mut header = [0; wal::HEADER];
+        match self.input.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => bail!("{name} ends before its last record"),
+            result => result?,
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into()?) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into()?);
+        let lsn = u64::from_le_bytes(header[8..16].try_into()?);
+        let mut payload = vec![0; len];
+        match self.input.read_exact(&mut payload) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => bail!("{name} ends before its last record"),
+            result => result?,
+        }
+        if wal::checksum(lsn, &payload) != crc {
+            bail!("Record {} of {name} fails its checksum, the backup is corrupt", self.records);
+        }
+        if payload.first() == Some(&END) {
+            let count = u64::from_le_bytes(payload[1..].try_into()?);
+            if count != self.records {
+                bail!("{name} should have {count} records but has {}", self.records);
+            }
+            return Ok(None);
+        }
+        self.records += 1;
+        Ok(Some((lsn, payload)))
+    }
+}
+
+fn open(path: &Path) -> Result<(Reader<BufReader<File>>, Backup)> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    Reader::new(BufReader::new(file), path.display().to_string())
+}
+
+/// The header of the backup at `path`.
+pub fn inspect(path: &Path) -> Result<Backup> {
+    Ok(open(path)?.1)
+}
+
+/// Rebuilds a database at `into`, which must not exist yet, from a full
+/// backup and the incremental ones taken after it, in order, up to
+/// `target`. Sealed backups need their `key`, which then encrypts the new
+/// database too. Returns what the new database holds.
+pub fn restore(backups: &[impl AsRef<Path>], into: &Path, target: Target, key: Option<&Key>) -> Result<Backup> {
+    if into.exists() {
+        bail!("{} already exists, restore into a new file", into.display());
+    }
+    let given = key.map(Cipher::new);
+    let mut store = MemoryStore::new();
+    let mut restored: Option<Backup> = None;
+    'backups: for path in backups {
+        let path = path.as_ref();
+        let (mut reader, backup) = open(path)?;
+        let cipher = match (backup.key, &given) {
+            (None, _) => None,
+            (Some(_), None) => bail!("{} is encrypted, restore it with its key", path.display()),
+            (Some(id), Some(cipher)) if id != cipher.id() => bail!("{} is encrypted with another key", path.display()),
+            (Some(_), Some(cipher)) => Some(cipher),
+        };
+        if restored.is_some_and(|first| first.key != backup.key) {
+            bail!("{} is sealed with another key than the backups before it", path.display());
+        }
+        match (&restored, backup.kind) {
+            (None, Kind::Full) => {
+                if target.stops_before(backup.tick, backup.time) {
+                    bail!(
+                        "{} is of transaction {} at {}, later than the point to restore to",
+                        path.display(),
+                        backup.tick,
+                        FunkValue::datetime(backup.time)
+                    );
+                }
+                restored = Some(backup);
+            }
+            (None, Kind::Incremental) => bail!("Restoring starts from a full backup, {} is incremental", path.display()),
+            (Some(_), Kind::Full) => bail!("Only the first backup may be full, not {}", path.display()),
+            (Some(last), Kind::Incremental) if backup.base != last.lsn => {
+                bail!("{} does not follow on from the backup before it", path.display())
+            }
+            (Some(_), Kind::Incremental) => {}
+        }
+        let restored = restored.as_mut().expect("set above");
+        while let Some((lsn, payload)) = reader.next()? {
+            let (tag, batch) = payload.split_first().unwrap_or((&END, &[]));
+            let opened;
+            let batch = match cipher {
+                Some(cipher) => {
+                    opened = cipher
+                        .open(&aad(lsn, reader.records - 1), batch)
+                        .with_context(|| format!("Record {} of {} fails authentication", reader.records - 1, path.display()))?;
+                    &opened
+                }
+                None => batch,
+            };
+            let batch = wal::decode_batch(batch).with_context(|| format!("Reading {}", path.display()))?;
+            match (*tag, backup.kind) {
+                (SNAPSHOT, Kind::Full) => {}
+                (COMMIT, Kind::Incremental) => match clock_of(&batch)? {
+                    Some((tick, time)) if target.stops_before(tick, time) => break 'backups,
+                    Some((tick, time)) => (restored.tick, restored.time) = (tick, time),
+                    None => {}
+                },
+                (tag, _) => bail!("Unexpected record {tag} in {}", path.display()),
+            }
+            store.apply(batch)?;
+            restored.lsn = lsn;
+        }
+    }
+    let Some(restored) = restored else {
+        bail!("Nothing to restore from");
+    };
+    if let Target::Txn(txn) = target {
+        if restored.tick != txn {
+            bail!("The backups reach up to transaction {}, not {txn}", restored.tick);
+        }
+    }
+    let cipher = restored.key.and(given);
+    store.save_as(into, restored.lsn, cipher.as_ref())?;
+    Ok(restored)
+}
+
+/// Writes a full backup of `db` to the file at `out`, or an incremental one
+/// on top of the backup at `since`, sealed with `key`, the database's.
+pub fn to_file(db: &Path, out: &Path, since: Option<&Path>, key: Option<&Key>) -> Result<Backup> {
+    let mut writer = BufWriter::new(File::create(out)?);
+    let backup = match since {
+        Some(since) => incremental(db, key, &inspect(since)?, &mut writer),
+        None => full(db, key, &mut writer),
+    };
+    let backup = match backup {
+        Ok(backup) => backup,
+        Err(err) => {
+            drop(writer);
+            let _ = fs::remove_file(out);
+            return Err(err);
+        }
+    };
+    writer.into_inner()?.sync_all()?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+    use crate::storage::{remove_scratch, scratch_path};
+    use crate::{FunkDb, FunkDbOptions};
+
+    const SCHEMA: &str = "module default { type Note { required text: str; } }";
+
+    fn cleanup(paths: &[&PathBuf]) {
+        for path in paths {
+            remove_scratch(path);
+        }
+    }
+
+    fn texts(db: &FunkDb) -> Result<Vec<String>> {
+        let mut texts: Vec<_> = db.objects("Note")?.iter().map(|note| note.get("text").unwrap().to_string()).collect();
+        texts.sort();
+        Ok(texts)
+    }
+
+    #[test]
+    fn backs_up_a_live_database_and_restores_to_a_point() -> Result<()> {
+        let path = scratch_path("live.funk");
+        let (base, more, into, early) =
+            (scratch_path("full.bak"), scratch_path("incremental.bak"), scratch_path("restored.funk"), scratch_path("early.funk"));
+        let db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Note").set("text", "a"))?;
+        let full = to_file(&path, &base, None, None)?;
+        assert_eq!(full.tick, db.db.clock());
+
+        // The writer never stops, and the backups take no lock.
+        db.insert(Object::new("Note").set("text", "b"))?;
+        let b = db.db.clock();
+        db.insert(Object::new("Note").set("text", "c"))?;
+        let incremental = to_file(&path, &more, Some(&base), None)?;
+        assert_eq!((incremental.base, incremental.tick), (full.lsn, db.db.clock()));
+
+        let restored = restore(&[&base, &more], &into, Target::Latest, None)?;
+        assert_eq!(restored.tick, db.db.clock());
+        assert_eq!(texts(&FunkDb::open(&into)?)?, ["a", "b", "c"]);
+        assert!(restore(&[&base, &more], &into, Target::Latest, None).is_err());
+
+        restore(&[&base, &more], &early, Target::Txn(b), None)?;
+        assert_eq!(texts(&FunkDb::open(&early)?)?, ["a", "b"]);
+        cleanup(&[&early]);
+        restore(&[&base, &more], &early, Target::Time(full.time), None)?;
+        assert_eq!(texts(&FunkDb::open(&early)?)?, ["a"]);
+        cleanup(&[&early]);
+        assert!(restore(&[&base, &more], &early, Target::Txn(db.db.clock() + 1), None).is_err());
+        assert!(restore(&[&more], &early, Target::Latest, None).is_err());
+        assert!(!early.exists());
+        drop(db);
+        cleanup(&[&path, &base, &more, &into]);
+        Ok(())
+    }
+
+    #[test]
+    fn incrementals_follow_checkpoints_that_do_not_pass_them() -> Result<()> {
+        let path = scratch_path("checkpointed.funk");
+        let (base, more) = (scratch_path("checkpointed.bak"), scratch_path("checkpointed-more.bak"));
+        let (last, into) = (scratch_path("checkpointed-last.bak"), scratch_path("checkpointed-restored.funk"));
+        let mut db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Note").set("text", "a"))?;
+        let full = to_file(&path, &base, None, None)?;
+
+        // Checkpointing right after the backup starts the log afresh from
+        // where it ends, so nothing it lacks is lost.
+        db.save()?;
+        db.insert(Object::new("Note").set("text", "b"))?;
+        let incremental = to_file(&path, &more, Some(&base), None)?;
+        assert_eq!(incremental.checkpoint, full.lsn);
+        assert_ne!(incremental.checkpoint, full.checkpoint);
+        restore(&[&base, &more], &into, Target::Latest, None)?;
+        assert_eq!(texts(&FunkDb::open(&into)?)?, ["a", "b"]);
+        cleanup(&[&into]);
+
+        // One after a commit the backup lacks drops that commit from the log.
+        db.insert(Object::new("Note").set("text", "c"))?;
+        db.save()?;
+        db.insert(Object::new("Note").set("text", "d"))?;
+        let err = to_file(&path, &last, Some(&more), None).unwrap_err();
+        assert!(err.to_string().contains("take a full backup"), "{err}");
+        assert!(!last.exists());
+        to_file(&path, &last, None, None)?;
+        restore(&[&last], &into, Target::Latest, None)?;
+        assert_eq!(texts(&FunkDb::open(&into)?)?, ["a", "b", "c", "d"]);
+        drop(db);
+        cleanup(&[&path, &base, &more, &last, &into]);
+        Ok(())
+    }
+
+    #[test]
+    fn backups_of_encrypted_databases_are_sealed() -> Result<()> {
+        let path = scratch_path("sealed.funk");
+        let (base, more, into) = (scratch_path("sealed.bak"), scratch_path("sealed-more.bak"), scratch_path("unsealed.funk"));
+        let key = Key::generate();
+        let with_key = || FunkDbOptions::builder().key(key.clone()).build();
+        let db = FunkDb::open_with(&path, with_key())?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Note").set("text", "secret a"))?;
+        assert!(to_file(&path, &base, None, None).is_err());
+        assert_eq!(to_file(&path, &base, None, Some(&key))?.key, Some(Cipher::new(&key).id()));
+        db.insert(Object::new("Note").set("text", "secret b"))?;
+        assert!(to_file(&path, &more, Some(&base), None).is_err());
+        to_file(&path, &more, Some(&base), Some(&key))?;
+        for backup in [&base, &more] {
+            let bytes = fs::read(backup)?;
+            assert!(!bytes.windows(6).any(|window| window == b"secret"), "{} is in the clear", backup.display());
+        }
+
+        let err = restore(&[&base, &more], &into, Target::Latest, None).unwrap_err().to_string();
+        assert!(err.contains("restore it with its key"), "{err}");
+        let err = restore(&[&base, &more], &into, Target::Latest, Some(&Key::generate())).unwrap_err().to_string();
+        assert!(err.contains("another key"), "{err}");
+        restore(&[&base, &more], &into, Target::Latest, Some(&key))?;
+        assert!(storage::checkpoint_of(&into).is_ok());
+        assert!(!fs::read(&into)?.windows(6).any(|window| window == b"secret"));
+        assert_eq!(texts(&FunkDb::open_with(&into, with_key())?)?, ["secret a", "secret b"]);
+        drop(db);
+        cleanup(&[&path, &base, &more, &into]);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_backups_are_refused() -> Result<()> {
+        let path = scratch_path("corrupt.funk");
+        let (base, into) = (scratch_path("corrupt.bak"), scratch_path("corrupt-restored.funk"));
+        let db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        for n in 0..3 {
+            db.insert(Object::new("Note").set("text", n.to_string()))?;
+        }
+        to_file(&path, &base, None, None)?;
+        let bytes = fs::read(&base)?;
+
+        fs::write(&base, &bytes[..bytes.len() - 3])?;
+        let err = restore(&[&base], &into, Target::Latest, None).unwrap_err();
+        assert!(err.to_string().contains("ends before"), "{err}");
+        let mut flipped = bytes.clone();
+        // Within the last snapshot record, just before the end record.
+        flipped[bytes.len() - 30] ^= 1;
+        fs::write(&base, &flipped)?;
+        let err = restore(&[&base], &into, Target::Latest, None).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{err}");
+        assert!(!into.exists());
+        drop(db);
+        cleanup(&[&path, &base]);
+        Ok(())
+    }
+}
@@ -1,5 +1,21 @@
-use funk::FunkDb;
+use anyhow::{anyhow, bail};
+use funk::backup::{self, Target};
+use funk::docs::{self, DocsFormat};
+use funk::dump;
+use funk::expiry;
+use funk::fsck;
+use funk::import::{self, Import};
+use funk::export::{self, ExportFormat};
+use funk::lint::{self, LintConfig, LintLevel, LintRule};
+use funk::suggest::did_you_mean;
+use funk::storage::{Backend, Compression, Key};
+use funk::value::FunkValue;
+use funk::{funkstd, sdl, FunkDb, FunkDbOptions};
 use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use strum::IntoEnumIterator;
 use typed_builder::TypedBuilder;
 
 fn main() -> anyhow::Result<()> {
@@ -30,21 +46,46 @@ fn parse_cli(argv: Vec<OsString>) -> Operation {
             "repl" => {
                 return op_repl();
             }
-            _ => {
+            "help" | "schema" | "lint" | "backup" | "restore" | "dump" | "vacuum" | "fsck" | "stats" | "import" => {
                 return Operation::default();
             }
+            _ => {
+                return unrecognized(&it, &SUBCOMMANDS);
+            }
         }
     }
 
-    let op = {
+    // Subcommands that take a sub-subcommand (`schema export`) start
+    // their positional arguments one word later.
+    let mut first_arg = 1;
+    let mode = {
         let it = argv[0]
             .to_ascii_lowercase()
             .into_string()
             .expect("valid unicode");
         match it.as_str() {
-            "create" => Operation::builder().mode(Mode::Create),
-            "repl" => Operation::builder().mode(Mode::EmptyRepl),
-            "open" => Operation::builder().mode(Mode::Open),
+            "create" => Mode::Create,
+            "repl" => Mode::EmptyRepl,
+            "open" => Mode::Open,
+            "lint" => Mode::Lint,
+            "backup" => Mode::Backup,
+            "restore" => Mode::Restore,
+            "dump" => Mode::Dump,
+            "vacuum" => Mode::Vacuum,
+            "fsck" => Mode::Fsck,
+            "stats" => Mode::Stats,
+            "import" => Mode::Import,
+            "schema" => {
+                first_arg = 2;
+                let sub = argv[1].to_ascii_lowercase().into_string().expect("...");
+                match sub.as_str() {
+                    "export" => Mode::SchemaExport,
+                    "docs" => Mode::SchemaDocs,
+                    _ => {
+                        return unrecognized(&sub, &SCHEMA_SUBCOMMANDS);
+                    }
+                }
+            }
             "help" => {
                 let help_term = argv[1].to_ascii_lowercase().into_string().expect("...");
                 match help_term.as_str() {
@@ -61,41 +102,86 @@ fn parse_cli(argv: Vec<OsString>) -> Operation {
                         return op_help_help();
                     }
                     _ => {
-                        return Operation::default();
+                        return unrecognized(&help_term, &SUBCOMMANDS);
                     }
                 }
             }
             _ => {
-                return Operation::default();
+                return unrecognized(&it, &SUBCOMMANDS);
             }
         }
     };
 
-    // TODO: Extract keyword arguments (--thing="that")
-    // For now, we'll just crash if there's a '--' in the concatenated argv vector
-    const DANGER: &str = "--";
-    for word in argv.clone().into_iter() {
-        if word
-            .to_ascii_lowercase()
-            .into_string()
-            .unwrap()
-            .contains(DANGER)
-        {
-            eprintln!("We do not yet handle keyword arguments.");
-            return Operation::default();
+    // Keyword arguments come as either `--thing=that` or `--thing that`,
+    // everything else is positional.
+    let mut args = vec![];
+    let mut kwargs = vec![];
+    let mut words = argv[first_arg..]
+        .iter()
+        .map(|word| word.to_str().expect("valid unicode").to_string());
+    while let Some(word) = words.next() {
+        match word.strip_prefix("--") {
+            Some("") => {
+                eprintln!("A bare `--` is not a keyword argument.");
+                return Operation::default();
+            }
+            Some(key) => match key.split_once('=') {
+                Some((key, value)) => kwargs.push((key.to_string(), value.to_string())),
+                None => match words.next() {
+                    Some(value) => kwargs.push((key.to_string(), value)),
+                    None => {
+                        eprintln!("`--{key}` is missing a value.");
+                        return Operation::default();
+                    }
+                },
+            },
+            None => args.push(word),
         }
     }
 
-    let op_args: Args = {
-        let inner: Vec<String> = argv[1..]
-            .to_owned()
-            .into_iter()
-            .map(|x| x.to_str().unwrap().to_string())
-            .collect();
-        Args(inner)
-    };
+    let mut op = Operation::builder().mode(mode).args(Args(args)).build();
+    if !kwargs.is_empty() {
+        op.kwargs = Some(Kwargs(kwargs));
+    }
+    op
+}
 
-    op.args(op_args).build()
+// Every word `parse_cli` accepts in first position, with the code
+// `isize::from(Mode)` gives the mode it leads to.
+const SUBCOMMANDS: [(&str, isize); 13] = [
+    ("help", 0),
+    ("open", 1),
+    ("create", 2),
+    ("repl", 3),
+    ("schema", 4),
+    ("lint", 6),
+    ("backup", 7),
+    ("restore", 8),
+    ("dump", 9),
+    ("vacuum", 10),
+    ("fsck", 11),
+    ("stats", 12),
+    ("import", 13),
+];
+
+// Words accepted after `schema`.
+const SCHEMA_SUBCOMMANDS: [(&str, isize); 2] = [("export", 4), ("docs", 5)];
+
+// A misspelled subcommand turns into help for its most likely candidate,
+// carrying the typo and the candidate along as arguments. Anything that
+// is not close to a known subcommand gets the simple help.
+fn unrecognized(word: &str, candidates: &[(&str, isize)]) -> Operation {
+    let names = candidates.iter().map(|(name, _)| *name);
+    match funk::suggest::closest(word, names) {
+        Some(name) => {
+            let (_, op_code) = candidates.iter().find(|(n, _)| *n == name).unwrap();
+            Operation::builder()
+                .mode(Mode::Help(HelpKind::ModeHelp(-2 - op_code)))
+                .args(Args(vec![word.to_string(), name.to_string()]))
+                .build()
+        }
+        None => Operation::default(),
+    }
 }
 
 fn op_repl() -> Operation {
@@ -136,6 +222,22 @@ pub(crate) struct Operation {
     kwargs: Option<Kwargs>,
 }
 
+impl Operation {
+    fn kwarg<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        self.kwargs_all(key).next()
+    }
+
+    // Every value given for `key`, so `--allow a --allow b,c` yields
+    // `a` and `b,c`.
+    fn kwargs_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.kwargs
+            .iter()
+            .flat_map(|Kwargs(kwargs)| kwargs.iter())
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
 #[derive(Default, Debug, Clone, Eq, PartialEq)]
 pub(crate) struct Args(pub Vec<String>);
 
@@ -150,6 +252,16 @@ pub(crate) enum Mode {
     Open,      // Implies REPL
     Create,    // Write file (no REPL)
     EmptyRepl, // Spawn a REPL not attached to any DB file
+    SchemaExport, // Print the schema as JSON Schema, GraphQL or TypeScript
+    SchemaDocs,   // Print Markdown or HTML reference documentation
+    Lint,         // Warn about questionable schema definitions
+    Backup,       // Copy a live database to a backup file
+    Restore,      // Rebuild a database from backups or a dump
+    Dump,         // Write the schema and objects out portably
+    Vacuum,       // Reclaim the space of deleted data
+    Fsck,         // Check a database end to end
+    Stats,        // Report sizes and compression ratios
+    Import,       // Load CSV or JSON Lines rows into a type
 }
 impl Default for Mode {
     fn default() -> Self {
@@ -165,11 +277,21 @@ impl From<Mode> for isize {
             Mode::Open => 1_isize,
             Mode::Create => 2_isize,
             Mode::EmptyRepl => 3_isize,
-            _ => -1_isize,
+            Mode::SchemaExport => 4_isize,
+            Mode::SchemaDocs => 5_isize,
+            Mode::Lint => 6_isize,
+            Mode::Backup => 7_isize,
+            Mode::Restore => 8_isize,
+            Mode::Dump => 9_isize,
+            Mode::Vacuum => 10_isize,
+            Mode::Fsck => 11_isize,
+            Mode::Stats => 12_isize,
+            Mode::Import => 13_isize,
         }
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
 pub(crate) enum HelpKind {
     // Simple command usage
@@ -181,17 +303,27 @@ pub(crate) enum HelpKind {
     // will internally match the argument mode string to its corresponding
     // integer. If this match fails, a negative integer is supplied.
     // This means they probably misspelled the subcommand.
-    // In the future, this allows a more refined client to assign a negative
-    // integer or span of negative integers to a set of most likely candidates
-    // for a particular subcommand that was misspelled, leading to helpful
-    // behavior like: "Did not recognize `relp`. Did you mean `repl`?"
+    // A misspelling close to a known subcommand with code `n` is given
+    // `-2 - n`, so the client can answer with helpful behavior like:
+    // "Did not recognize `relp`. Did you mean `repl`?"
+    // `-1` is left for words that are not close to anything.
     ModeHelp(isize),
 
     // How do I ask for help?
     // <cmd> help help # obviously
+    #[allow(dead_code)]
     HelpHelp,
 }
 
+impl HelpKind {
+    fn misspelled(self) -> HelpKind {
+        match self {
+            HelpKind::ModeHelp(op_code) if op_code >= 0 => HelpKind::ModeHelp(-2 - op_code),
+            other => other,
+        }
+    }
+}
+
 impl<'a> From<&'a str> for HelpKind {
     fn from(it: &'a str) -> HelpKind {
         match it {
@@ -199,25 +331,52 @@ impl<'a> From<&'a str> for HelpKind {
             "open" => HelpKind::ModeHelp(1_isize),
             "create" => HelpKind::ModeHelp(2_isize),
             "repl" => HelpKind::ModeHelp(3_isize),
-            _ => HelpKind::ModeHelp(-1_isize),
+            "schema" => HelpKind::ModeHelp(4_isize),
+            "lint" => HelpKind::ModeHelp(6_isize),
+            "backup" => HelpKind::ModeHelp(7_isize),
+            "restore" => HelpKind::ModeHelp(8_isize),
+            "dump" => HelpKind::ModeHelp(9_isize),
+            "vacuum" => HelpKind::ModeHelp(10_isize),
+            "fsck" => HelpKind::ModeHelp(11_isize),
+            "stats" => HelpKind::ModeHelp(12_isize),
+            "import" => HelpKind::ModeHelp(13_isize),
+            _ => match funk::suggest::closest(it, SUBCOMMANDS.map(|(name, _)| name)) {
+                Some(name) => HelpKind::from(name).misspelled(),
+                None => HelpKind::ModeHelp(-1_isize),
+            },
         }
     }
 }
 
 pub(crate) fn dispatch(op: Operation) -> anyhow::Result<()> {
     match op.mode {
-        Mode::Help(kind) => print_help(/* kind = */ kind),
+        Mode::Help(kind) => print_help(/* kind = */ kind, op.args.as_ref()),
         Mode::HelpHelp => print_helphelp(),
         Mode::EmptyRepl => panic!("Not implemented: REPL"),
         Mode::Create => try_create(op)?,
         Mode::Open => panic!("Not implemented: REPL"),
+        Mode::SchemaExport => try_schema_export(op)?,
+        Mode::SchemaDocs => try_schema_docs(op)?,
+        Mode::Lint => try_lint(op)?,
+        Mode::Backup => try_backup(op)?,
+        Mode::Restore => try_restore(op)?,
+        Mode::Dump => try_dump(op)?,
+        Mode::Vacuum => try_vacuum(op)?,
+        Mode::Fsck => try_fsck(op)?,
+        Mode::Stats => try_stats(op)?,
+        Mode::Import => try_import(op)?,
     };
 
     Ok(())
 }
 
-fn print_help(_kind: HelpKind) {
-    println!("Hello");
+fn print_help(kind: HelpKind, args: Option<&Args>) {
+    match (kind, args) {
+        (HelpKind::ModeHelp(op_code), Some(Args(args))) if op_code <= -2 && args.len() == 2 => {
+            println!("Did not recognize `{}`. Did you mean `{}`?", args[0], args[1]);
+        }
+        _ => println!("Hello"),
+    }
 }
 
 fn print_helphelp() {
@@ -225,12 +384,294 @@ fn print_helphelp() {
 }
 
 fn try_create(op: Operation) -> anyhow::Result<()> {
+    let mut options = FunkDbOptions::default();
+    if let Some(backend) = op.kwarg("backend") {
+        options.backend = Some(backend.parse().map_err(|_| {
+            let backends: Vec<String> = Backend::iter().map(|b| b.to_string()).collect();
+            let hint = did_you_mean(backend, backends.iter().map(String::as_str));
+            anyhow!("Unknown backend `{backend}`.{hint}")
+        })?);
+    }
+    options.compression = compression(&op)?;
     let path = &op.args.unwrap().0[0];
-    let mut db = FunkDb::open(path)?;
+    let mut db = FunkDb::open_with(path, options)?;
     let _ = &mut db.save()?;
     Ok(())
 }
 
+fn try_schema_export(op: Operation) -> anyhow::Result<()> {
+    let Some(format) = op.kwarg("format") else {
+        let formats: Vec<String> = ExportFormat::iter().map(|f| f.to_string()).collect();
+        bail!("Missing `--format`, expected one of: {}", formats.join(", "));
+    };
+    let format: ExportFormat = format.parse().map_err(|_| {
+        let formats: Vec<String> = ExportFormat::iter().map(|f| f.to_string()).collect();
+        let hint = did_you_mean(format, formats.iter().map(String::as_str));
+        anyhow!("Unknown export format `{format}`.{hint}")
+    })?;
+    let Some(path) = op.args.as_ref().and_then(|Args(args)| args.first()) else {
+        bail!("Missing the schema file to export");
+    };
+    let interner = sdl::parse(&std::fs::read_to_string(path)?)?;
+    print!("{}", export::export(&interner, format));
+    Ok(())
+}
+
+fn try_schema_docs(op: Operation) -> anyhow::Result<()> {
+    let format: DocsFormat = match op.kwarg("format") {
+        Some(format) => format.parse().map_err(|_| {
+            let formats: Vec<String> = DocsFormat::iter().map(|f| f.to_string()).collect();
+            let hint = did_you_mean(format, formats.iter().map(String::as_str));
+            anyhow!("Unknown docs format `{format}`.{hint}")
+        })?,
+        None => DocsFormat::Markdown,
+    };
+    let Some(path) = op.args.as_ref().and_then(|Args(args)| args.first()) else {
+        bail!("Missing the schema file to document");
+    };
+    let interner = sdl::parse(&std::fs::read_to_string(path)?)?;
+    print!("{}", docs::render(&interner, format));
+    Ok(())
+}
+
+fn try_lint(op: Operation) -> anyhow::Result<()> {
+    let mut config = LintConfig::default();
+    for (key, level) in [("allow", LintLevel::Allow), ("deny", LintLevel::Deny)] {
+        for rule in op.kwargs_all(key).flat_map(|rules| rules.split(',')) {
+            let rule: LintRule = rule.parse().map_err(|_| {
+                let rules: Vec<String> = LintRule::iter().map(|r| r.to_string()).collect();
+                let hint = did_you_mean(rule, rules.iter().map(String::as_str));
+                anyhow!("Unknown lint rule `{rule}`.{hint}")
+            })?;
+            config = match level {
+                LintLevel::Allow => config.allow(rule),
+                _ => config.deny(rule),
+            };
+        }
+    }
+    let Some(path) = op.args.as_ref().and_then(|Args(args)| args.first()) else {
+        bail!("Missing the schema file to lint");
+    };
+    let interner = sdl::parse(&std::fs::read_to_string(path)?)?;
+    let findings = lint::lint(&interner, &config);
+    for finding in findings.iter() {
+        println!("{finding}");
+    }
+    let denied = findings
+        .iter()
+        .filter(|finding| finding.level == LintLevel::Deny)
+        .count();
+    if denied > 0 {
+        bail!("{denied} denied lint finding(s)");
+    }
+    Ok(())
+}
+
+fn try_backup(op: Operation) -> anyhow::Result<()> {
+    let Some([db, out]) = op.args.as_ref().and_then(|Args(args)| <&[String; 2]>::try_from(args.as_slice()).ok()) else {
+        bail!("Expected the database to back up and the file to write the backup to");
+    };
+    let since = op.kwarg("since").map(Path::new);
+    let backup = backup::to_file(Path::new(db), Path::new(out), since, Key::from_env()?.as_ref())?;
+    let kind = match since {
+        Some(_) => "an incremental",
+        None => "a full",
+    };
+    println!(
+        "Wrote {kind} backup of {db} up to transaction {} ({}) to {out}",
+        backup.tick,
+        FunkValue::datetime(backup.time)
+    );
+    Ok(())
+}
+
+fn try_restore(op: Operation) -> anyhow::Result<()> {
+    let Some(into) = op.kwarg("into") else {
+        bail!("Missing `--into`, the new database to restore to");
+    };
+    let Some(backups) = op.args.as_ref().map(|Args(args)| args).filter(|args| !args.is_empty()) else {
+        bail!("Missing the backups to restore, a full one then any incremental ones after it, or a dump");
+    };
+    let mut magic = [0; backup::MAGIC.len()];
+    let read = std::io::Read::read(&mut File::open(&backups[0])?, &mut magic)?;
+    if &magic[..read] != backup::MAGIC {
+        return restore_dump(&op, backups, into);
+    }
+    let target = match (op.kwarg("txn"), op.kwarg("time")) {
+        (Some(_), Some(_)) => bail!("Restore up to `--txn` or `--time`, not both"),
+        (Some(txn), None) => Target::Txn(txn.parse().map_err(|_| anyhow!("`--txn` takes a transaction id, not `{txn}`"))?),
+        (None, Some(time)) => match FunkValue::parse(funkstd::datetime, time)? {
+            FunkValue::datetime(micros) => Target::Time(micros),
+            _ => unreachable!("datetimes parse to datetimes"),
+        },
+        (None, None) => Target::Latest,
+    };
+    let restored = backup::restore(backups, Path::new(into), target, Key::from_env()?.as_ref())?;
+    println!(
+        "Restored {into} to transaction {} ({})",
+        restored.tick,
+        FunkValue::datetime(restored.time)
+    );
+    Ok(())
+}
+
+// A dump loads into a new database on any backend, as a whole.
+fn restore_dump(op: &Operation, args: &[String], into: &str) -> anyhow::Result<()> {
+    let [path] = args else {
+        bail!("A dump is restored on its own, not along with {}", args[1..].join(", "));
+    };
+    if op.kwarg("txn").is_some() || op.kwarg("time").is_some() {
+        bail!("A dump restores as it was taken, `--txn` and `--time` are for backups");
+    }
+    let db = FunkDb::open(into)?;
+    let summary = dump::load(&db, BufReader::new(File::open(path)?))?;
+    println!("Restored {} objects from {path} into {into}", summary.objects);
+    Ok(())
+}
+
+fn try_dump(op: Operation) -> anyhow::Result<()> {
+    let Some((db, out)) = op.args.as_ref().and_then(|Args(args)| args.split_first()) else {
+        bail!("Missing the database to dump");
+    };
+    let options = FunkDbOptions::builder().read_only().build();
+    let db = FunkDb::open_with(db, options)?;
+    let txn = db.begin();
+    match out {
+        [] => {
+            dump::write(&txn, BufWriter::new(std::io::stdout().lock()))?;
+        }
+        [out] => {
+            let summary = dump::write(&txn, BufWriter::new(File::create(out)?))?;
+            println!("Dumped {} objects of {} to {out}", summary.objects, db.path().display());
+        }
+        _ => bail!("Expected the database to dump and at most one file to write to"),
+    }
+    Ok(())
+}
+
+// Without `--retain` the database keeps the window it was given last;
+// `--retain 0s` stops keeping history.
+fn try_vacuum(op: Operation) -> anyhow::Result<()> {
+    let Some([db]) = op.args.as_ref().and_then(|Args(args)| <&[String; 1]>::try_from(args.as_slice()).ok()) else {
+        bail!("Expected the database to vacuum");
+    };
+    let mut options = FunkDbOptions::default();
+    if let Some(retain) = op.kwarg("retain") {
+        options.retain = Some(expiry::parse_duration(retain)?);
+    }
+    let report = FunkDb::open_with(db, options)?.vacuum()?;
+    println!("Vacuumed {db}: {report}");
+    Ok(())
+}
+
+// Prints the report as JSON, and fails unless the database is clean.
+fn try_fsck(op: Operation) -> anyhow::Result<()> {
+    let Some([db]) = op.args.as_ref().and_then(|Args(args)| <&[String; 1]>::try_from(args.as_slice()).ok()) else {
+        bail!("Expected the database to check");
+    };
+    let repair = match op.kwarg("repair") {
+        Some("indexes") => true,
+        Some(other) => bail!("Only indexes can be repaired, not `{other}`"),
+        None => false,
+    };
+    let options = FunkDbOptions {
+        read_only: !repair,
+        ..FunkDbOptions::default()
+    };
+    let report = match FunkDb::open_with(db, options) {
+        Ok(db) => db.fsck(repair)?,
+        Err(err) => fsck::Report {
+            problems: vec![fsck::Problem::new(fsck::Check::Storage, db, format!("{err:#}"))],
+            ..fsck::Report::default()
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+    if !report.is_clean() {
+        bail!("{} problem(s) found in {db}", report.problems.len());
+    }
+    Ok(())
+}
+
+// `--compress lz4`, for the pages of a `.funk` file.
+fn compression(op: &Operation) -> anyhow::Result<Option<Compression>> {
+    let Some(compression) = op.kwarg("compress") else {
+        return Ok(None);
+    };
+    let compression = compression.parse().map_err(|_| {
+        let known: Vec<String> = Compression::iter().map(|c| c.to_string()).collect();
+        let hint = did_you_mean(compression, known.iter().map(String::as_str));
+        anyhow!("Unknown compression `{compression}`.{hint}")
+    })?;
+    Ok(Some(compression))
+}
+
+// Prints the stats as JSON. With `--compress`, rewrites the pages with it
+// first, which needs the database to itself.
+fn try_stats(op: Operation) -> anyhow::Result<()> {
+    let Some([db]) = op.args.as_ref().and_then(|Args(args)| <&[String; 1]>::try_from(args.as_slice()).ok()) else {
+        bail!("Expected the database to measure");
+    };
+    let compression = compression(&op)?;
+    let options = FunkDbOptions {
+        read_only: compression.is_none(),
+        compression,
+        ..FunkDbOptions::default()
+    };
+    let stats = FunkDb::open_with(db, options)?.stats()?;
+    println!("{}", serde_json::to_string_pretty(&stats.to_json())?);
+    Ok(())
+}
+
+// `--map column=field` and `--key link=property`, any number of each.
+fn pairs(op: &Operation, key: &str) -> anyhow::Result<std::collections::BTreeMap<String, String>> {
+    op.kwargs_all(key)
+        .map(|pair| match pair.split_once('=') {
+            Some((from, to)) => Ok((from.to_string(), to.to_string())),
+            None => bail!("Expected `--{key} a=b`, found `{pair}`"),
+        })
+        .collect()
+}
+
+// Rejected rows go to `--errors`, by default next to the input, which is
+// removed again if none were.
+fn try_import(op: Operation) -> anyhow::Result<()> {
+    let Some([db, file]) = op.args.as_ref().and_then(|Args(args)| <&[String; 2]>::try_from(args.as_slice()).ok()) else {
+        bail!("Expected the database to import into and the file to import");
+    };
+    let Some(type_name) = op.kwarg("type") else {
+        bail!("Missing `--type`, the type to import into");
+    };
+    let format = match op.kwarg("format") {
+        Some(format) => format.parse().map_err(|_| anyhow!("Unknown format `{format}`, expected csv or jsonl"))?,
+        None => import::Format::of(Path::new(file))?,
+    };
+    let batch = match op.kwarg("batch") {
+        Some(batch) => batch.parse().map_err(|_| anyhow!("`--batch` takes a number of rows, not `{batch}`"))?,
+        None => import::BATCH,
+    };
+    let import = Import::builder()
+        .type_name(type_name)
+        .format(format)
+        .map(pairs(&op, "map")?)
+        .keys(pairs(&op, "key")?)
+        .batch(batch)
+        .build();
+    let errors = match op.kwarg("errors") {
+        Some(errors) => errors.to_string(),
+        None => format!("{file}.rejected.jsonl"),
+    };
+    let db = FunkDb::open(db)?;
+    let input = BufReader::new(File::open(file)?);
+    let summary = db.import(&import, input, BufWriter::new(File::create(&errors)?))?;
+    if summary.rejected == 0 {
+        std::fs::remove_file(&errors)?;
+        println!("Imported {} rows of {file} into {}", summary.imported, import.type_name);
+    } else {
+        println!("Imported {} rows of {file} into {}, rejected {} to {errors}", summary.imported, import.type_name, summary.rejected);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod clitest {
     use super::{dispatch, parse_cli, Args, HelpKind, Kwargs, Mode, Operation};
@@ -281,6 +722,184 @@ mod clitest {
         assert!(works("new --help"));
     }
 
+    #[test]
+    fn cli_parses_schema_export() {
+        use std::ffi::OsString;
+        let given: Vec<OsString> = "schema export --format graphql schema.esdl"
+            .split(' ')
+            .map(OsString::from)
+            .collect();
+        let actual = parse_cli(given);
+        assert_eq!(actual.mode, Mode::SchemaExport);
+        assert_eq!(actual.args, Some(Args(vec![String::from("schema.esdl")])));
+        assert_eq!(actual.kwarg("format"), Some("graphql"));
+
+        let given: Vec<OsString> = "schema export --format=typescript schema.esdl"
+            .split(' ')
+            .map(OsString::from)
+            .collect();
+        assert_eq!(parse_cli(given).kwarg("format"), Some("typescript"));
+
+        let given: Vec<OsString> = "schema docs schema.esdl".split(' ').map(OsString::from).collect();
+        assert_eq!(parse_cli(given).mode, Mode::SchemaDocs);
+    }
+
+    #[test]
+    fn cli_parses_lint() {
+        use std::ffi::OsString;
+        let given: Vec<OsString> = "lint --allow shadows-std --allow=a,b schema.esdl"
+            .split(' ')
+            .map(OsString::from)
+            .collect();
+        let actual = parse_cli(given);
+        assert_eq!(actual.mode, Mode::Lint);
+        assert_eq!(actual.args, Some(Args(vec![String::from("schema.esdl")])));
+        assert_eq!(actual.kwargs_all("allow").collect::<Vec<_>>(), vec!["shadows-std", "a,b"]);
+    }
+
+    #[test]
+    fn cli_parses_backup_and_restore() {
+        use std::ffi::OsString;
+        let parse = |given: &str| parse_cli(given.split(' ').map(OsString::from).collect());
+
+        let backup = parse("backup live.funk full.bak");
+        assert_eq!(backup.mode, Mode::Backup);
+        assert_eq!(backup.args, Some(Args(vec![String::from("live.funk"), String::from("full.bak")])));
+        assert_eq!(parse("backup live.funk more.bak --since full.bak").kwarg("since"), Some("full.bak"));
+
+        let restore = parse("restore full.bak more.bak --into new.funk --time 2024-05-01T12:00:00Z");
+        assert_eq!(restore.mode, Mode::Restore);
+        assert_eq!(restore.args, Some(Args(vec![String::from("full.bak"), String::from("more.bak")])));
+        assert_eq!(restore.kwarg("into"), Some("new.funk"));
+        assert_eq!(restore.kwarg("time"), Some("2024-05-01T12:00:00Z"));
+        assert!(dispatch(parse("restore full.bak --into new.funk --txn 3 --time 2024-05-01T12:00:00Z")).is_err());
+        assert_eq!(HelpKind::from("restroe"), HelpKind::ModeHelp(-10));
+    }
+
+    /// A path in the temporary directory no other test, or run, uses, like
+    /// the library's own scratch databases.
+    fn scratch_path(name: &str) -> std::path::PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("funkdb-{}-{n}-{name}", std::process::id()))
+    }
+
+    /// Removes a scratch file and whatever lies beside it: its log, the log
+    /// a rewrite left, and its lock.
+    fn remove_scratch(path: &std::path::Path) {
+        use funk::storage::{lock, wal};
+        let log = wal::path_for(path);
+        for path in [path.to_path_buf(), wal::next_path_for(&log), log, lock::path_for(path)] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn parse(given: &str) -> Operation {
+        use std::ffi::OsString;
+        parse_cli(given.split(' ').map(OsString::from).collect())
+    }
+
+    #[test]
+    fn vacuums_a_database() -> anyhow::Result<()> {
+        let db = scratch_path("vacuum.funk");
+        dispatch(parse(&format!("create {}", db.display())))?;
+        let vacuum = parse(&format!("vacuum {} --retain 1h", db.display()));
+        assert_eq!(vacuum.mode, Mode::Vacuum);
+        assert_eq!(vacuum.kwarg("retain"), Some("1h"));
+        dispatch(vacuum)?;
+        dispatch(parse(&format!("vacuum {}", db.display())))?;
+        assert!(dispatch(parse(&format!("vacuum {} --retain never", db.display()))).is_err());
+        assert_eq!(HelpKind::from("vacume"), HelpKind::ModeHelp(-12));
+        remove_scratch(&db);
+        Ok(())
+    }
+
+    #[test]
+    fn checks_and_repairs_a_database() -> anyhow::Result<()> {
+        let db = scratch_path("fsck.funk");
+        dispatch(parse(&format!("create {}", db.display())))?;
+        let fsck = parse(&format!("fsck {}", db.display()));
+        assert_eq!(fsck.mode, Mode::Fsck);
+        dispatch(fsck)?;
+        dispatch(parse(&format!("fsck {} --repair indexes", db.display())))?;
+        assert!(dispatch(parse(&format!("fsck {} --repair objects", db.display()))).is_err());
+        std::fs::write(&db, b"not a database")?;
+        assert!(dispatch(parse(&format!("fsck {}", db.display()))).is_err());
+        remove_scratch(&db);
+        Ok(())
+    }
+
+    #[test]
+    fn reports_on_a_database() -> anyhow::Result<()> {
+        let db = scratch_path("stats.funk");
+        dispatch(parse(&format!("create {}", db.display())))?;
+        let stats = parse(&format!("stats {} --compress zstd", db.display()));
+        assert_eq!(stats.mode, Mode::Stats);
+        dispatch(stats)?;
+        dispatch(parse(&format!("stats {}", db.display())))?;
+        assert!(dispatch(parse(&format!("stats {} --compress zip", db.display()))).is_err());
+        remove_scratch(&db);
+        Ok(())
+    }
+
+    #[test]
+    fn imports_rows_into_a_type() -> anyhow::Result<()> {
+        let (db, rows) = (scratch_path("import.funk"), scratch_path("import.csv"));
+        let errors = std::path::PathBuf::from(format!("{}.rejected.jsonl", rows.display()));
+        {
+            let db = funk::FunkDb::open(&db)?;
+            db.set_catalog("module default { type Person { required name: str; } type Pet { name: str; owner: Person; } }")?;
+            db.insert(funk::object::Object::new("Person").set("name", "ann"))?;
+        }
+        std::fs::write(&rows, "pet,owner\nrex,ann\ntom,bob\n")?;
+        let import = parse(&format!("import {} {} --type Pet --map pet=name --key owner=name --batch 1", db.display(), rows.display()));
+        assert_eq!(import.mode, Mode::Import);
+        assert_eq!(import.kwarg("key"), Some("owner=name"));
+        dispatch(import)?;
+        assert_eq!(std::fs::read_to_string(&errors)?.lines().count(), 1);
+        std::fs::write(&rows, "pet\nfelix\n")?;
+        dispatch(parse(&format!("import {} {} --type Pet --map pet=name", db.display(), rows.display())))?;
+        assert!(!errors.exists());
+        assert_eq!(funk::FunkDb::open(&db)?.objects("Pet")?.len(), 2);
+        assert!(dispatch(parse(&format!("import {} {} --type Pet --map pet", db.display(), rows.display()))).is_err());
+        assert!(dispatch(parse(&format!("import {} {}", db.display(), rows.display()))).is_err());
+        for path in [&db, &rows, &errors] {
+            remove_scratch(path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dumps_restore_into_a_new_database() -> anyhow::Result<()> {
+        let (db, out, into) = (scratch_path("dump.funk"), scratch_path("dump.jsonl"), scratch_path("dump-restored.funk"));
+        dispatch(parse(&format!("create {}", db.display())))?;
+        dispatch(parse(&format!("dump {} {}", db.display(), out.display())))?;
+        assert!(dispatch(parse(&format!("restore {} --into {} --txn 1", out.display(), into.display()))).is_err());
+        dispatch(parse(&format!("restore {} --into {}", out.display(), into.display())))?;
+        assert!(into.exists());
+        for path in [&db, &out, &into] {
+            remove_scratch(path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn suggests_misspelled_subcommands() {
+        use std::ffi::OsString;
+        let parse = |given: &str| parse_cli(given.split(' ').map(OsString::from).collect());
+
+        let expected_op = Operation::builder()
+            .mode(Mode::Help(HelpKind::ModeHelp(-2 - isize::from(Mode::EmptyRepl))))
+            .args(Args(vec![String::from("relp"), String::from("repl")]))
+            .build();
+        assert_eq!(parse("relp"), expected_op);
+        assert_eq!(parse("crate test.funk").args.unwrap().0[1], "create");
+        assert_eq!(parse("schema exprot --format graphql x.esdl").args.unwrap().0[1], "export");
+        assert_eq!(HelpKind::from("lnit"), HelpKind::ModeHelp(-8));
+        assert_eq!(HelpKind::from("zzzz"), HelpKind::ModeHelp(-1));
+    }
+
     #[ignore]
     #[test]
     fn spawn_repl() {
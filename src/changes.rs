@@ -0,0 +1,291 @@
+//! The change feed: every insert, update, delete and expiry of the objects
+//! of a type, in commit order.
+//!
+//! Changes to objects of `module::Type` are appended to the tree
+//! `changes/module::Type` by the transaction making them, so they commit
+//! or roll back along with it, and are keyed by their [`Position`]: the
+//! tick of that commit, which doubles as its transaction id, then their
+//! order within it. Each value is
+//!
+//! ```text
+//! op (1 byte) | object id (16 bytes) | old record | new record
+//! ```
+//!
+//! where a record is `0x00` when there is none, as for the old record of
+//! an insert, or `0x01`, a varint length and [`Object::encode`].
+//!
+//! A [`Position`] prints as `tick.seq` and parses back, so a consumer can
+//! keep the last one it handled and carry on [`read`]ing after it, from
+//! the API or from a [`FunkDbServer`](crate::FunkDbServer) socket.
+use crate::codec::{self, Cursor};
+use crate::object::{self, Object};
+use crate::storage::{Snapshot, Transaction};
+use crate::Interner;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub fn tree(type_name: &str) -> String {
+    format!("changes/{type_name}")
+}
+
+/// Where a change sits in the feed of its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    /// The tick of the commit that made the change.
+    pub tick: u64,
+    /// The change's place among those of the same commit.
+    pub seq: u32,
+}
+
+impl Position {
+    pub fn to_key(self) -> Vec<u8> {
+        [self.tick.to_be_bytes().as_slice(), &self.seq.to_be_bytes()].concat()
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<Self> {
+        let Ok(key) = <[u8; 12]>::try_from(key) else {
+            bail!("A change feed key has 12 bytes, not {}", key.len());
+        };
+        Ok(Self {
+            tick: u64::from_be_bytes(key[..8].try_into()?),
+            seq: u32::from_be_bytes(key[8..].try_into()?),
+        })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.tick, self.seq)
+    }
+}
+
+impl FromStr for Position {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("`{text}` is not a change feed position, those look like `12.0`");
+        let (tick, seq) = text.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            tick: tick.parse().map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Insert,
+    Update,
+    Delete,
+    /// Deleted for having outlived its type's `expire` clause.
+    Expire,
+}
+
+impl Op {
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Self::Insert),
+            1 => Ok(Self::Update),
+            2 => Ok(Self::Delete),
+            3 => Ok(Self::Expire),
+            byte => bail!("Unknown change {byte}"),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Insert => "insert",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::Expire => "expire",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub position: Position,
+    /// The object's `__type__`, as `module::Type`.
+    pub type_name: String,
+    pub op: Op,
+    pub id: Uuid,
+    /// The object before the change, unless it is an insert.
+    pub old: Option<Object>,
+    /// The object after the change, if it is an insert or an update.
+    pub new: Option<Object>,
+}
+
+impl Change {
+    /// The id of the transaction that made the change: its commit's tick.
+    pub fn txn(&self) -> u64 {
+        self.position.tick
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "position": self.position.to_string(),
+            "txn": self.txn(),
+            "type": self.type_name,
+            "op": self.op.to_string(),
+            "id": self.id.to_string(),
+            "old": self.old.as_ref().map(Object::to_json),
+            "new": self.new.as_ref().map(Object::to_json),
+        })
+    }
+}
+
+fn put_record(out: &mut Vec<u8>, object: Option<&Object>) {
+    let Some(object) = object else {
+        out.push(0x00);
+        return;
+    };
+    let record = object.encode();
+    out.push(0x01);
+    codec::put_varint(out, record.len() as u128);
+    out.extend(record);
+}
+
+fn take_record(cursor: &mut Cursor, id: Uuid) -> Result<Option<Object>> {
+    match cursor.byte()? {
+        0x00 => Ok(None),
+        0x01 => {
+            let len = usize::try_from(cursor.varint()?)?;
+            Ok(Some(Object::decode(id, cursor.take(len)?)?))
+        }
+        byte => bail!("Bad marker {byte:#04x} before a record in the change feed"),
+    }
+}
+
+/// Appends a change to the feed of the type of `old` or `new`, whichever
+/// there is.
+pub(crate) fn record(txn: &mut Transaction, op: Op, old: Option<&Object>, new: Option<&Object>) {
+    let object = new.or(old).expect("a change to some object");
+    let mut value = vec![op as u8];
+    value.extend(object.id.as_bytes());
+    put_record(&mut value, old);
+    put_record(&mut value, new);
+    txn.append(&tree(&object.type_name), value);
+}
+
+fn decode(type_name: &str, key: &[u8], value: &[u8]) -> Result<Change> {
+    let mut cursor = Cursor::new(value);
+    let op = Op::from_byte(cursor.byte()?)?;
+    let id = Uuid::from_slice(cursor.take(16)?)?;
+    let old = take_record(&mut cursor, id)?;
+    let new = take_record(&mut cursor, id)?;
+    if !cursor.is_empty() {
+        bail!("Trailing bytes after a change to {id}");
+    }
+    Ok(Change {
+        position: Position::from_key(key)?,
+        type_name: type_name.to_string(),
+        op,
+        id,
+        old,
+        new,
+    })
+}
+
+/// The changes to objects of `type_name` and the types extending it, in
+/// the order they were committed, starting after `after` if given and
+/// stopping at `limit` of them.
+pub fn read(
+    txn: &(impl Snapshot + ?Sized),
+    schema: &Interner,
+    type_name: &str,
+    after: Option<Position>,
+    limit: usize,
+) -> Result<Vec<Change>> {
+    let (type_name, _) = object::resolve(schema, type_name)?;
+    let start = after.map_or(Bound::Unbounded, |position| Bound::Excluded(position.to_key()));
+    let mut changes = vec![];
+    for subtype in object::subtypes(schema, &type_name) {
+        let pairs = txn.scan(&tree(&subtype), (start.as_ref().map(Vec::as_slice), Bound::Unbounded))?;
+        // Each feed is in order already, so only its first `limit` can
+        // make the cut.
+        for (key, value) in pairs.into_iter().take(limit) {
+            changes.push(decode(&subtype, &key, &value)?);
+        }
+    }
+    changes.sort_by_key(|change| change.position);
+    changes.truncate(limit);
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+    use crate::FunkDb;
+
+    const SCHEMA: &str = "
+        module default {
+            abstract type Named { required name: str; }
+            type FunksGiven extending Named { required expires: int32; }
+            type ReasonForLiving extending Named { multi funks: FunksGiven; }
+        }
+    ";
+
+    #[test]
+    fn positions_print_and_parse() -> Result<()> {
+        let position = Position { tick: 12, seq: 3 };
+        assert_eq!(position.to_string().parse::<Position>()?, position);
+        assert_eq!(Position::from_key(&position.to_key())?, position);
+        assert!(Position { tick: 2, seq: 9 }.to_key() < position.to_key());
+        for bad in ["12", "a.0", "12.-1", ""] {
+            assert!(bad.parse::<Position>().is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn feeds_survive_and_resume() -> Result<()> {
+        let path = scratch_path("changes.funk");
+        let db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        let given = db.insert(Object::new("FunksGiven").set("name", "rent").set("expires", 30))?;
+        let reason = db.insert(Object::new("ReasonForLiving").set("name", "sun").link("funks", given))?;
+        // Deleting `given` updates `reason` in the same transaction.
+        db.delete(given)?;
+        let mut rolled_back = db.begin();
+        let schema = FunkDb::schema_in(&rolled_back)?;
+        object::insert(&mut rolled_back, &schema, Object::new("FunksGiven").set("name", "x").set("expires", 1))?;
+        drop(rolled_back);
+
+        let all = db.changes("Named", None, usize::MAX)?;
+        let summary: Vec<_> = all.iter().map(|change| (change.op, change.id)).collect();
+        assert_eq!(summary, [(Op::Insert, given), (Op::Insert, reason), (Op::Update, reason), (Op::Delete, given)]);
+        assert_eq!(all[2].txn(), all[3].txn());
+        assert_eq!(all[2].old.as_ref().unwrap().targets("funks"), [given]);
+        assert!(all[2].new.as_ref().unwrap().targets("funks").is_empty());
+        assert_eq!(all[3].old.as_ref().unwrap().get("expires"), Some(&crate::value::FunkValue::int32(30)));
+        assert!(all[0].old.is_none() && all[3].new.is_none());
+        assert_eq!(all[0].to_json()["new"]["expires"], json!([30]));
+        assert_eq!(db.changes("FunksGiven", None, usize::MAX)?.len(), 2);
+
+        // A consumer picks up after the last change it handled, even once
+        // the database was closed in between.
+        let first = db.changes("Named", None, 2)?;
+        assert_eq!(first, all[..2]);
+        drop(db);
+        let db = FunkDb::open(&path)?;
+        let cursor = first.last().unwrap().position;
+        assert_eq!(db.changes("Named", Some(cursor), usize::MAX)?, all[2..]);
+        let again = db.insert(Object::new("FunksGiven").set("name", "gas").set("expires", 7))?;
+        let newer = db.changes("Named", Some(all[3].position), usize::MAX)?;
+        assert_eq!(newer.len(), 1);
+        assert_eq!((newer[0].op, newer[0].id), (Op::Insert, again));
+        assert!(newer[0].position > all[3].position);
+        drop(db);
+        let _ = std::fs::remove_file(crate::storage::wal::path_for(&path));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+//! Byte encodings of [`FunkValue`]s.
+//!
+//! There are two of them:
+//!
+//! * [`encode`]/[`decode`] is the compact, self-describing form values
+//!   are stored in: a tag byte naming the `funkstd` kind, then integers as
+//!   LEB128 varints (zigzagged when signed) and strings length-prefixed.
+//! * [`encode_key`]/[`decode_key`] is the order-preserving form for index
+//!   and range keys: comparing two encoded values bytewise gives the same
+//!   answer as comparing the values. Integers are big-endian with the sign
+//!   bit flipped, and strings escape `0x00` as `0x00 0xff` and end with
+//!   `0x00 0x01`, so every encoding is self-delimiting and concatenating
+//!   them ([`encode_composite`]) orders tuples lexicographically.
+//!
+//! Vectors are stored as a length and little-endian `f32`s, and as keys
+//! each component is `0x01` and the float's bits made to sort, with
+//! `0x00` at the end.
+//!
+//! Key encodings carry no tag; the reader has to know the kinds, which
+//! for an index the schema always does.
+use crate::funkstd;
+use crate::value::FunkValue;
+use anyhow::{bail, Result};
+use strum::IntoEnumIterator;
+
+fn tag(kind: funkstd) -> u8 {
+    funkstd::iter().position(|other| other == kind).unwrap() as u8
+}
+
+pub fn put_varint(out: &mut Vec<u8>, mut n: u128) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn zigzag(n: i128) -> u128 {
+    ((n << 1) ^ (n >> 127)) as u128
+}
+
+fn unzigzag(n: u128) -> i128 {
+    ((n >> 1) as i128) ^ -((n & 1) as i128)
+}
+
+/// Appends the compact form of `value` to `out`.
+pub fn encode_into(out: &mut Vec<u8>, value: &FunkValue) {
+    out.push(tag(value.kind()));
+    match value {
+        FunkValue::bool(v) => out.push(u8::from(*v)),
+        FunkValue::int8(v) => put_varint(out, zigzag((*v).into())),
+        FunkValue::int16(v) => put_varint(out, zigzag((*v).into())),
+        FunkValue::int32(v) => put_varint(out, zigzag((*v).into())),
+        FunkValue::int64(v) => put_varint(out, zigzag((*v).into())),
+        FunkValue::int128(v) => put_varint(out, zigzag(*v)),
+        FunkValue::str(v) => {
+            put_varint(out, v.len() as u128);
+            out.extend(v.as_bytes());
+        }
+        FunkValue::uint8(v) => put_varint(out, (*v).into()),
+        FunkValue::uint16(v) => put_varint(out, (*v).into()),
+        FunkValue::uint32(v) => put_varint(out, (*v).into()),
+        FunkValue::uint64(v) => put_varint(out, (*v).into()),
+        FunkValue::uint128(v) => put_varint(out, *v),
+        FunkValue::vector(v) => {
+            put_varint(out, v.len() as u128);
+            for x in v {
+                out.extend(x.to_le_bytes());
+            }
+        }
+        FunkValue::datetime(v) => put_varint(out, zigzag((*v).into())),
+    }
+}
+
+pub fn encode(value: &FunkValue) -> Vec<u8> {
+    let mut out = vec![];
+    encode_into(&mut out, value);
+    out
+}
+
+/// Reads bytes front to back, failing instead of panicking when they
+/// run out.
+pub struct Cursor<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            bail!("Expected {n} more bytes, found {}", self.bytes.len());
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn varint(&mut self) -> Result<u128> {
+        let mut n = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = self.byte()?;
+            n |= u128::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        bail!("Varint is longer than 128 bits")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// Reads one compactly encoded value off the front of `cursor`.
+pub fn decode_from(cursor: &mut Cursor) -> Result<FunkValue> {
+    let tag = cursor.byte()?;
+    let Some(kind) = funkstd::iter().nth(tag.into()) else {
+        bail!("Unknown value tag {tag}");
+    };
+    macro_rules! signed {
+        ($variant:ident) => {
+            FunkValue::$variant(unzigzag(cursor.varint()?).try_into()?)
+        };
+    }
+    macro_rules! unsigned {
+        ($variant:ident) => {
+            FunkValue::$variant(cursor.varint()?.try_into()?)
+        };
+    }
+    let value = match kind {
+        funkstd::bool => match cursor.byte()? {
+            0 => FunkValue::bool(false),
+            1 => FunkValue::bool(true),
+            byte => bail!("{byte} is not a bool"),
+        },
+        funkstd::int8 => signed!(int8),
+        funkstd::int16 => signed!(int16),
+        funkstd::int32 => signed!(int32),
+        funkstd::int64 => signed!(int64),
+        funkstd::int128 => FunkValue::int128(unzigzag(cursor.varint()?)),
+        funkstd::str => {
+            let len = usize::try_from(cursor.varint()?)?;
+            FunkValue::str(String::from_utf8(cursor.take(len)?.to_vec())?)
+        }
+        funkstd::uint8 => unsigned!(uint8),
+        funkstd::uint16 => unsigned!(uint16),
+        funkstd::uint32 => unsigned!(uint32),
+        funkstd::uint64 => unsigned!(uint64),
+        funkstd::uint128 => FunkValue::uint128(cursor.varint()?),
+        funkstd::vector => {
+            let len = usize::try_from(cursor.varint()?)?;
+            let bytes = cursor.take(len.saturating_mul(4))?;
+            FunkValue::vector(bytes.chunks_exact(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect())
+        }
+        funkstd::datetime => signed!(datetime),
+    };
+    Ok(value)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<FunkValue> {
+    let mut cursor = Cursor::new(bytes);
+    let value = decode_from(&mut cursor)?;
+    if !cursor.is_empty() {
+        bail!("{} trailing bytes after {value}", cursor.bytes.len());
+    }
+    Ok(value)
+}
+
+/// Appends the order-preserving form of `value` to `out`.
+pub fn encode_key_into(out: &mut Vec<u8>, value: &FunkValue) {
+    match value {
+        FunkValue::bool(v) => out.push(u8::from(*v)),
+        FunkValue::int8(v) => out.extend((*v as u8 ^ 0x80).to_be_bytes()),
+        FunkValue::int16(v) => out.extend((*v as u16 ^ (1 << 15)).to_be_bytes()),
+        FunkValue::int32(v) => out.extend((*v as u32 ^ (1 << 31)).to_be_bytes()),
+        FunkValue::int64(v) => out.extend((*v as u64 ^ (1 << 63)).to_be_bytes()),
+        FunkValue::int128(v) => out.extend((*v as u128 ^ (1 << 127)).to_be_bytes()),
+        FunkValue::str(v) => {
+            for byte in v.bytes() {
+                match byte {
+                    0 => out.extend([0x00, 0xff]),
+                    byte => out.push(byte),
+                }
+            }
+            out.extend([0x00, 0x01]);
+        }
+        FunkValue::uint8(v) => out.extend(v.to_be_bytes()),
+        FunkValue::uint16(v) => out.extend(v.to_be_bytes()),
+        FunkValue::uint32(v) => out.extend(v.to_be_bytes()),
+        FunkValue::uint64(v) => out.extend(v.to_be_bytes()),
+        FunkValue::uint128(v) => out.extend(v.to_be_bytes()),
+        FunkValue::vector(v) => {
+            for x in v {
+                // Negative floats sort backwards as bits, positive ones
+                // need to come after them.
+                let bits = x.to_bits();
+                let bits = match bits >> 31 {
+                    1 => !bits,
+                    _ => bits ^ (1 << 31),
+                };
+                out.push(0x01);
+                out.extend(bits.to_be_bytes());
+            }
+            out.push(0x00);
+        }
+        FunkValue::datetime(v) => out.extend((*v as u64 ^ (1 << 63)).to_be_bytes()),
+    }
+}
+
+pub fn encode_key(value: &FunkValue) -> Vec<u8> {
+    let mut out = vec![];
+    encode_key_into(&mut out, value);
+    out
+}
+
+/// Concatenated keys of `values`; sorts like the tuple of the values.
+pub fn encode_composite(values: &[FunkValue]) -> Vec<u8> {
+    let mut out = vec![];
+    for value in values {
+        encode_key_into(&mut out, value);
+    }
+    out
+}
+
+/// Reads one key of `kind` off the front of `cursor`.
+pub fn decode_key_from(cursor: &mut Cursor, kind: funkstd) -> Result<FunkValue> {
+    macro_rules! fixed {
+        ($variant:ident, $uint:ty, $int:ty) => {{
+            let bits = <$uint>::from_be_bytes(cursor.take(std::mem::size_of::<$uint>())?.try_into()?);
+            FunkValue::$variant((bits ^ (1 << (<$uint>::BITS - 1))) as $int)
+        }};
+        ($variant:ident, $uint:ty) => {
+            FunkValue::$variant(<$uint>::from_be_bytes(cursor.take(std::mem::size_of::<$uint>())?.try_into()?))
+        };
+    }
+    let value = match kind {
+        funkstd::bool => match cursor.byte()? {
+            0 => FunkValue::bool(false),
+            1 => FunkValue::bool(true),
+            byte => bail!("{byte} is not a bool"),
+        },
+        funkstd::int8 => fixed!(int8, u8, i8),
+        funkstd::int16 => fixed!(int16, u16, i16),
+        funkstd::int32 => fixed!(int32, u32, i32),
+        funkstd::int64 => fixed!(int64, u64, i64),
+        funkstd::int128 => fixed!(int128, u128, i128),
+        funkstd::str => {
+            let mut bytes = vec![];
+            loop {
+                match cursor.byte()? {
+                    0 => match cursor.byte()? {
+                        0xff => bytes.push(0),
+                        0x01 => break,
+                        byte => bail!("Bad escape 0x00 {byte:#04x} in a str key"),
+                    },
+                    byte => bytes.push(byte),
+                }
+            }
+            FunkValue::str(String::from_utf8(bytes)?)
+        }
+        funkstd::uint8 => fixed!(uint8, u8),
+        funkstd::uint16 => fixed!(uint16, u16),
+        funkstd::uint32 => fixed!(uint32, u32),
+        funkstd::uint64 => fixed!(uint64, u64),
+        funkstd::uint128 => fixed!(uint128, u128),
+        funkstd::vector => {
+            let mut v = vec![];
+            loop {
+                match cursor.byte()? {
+                    0x00 => break,
+                    0x01 => {
+                        let bits = u32::from_be_bytes(cursor.take(4)?.try_into()?);
+                        let bits = match bits >> 31 {
+                            1 => bits ^ (1 << 31),
+                            _ => !bits,
+                        };
+                        v.push(f32::from_bits(bits));
+                    }
+                    byte => bail!("Bad marker {byte:#04x} in a vector key"),
+                }
+            }
+            FunkValue::vector(v)
+        }
+        funkstd::datetime => fixed!(datetime, u64, i64),
+    };
+    Ok(value)
+}
+
+pub fn decode_key(kind: funkstd, bytes: &[u8]) -> Result<FunkValue> {
+    decode_composite(&[kind], bytes).map(|mut values| values.remove(0))
+}
+
+pub fn decode_composite(kinds: &[funkstd], bytes: &[u8]) -> Result<Vec<FunkValue>> {
+    let mut cursor = Cursor::new(bytes);
+    let values = kinds
+        .iter()
+        .map(|kind| decode_key_from(&mut cursor, *kind))
+        .collect::<Result<_>>()?;
+    if !cursor.is_empty() {
+        bail!("{} trailing bytes after the key", cursor.bytes.len());
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn any_value() -> impl Strategy<Value = FunkValue> {
+        prop_oneof![
+            any::<bool>().prop_map(FunkValue::bool),
+            any::<i8>().prop_map(FunkValue::int8),
+            any::<i16>().prop_map(FunkValue::int16),
+            any::<i32>().prop_map(FunkValue::int32),
+            any::<i64>().prop_map(FunkValue::int64),
+            any::<i128>().prop_map(FunkValue::int128),
+            any::<String>().prop_map(FunkValue::str),
+            // Strings made of the bytes around the escape are the tricky ones.
+            "[\\x00\\x01\\xff a]{0,6}".prop_map(FunkValue::str),
+            any::<u8>().prop_map(FunkValue::uint8),
+            any::<u16>().prop_map(FunkValue::uint16),
+            any::<u32>().prop_map(FunkValue::uint32),
+            any::<u64>().prop_map(FunkValue::uint64),
+            any::<u128>().prop_map(FunkValue::uint128),
+            // Eighths stay exact and never give `-0.0`, which would equal
+            // `0.0` but not encode like it.
+            prop::collection::vec(-64i16..64, 0..4)
+                .prop_map(|v| FunkValue::vector(v.into_iter().map(|x| f32::from(x) / 8.0).collect())),
+            any::<i64>().prop_map(FunkValue::datetime),
+        ]
+    }
+
+    /// Two values of the same kind.
+    fn same_kind_pair() -> impl Strategy<Value = (FunkValue, FunkValue)> {
+        any_value().prop_flat_map(|a| {
+            let kind = a.kind();
+            (Just(a), any_value().prop_filter("same kind", move |b| b.kind() == kind))
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(512))]
+
+        #[test]
+        fn compact_round_trips(value in any_value()) {
+            prop_assert_eq!(decode(&encode(&value)).unwrap(), value);
+        }
+
+        #[test]
+        fn json_round_trips(value in any_value()) {
+            prop_assert_eq!(FunkValue::from_json(value.kind(), &value.to_json()).unwrap(), value);
+        }
+
+        #[test]
+        fn key_round_trips(value in any_value()) {
+            prop_assert_eq!(decode_key(value.kind(), &encode_key(&value)).unwrap(), value);
+        }
+
+        #[test]
+        fn keys_sort_like_values((a, b) in same_kind_pair()) {
+            prop_assert_eq!(encode_key(&a).cmp(&encode_key(&b)), a.partial_cmp(&b).unwrap());
+        }
+
+        #[test]
+        fn composite_keys_sort_like_tuples(
+            a in (any::<i32>(), "[\\x00a-c]{0,4}", any::<bool>()),
+            b in (any::<i32>(), "[\\x00a-c]{0,4}", any::<bool>()),
+        ) {
+            let encode = |(n, s, flag): &(i32, String, bool)| {
+                encode_composite(&[FunkValue::int32(*n), FunkValue::str(s.clone()), FunkValue::bool(*flag)])
+            };
+            prop_assert_eq!(encode(&a).cmp(&encode(&b)), a.cmp(&b));
+            let kinds = [funkstd::int32, funkstd::str, funkstd::bool];
+            let decoded = decode_composite(&kinds, &encode(&a)).unwrap();
+            prop_assert_eq!(decoded[1].clone(), FunkValue::str(a.1.clone()));
+        }
+
+        #[test]
+        fn string_keys_compose((a, b) in ("[\\x00a]{0,4}", "[\\x00a]{0,4}"), (c, d) in ("[\\x00a]{0,4}", "[\\x00a]{0,4}")) {
+            let key = |x: &str, y: &str| encode_composite(&[FunkValue::from(x), FunkValue::from(y)]);
+            prop_assert_eq!(key(&a, &b).cmp(&key(&c, &d)), (&a, &b).cmp(&(&c, &d)));
+        }
+    }
+
+    #[test]
+    fn compact_and_malformed() {
+        assert_eq!(encode(&FunkValue::int64(-1)), vec![4, 1]);
+        assert_eq!(encode(&FunkValue::uint128(u128::MAX)).len(), 1 + 19);
+        assert!(decode(&[0, 2]).is_err());
+        assert!(decode(&[3, 0x80]).is_err());
+        assert!(decode(&[99]).is_err());
+        assert!(decode_key(funkstd::str, b"a\x00\x02").is_err());
+        assert!(decode_key(funkstd::int32, &[0, 0]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Entity-relationship diagrams of the committed schema, as Graphviz DOT
+//! or Mermaid text. Nothing here renders pictures; pipe the output into
+//! `dot -Tsvg` or paste it into a Mermaid block.
+//!
+//! Every [`FunkTy`] becomes a node listing its properties, every
+//! [`FunkLinkMap`] entry becomes an edge labelled with its cardinality
+//! and every module becomes a cluster.
+use crate::export::{flat_name, link_target};
+use crate::{FunkLinkMap, FunkTy, Interner, Named};
+use std::collections::BTreeMap;
+use strum::{Display, EnumIter, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter)]
+pub enum DiagramFormat {
+    #[strum(serialize = "dot")]
+    Dot,
+    #[strum(serialize = "mermaid")]
+    Mermaid,
+}
+
+pub fn render(interner: &Interner, format: DiagramFormat) -> String {
+    match format {
+        DiagramFormat::Dot => dot(interner),
+        DiagramFormat::Mermaid => mermaid(interner),
+    }
+}
+
+pub(crate) fn cardinality(required: bool, is_multi: bool) -> &'static str {
+    match (required, is_multi) {
+        (true, true) => "multi, required",
+        (false, true) => "multi, optional",
+        (true, false) => "single, required",
+        (false, false) => "single, optional",
+    }
+}
+
+/// UML-style multiplicity, as Mermaid wants it on either end of an edge.
+fn multiplicity(required: bool, is_multi: bool) -> &'static str {
+    match (required, is_multi) {
+        (true, true) => "1..*",
+        (false, true) => "0..*",
+        (true, false) => "1",
+        (false, false) => "0..1",
+    }
+}
+
+fn member_lines(funk_ty: &FunkTy) -> Vec<String> {
+    funk_ty
+        .properties
+        .iter()
+        .map(|(name, (_, required, is_multi))| {
+            let mut line = String::new();
+            if *required {
+                line.push_str("required ");
+            }
+            if *is_multi {
+                line.push_str("multi ");
+            }
+            line.push_str(&format!("{name}: {}", funk_ty.property_type(name).unwrap()));
+            line
+        })
+        .collect()
+}
+
+fn by_module<'a, 'i>(interner: &'a Interner<'i>) -> BTreeMap<&'a str, Vec<&'a FunkTy<'i>>> {
+    let mut modules: BTreeMap<&str, Vec<&FunkTy>> = BTreeMap::new();
+    for (module, funk_ty) in interner.types() {
+        modules.entry(module).or_default().push(funk_ty);
+    }
+    modules
+}
+
+fn dot_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn dot(interner: &Interner) -> String {
+    let mut out = String::from("digraph schema {\n  rankdir=LR;\n  node [shape=record];\n");
+    let mut edges = vec![];
+    for (module, types) in by_module(interner) {
+        out.push_str(&format!("\n  subgraph \"cluster_{module}\" {{\n    label=\"{module}\";\n"));
+        for funk_ty in types {
+            let name = funk_ty.get_name().unwrap();
+            let members: String = member_lines(funk_ty)
+                .iter()
+                .map(|line| format!("{}\\l", dot_escape(line)))
+                .collect();
+            out.push_str(&format!(
+                "    \"{module}::{name}\" [label=\"{{{}|{members}}}\"];\n",
+                dot_escape(name)
+            ));
+            edges.extend(dot_edges(interner, module, name, &funk_ty.links));
+        }
+        out.push_str("  }\n");
+    }
+    if !edges.is_empty() {
+        out.push('\n');
+    }
+    for edge in edges {
+        out.push_str(&edge);
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_edges(interner: &Interner, module: &str, name: &str, links: &FunkLinkMap) -> Vec<String> {
+    links
+        .iter()
+        .map(|(link, (target, required, is_multi))| {
+            let (target_module, target_name) = link_target(interner, module, target);
+            format!(
+                "  \"{module}::{name}\" -> \"{target_module}::{target_name}\" [label=\"{link}\\n{}\"];\n",
+                cardinality(*required, *is_multi)
+            )
+        })
+        .collect()
+}
+
+fn mermaid(interner: &Interner) -> String {
+    let mut out = String::from("classDiagram\n");
+    let mut edges = vec![];
+    for (module, types) in by_module(interner) {
+        out.push_str(&format!("  namespace {module} {{\n"));
+        for funk_ty in types {
+            let name = funk_ty.get_name().unwrap();
+            let id = flat_name(module, name);
+            out.push_str(&format!("    class {id} {{\n"));
+            for line in member_lines(funk_ty) {
+                out.push_str(&format!("      {line}\n"));
+            }
+            out.push_str("    }\n");
+            for (link, (target, required, is_multi)) in funk_ty.links.iter() {
+                let (target_module, target_name) = link_target(interner, module, target);
+                edges.push(format!(
+                    "  {id} --> \"{}\" {} : {link} ({})\n",
+                    multiplicity(*required, *is_multi),
+                    flat_name(target_module, target_name),
+                    cardinality(*required, *is_multi)
+                ));
+            }
+        }
+        out.push_str("  }\n");
+    }
+    for edge in edges {
+        out.push_str(&edge);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+
+    const SCHEMA: &str = "
+        module default {
+            type FunksGiven {
+                required expires: int32;
+                significance: str;
+            }
+            type ReasonForLiving {
+                required online: bool;
+                multi funks: FunksGiven;
+                required owner: auth::User;
+            }
+        }
+        module auth {
+            type User { required name: str; }
+        }
+    ";
+
+    #[test]
+    fn dot_clusters_and_edges() -> anyhow::Result<()> {
+        let dot = render(&sdl::parse(SCHEMA)?, DiagramFormat::Dot);
+        assert!(dot.contains("subgraph \"cluster_auth\" {"), "{dot}");
+        assert!(
+            dot.contains("\"default::FunksGiven\" [label=\"{FunksGiven|required expires: int32\\lsignificance: str\\l}\"];"),
+            "{dot}"
+        );
+        assert!(
+            dot.contains("\"default::ReasonForLiving\" -> \"default::FunksGiven\" [label=\"funks\\nmulti, optional\"];"),
+            "{dot}"
+        );
+        assert!(
+            dot.contains("\"default::ReasonForLiving\" -> \"auth::User\" [label=\"owner\\nsingle, required\"];"),
+            "{dot}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mermaid_namespaces_and_multiplicity() -> anyhow::Result<()> {
+        let mermaid = render(&sdl::parse(SCHEMA)?, DiagramFormat::Mermaid);
+        assert!(mermaid.starts_with("classDiagram\n  namespace auth {\n    class auth_User {\n"), "{mermaid}");
+        assert!(
+            mermaid.contains("  ReasonForLiving --> \"0..*\" FunksGiven : funks (multi, optional)\n"),
+            "{mermaid}"
+        );
+        assert!(
+            mermaid.contains("  ReasonForLiving --> \"1\" auth_User : owner (single, required)\n"),
+            "{mermaid}"
+        );
+        Ok(())
+    }
+}
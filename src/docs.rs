@@ -0,0 +1,354 @@
+//! Reference documentation for the committed schema, rendered as
+//! Markdown or as a single self-contained HTML page.
+//!
+//! Titles, descriptions and deprecation notices come from the
+//! [`Annotations`] stored next to each module, type, property and link.
+//! Every link is cross-referenced both ways: the field points at its
+//! target, and the target lists everything that links to it.
+use crate::diagram::cardinality;
+use crate::export::link_target;
+use crate::{Annotations, Interner, Named};
+use std::collections::BTreeMap;
+use strum::{Display, EnumIter, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter)]
+pub enum DocsFormat {
+    #[strum(serialize = "markdown")]
+    Markdown,
+    #[strum(serialize = "html")]
+    Html,
+}
+
+pub fn render(interner: &Interner, format: DocsFormat) -> String {
+    let modules = collect(interner);
+    match format {
+        DocsFormat::Markdown => markdown(&modules),
+        DocsFormat::Html => html(&modules),
+    }
+}
+
+struct ModuleDoc<'a> {
+    name: &'a str,
+    annotations: Option<&'a Annotations<'a>>,
+    types: Vec<TypeDoc<'a>>,
+}
+
+struct TypeDoc<'a> {
+    name: &'a str,
+    anchor: String,
+    annotations: Option<&'a Annotations<'a>>,
+    fields: Vec<FieldDoc<'a>>,
+    // `(anchor, "Type.link")` for every link pointing at this type.
+    linked_from: Vec<(String, String)>,
+}
+
+struct FieldDoc<'a> {
+    name: &'a str,
+    // The scalar name, or the target type and its anchor.
+    target: (String, Option<String>),
+    cardinality: &'static str,
+    annotations: Option<&'a Annotations<'a>>,
+}
+
+fn anchor(module: &str, type_name: &str) -> String {
+    format!("type-{module}-{type_name}")
+}
+
+fn collect<'a>(interner: &'a Interner<'a>) -> Vec<ModuleDoc<'a>> {
+    let mut modules: BTreeMap<&str, ModuleDoc> = BTreeMap::new();
+    let mut linked_from: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+
+    for module in interner.modules().filter(|module| *module != "std") {
+        modules.insert(
+            module,
+            ModuleDoc {
+                name: module,
+                annotations: interner.annotations_of(module, None, None),
+                types: vec![],
+            },
+        );
+    }
+
+    for (module, funk_ty) in interner.types() {
+        let type_name = funk_ty.get_name().unwrap();
+        let mut fields = vec![];
+        for (name, (_, required, is_multi)) in funk_ty.properties.iter() {
+            fields.push(FieldDoc {
+                name,
+                target: (funk_ty.property_type(name).unwrap(), None),
+                cardinality: cardinality(*required, *is_multi),
+                annotations: interner.annotations_of(module, Some(type_name), Some(name)),
+            });
+        }
+        for (name, (target, required, is_multi)) in funk_ty.links.iter() {
+            let (target_module, target_name) = link_target(interner, module, target);
+            let target_anchor = anchor(target_module, target_name);
+            linked_from
+                .entry(target_anchor.clone())
+                .or_default()
+                .push((anchor(module, type_name), format!("{type_name}.{name}")));
+            fields.push(FieldDoc {
+                name,
+                target: (format!("{target_module}::{target_name}"), Some(target_anchor)),
+                cardinality: cardinality(*required, *is_multi),
+                annotations: interner.annotations_of(module, Some(type_name), Some(name)),
+            });
+        }
+        if let Some(module_doc) = modules.get_mut(module) {
+            module_doc.types.push(TypeDoc {
+                name: type_name,
+                anchor: anchor(module, type_name),
+                annotations: interner.annotations_of(module, Some(type_name), None),
+                fields,
+                linked_from: vec![],
+            });
+        }
+    }
+
+    let mut modules: Vec<ModuleDoc> = modules.into_values().collect();
+    for ty in modules.iter_mut().flat_map(|module| module.types.iter_mut()) {
+        ty.linked_from = linked_from.remove(&ty.anchor).unwrap_or_default();
+    }
+    modules
+}
+
+fn title<'a>(annotations: Option<&'a Annotations>) -> Option<&'a str> {
+    annotations.and_then(|a| a.title.as_deref())
+}
+
+fn description<'a>(annotations: Option<&'a Annotations>) -> Option<&'a str> {
+    annotations.and_then(|a| a.description.as_deref())
+}
+
+fn deprecated<'a>(annotations: Option<&'a Annotations>) -> Option<&'a str> {
+    annotations.and_then(|a| a.deprecated.as_deref())
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
+
+fn markdown(modules: &[ModuleDoc]) -> String {
+    let mut md = String::from("# Schema reference\n\n");
+    for module in modules {
+        md.push_str(&format!("- [Module `{0}`](#module-{0})\n", module.name));
+        for ty in module.types.iter() {
+            md.push_str(&format!("  - [{}](#{})\n", ty.name, ty.anchor));
+        }
+    }
+
+    for module in modules {
+        md.push_str(&format!("\n<a id=\"module-{0}\"></a>\n\n## Module `{0}`", module.name));
+        if let Some(title) = title(module.annotations) {
+            md.push_str(&format!(" — {title}"));
+        }
+        md.push_str("\n\n");
+        md.push_str(&markdown_prose(module.annotations));
+        for ty in module.types.iter() {
+            md.push_str(&format!("<a id=\"{}\"></a>\n\n### {}", ty.anchor, ty.name));
+            if let Some(title) = title(ty.annotations) {
+                md.push_str(&format!(" — {title}"));
+            }
+            md.push_str("\n\n");
+            md.push_str(&markdown_prose(ty.annotations));
+            if !ty.fields.is_empty() {
+                md.push_str("| Field | Type | Cardinality | Notes |\n| --- | --- | --- | --- |\n");
+            }
+            for field in ty.fields.iter() {
+                let target = match &field.target {
+                    (name, Some(anchor)) => format!("[`{name}`](#{anchor})"),
+                    (name, None) => format!("`{name}`"),
+                };
+                let mut notes = vec![];
+                if let Some(title) = title(field.annotations) {
+                    notes.push(format!("**{title}**"));
+                }
+                if let Some(description) = description(field.annotations) {
+                    notes.push(description.to_string());
+                }
+                if let Some(reason) = deprecated(field.annotations) {
+                    notes.push(format!("*Deprecated:* {reason}"));
+                }
+                md.push_str(&format!(
+                    "| `{}` | {target} | {} | {} |\n",
+                    field.name,
+                    field.cardinality,
+                    markdown_cell(&notes.join(" "))
+                ));
+            }
+            if !ty.linked_from.is_empty() {
+                let links: Vec<String> = ty
+                    .linked_from
+                    .iter()
+                    .map(|(anchor, label)| format!("[{label}](#{anchor})"))
+                    .collect();
+                md.push_str(&format!("\nLinked from: {}\n", links.join(", ")));
+            }
+            md.push('\n');
+        }
+    }
+    md.truncate(md.trim_end().len());
+    md.push('\n');
+    md
+}
+
+fn markdown_prose(annotations: Option<&Annotations>) -> String {
+    let mut md = String::new();
+    if let Some(reason) = deprecated(annotations) {
+        md.push_str(&format!("> **Deprecated:** {reason}\n\n"));
+    }
+    if let Some(description) = description(annotations) {
+        md.push_str(&format!("{description}\n\n"));
+    }
+    md
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html(modules: &[ModuleDoc]) -> String {
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Schema reference</title>\n</head>\n<body>\n<h1>Schema reference</h1>\n<nav>\n<ul>\n",
+    );
+    for module in modules {
+        page.push_str(&format!(
+            "<li><a href=\"#module-{0}\">Module <code>{0}</code></a>\n<ul>\n",
+            escape_html(module.name)
+        ));
+        for ty in module.types.iter() {
+            page.push_str(&format!("<li><a href=\"#{}\">{}</a></li>\n", ty.anchor, escape_html(ty.name)));
+        }
+        page.push_str("</ul>\n</li>\n");
+    }
+    page.push_str("</ul>\n</nav>\n");
+
+    for module in modules {
+        page.push_str(&format!(
+            "<section id=\"module-{0}\">\n<h2>Module <code>{0}</code>",
+            escape_html(module.name)
+        ));
+        if let Some(title) = title(module.annotations) {
+            page.push_str(&format!(" — {}", escape_html(title)));
+        }
+        page.push_str("</h2>\n");
+        page.push_str(&html_prose(module.annotations));
+        for ty in module.types.iter() {
+            page.push_str(&format!("<section id=\"{}\">\n<h3>{}", ty.anchor, escape_html(ty.name)));
+            if let Some(title) = title(ty.annotations) {
+                page.push_str(&format!(" — {}", escape_html(title)));
+            }
+            page.push_str("</h3>\n");
+            page.push_str(&html_prose(ty.annotations));
+            if !ty.fields.is_empty() {
+                page.push_str("<table>\n<tr><th>Field</th><th>Type</th><th>Cardinality</th><th>Notes</th></tr>\n");
+                for field in ty.fields.iter() {
+                    let target = match &field.target {
+                        (name, Some(anchor)) => {
+                            format!("<a href=\"#{anchor}\"><code>{}</code></a>", escape_html(name))
+                        }
+                        (name, None) => format!("<code>{}</code>", escape_html(name)),
+                    };
+                    let mut notes = vec![];
+                    if let Some(title) = title(field.annotations) {
+                        notes.push(format!("<strong>{}</strong>", escape_html(title)));
+                    }
+                    if let Some(description) = description(field.annotations) {
+                        notes.push(escape_html(description));
+                    }
+                    if let Some(reason) = deprecated(field.annotations) {
+                        notes.push(format!("<em>Deprecated:</em> {}", escape_html(reason)));
+                    }
+                    page.push_str(&format!(
+                        "<tr><td><code>{}</code></td><td>{target}</td><td>{}</td><td>{}</td></tr>\n",
+                        escape_html(field.name),
+                        field.cardinality,
+                        notes.join(" ")
+                    ));
+                }
+                page.push_str("</table>\n");
+            }
+            if !ty.linked_from.is_empty() {
+                let links: Vec<String> = ty
+                    .linked_from
+                    .iter()
+                    .map(|(anchor, label)| format!("<a href=\"#{anchor}\">{}</a>", escape_html(label)))
+                    .collect();
+                page.push_str(&format!("<p>Linked from: {}</p>\n", links.join(", ")));
+            }
+            page.push_str("</section>\n");
+        }
+        page.push_str("</section>\n");
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn html_prose(annotations: Option<&Annotations>) -> String {
+    let mut html = String::new();
+    if let Some(reason) = deprecated(annotations) {
+        html.push_str(&format!("<p><strong>Deprecated:</strong> {}</p>\n", escape_html(reason)));
+    }
+    if let Some(description) = description(annotations) {
+        html.push_str(&format!("<p>{}</p>\n", escape_html(description)));
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+
+    const SCHEMA: &str = "
+        module default {
+            annotation title := 'Funks & reasons';
+            annotation description := 'Why we live.';
+            type FunksGiven {
+                annotation title := 'Funks given';
+                required expires: int32 {
+                    annotation deprecated := 'Use valid_until';
+                };
+            }
+            type ReasonForLiving {
+                multi funks: FunksGiven {
+                    annotation description := 'Every <funk> | given';
+                };
+            }
+        }
+    ";
+
+    #[test]
+    fn markdown_cross_links() -> anyhow::Result<()> {
+        let md = render(&sdl::parse(SCHEMA)?, DocsFormat::Markdown);
+        assert!(md.contains("## Module `default` — Funks & reasons\n"), "{md}");
+        assert!(md.contains("### FunksGiven — Funks given\n"), "{md}");
+        assert!(md.contains("Why we live.\n"), "{md}");
+        assert!(
+            md.contains("| `expires` | `int32` | single, required | *Deprecated:* Use valid_until |\n"),
+            "{md}"
+        );
+        assert!(
+            md.contains("| `funks` | [`default::FunksGiven`](#type-default-FunksGiven) | multi, optional | Every <funk> \\| given |\n"),
+            "{md}"
+        );
+        assert!(
+            md.contains("Linked from: [ReasonForLiving.funks](#type-default-ReasonForLiving)\n"),
+            "{md}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn html_escapes_and_anchors() -> anyhow::Result<()> {
+        let page = render(&sdl::parse(SCHEMA)?, DocsFormat::Html);
+        assert!(page.contains("<h2>Module <code>default</code> — Funks &amp; reasons</h2>\n"), "{page}");
+        assert!(page.contains("<section id=\"type-default-FunksGiven\">\n"), "{page}");
+        assert!(page.contains("<a href=\"#type-default-FunksGiven\"><code>default::FunksGiven</code></a>"), "{page}");
+        assert!(page.contains("Every &lt;funk&gt; | given"), "{page}");
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Logical dumps: the schema and every object, apart from the engine that
+//! stored them and the way it lays them out, so they load into a database
+//! on any backend and in later versions.
+//!
+//! A dump is JSON Lines, written and read a line at a time:
+//!
+//! ```text
+//! {"funkdb_dump":1,"schema":"module default { ... }"}
+//! {"object":{"id":"…","__type__":"default::Person","name":["ann"]}}
+//! {"links":{"id":"…","friends":["…","…"]}}
+//! {"end":{"objects":2,"links":1}}
+//! ```
+//!
+//! The first line carries the format [`VERSION`] and the schema as SDL.
+//! Objects come next, with their properties written like
+//! [`FunkValue::to_json`] and read back as the type the schema gives
+//! them. Links follow every object, so loading can insert all of them,
+//! ids kept, before pointing links at any. The last line tells a complete
+//! dump from one cut short.
+//!
+//! Expired objects are left out. Objects of types that `expire after` a
+//! while start their time over once loaded.
+use crate::object::{self, Object};
+use crate::storage::Snapshot;
+use crate::value::FunkValue;
+use crate::{FunkDb, Interner, Named};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, Write};
+use uuid::Uuid;
+
+/// The format version dumps are written in. Loading refuses later ones.
+pub const VERSION: u64 = 1;
+
+/// How much a dump holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub objects: usize,
+    /// Objects with at least one link.
+    pub links: usize,
+}
+
+/// Every object of a concrete type, type by type.
+fn objects(txn: &(impl Snapshot + ?Sized), schema: &Interner) -> Result<Vec<Vec<Object>>> {
+    let mut objects = vec![];
+    for (module, funk_ty) in schema.types().filter(|(_, funk_ty)| !funk_ty.is_abstract) {
+        let type_name = format!("{module}::{}", funk_ty.get_name().unwrap());
+        let mut found = object::scan(txn, schema, &type_name)?;
+        found.retain(|object| object.type_name == type_name);
+        objects.push(found);
+    }
+    Ok(objects)
+}
+
+/// Writes the schema and every object `txn` sees to `out`.
+pub fn write(txn: &(impl Snapshot + ?Sized), mut out: impl Write) -> Result<Summary> {
+    let source = crate::storage::catalog(txn)?.unwrap_or_default();
+    let schema = FunkDb::schema_in(txn)?;
+    writeln!(out, "{}", json!({ "funkdb_dump": VERSION, "schema": source }))?;
+    let mut summary = Summary::default();
+    let objects = objects(txn, &schema)?;
+    for object in objects.iter().flatten() {
+        let properties = Object {
+            links: Default::default(),
+            ..object.clone()
+        };
+        writeln!(out, "{}", json!({ "object": properties.to_json() }))?;
+        summary.objects += 1;
+    }
+    for object in objects.iter().flatten().filter(|object| !object.links.is_empty()) {
+        let mut links = Map::new();
+        links.insert("id".to_string(), object.id.to_string().into());
+        for (name, targets) in object.links.iter() {
+            links.insert(name.clone(), targets.iter().map(Uuid::to_string).collect());
+        }
+        writeln!(out, "{}", json!({ "links": links }))?;
+        summary.links += 1;
+    }
+    writeln!(out, "{}", json!({ "end": { "objects": summary.objects, "links": summary.links } }))?;
+    out.flush()?;
+    Ok(summary)
+}
+
+fn id_of(fields: &Map<String, Value>) -> Result<Uuid> {
+    match fields.get("id").and_then(Value::as_str) {
+        Some(id) => Ok(Uuid::parse_str(id)?),
+        None => bail!("Missing the `id`"),
+    }
+}
+
+/// The object a dumped `object` line stands for.
+fn object_from(schema: &Interner, fields: &Map<String, Value>) -> Result<Object> {
+    let Some(type_name) = fields.get("__type__").and_then(Value::as_str) else {
+        bail!("Missing the `__type__`");
+    };
+    let (type_name, funk_ty) = object::resolve(schema, type_name)?;
+    let mut object = Object::new(type_name.clone()).with_id(id_of(fields)?);
+    for (name, values) in fields.iter().filter(|(name, _)| !object::IMPLICIT_FIELDS.contains(&name.as_str())) {
+        let Some((kind, _, _)) = funk_ty.properties.get(name.as_str()) else {
+            bail!("`{type_name}` has no property `{name}`");
+        };
+        let Some(values) = values.as_array() else {
+            bail!("`{name}` should hold an array of values");
+        };
+        let values = values.iter().map(|value| FunkValue::from_json(*kind, value)).collect::<Result<_>>();
+        object.properties.insert(name.clone(), values.with_context(|| format!("Reading `{type_name}.{name}`"))?);
+    }
+    Ok(object)
+}
+
+/// Loads a dump into `db`, which must not have a schema yet. The objects
+/// go in one transaction, so a dump that fails to load leaves none behind,
+/// only its schema.
+pub fn load(db: &FunkDb, input: impl BufRead) -> Result<Summary> {
+    if db.catalog()?.is_some() {
+        bail!("{} already has a schema, load dumps into a fresh database", db.path().display());
+    }
+    let mut lines = input.lines().enumerate().map(|(n, line)| -> Result<(usize, Value)> {
+        let line = line?;
+        let json: Value = serde_json::from_str(&line).with_context(|| format!("Line {} of the dump", n + 1))?;
+        Ok((n + 1, json))
+    });
+    let Some(header) = lines.next() else {
+        bail!("The dump is empty");
+    };
+    let (_, header) = header?;
+    let Some(version) = header.get("funkdb_dump").and_then(Value::as_u64) else {
+        bail!("Not a FunkDB dump");
+    };
+    if version > VERSION {
+        bail!("The dump is in format version {version}, this FunkDB reads up to {VERSION}");
+    }
+    let source = header.get("schema").and_then(Value::as_str).unwrap_or_default();
+    db.set_catalog(source)?;
+
+    let mut txn = db.begin();
+    let schema = FunkDb::schema_in(&txn)?;
+    let mut summary = Summary::default();
+    let mut ended = None;
+    for line in lines {
+        let (n, json) = line?;
+        if ended.is_some() {
+            bail!("Line {n} of the dump follows its end");
+        }
+        let loaded = (|| -> Result<()> {
+            let Some((kind, fields)) = json.as_object().and_then(|line| line.iter().next()) else {
+                bail!("Expected an object, links or the end");
+            };
+            match (kind.as_str(), fields) {
+                ("object", Value::Object(fields)) => {
+                    object::insert_unlinked(&mut txn, &schema, object_from(&schema, fields)?)?;
+                    summary.objects += 1;
+                }
+                ("links", Value::Object(fields)) => {
+                    let id = id_of(fields)?;
+                    let Some(mut object) = object::get(&txn, id)? else {
+                        bail!("Links for {id}, which the dump does not have");
+                    };
+                    for (name, targets) in fields.iter().filter(|(name, _)| *name != "id") {
+                        let targets = targets.as_array().ok_or_else(|| anyhow!("`{name}` should hold an array of ids"))?;
+                        for target in targets {
+                            let target = target.as_str().ok_or_else(|| anyhow!("`{target}` is not an id"))?;
+                            object = object.link(name, Uuid::parse_str(target)?);
+                        }
+                    }
+                    object::update(&mut txn, &schema, object)?;
+                    summary.links += 1;
+                }
+                ("end", fields) => ended = Some(fields.clone()),
+                (kind, _) => bail!("Unknown line `{kind}`"),
+            }
+            Ok(())
+        })();
+        loaded.with_context(|| format!("Line {n} of the dump"))?;
+    }
+    let Some(end) = ended else {
+        bail!("The dump ends early, after {} objects", summary.objects);
+    };
+    let count = |key: &str| end.get(key).and_then(Value::as_u64).map(|count| count as usize);
+    if count("objects") != Some(summary.objects) || count("links") != Some(summary.links) {
+        bail!("The dump should have {end} but has {} objects and {} links", summary.objects, summary.links);
+    }
+    // Required links were only checked when set, so objects that should
+    // have had some but had none in the dump are caught here.
+    for object in objects(&txn, &schema)?.into_iter().flatten() {
+        let (type_name, funk_ty) = object::resolve(&schema, &object.type_name)?;
+        let missing = funk_ty.links.iter().find(|(name, (_, required, _))| *required && object.targets(name).is_empty());
+        if let Some((name, _)) = missing {
+            bail!("`{type_name}.{name}` is required, but {} has no links for it in the dump", object.id);
+        }
+    }
+    txn.commit()?;
+    db.wait_for_indexes()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+    use crate::FunkDbOptions;
+    use std::io::BufReader;
+
+    const SCHEMA: &str = "
+        module default {
+            abstract type Named { required name: str; }
+            type Person extending Named {
+                multi nicknames: str;
+                born: datetime;
+                big: uint128;
+                embedding: vector<2>;
+                required home: Place;
+                multi friends: Person;
+                index on (.name);
+            }
+            type Place extending Named { }
+        }
+    ";
+
+    #[test]
+    fn dumps_load_across_backends() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(SCHEMA)?;
+        let home = db.insert(Object::new("Place").set("name", "here"))?;
+        let ann = db.insert(
+            Object::new("Person")
+                .set("name", "ann")
+                .push("nicknames", "a")
+                .push("nicknames", "annie")
+                .set("born", FunkValue::parse(crate::funkstd::datetime, "1990-04-01T08:00:00.5Z")?)
+                .set("big", FunkValue::uint128(u128::MAX))
+                .set("embedding", FunkValue::vector(vec![0.25, -1.5]))
+                .link("home", home),
+        )?;
+        // Links in both directions, which only load once both objects are in.
+        let bob = db.insert(Object::new("Person").set("name", "bob").link("home", home).link("friends", ann))?;
+        db.update(db.object(ann)?.unwrap().link("friends", bob))?;
+
+        let mut dumped = vec![];
+        assert_eq!(write(&db.begin(), &mut dumped)?, Summary { objects: 3, links: 2 });
+
+        let path = scratch_path("dump.db");
+        let location = format!("sqlite://{}", path.display());
+        let restored = FunkDb::open(&location)?;
+        load(&restored, BufReader::new(dumped.as_slice()))?;
+        assert_eq!(restored.catalog()?, db.catalog()?);
+        for id in [home, ann, bob] {
+            assert_eq!(restored.object(id)?, db.object(id)?);
+        }
+        assert_eq!(restored.select("Person", &[crate::query::Filter::eq("name", "bob")])?.len(), 1);
+        assert!(load(&restored, BufReader::new(dumped.as_slice())).is_err());
+        drop(restored);
+        std::fs::remove_file(&path)?;
+
+        // A dump cut short loads nothing.
+        let text = String::from_utf8(dumped)?;
+        let cut: Vec<&str> = text.lines().take(4).collect();
+        let fresh = FunkDb::open_with("mem://", FunkDbOptions::default())?;
+        let err = load(&fresh, BufReader::new(cut.join("\n").as_bytes())).unwrap_err();
+        assert!(err.to_string().contains("ends early"), "{err}");
+        assert!(fresh.objects("Named")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn datetimes_past_four_digit_years_load_back() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog("module default { type E { at: datetime; } }")?;
+        let ids = [i64::MIN, -62_167_219_200_000_001, 253_402_300_800_000_000, i64::MAX]
+            .into_iter()
+            .map(|micros| db.insert(Object::new("E").set("at", FunkValue::datetime(micros))))
+            .collect::<Result<Vec<_>>>()?;
+        let mut dumped = vec![];
+        write(&db.begin(), &mut dumped)?;
+        let restored = FunkDb::open("mem://")?;
+        load(&restored, BufReader::new(dumped.as_slice()))?;
+        for id in ids {
+            assert_eq!(restored.object(id)?, db.object(id)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn later_formats_are_refused() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        let dump = format!("{}\n", json!({ "funkdb_dump": VERSION + 1, "schema": "" }));
+        assert!(load(&db, BufReader::new(dump.as_bytes())).is_err());
+        assert!(load(&db, BufReader::new(&b"{\"object\":{}}\n"[..])).is_err());
+        Ok(())
+    }
+}
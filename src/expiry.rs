@@ -0,0 +1,259 @@
+//! Objects that expire.
+//!
+//! A type declares how long its objects live with one of
+//!
+//! ```text
+//! expire after '30d';       # counted from each write of an object
+//! expire on (.expires);     # at the `datetime` an object holds
+//! ```
+//!
+//! and types extending it inherit that. Every object with a deadline has
+//! it in [`DEADLINES`], by id, and again in [`DUE`], ordered by deadline
+//! then id. Reads check an object's deadline and treat it as gone once it
+//! passed, so objects disappear the moment they expire. A [`sweep`] in
+//! the background deletes them for good later, one transaction each, like
+//! [`object::delete`] would, and records the expiry in their type's
+//! [change feed](crate::changes). An object a required link still points
+//! at stays, invisible, until that link lets go of it.
+use crate::index::retrying;
+use crate::object::{self, Object};
+use crate::storage::{Isolation, Mvcc, Snapshot, Transaction};
+use crate::value::FunkValue;
+use crate::{FunkDb, Interner};
+use anyhow::{bail, Result};
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Maps every object id that has a deadline to it.
+pub const DEADLINES: &str = "__expires__";
+
+/// Deadline and id of every object that has one, soonest first.
+pub const DUE: &str = "__expiry__";
+
+/// How often the sweeper looks for expired objects, unless told otherwise.
+pub const SWEEP_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ttl {
+    /// `expire after '30d';`
+    After(Duration),
+    /// `expire on (.expires);`, naming a `datetime` property.
+    On(String),
+}
+
+/// Reads a duration like `30d`, `1h30m` or `250ms`. The units are `d`,
+/// `h`, `m`, `s` and `ms`.
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        bail!("Expected a duration like `30d` or `1h30m`");
+    }
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            bail!("Expected a number in the duration `{text}`, found `{rest}`");
+        }
+        let amount: u64 = rest[..digits].parse()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let seconds = match &rest[..unit] {
+            "ms" => {
+                total += Duration::from_millis(amount);
+                rest = &rest[unit..];
+                continue;
+            }
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "" => bail!("`{amount}` in the duration `{text}` needs a unit: d, h, m, s or ms"),
+            other => bail!("Unknown unit `{other}` in the duration `{text}`, expected d, h, m, s or ms"),
+        };
+        total += Duration::from_secs(amount.saturating_mul(seconds));
+        rest = &rest[unit..];
+    }
+    Ok(total)
+}
+
+/// Microseconds since the epoch, the unit of `datetime`.
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_micros() as i64,
+        Err(_) => 0,
+    }
+}
+
+fn due_key(deadline: i64, id: Uuid) -> Vec<u8> {
+    [((deadline as u64) ^ (1 << 63)).to_be_bytes().as_slice(), id.as_bytes()].concat()
+}
+
+/// When `object`, about to be written, expires under its type's TTL.
+fn deadline(schema: &Interner, object: &Object) -> Result<Option<i64>> {
+    let (_, funk_ty) = object::resolve(schema, &object.type_name)?;
+    let deadline = match &funk_ty.ttl {
+        None => None,
+        Some(Ttl::After(ttl)) => Some(now().saturating_add(ttl.as_micros().try_into().unwrap_or(i64::MAX))),
+        Some(Ttl::On(field)) => match object.get(field) {
+            Some(FunkValue::datetime(deadline)) => Some(*deadline),
+            _ => None,
+        },
+    };
+    Ok(deadline)
+}
+
+/// The deadline `id` has, if any.
+pub fn deadline_of(txn: &(impl Snapshot + ?Sized), id: Uuid) -> Result<Option<i64>> {
+    match txn.get(DEADLINES, id.as_bytes())? {
+        Some(bytes) => Ok(Some(i64::from_be_bytes(bytes.as_slice().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+/// Whether `id` has a deadline and it passed, by the time `txn` reads as
+/// of.
+pub fn is_expired(txn: &(impl Snapshot + ?Sized), id: Uuid) -> Result<bool> {
+    let now = txn.time().unwrap_or_else(now);
+    Ok(deadline_of(txn, id)?.is_some_and(|deadline| deadline <= now))
+}
+
+/// Whether objects of `type_name`, or of a type extending it, may expire.
+pub fn expires(schema: &Interner, type_name: &str) -> bool {
+    object::subtypes(schema, type_name)
+        .iter()
+        .any(|subtype| object::resolve(schema, subtype).is_ok_and(|(_, funk_ty)| funk_ty.ttl.is_some()))
+}
+
+/// Sets the deadline of `object`, which is being written, or clears the
+/// one it had if its type no longer gives it one.
+pub(crate) fn arm(txn: &mut Transaction, schema: &Interner, object: &Object) -> Result<()> {
+    disarm(txn, object.id)?;
+    if let Some(deadline) = deadline(schema, object)? {
+        txn.put(DEADLINES, object.id.as_bytes().to_vec(), deadline.to_be_bytes().to_vec());
+        txn.put(DUE, due_key(deadline, object.id), vec![]);
+    }
+    Ok(())
+}
+
+/// Clears the deadline of `id`, if it had one.
+pub(crate) fn disarm(txn: &mut Transaction, id: Uuid) -> Result<()> {
+    if let Some(deadline) = deadline_of(txn, id)? {
+        txn.delete(DEADLINES, id.as_bytes().to_vec());
+        txn.delete(DUE, due_key(deadline, id));
+    }
+    Ok(())
+}
+
+/// Deletes every object whose deadline passed, each in a transaction of
+/// its own, and returns how many went. Those a required link still holds
+/// on to are left for a later sweep.
+pub fn sweep(db: &Mvcc) -> Result<usize> {
+    let due: Vec<Uuid> = {
+        let txn = db.begin(Isolation::Snapshot);
+        let end = due_key(now().saturating_add(1), Uuid::nil());
+        txn.scan(DUE, (Bound::Unbounded, Bound::Excluded(&end)))?
+            .into_iter()
+            .map(|(key, _)| Ok(Uuid::from_slice(&key[8..])?))
+            .collect::<Result<_>>()?
+    };
+    let mut swept = 0;
+    for id in due {
+        let expired = retrying(db, |txn| {
+            let schema = FunkDb::schema_in(txn)?;
+            match object::expire(txn, &schema, id) {
+                Err(err) if err.downcast_ref::<object::Required>().is_some() => Ok(false),
+                result => result,
+            }
+        })?;
+        swept += usize::from(expired);
+    }
+    Ok(swept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changes::Op;
+    use crate::query::Filter;
+    use crate::FunkDbOptions;
+    use std::thread;
+
+    const SCHEMA: &str = "
+        module default {
+            type Invite { required code: str; at: datetime; expire on (.at); index on (.code); }
+            type Session { expire after '200ms'; }
+            type Guest { required invite: Invite; }
+        }
+    ";
+
+    #[test]
+    fn parses_durations() -> Result<()> {
+        assert_eq!(parse_duration("30d")?, Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(parse_duration("1h30m15s250ms")?, Duration::from_millis(5_415_250));
+        for bad in ["", "d", "30", "3w", "1h 30m"] {
+            assert!(parse_duration(bad).is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn expired_objects_vanish_then_get_swept() -> Result<()> {
+        // The sweeper will not come round during the test, it sweeps by hand.
+        let options = FunkDbOptions::builder().sweep_every(Duration::from_secs(3600)).build();
+        let db = FunkDb::open_with("mem://", options)?;
+        db.set_catalog(SCHEMA)?;
+        let past = FunkValue::from(SystemTime::now() - Duration::from_secs(60));
+        let future = FunkValue::from(SystemTime::now() + Duration::from_secs(3600));
+        let stale = db.insert(Object::new("Invite").set("code", "a").set("at", past.clone()))?;
+        let fresh = db.insert(Object::new("Invite").set("code", "b").set("at", future))?;
+        db.insert(Object::new("Invite").set("code", "c"))?;
+        let held = db.insert(Object::new("Invite").set("code", "d").set("at", past))?;
+        let guest = db.insert(Object::new("Guest").link("invite", held))?;
+        let session = db.insert(Object::new("Session"))?;
+
+        // Gone for every read the moment they expire.
+        assert_eq!(db.object(stale)?, None);
+        let mut codes: Vec<_> = db.objects("Invite")?.iter().map(|invite| invite.get("code").unwrap().to_string()).collect();
+        codes.sort();
+        assert_eq!(codes, ["b", "c"]);
+        assert!(db.select("Invite", &[Filter::eq("code", "a")])?.is_empty());
+        assert!(db.update(Object::new("Invite").with_id(stale).set("code", "e")).is_err());
+        assert!(db.object(session)?.is_some());
+        thread::sleep(Duration::from_millis(250));
+        assert!(db.object(session)?.is_none());
+
+        // Swept for good, but not while a required link holds on.
+        assert_eq!(db.sweep()?, 2);
+        let txn = db.begin();
+        assert!(object::stored(&txn, stale)?.is_none() && object::stored(&txn, session)?.is_none());
+        assert!(object::stored(&txn, held)?.is_some());
+        assert!(txn.get(DEADLINES, stale.as_bytes())?.is_none());
+        drop(txn);
+        db.delete(guest)?;
+        assert_eq!(db.sweep()?, 1);
+        let ops: Vec<Op> = db.changes("Invite", None, usize::MAX)?.into_iter().filter(|change| change.id == stale).map(|change| change.op).collect();
+        assert_eq!(ops, [Op::Insert, Op::Expire]);
+
+        // A write sets the deadline anew.
+        db.update(db.object(fresh)?.unwrap().unset("at"))?;
+        assert_eq!(deadline_of(&db.begin(), fresh)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn the_sweeper_runs_in_the_background() -> Result<()> {
+        let options = FunkDbOptions::builder().sweep_every(Duration::from_millis(10)).build();
+        let db = FunkDb::open_with("mem://", options)?;
+        db.set_catalog(SCHEMA)?;
+        let past = FunkValue::from(SystemTime::now() - Duration::from_secs(1));
+        let id = db.insert(Object::new("Invite").set("code", "a").set("at", past))?;
+        for _ in 0..500 {
+            if object::stored(&db.begin(), id)?.is_none() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        bail!("{id} expired but was never swept");
+    }
+}
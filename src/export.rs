@@ -0,0 +1,365 @@
+//! Renders the committed schema for other ecosystems: JSON Schema for
+//! validators, GraphQL SDL for the API layer and TypeScript interfaces
+//! for the frontend.
+//!
+//! Every exporter walks [`Interner::types`], so the output only ever
+//! describes what has actually been committed. The diagram formats are
+//! handed off to [`crate::diagram`].
+use crate::diagram::{self, DiagramFormat};
+use crate::{funkstd, FunkTy, Interner, Named};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use strum::{Display, EnumIter, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter)]
+pub enum ExportFormat {
+    #[strum(serialize = "json-schema")]
+    JsonSchema,
+    #[strum(serialize = "graphql")]
+    GraphQl,
+    #[strum(serialize = "typescript")]
+    TypeScript,
+    #[strum(serialize = "dot")]
+    Dot,
+    #[strum(serialize = "mermaid")]
+    Mermaid,
+}
+
+pub fn export(interner: &Interner, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::JsonSchema => json_schema(interner),
+        ExportFormat::GraphQl => graphql(interner),
+        ExportFormat::TypeScript => typescript(interner),
+        ExportFormat::Dot => diagram::render(interner, DiagramFormat::Dot),
+        ExportFormat::Mermaid => diagram::render(interner, DiagramFormat::Mermaid),
+    }
+}
+
+/// GraphQL and TypeScript have no modules, so anything outside of
+/// `default` gets its module folded into the type name.
+pub(crate) fn flat_name(module: &str, type_name: &str) -> String {
+    if module == "default" {
+        type_name.to_string()
+    } else {
+        format!("{module}_{type_name}")
+    }
+}
+
+/// Where a link points, as `(module, type)`.
+pub(crate) fn link_target<'a>(
+    interner: &'a Interner,
+    module: &'a str,
+    target: &'a FunkTy,
+) -> (&'a str, &'a str) {
+    let name = target.get_name().unwrap();
+    match interner.resolve_type(module, name) {
+        Some((module, funk_ty)) => (module, funk_ty.get_name().unwrap()),
+        None => (module, name),
+    }
+}
+
+fn integer_bounds(kind: funkstd) -> Option<(String, String)> {
+    let bounds = match kind {
+        funkstd::int8 => (i8::MIN.to_string(), i8::MAX.to_string()),
+        funkstd::int16 => (i16::MIN.to_string(), i16::MAX.to_string()),
+        funkstd::int32 => (i32::MIN.to_string(), i32::MAX.to_string()),
+        funkstd::int64 => (i64::MIN.to_string(), i64::MAX.to_string()),
+        funkstd::uint8 => ("0".to_string(), u8::MAX.to_string()),
+        funkstd::uint16 => ("0".to_string(), u16::MAX.to_string()),
+        funkstd::uint32 => ("0".to_string(), u32::MAX.to_string()),
+        funkstd::uint64 => ("0".to_string(), u64::MAX.to_string()),
+        funkstd::int128 | funkstd::uint128 | funkstd::bool | funkstd::str | funkstd::vector | funkstd::datetime => {
+            return None
+        }
+    };
+    Some(bounds)
+}
+
+/// How a value of `kind` looks once [`FunkValue::to_json`] wrote it:
+/// 128-bit integers and datetimes are strings there, in the form they
+/// print in.
+///
+/// [`FunkValue::to_json`]: crate::value::FunkValue::to_json
+fn json_scalar(kind: funkstd) -> Value {
+    match integer_bounds(kind) {
+        Some((minimum, maximum)) => json!({
+            "type": "integer",
+            "minimum": minimum.parse::<serde_json::Number>().unwrap(),
+            "maximum": maximum.parse::<serde_json::Number>().unwrap(),
+        }),
+        None if kind == funkstd::bool => json!({ "type": "boolean" }),
+        None if kind == funkstd::vector => json!({ "type": "array", "items": { "type": "number" } }),
+        None if kind == funkstd::int128 => json!({ "type": "string", "pattern": "^-?(0|[1-9][0-9]*)$" }),
+        None if kind == funkstd::uint128 => json!({ "type": "string", "pattern": "^(0|[1-9][0-9]*)$" }),
+        None if kind == funkstd::datetime => json!({
+            "type": "string",
+            "format": "date-time",
+            "pattern": "^([0-9]{4}|[+-][0-9]{4,6})-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}:[0-9]{2}(\\.[0-9]{6})?Z$",
+        }),
+        None => json!({ "type": "string" }),
+    }
+}
+
+fn json_cardinality(item: Value, required: bool, is_multi: bool) -> Value {
+    match (required, is_multi) {
+        (true, true) => json!({ "type": "array", "items": item, "minItems": 1 }),
+        (false, true) => json!({ "type": "array", "items": item }),
+        (_, false) => item,
+    }
+}
+
+fn json_schema(interner: &Interner) -> String {
+    let mut defs = Map::new();
+    for (module, funk_ty) in interner.types() {
+        let mut properties = Map::new();
+        let mut required_names = vec![];
+        for (name, (kind, required, is_multi)) in funk_ty.properties.iter() {
+            properties.insert(
+                name.to_string(),
+                json_cardinality(json_scalar(*kind), *required, *is_multi),
+            );
+            if *required {
+                required_names.push(Value::from(name.as_ref()));
+            }
+        }
+        for (name, (target, required, is_multi)) in funk_ty.links.iter() {
+            let (target_module, target_name) = link_target(interner, module, target);
+            let reference = json!({ "$ref": format!("#/$defs/{target_module}::{target_name}") });
+            properties.insert(
+                name.to_string(),
+                json_cardinality(reference, *required, *is_multi),
+            );
+            if *required {
+                required_names.push(Value::from(name.as_ref()));
+            }
+        }
+        defs.insert(
+            format!("{module}::{}", funk_ty.get_name().unwrap()),
+            json!({
+                "type": "object",
+                "properties": properties,
+                "required": required_names,
+                "additionalProperties": false,
+            }),
+        );
+    }
+    let document = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$defs": defs,
+    });
+    serde_json::to_string_pretty(&document).unwrap()
+}
+
+fn graphql_scalar(kind: funkstd) -> &'static str {
+    // `Int` is a signed 32-bit integer, everything wider gets a custom scalar.
+    match kind {
+        funkstd::bool => "Boolean",
+        funkstd::str => "String",
+        funkstd::int8 | funkstd::int16 | funkstd::int32 => "Int",
+        funkstd::uint8 | funkstd::uint16 => "Int",
+        funkstd::int64 => "Int64",
+        funkstd::int128 => "Int128",
+        funkstd::uint32 => "UInt32",
+        funkstd::uint64 => "UInt64",
+        funkstd::uint128 => "UInt128",
+        funkstd::vector => "[Float!]",
+        funkstd::datetime => "DateTime",
+    }
+}
+
+fn graphql_cardinality(item: &str, required: bool, is_multi: bool) -> String {
+    match (required, is_multi) {
+        (true, true) => format!("[{item}!]!"),
+        (false, true) => format!("[{item}!]"),
+        (true, false) => format!("{item}!"),
+        (false, false) => item.to_string(),
+    }
+}
+
+fn graphql(interner: &Interner) -> String {
+    let mut custom_scalars = BTreeSet::new();
+    let mut body = String::new();
+    for (module, funk_ty) in interner.types() {
+        body.push_str(&format!("type {} {{\n", flat_name(module, funk_ty.get_name().unwrap())));
+        for (name, (kind, required, is_multi)) in funk_ty.properties.iter() {
+            let scalar = graphql_scalar(*kind);
+            if !matches!(scalar, "Boolean" | "String" | "Int" | "[Float!]") {
+                custom_scalars.insert(scalar);
+            }
+            body.push_str(&format!("  {name}: {}\n", graphql_cardinality(scalar, *required, *is_multi)));
+        }
+        for (name, (target, required, is_multi)) in funk_ty.links.iter() {
+            let (target_module, target_name) = link_target(interner, module, target);
+            let item = flat_name(target_module, target_name);
+            body.push_str(&format!("  {name}: {}\n", graphql_cardinality(&item, *required, *is_multi)));
+        }
+        body.push_str("}\n\n");
+    }
+    let mut sdl: String = custom_scalars
+        .into_iter()
+        .map(|scalar| format!("scalar {scalar}\n"))
+        .collect();
+    if !sdl.is_empty() {
+        sdl.push('\n');
+    }
+    sdl.push_str(body.trim_end());
+    sdl.push('\n');
+    sdl
+}
+
+fn typescript_scalar(kind: funkstd) -> &'static str {
+    // Anything that can leave `Number.MAX_SAFE_INTEGER` behind is a bigint.
+    match kind {
+        funkstd::bool => "boolean",
+        funkstd::str | funkstd::datetime => "string",
+        funkstd::int64 | funkstd::int128 | funkstd::uint64 | funkstd::uint128 => "bigint",
+        funkstd::vector => "number[]",
+        _ => "number",
+    }
+}
+
+fn typescript_field(name: &str, item: &str, required: bool, is_multi: bool) -> String {
+    match (required, is_multi) {
+        (true, true) => format!("  {name}: {item}[];\n"),
+        (false, true) => format!("  {name}?: {item}[];\n"),
+        (true, false) => format!("  {name}: {item};\n"),
+        (false, false) => format!("  {name}?: {item} | null;\n"),
+    }
+}
+
+fn typescript(interner: &Interner) -> String {
+    let mut ts = String::new();
+    for (module, funk_ty) in interner.types() {
+        ts.push_str(&format!(
+            "export interface {} {{\n",
+            flat_name(module, funk_ty.get_name().unwrap())
+        ));
+        for (name, (kind, required, is_multi)) in funk_ty.properties.iter() {
+            ts.push_str(&typescript_field(name, typescript_scalar(*kind), *required, *is_multi));
+        }
+        for (name, (target, required, is_multi)) in funk_ty.links.iter() {
+            let (target_module, target_name) = link_target(interner, module, target);
+            let item = flat_name(target_module, target_name);
+            ts.push_str(&typescript_field(name, &item, *required, *is_multi));
+        }
+        ts.push_str("}\n\n");
+    }
+    let mut ts = ts.trim_end().to_string();
+    ts.push('\n');
+    ts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+    use crate::value::FunkValue;
+
+    const SCHEMA: &str = "
+        module default {
+            type FunksGiven {
+                required expires: int32;
+                significance: str;
+                multi receipts: uint64;
+            }
+            type ReasonForLiving {
+                required online: bool;
+                required multi funks: FunksGiven;
+                best: other::Thing;
+            }
+        }
+        module other {
+            type Thing { name: str; }
+        }
+    ";
+
+    #[test]
+    fn json_schema_refs_and_required() -> anyhow::Result<()> {
+        let interner = sdl::parse(SCHEMA)?;
+        let document: Value = serde_json::from_str(&export(&interner, ExportFormat::JsonSchema))?;
+        let funks_given = &document["$defs"]["default::FunksGiven"];
+        assert_eq!(funks_given["required"], json!(["expires"]));
+        assert_eq!(funks_given["properties"]["expires"]["maximum"], json!(i32::MAX));
+        assert_eq!(funks_given["properties"]["receipts"]["type"], json!("array"));
+        let funks = &document["$defs"]["default::ReasonForLiving"]["properties"]["funks"];
+        assert_eq!(funks["items"]["$ref"], json!("#/$defs/default::FunksGiven"));
+        assert_eq!(funks["minItems"], json!(1));
+        Ok(())
+    }
+
+    /// Whether `value` fits `schema`, as far as the keywords the export
+    /// uses go; patterns are left to the caller.
+    fn fits(schema: &Value, value: &Value) -> bool {
+        let bound = |key: &str| schema.get(key).map(|bound| bound.to_string().parse::<i128>().unwrap());
+        let number = || value.to_string().parse::<i128>().ok();
+        let typed = match schema["type"].as_str().unwrap() {
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "boolean" => value.is_boolean(),
+            "array" => value.as_array().is_some_and(|items| items.iter().all(|item| fits(&schema["items"], item))),
+            other => panic!("unexpected type {other}"),
+        };
+        typed
+            && bound("minimum").is_none_or(|minimum| number().is_some_and(|n| n >= minimum))
+            && bound("maximum").is_none_or(|maximum| number().is_some_and(|n| n <= maximum))
+    }
+
+    #[test]
+    fn json_schema_admits_values_as_written() -> anyhow::Result<()> {
+        let interner = sdl::parse(
+            "module default {
+                type Every {
+                    b: bool; s: str; v: vector<2>; at: datetime;
+                    i8: int8; i16: int16; i32: int32; i64: int64; i128: int128;
+                    u8: uint8; u16: uint16; u32: uint32; u64: uint64; u128: uint128;
+                }
+            }",
+        )?;
+        let document: Value = serde_json::from_str(&export(&interner, ExportFormat::JsonSchema))?;
+        let properties = &document["$defs"]["default::Every"]["properties"];
+        for (name, value) in [
+            ("b", FunkValue::bool(true)),
+            ("s", FunkValue::str("funk".to_string())),
+            ("v", FunkValue::vector(vec![0.5, -1.0])),
+            ("at", FunkValue::datetime(i64::MIN)),
+            ("i8", FunkValue::int8(i8::MIN)),
+            ("i16", FunkValue::int16(i16::MAX)),
+            ("i32", FunkValue::int32(i32::MIN)),
+            ("i64", FunkValue::int64(i64::MIN)),
+            ("i128", FunkValue::int128(i128::MIN)),
+            ("u8", FunkValue::uint8(u8::MAX)),
+            ("u16", FunkValue::uint16(u16::MAX)),
+            ("u32", FunkValue::uint32(u32::MAX)),
+            ("u64", FunkValue::uint64(u64::MAX)),
+            ("u128", FunkValue::uint128(u128::MAX)),
+        ] {
+            assert!(fits(&properties[name], &value.to_json()), "{name}: {} against {}", value.to_json(), properties[name]);
+        }
+        assert_eq!(properties["i64"]["maximum"], json!(i64::MAX));
+        assert_eq!(properties["u128"]["pattern"], json!("^(0|[1-9][0-9]*)$"));
+        assert_eq!(properties["at"]["format"], json!("date-time"));
+        Ok(())
+    }
+
+    #[test]
+    fn graphql_and_typescript() -> anyhow::Result<()> {
+        let interner = sdl::parse(SCHEMA)?;
+        let sdl = export(&interner, ExportFormat::GraphQl);
+        assert!(sdl.starts_with("scalar UInt64\n"), "{sdl}");
+        assert!(sdl.contains("  funks: [FunksGiven!]!\n"), "{sdl}");
+        assert!(sdl.contains("  best: other_Thing\n"), "{sdl}");
+
+        let ts = export(&interner, ExportFormat::TypeScript);
+        assert!(ts.contains("  receipts?: bigint[];\n"), "{ts}");
+        assert!(ts.contains("  significance?: string | null;\n"), "{ts}");
+        assert!(ts.contains("export interface other_Thing {\n"), "{ts}");
+        Ok(())
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!("graphql".parse::<ExportFormat>(), Ok(ExportFormat::GraphQl));
+        assert_eq!(ExportFormat::JsonSchema.to_string(), "json-schema");
+    }
+}
@@ -0,0 +1,306 @@
+//! Checking a database end to end: `funkdb fsck`.
+//!
+//! [`check`] reads everything there is and reports every problem it finds,
+//! rather than stopping at the first:
+//!
+//! - what the engine keeps on disk against its checksums, see
+//!   [`StorageEngine::verify`];
+//! - that the catalog parses into types whose links lead to types;
+//! - that every object record decodes, is registered as its type in
+//!   [`IDS`] and keeps its type's rules: required and single fields,
+//!   kinds and vector dimensions;
+//! - that every link points at an object there is;
+//! - that every ready index agrees with the objects it covers.
+//!
+//! Each [`Problem`] names the [`Check`] that found it and what it is about,
+//! an object id, index or file, and the [`Report`] prints as JSON.
+//! [`repair`] rebuilds the indexes that disagree; anything else needs a
+//! backup or a dump.
+//!
+//! [`StorageEngine::verify`]: crate::storage::StorageEngine::verify
+use crate::index::{self, Index, State};
+use crate::object::{self, Object, IDS};
+use crate::storage::Snapshot;
+use crate::{FunkDb, Named};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use strum::Display;
+use uuid::Uuid;
+
+/// What found a [`Problem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Check {
+    /// Opening the database, or the checksums of what it keeps on disk.
+    #[strum(serialize = "storage")]
+    Storage,
+    #[strum(serialize = "catalog")]
+    Catalog,
+    #[strum(serialize = "object")]
+    Object,
+    #[strum(serialize = "link")]
+    Link,
+    #[strum(serialize = "index")]
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub check: Check,
+    /// The object id, index name or file the problem is with.
+    pub subject: String,
+    pub detail: String,
+}
+
+impl Problem {
+    pub fn new(check: Check, subject: impl ToString, detail: impl ToString) -> Self {
+        Self {
+            check,
+            subject: subject.to_string(),
+            detail: detail.to_string(),
+        }
+    }
+}
+
+/// What [`check`] found, and what [`repair`] fixed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Object records checked.
+    pub objects: usize,
+    /// Ready indexes checked.
+    pub indexes: usize,
+    pub problems: Vec<Problem>,
+    /// Indexes rebuilt, by name.
+    pub repaired: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let problems: Vec<Value> = self
+            .problems
+            .iter()
+            .map(|problem| json!({ "check": problem.check.to_string(), "subject": problem.subject, "detail": problem.detail }))
+            .collect();
+        json!({
+            "clean": self.is_clean(),
+            "objects": self.objects,
+            "indexes": self.indexes,
+            "problems": problems,
+            "repaired": self.repaired,
+        })
+    }
+}
+
+/// Checks `db` through and through, see the [module](self).
+pub fn check(db: &FunkDb) -> Result<Report> {
+    let mut report = Report::default();
+    let (trees, corrupt) = {
+        let store = db.db.store();
+        (store.trees()?, store.verify()?)
+    };
+    for problem in corrupt {
+        report.problems.push(Problem::new(Check::Storage, db.path().display(), problem));
+    }
+    let txn = db.begin();
+    let schema = match FunkDb::schema_in(&txn) {
+        Ok(schema) => schema,
+        Err(err) => {
+            report.problems.push(Problem::new(Check::Catalog, crate::storage::CATALOG, format!("{err:#}")));
+            return Ok(report);
+        }
+    };
+    for (module, funk_ty) in schema.types() {
+        let type_name = format!("{module}::{}", funk_ty.get_name().unwrap());
+        for (name, (target, _, _)) in funk_ty.links.iter() {
+            let target = target.get_name().unwrap();
+            if schema.resolve_type(module, target).is_none() {
+                let detail = format!("`{type_name}.{name}` links to `{target}`, which is not a type");
+                report.problems.push(Problem::new(Check::Catalog, &type_name, detail));
+            }
+        }
+    }
+
+    // Every record, by type, whether the schema still has the type or not.
+    let registered: BTreeMap<Uuid, String> = txn
+        .scan(IDS, (Bound::Unbounded, Bound::Unbounded))?
+        .into_iter()
+        .map(|(id, type_name)| Ok((Uuid::from_slice(&id)?, String::from_utf8(type_name)?)))
+        .collect::<Result<_>>()?;
+    let mut objects: BTreeMap<String, Vec<Object>> = BTreeMap::new();
+    let mut recorded = BTreeSet::new();
+    for type_name in trees.iter().filter_map(|tree| tree.strip_prefix("objects/")) {
+        for (key, bytes) in txn.scan(&object::tree(type_name), (Bound::Unbounded, Bound::Unbounded))? {
+            report.objects += 1;
+            let Ok(id) = Uuid::from_slice(&key) else {
+                report.problems.push(Problem::new(Check::Object, type_name, format!("A record has the key {key:02x?}")));
+                continue;
+            };
+            recorded.insert(id);
+            match registered.get(&id) {
+                Some(registered) if registered == type_name => {}
+                Some(registered) => {
+                    let detail = format!("Recorded as a `{type_name}` but registered as a `{registered}`");
+                    report.problems.push(Problem::new(Check::Object, id, detail));
+                }
+                None => report.problems.push(Problem::new(Check::Object, id, format!("Recorded as a `{type_name}` but not registered"))),
+            }
+            match Object::decode(id, &bytes) {
+                Ok(object) => objects.entry(type_name.to_string()).or_default().push(object),
+                Err(err) => report.problems.push(Problem::new(Check::Object, id, format!("The record does not decode: {err:#}"))),
+            }
+        }
+    }
+    for (id, type_name) in registered.iter().filter(|(id, _)| !recorded.contains(*id)) {
+        report.problems.push(Problem::new(Check::Object, id, format!("Registered as a `{type_name}` but has no record")));
+    }
+
+    for object in objects.values().flatten() {
+        let mut linked = object.clone();
+        for (name, targets) in linked.links.iter_mut() {
+            for target in targets.iter().filter(|target| !registered.contains_key(*target)) {
+                let detail = format!("`{name}` points at {target}, which does not exist");
+                report.problems.push(Problem::new(Check::Link, object.id, detail));
+            }
+            targets.retain(|target| registered.contains_key(target));
+        }
+        if let Err(err) = object::validate(&txn, &schema, &mut linked, false) {
+            report.problems.push(Problem::new(Check::Object, object.id, format!("{err:#}")));
+        }
+    }
+
+    let states: BTreeMap<Index, State> = index::states(&txn)?.into_iter().collect();
+    for declared in index::declared(&schema) {
+        if !states.contains_key(&declared) {
+            report.problems.push(Problem::new(Check::Index, declared.name(), "Declared, but never built"));
+        }
+    }
+    for (index, state) in states.iter() {
+        if *state != State::Ready {
+            continue;
+        }
+        report.indexes += 1;
+        let covered: Vec<Object> = object::subtypes(&schema, &index.type_name)
+            .iter()
+            .flat_map(|type_name| objects.get(type_name).into_iter().flatten().cloned())
+            .collect();
+        for problem in index::verify(&txn, index, &covered)? {
+            report.problems.push(Problem::new(Check::Index, index.name(), problem));
+        }
+    }
+    Ok(report)
+}
+
+/// Rebuilds the indexes `report` found problems with, and returns their
+/// names.
+pub fn repair(db: &FunkDb, report: &Report) -> Result<Vec<String>> {
+    let broken: BTreeSet<&str> = report
+        .problems
+        .iter()
+        .filter(|problem| problem.check == Check::Index)
+        .map(|problem| problem.subject.as_str())
+        .collect();
+    let mut repaired = vec![];
+    for name in broken {
+        index::rebuild(&db.db, &Index::from_name(name)?)?;
+        repaired.push(name.to_string());
+    }
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Filter;
+    use crate::storage::{remove_scratch, scratch_path, wal, Batch, StorageEngine};
+    use crate::value::FunkValue;
+    use std::io::Write;
+
+    const SCHEMA: &str = "
+        module default {
+            type Person {
+                required name: str;
+                embedding: vector<2>;
+                multi friends: Person;
+                index on (.name);
+                index hnsw::l2 on (.embedding);
+            }
+        }
+    ";
+
+    fn checks(report: &Report) -> BTreeSet<String> {
+        report.problems.iter().map(|problem| problem.check.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_what_is_wrong_and_rebuilds_indexes() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(SCHEMA)?;
+        db.wait_for_indexes()?;
+        let ann = db.insert(Object::new("Person").set("name", "ann").set("embedding", FunkValue::vector(vec![0.0, 1.0])))?;
+        let bob = db.insert(Object::new("Person").set("name", "bob").link("friends", ann))?;
+        let report = db.fsck(false)?;
+        assert!(report.is_clean(), "{}", report.to_json());
+        assert_eq!((report.objects, report.indexes), (2, 2));
+
+        let (ordered, nearest): (Vec<_>, Vec<_>) = index::declared(&FunkDb::schema_in(&db.begin())?)
+            .into_iter()
+            .partition(|index| index.kind == index::Kind::Ordered);
+        let ghost = Uuid::new_v4();
+        let ghostly = db.object(bob)?.unwrap().link("friends", ghost);
+        {
+            let mut store = db.db.store();
+            let mut batch = Batch::new();
+            // Ann lost her name and her entry in the ordered index, bob
+            // points at a ghost, and the graph has a node of nobody.
+            let nameless = Object::new("default::Person").with_id(ann).set("embedding", FunkValue::vector(vec![0.0, 1.0]));
+            batch.put("objects/default::Person", ann.as_bytes().to_vec(), nameless.encode());
+            batch.put("objects/default::Person", bob.as_bytes().to_vec(), ghostly.encode());
+            let entries = store.scan(&ordered[0].tree(), (Bound::Unbounded, Bound::Unbounded))?;
+            batch.delete(&ordered[0].tree(), entries[0].0.clone());
+            batch.put(&nearest[0].tree(), [&[0x01][..], ghost.as_bytes()].concat(), vec![0]);
+            batch.put(IDS, Uuid::new_v4().as_bytes().to_vec(), b"default::Person".to_vec());
+            store.apply(batch)?;
+        }
+        let report = db.fsck(false)?;
+        assert_eq!(checks(&report), BTreeSet::from(["index", "link", "object"].map(String::from)));
+        let json = report.to_json();
+        assert_eq!(json["clean"], false);
+        assert!(json["problems"].as_array().unwrap().iter().any(|problem| problem["subject"] == bob.to_string()));
+
+        let report = db.fsck(true)?;
+        assert_eq!(report.repaired.len(), 2);
+        assert!(!checks(&report).contains("index"), "{}", report.to_json());
+        assert_eq!(db.select("Person", &[Filter::eq("name", "bob")])?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn finds_damage_on_disk() -> Result<()> {
+        let path = scratch_path("fsck.funk");
+        let mut db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Person").set("name", "ann"))?;
+        db.save()?;
+        db.insert(Object::new("Person").set("name", "bob"))?;
+        assert!(db.fsck(false)?.is_clean());
+
+        let mut bytes = std::fs::read(&path)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&path, bytes)?;
+        std::fs::OpenOptions::new().append(true).open(wal::path_for(&path))?.write_all(b"torn")?;
+        let report = db.fsck(false)?;
+        let details: Vec<&str> = report.problems.iter().map(|problem| problem.detail.as_str()).collect();
+        assert_eq!(checks(&report), BTreeSet::from(["storage".to_string()]));
+        assert!(details[0].contains("Checksum mismatch"), "{details:?}");
+        assert!(details[1].contains("not an intact record"), "{details:?}");
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+//! Full-text search: `index fts::index on (.field)` and [`search`].
+//!
+//! Text is cut into words at anything that is not a letter or a digit,
+//! lowercased, and English words are reduced to their stem with the Porter
+//! algorithm, so `giving`, `gives` and `give` are all the term `give`. A
+//! full-text index keeps, in the tree of its [`Index`]:
+//!
+//! ```text
+//! 0x00 | object id               -> number of words in the field
+//! 0x01 | term | 0x00 | object id -> positions of the term, u32 each
+//! 0x02 | word | 0x00 | object id -> positions of the word, u32 each
+//! ```
+//!
+//! The index is written by the same transaction as the object, like any
+//! other index. A query is a list of words, `"quoted phrases"` and
+//! `prefixes*`, all of which must match, and the objects that match are
+//! ranked by BM25. A prefix is matched against the words as written,
+//! lowercased, rather than their stems, so `running*` finds `running` even
+//! though its term is `run`.
+use crate::expiry;
+use crate::index::{self, Index, Kind, State};
+use crate::object::{self, Object};
+use crate::storage::{prefix_end, Snapshot};
+use crate::value::FunkValue;
+use crate::Interner;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use uuid::Uuid;
+
+/// How quickly repeating a term stops adding to the score.
+const K1: f64 = 1.2;
+/// How much longer fields are penalised.
+const B: f64 = 0.75;
+
+/// The words of `text`, lowercased, in order.
+fn split(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '\''))
+        .map(|word| word.replace('\'', "").to_lowercase())
+        .filter(|word| !word.is_empty())
+}
+
+/// The term a lowercase word is indexed by.
+fn term(word: &str) -> String {
+    match word.bytes().all(|byte| byte.is_ascii_lowercase()) {
+        true => stem(word),
+        false => word.to_string(),
+    }
+}
+
+/// The terms of `text`, in order.
+pub fn terms(text: &str) -> Vec<String> {
+    split(text).map(|word| term(&word)).collect()
+}
+
+/// The entries `object` has in a full-text index on `field`.
+pub(crate) fn entries(object: &Object, field: &str) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut positions: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    let mut spelled: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    let mut at = 0u32;
+    let mut words = 0u32;
+    for value in object.get_all(field) {
+        let FunkValue::str(text) = value else { continue };
+        for word in split(text) {
+            positions.entry(term(&word)).or_default().extend(at.to_be_bytes());
+            spelled.entry(word).or_default().extend(at.to_be_bytes());
+            at += 1;
+            words += 1;
+        }
+        // Keeps phrases from running from one value of a multi property
+        // into the next.
+        at += 1;
+    }
+    let mut entries = BTreeMap::new();
+    if positions.is_empty() {
+        return entries;
+    }
+    entries.insert([&[0x00], object.id.as_bytes().as_slice()].concat(), words.to_be_bytes().to_vec());
+    for (term, positions) in positions {
+        entries.insert(posting(0x01, &term, object.id), positions);
+    }
+    for (word, positions) in spelled {
+        entries.insert(posting(0x02, &word, object.id), positions);
+    }
+    entries
+}
+
+fn posting(tag: u8, term: &str, id: Uuid) -> Vec<u8> {
+    [&[tag], term.as_bytes(), &[0x00], id.as_bytes()].concat()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+/// A word that breaks into several terms, like `e-mail`, is a phrase.
+fn words(text: &str) -> impl Iterator<Item = Clause> + '_ {
+    text.split_whitespace().filter_map(|word| {
+        let mut terms = terms(word);
+        match terms.len() {
+            0 => None,
+            1 => Some(Clause::Term(terms.remove(0))),
+            _ => Some(Clause::Phrase(terms)),
+        }
+    })
+}
+
+fn parse(query: &str) -> Result<Vec<Clause>> {
+    let mut clauses = vec![];
+    let mut rest = query;
+    while let Some(start) = rest.find(['"', '*']) {
+        let (before, after) = rest.split_at(start);
+        if let Some(after) = after.strip_prefix('*') {
+            // The word right before the star is the prefix.
+            let cut = before.rfind(|c: char| !(c.is_alphanumeric() || c == '\'')).map_or(0, |at| at + 1);
+            clauses.extend(words(&before[..cut]));
+            let prefix = before[cut..].replace('\'', "").to_lowercase();
+            if prefix.is_empty() {
+                bail!("`*` must follow the start of a word");
+            }
+            clauses.push(Clause::Prefix(prefix));
+            rest = after;
+            continue;
+        }
+        clauses.extend(words(before));
+        let Some(end) = after[1..].find('"') else {
+            bail!("Unterminated phrase in `{query}`");
+        };
+        let phrase = terms(&after[1..end + 1]);
+        if !phrase.is_empty() {
+            clauses.push(Clause::Phrase(phrase));
+        }
+        rest = &after[end + 2..];
+    }
+    clauses.extend(words(rest));
+    if clauses.is_empty() {
+        bail!("Nothing to search for in `{query}`");
+    }
+    Ok(clauses)
+}
+
+/// Where the entries of an index are read from: its tree once ready, or
+/// worked out from the objects while it is still being built.
+enum Source {
+    Tree(String),
+    Built(BTreeMap<Vec<u8>, Vec<u8>>),
+}
+
+impl Source {
+    fn of(txn: &(impl Snapshot + ?Sized), schema: &Interner, index: &Index) -> Result<Self> {
+        if index::state(txn, index)? == Some(State::Ready) {
+            return Ok(Source::Tree(index.tree()));
+        }
+        let mut entries = BTreeMap::new();
+        for object in object::scan(txn, schema, &index.type_name)? {
+            entries.extend(index.entries(&object));
+        }
+        Ok(Source::Built(entries))
+    }
+
+    fn prefixed(&self, txn: &(impl Snapshot + ?Sized), prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            Source::Tree(tree) => {
+                let end = prefix_end(prefix);
+                let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                txn.scan(tree, (Bound::Included(prefix), end))
+            }
+            Source::Built(entries) => Ok(entries
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()),
+        }
+    }
+}
+
+fn positions(value: &[u8]) -> Vec<u32> {
+    value
+        .chunks_exact(4)
+        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// The objects holding `term`, with where it occurs in each.
+fn postings(txn: &(impl Snapshot + ?Sized), source: &Source, term: &str) -> Result<BTreeMap<Uuid, Vec<u32>>> {
+    let prefix = [&[0x01], term.as_bytes(), &[0x00]].concat();
+    source
+        .prefixed(txn, &prefix)?
+        .into_iter()
+        .map(|(key, value)| Ok((index::id_of(&key)?, positions(&value))))
+        .collect()
+}
+
+/// Scores the objects matching each clause through one index.
+struct Scorer {
+    lengths: BTreeMap<Uuid, f64>,
+    average: f64,
+}
+
+impl Scorer {
+    fn bm25(&self, id: &Uuid, frequency: usize, found_in: usize) -> f64 {
+        let count = self.lengths.len() as f64;
+        let found_in = found_in as f64;
+        let idf = (1.0 + (count - found_in + 0.5) / (found_in + 0.5)).ln();
+        let frequency = frequency as f64;
+        let length = self.lengths.get(id).copied().unwrap_or(self.average);
+        idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length / self.average))
+    }
+
+    fn term(&self, postings: &BTreeMap<Uuid, Vec<u32>>) -> BTreeMap<Uuid, f64> {
+        postings
+            .iter()
+            .map(|(id, at)| (*id, self.bm25(id, at.len(), postings.len())))
+            .collect()
+    }
+}
+
+fn clause_scores(
+    txn: &(impl Snapshot + ?Sized),
+    source: &Source,
+    clauses: &[Clause],
+) -> Result<Vec<BTreeMap<Uuid, f64>>> {
+    let mut lengths = BTreeMap::new();
+    for (key, value) in source.prefixed(txn, &[0x00])? {
+        lengths.insert(index::id_of(&key)?, f64::from(u32::from_be_bytes(value[..].try_into()?)));
+    }
+    if lengths.is_empty() {
+        return Ok(vec![BTreeMap::new(); clauses.len()]);
+    }
+    let average = lengths.values().sum::<f64>() / lengths.len() as f64;
+    let scorer = Scorer { lengths, average };
+    let mut scores = vec![];
+    for clause in clauses {
+        let found = match clause {
+            Clause::Term(term) => scorer.term(&postings(txn, source, term)?),
+            Clause::Prefix(prefix) => {
+                let mut by_word: BTreeMap<Vec<u8>, BTreeMap<Uuid, Vec<u32>>> = BTreeMap::new();
+                for (key, value) in source.prefixed(txn, &[&[0x02], prefix.as_bytes()].concat())? {
+                    let word = key[1..key.len() - 17].to_vec();
+                    by_word.entry(word).or_default().insert(index::id_of(&key)?, positions(&value));
+                }
+                let mut found: BTreeMap<Uuid, f64> = BTreeMap::new();
+                for postings in by_word.values() {
+                    for (id, score) in scorer.term(postings) {
+                        *found.entry(id).or_default() += score;
+                    }
+                }
+                found
+            }
+            Clause::Phrase(terms) => {
+                let postings = terms
+                    .iter()
+                    .map(|term| postings(txn, source, term))
+                    .collect::<Result<Vec<_>>>()?;
+                let term_scores: Vec<_> = postings.iter().map(|postings| scorer.term(postings)).collect();
+                let mut found = BTreeMap::new();
+                for (id, starts) in postings[0].iter() {
+                    let follows = |start: &u32| {
+                        postings.iter().enumerate().skip(1).all(|(offset, postings)| {
+                            postings.get(id).is_some_and(|at| at.contains(&(start + offset as u32)))
+                        })
+                    };
+                    if starts.iter().any(follows) {
+                        let score = term_scores.iter().map(|scores| scores[id]).sum();
+                        found.insert(*id, score);
+                    }
+                }
+                found
+            }
+        };
+        scores.push(found);
+    }
+    Ok(scores)
+}
+
+/// The objects of `type_name`, or of types extending it, matching every
+/// part of `query` in one of the full-text indexes covering them, best
+/// match first and with their BM25 score.
+pub fn search(
+    txn: &(impl Snapshot + ?Sized),
+    schema: &Interner,
+    type_name: &str,
+    query: &str,
+) -> Result<Vec<(Object, f64)>> {
+    let (type_name, _) = object::resolve(schema, type_name)?;
+    let clauses = parse(query)?;
+    let indexes: Vec<Index> = index::declared(schema)
+        .into_iter()
+        .filter(|index| index.kind == Kind::FullText && index.covers(schema, &type_name))
+        .collect();
+    if indexes.is_empty() {
+        bail!("`{type_name}` has no full-text index, declare one with `index fts::index on (.field);`");
+    }
+    let mut scores = vec![BTreeMap::<Uuid, f64>::new(); clauses.len()];
+    for index in indexes.iter() {
+        let source = Source::of(txn, schema, index)?;
+        for (all, found) in scores.iter_mut().zip(clause_scores(txn, &source, &clauses)?) {
+            for (id, score) in found {
+                *all.entry(id).or_default() += score;
+            }
+        }
+    }
+    let (first, rest) = scores.split_first().unwrap();
+    let mut found = vec![];
+    for (id, score) in first.iter() {
+        let Some(others) = rest.iter().map(|scores| scores.get(id)).collect::<Option<Vec<_>>>() else {
+            continue;
+        };
+        let Some(object) = object::get(txn, *id)? else {
+            if expiry::is_expired(txn, *id)? {
+                continue;
+            }
+            bail!("A full-text index has an entry for {id}, which does not exist");
+        };
+        if object::is_subtype(schema, &object.type_name, &type_name) {
+            found.push((object, score + others.into_iter().sum::<f64>()));
+        }
+    }
+    found.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.id.cmp(&b.id)));
+    Ok(found)
+}
+
+fn is_consonant(word: &[u8], i: usize) -> bool {
+    match word[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(word, i - 1),
+        _ => true,
+    }
+}
+
+/// The number of vowel-consonant sequences in `word`, Porter's `m`.
+fn measure(word: &[u8]) -> usize {
+    let mut i = 0;
+    while i < word.len() && is_consonant(word, i) {
+        i += 1;
+    }
+    let mut m = 0;
+    loop {
+        while i < word.len() && !is_consonant(word, i) {
+            i += 1;
+        }
+        if i == word.len() {
+            return m;
+        }
+        while i < word.len() && is_consonant(word, i) {
+            i += 1;
+        }
+        m += 1;
+    }
+}
+
+fn has_vowel(word: &[u8]) -> bool {
+    (0..word.len()).any(|i| !is_consonant(word, i))
+}
+
+fn ends_in_double_consonant(word: &[u8]) -> bool {
+    let n = word.len();
+    n >= 2 && word[n - 1] == word[n - 2] && is_consonant(word, n - 1)
+}
+
+/// Whether `word` ends consonant, vowel, consonant, the last not being
+/// `w`, `x` or `y`, as in `hop` or `fil`.
+fn ends_cvc(word: &[u8]) -> bool {
+    let n = word.len();
+    n >= 3
+        && is_consonant(word, n - 3)
+        && !is_consonant(word, n - 2)
+        && is_consonant(word, n - 1)
+        && !matches!(word[n - 1], b'w' | b'x' | b'y')
+}
+
+/// Replaces the first of `rules` whose suffix `word` ends with, if what
+/// comes before it passes `condition`. Returns whether a suffix matched.
+fn replace(word: &mut Vec<u8>, rules: &[(&str, &str)], condition: impl Fn(&[u8]) -> bool) -> bool {
+    let Some((suffix, replacement)) = rules.iter().find(|(suffix, _)| word.ends_with(suffix.as_bytes())) else {
+        return false;
+    };
+    let stem = word.len() - suffix.len();
+    if condition(&word[..stem]) {
+        word.truncate(stem);
+        word.extend(replacement.as_bytes());
+    }
+    true
+}
+
+/// The Porter stem of a lowercase ASCII word.
+pub fn stem(word: &str) -> String {
+    let mut w = word.as_bytes().to_vec();
+    if w.len() <= 2 {
+        return word.to_string();
+    }
+
+    replace(&mut w, &[("sses", "ss"), ("ies", "i"), ("ss", "ss"), ("s", "")], |_| true);
+
+    if !replace(&mut w, &[("eed", "ee")], |stem| measure(stem) > 0) {
+        let before = w.len();
+        replace(&mut w, &[("ed", ""), ("ing", "")], has_vowel);
+        if w.len() < before {
+            if w.ends_with(b"at") || w.ends_with(b"bl") || w.ends_with(b"iz") {
+                w.push(b'e');
+            } else if ends_in_double_consonant(&w) && !matches!(w[w.len() - 1], b'l' | b's' | b'z') {
+                w.pop();
+            } else if measure(&w) == 1 && ends_cvc(&w) {
+                w.push(b'e');
+            }
+        }
+    }
+
+    replace(&mut w, &[("y", "i")], has_vowel);
+
+    let m_above = |n: usize| move |stem: &[u8]| measure(stem) > n;
+    replace(
+        &mut w,
+        &[
+            ("ational", "ate"),
+            ("tional", "tion"),
+            ("enci", "ence"),
+            ("anci", "ance"),
+            ("izer", "ize"),
+            ("abli", "able"),
+            ("alli", "al"),
+            ("entli", "ent"),
+            ("eli", "e"),
+            ("ousli", "ous"),
+            // Before `ation`, which it ends with.
+            ("ization", "ize"),
+            ("ation", "ate"),
+            ("ator", "ate"),
+            ("alism", "al"),
+            ("iveness", "ive"),
+            ("fulness", "ful"),
+            ("ousness", "ous"),
+            ("aliti", "al"),
+            ("iviti", "ive"),
+            ("biliti", "ble"),
+        ],
+        m_above(0),
+    );
+
+    replace(
+        &mut w,
+        &[
+            ("icate", "ic"),
+            ("ative", ""),
+            ("alize", "al"),
+            ("iciti", "ic"),
+            ("ical", "ic"),
+            ("ness", ""),
+            ("ful", ""),
+        ],
+        m_above(0),
+    );
+
+    let step4 = [
+        "ement", "ance", "ence", "able", "ible", "ment", "ant", "ent", "ism", "ate", "iti", "ous", "ive", "ize",
+        "al", "er", "ic", "ou",
+    ];
+    let step4: Vec<(&str, &str)> = step4.iter().map(|suffix| (*suffix, "")).collect();
+    if !replace(&mut w, &step4, m_above(1)) {
+        replace(&mut w, &[("ion", "")], |stem| {
+            measure(stem) > 1 && matches!(stem.last(), Some(b's' | b't'))
+        });
+    }
+
+    if w.ends_with(b"e") {
+        let stem = &w[..w.len() - 1];
+        let m = measure(stem);
+        if m > 1 || (m == 1 && !ends_cvc(stem)) {
+            w.pop();
+        }
+    }
+    if measure(&w) > 1 && ends_in_double_consonant(&w) && w.ends_with(b"l") {
+        w.pop();
+    }
+
+    String::from_utf8(w).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunkDb;
+
+    #[test]
+    fn stems_english_words() {
+        let cases = [
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("cats", "cat"),
+            ("agreed", "agre"),
+            ("plastered", "plaster"),
+            ("motoring", "motor"),
+            ("sing", "sing"),
+            ("conflated", "conflat"),
+            ("hopping", "hop"),
+            ("falling", "fall"),
+            ("filing", "file"),
+            ("happy", "happi"),
+            ("relational", "relat"),
+            ("conditional", "condit"),
+            ("generalizations", "gener"),
+            ("hopefulness", "hope"),
+            ("electrical", "electr"),
+            ("adoption", "adopt"),
+            ("controlling", "control"),
+            ("giving", "give"),
+            ("gives", "give"),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(stem(word), expected, "{word}");
+        }
+        assert_eq!(terms("Don't STOP, ünïcode 42!"), ["dont", "stop", "ünïcode", "42"]);
+        assert_eq!(
+            parse(r#"funk* "given freely" joy"#).unwrap(),
+            [
+                Clause::Prefix("funk".into()),
+                Clause::Phrase(vec!["given".into(), "freeli".into()]),
+                Clause::Term("joi".into())
+            ]
+        );
+        assert!(parse(r#"an "open phrase"#).is_err());
+        assert!(parse(" *").is_err());
+    }
+
+    #[test]
+    fn ranks_phrases_and_prefixes() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(
+            "module default {
+                type Note { body: str; index fts::index on (.body); }
+            }",
+        )?;
+        let bodies = [
+            "Funks given freely to every friend",
+            "No funks given",
+            "Given the funk, a funky funk was given freely",
+            "Nothing to see here",
+        ];
+        let ids: Vec<Uuid> = bodies
+            .iter()
+            .map(|body| db.insert(Object::new("Note").set("body", *body)))
+            .collect::<Result<_>>()?;
+        let found = |query: &str| -> Result<Vec<Uuid>> {
+            Ok(db.search("Note", query)?.into_iter().map(|(object, _)| object.id).collect())
+        };
+        // Worked out from the objects while the index is being built, and
+        // read from it once it is ready, with the same answers.
+        let building = found("funk given")?;
+        db.wait_for_indexes()?;
+        assert_eq!(found("funk given")?, building);
+        // Shorter fields rank higher for the same number of hits.
+        assert_eq!(building, [ids[1], ids[2], ids[0]]);
+
+        assert_eq!(found(r#""given freely""#)?, [ids[0], ids[2]]);
+        assert!(found(r#""freely given""#)?.is_empty());
+        let mut prefixed = found("fri* fun*")?;
+        prefixed.sort();
+        assert_eq!(prefixed, [ids[0]]);
+        assert!(found("funk")?.contains(&ids[2]));
+
+        // The index changes with the object, in the same transaction.
+        db.update(db.object(ids[2])?.unwrap().set("body", "nothing"))?;
+        assert_eq!(found("funk")?, [ids[1], ids[0]]);
+        assert_eq!(found("nothing")?.len(), 2);
+        db.delete(ids[3])?;
+        assert_eq!(found("nothing")?, [ids[2]]);
+
+        let err = db.search("Note", "  ").unwrap_err();
+        assert!(err.to_string().contains("Nothing to search for"), "{err}");
+        db.set_catalog("module default { type Note { body: str; } }")?;
+        let err = db.search("Note", "funk").unwrap_err();
+        assert!(err.to_string().contains("no full-text index"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn words_match_their_own_prefixes() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(
+            "module default {
+                type Note { body: str; index fts::index on (.body); }
+            }",
+        )?;
+        let happy = db.insert(Object::new("Note").set("body", "Happy running"))?;
+        let runs = db.insert(Object::new("Note").set("body", "Runs"))?;
+        let found = |query: &str| -> Result<Vec<Uuid>> {
+            let mut found: Vec<_> = db.search("Note", query)?.into_iter().map(|(object, _)| object.id).collect();
+            found.sort();
+            Ok(found)
+        };
+        // Every start of a word finds it, though the stems are `happi` and
+        // `run`, while words are still found by their stem.
+        for query in ["happy*", "happ*", "running*", "runn*", "hap* run*"] {
+            assert_eq!(found(query)?, [happy], "{query}");
+            db.wait_for_indexes()?;
+            assert_eq!(found(query)?, [happy], "{query}");
+        }
+        let mut both = vec![happy, runs];
+        both.sort();
+        assert_eq!(found("run*")?, both);
+        assert_eq!(found("run")?, both);
+        assert!(found("happyx*")?.is_empty());
+        Ok(())
+    }
+}
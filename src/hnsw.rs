@@ -0,0 +1,669 @@
+//! Approximate nearest neighbours: `index hnsw::cosine on (.embedding)`,
+//! `hnsw::l2` or `hnsw::dot`, and [`nearest`].
+//!
+//! The index is a hierarchical navigable small world graph kept in the
+//! tree of its [`Index`], so it is read and written by transactions like
+//! everything else:
+//!
+//! ```text
+//! 0x00                      -> entry point id | its level
+//! 0x01 | id                 -> level | the vector, f32 little-endian
+//! 0x02 | level | id         -> neighbour ids, 16 bytes each
+//! ```
+//!
+//! Every object sits on level 0 and, with falling odds, on the levels
+//! above; its random id decides how high. A search walks down from the
+//! entry point on the top level, greedily on the way, and widely on
+//! level 0. Removing an object reconnects its neighbours among
+//! themselves. Links to it from objects it did not link to are dropped
+//! when those next change, or by a [vacuum](crate::vacuum), and skipped
+//! until then.
+//!
+//! Concurrent writes near each other in the graph change the same
+//! neighbour lists, and so conflict; retry them like any other conflict.
+use crate::expiry;
+use crate::index::{self, Index, Kind, State, Tidied};
+use crate::object::{self, Object};
+use crate::query::{self, Filter};
+use crate::storage::{Snapshot, Transaction};
+use crate::value::FunkValue;
+use crate::Interner;
+use anyhow::{bail, Result};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::ops::Bound;
+use uuid::Uuid;
+
+/// Neighbours per object on the levels above 0.
+const M: usize = 16;
+/// Neighbours per object on level 0.
+const M0: usize = 2 * M;
+/// How many candidates an insert considers per level.
+const EF_CONSTRUCTION: usize = 64;
+/// How many candidates a search considers on level 0, at least.
+const EF_SEARCH: usize = 64;
+const MAX_LEVEL: u8 = 16;
+/// Up to how many objects left by the filters are compared one by one
+/// rather than searched for through the graph.
+const EXACT: usize = 1024;
+
+const ENTRY: &[u8] = &[0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Metric {
+    Cosine,
+    L2,
+    Dot,
+}
+
+impl Metric {
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Cosine => "hnsw::cosine",
+            Metric::L2 => "hnsw::l2",
+            Metric::Dot => "hnsw::dot",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Metric::Cosine, Metric::L2, Metric::Dot]
+            .into_iter()
+            .find(|metric| metric.name() == name)
+    }
+
+    /// Smaller is nearer. Dot products are negated to fit.
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        match self {
+            Metric::Cosine => {
+                let norms = (dot(a, a) * dot(b, b)).sqrt();
+                if norms == 0.0 {
+                    return 1.0;
+                }
+                1.0 - dot(a, b) / norms
+            }
+            Metric::L2 => a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+            Metric::Dot => -dot(a, b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Near {
+    distance: f32,
+    id: Uuid,
+}
+
+impl Eq for Near {}
+
+impl Ord for Near {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Near {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn node_key(id: Uuid) -> Vec<u8> {
+    [&[0x01], id.as_bytes().as_slice()].concat()
+}
+
+fn links_key(level: u8, id: Uuid) -> Vec<u8> {
+    [&[0x02, level], id.as_bytes().as_slice()].concat()
+}
+
+/// How many levels above 0 the object with `id` sits on. Version 4 ids
+/// are random, and their last 53 bits entirely so.
+fn level_for(id: Uuid) -> u8 {
+    let bits = id.as_u128() as u64 & ((1 << 53) - 1);
+    let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+    let level = (-uniform.ln() / (M as f64).ln()).floor();
+    (level as u8).min(MAX_LEVEL)
+}
+
+fn vector_of<'o>(object: &'o Object, field: &str) -> Option<&'o [f32]> {
+    match object.get(field) {
+        Some(FunkValue::vector(v)) => Some(v),
+        _ => None,
+    }
+}
+
+/// One index's graph, read through `txn`.
+struct Graph<'t, S: Snapshot + ?Sized> {
+    txn: &'t S,
+    tree: String,
+    metric: Metric,
+}
+
+impl<S: Snapshot + ?Sized> Graph<'_, S> {
+    fn entry(&self) -> Result<Option<(Uuid, u8)>> {
+        match self.txn.get(&self.tree, ENTRY)? {
+            Some(bytes) if bytes.len() == 17 => Ok(Some((Uuid::from_slice(&bytes[..16])?, bytes[16]))),
+            Some(_) => bail!("Malformed entry point in `{}`", self.tree),
+            None => Ok(None),
+        }
+    }
+
+    fn node(&self, id: Uuid) -> Result<Option<(u8, Vec<f32>)>> {
+        let Some(bytes) = self.txn.get(&self.tree, &node_key(id))? else {
+            return Ok(None);
+        };
+        let Some((level, floats)) = bytes.split_first() else {
+            bail!("Malformed node {id} in `{}`", self.tree);
+        };
+        let vector = floats
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+            .collect();
+        Ok(Some((*level, vector)))
+    }
+
+    fn links(&self, level: u8, id: Uuid) -> Result<Vec<Uuid>> {
+        let bytes = self.txn.get(&self.tree, &links_key(level, id))?.unwrap_or_default();
+        bytes.chunks_exact(16).map(|id| Ok(Uuid::from_slice(id)?)).collect()
+    }
+
+    fn near(&self, target: &[f32], id: Uuid) -> Result<Option<Near>> {
+        Ok(self.node(id)?.map(|(_, vector)| Near {
+            distance: self.metric.distance(target, &vector),
+            id,
+        }))
+    }
+
+    /// The `ef` objects nearest `target` on `level` that `admit` lets
+    /// through, found by walking out from `from`; nearest first.
+    fn search_level(
+        &self,
+        target: &[f32],
+        from: &[Near],
+        ef: usize,
+        level: u8,
+        admit: &dyn Fn(&Uuid) -> bool,
+    ) -> Result<Vec<Near>> {
+        let mut visited: HashSet<Uuid> = from.iter().map(|near| near.id).collect();
+        let mut candidates: BinaryHeap<Reverse<Near>> = from.iter().copied().map(Reverse).collect();
+        let mut found: BinaryHeap<Near> = from.iter().copied().filter(|near| admit(&near.id)).collect();
+        while let Some(Reverse(nearest)) = candidates.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|farthest| nearest.distance > farthest.distance) {
+                break;
+            }
+            for id in self.links(level, nearest.id)? {
+                if !visited.insert(id) {
+                    continue;
+                }
+                let Some(near) = self.near(target, id)? else {
+                    continue;
+                };
+                if found.len() < ef || found.peek().is_some_and(|farthest| near < *farthest) {
+                    candidates.push(Reverse(near));
+                    if admit(&id) {
+                        found.push(near);
+                        if found.len() > ef {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(found.into_sorted_vec())
+    }
+
+    /// Walks down to level `to`, keeping only the nearest object on the
+    /// way.
+    fn descend(&self, target: &[f32], to: u8) -> Result<Vec<Near>> {
+        let Some((entry, top)) = self.entry()? else {
+            return Ok(vec![]);
+        };
+        let Some(mut nearest) = self.near(target, entry)? else {
+            bail!("The entry point of `{}` is missing", self.tree);
+        };
+        for level in (to + 1..=top).rev() {
+            if let Some(nearer) = self.search_level(target, &[nearest], 1, level, &|_| true)?.first() {
+                nearest = *nearer;
+            }
+        }
+        Ok(vec![nearest])
+    }
+
+    /// The at most `max` of `ids` nearest `target`, leaving out any that
+    /// are gone.
+    fn closest(&self, target: &[f32], ids: impl IntoIterator<Item = Uuid>, max: usize) -> Result<Vec<Uuid>> {
+        let mut nears = vec![];
+        for id in ids {
+            nears.extend(self.near(target, id)?);
+        }
+        nears.sort();
+        Ok(nears.into_iter().take(max).map(|near| near.id).collect())
+    }
+}
+
+fn graph<'t, S: Snapshot + ?Sized>(txn: &'t S, index: &Index) -> Graph<'t, S> {
+    let Kind::Nearest(metric) = index.kind else {
+        unreachable!("{index} is not a vector index");
+    };
+    Graph {
+        txn,
+        tree: index.tree(),
+        metric,
+    }
+}
+
+fn max_links(level: u8) -> usize {
+    match level {
+        0 => M0,
+        _ => M,
+    }
+}
+
+fn encode_links(ids: &[Uuid]) -> Vec<u8> {
+    ids.iter().flat_map(|id| *id.as_bytes()).collect()
+}
+
+/// Adds `object` to the graph of `index`, unless it is in there already
+/// or has no vector.
+pub(crate) fn insert(txn: &mut Transaction, index: &Index, object: &Object) -> Result<()> {
+    let Some(vector) = vector_of(object, &index.fields[0]) else {
+        return Ok(());
+    };
+    let tree = index.tree();
+    let id = object.id;
+    let level = level_for(id);
+    let (entry, links) = {
+        let graph = graph(txn, index);
+        if graph.node(id)?.is_some() {
+            return Ok(());
+        }
+        let entry = graph.entry()?;
+        let mut links = vec![];
+        if let Some((_, top)) = entry {
+            let mut nearest = graph.descend(vector, level.min(top))?;
+            for at in (0..=level.min(top)).rev() {
+                let found = graph.search_level(vector, &nearest, EF_CONSTRUCTION, at, &|_| true)?;
+                let neighbours: Vec<Uuid> = found.iter().take(max_links(at)).map(|near| near.id).collect();
+                // Each neighbour links back, dropping its farthest link
+                // when it has too many.
+                let mut back = vec![];
+                for neighbour in neighbours.iter() {
+                    let mut theirs = graph.links(at, *neighbour)?;
+                    theirs.push(id);
+                    if theirs.len() > max_links(at) {
+                        let Some((_, their_vector)) = graph.node(*neighbour)? else {
+                            continue;
+                        };
+                        // `id` is not stored yet, so it is measured here.
+                        let mut nears = vec![Near {
+                            distance: graph.metric.distance(&their_vector, vector),
+                            id,
+                        }];
+                        for other in theirs.iter().filter(|other| **other != id) {
+                            nears.extend(graph.near(&their_vector, *other)?);
+                        }
+                        nears.sort();
+                        theirs = nears.into_iter().take(max_links(at)).map(|near| near.id).collect();
+                    }
+                    back.push((*neighbour, theirs));
+                }
+                links.push((at, neighbours, back));
+                nearest = found;
+            }
+        }
+        (entry, links)
+    };
+    for (at, neighbours, back) in links {
+        txn.put(&tree, links_key(at, id), encode_links(&neighbours));
+        for (neighbour, theirs) in back {
+            txn.put(&tree, links_key(at, neighbour), encode_links(&theirs));
+        }
+    }
+    let mut record = vec![level];
+    record.extend(vector.iter().flat_map(|x| x.to_le_bytes()));
+    txn.put(&tree, node_key(id), record);
+    if entry.is_none_or(|(_, top)| level > top) {
+        txn.put(&tree, ENTRY.to_vec(), [id.as_bytes().as_slice(), &[level]].concat());
+    }
+    Ok(())
+}
+
+/// Takes the object with `id` out of the graph of `index`, if it is in.
+pub(crate) fn remove(txn: &mut Transaction, index: &Index, id: Uuid) -> Result<()> {
+    let tree = index.tree();
+    let mut writes = vec![];
+    let mut entry = None;
+    {
+        let graph = graph(txn, index);
+        let Some((level, _)) = graph.node(id)? else {
+            return Ok(());
+        };
+        for at in 0..=level {
+            let gone = graph.links(at, id)?;
+            for neighbour in gone.iter() {
+                let Some((_, their_vector)) = graph.node(*neighbour)? else {
+                    continue;
+                };
+                let mut theirs = graph.links(at, *neighbour)?;
+                theirs.retain(|other| *other != id);
+                let reachable = gone.iter().filter(|other| *other != neighbour && !theirs.contains(other));
+                theirs.extend(reachable.copied().collect::<Vec<_>>());
+                let theirs = graph.closest(&their_vector, theirs, max_links(at))?;
+                writes.push((links_key(at, *neighbour), Some(encode_links(&theirs))));
+            }
+            writes.push((links_key(at, id), None));
+        }
+        if graph.entry()?.is_some_and(|(entry, _)| entry == id) {
+            // Rare enough, one in so many removals, to look for the next
+            // highest object the slow way.
+            let mut highest: Option<(Uuid, u8)> = None;
+            let nodes = (Bound::Included([0x01].as_slice()), Bound::Excluded([0x02].as_slice()));
+            for (key, value) in graph.txn.scan(&tree, nodes)? {
+                let other = Uuid::from_slice(&key[1..])?;
+                if other != id && highest.is_none_or(|(_, top)| value[0] > top) {
+                    highest = Some((other, value[0]));
+                }
+            }
+            entry = Some(highest);
+        }
+    }
+    for (key, value) in writes {
+        match value {
+            Some(value) => txn.put(&tree, key, value),
+            None => txn.delete(&tree, key),
+        }
+    }
+    txn.delete(&tree, node_key(id));
+    match entry {
+        Some(Some((id, level))) => txn.put(&tree, ENTRY.to_vec(), [id.as_bytes().as_slice(), &[level]].concat()),
+        Some(None) => txn.delete(&tree, ENTRY.to_vec()),
+        None => {}
+    }
+    Ok(())
+}
+
+/// What is wrong with the graph of `index`, given the `objects` it covers:
+/// objects with a vector missing from it or in it with another one, nodes
+/// of no such object, and an entry point that is not a node. Links to
+/// removed objects are no fault, see the [module](self).
+pub(crate) fn verify<S: Snapshot + ?Sized>(txn: &S, index: &Index, objects: &[Object]) -> Result<Vec<String>> {
+    let graph = graph(txn, index);
+    let mut problems = vec![];
+    let mut expected = HashSet::new();
+    for object in objects {
+        let Some(vector) = vector_of(object, &index.fields[0]) else {
+            continue;
+        };
+        expected.insert(object.id);
+        match graph.node(object.id)? {
+            None => problems.push(format!("{} is missing from the graph", object.id)),
+            Some((level, stored)) if level != level_for(object.id) || stored != vector => {
+                problems.push(format!("{} is in the graph with another vector", object.id))
+            }
+            Some(_) => {}
+        }
+    }
+    let nodes = (Bound::Included([0x01].as_slice()), Bound::Excluded([0x02].as_slice()));
+    for (key, _) in txn.scan(&graph.tree, nodes)? {
+        let id = Uuid::from_slice(&key[1..])?;
+        if !expected.contains(&id) {
+            problems.push(format!("{id} is in the graph without a vector to be there for"));
+        }
+    }
+    match graph.entry()? {
+        Some((id, _)) if !expected.contains(&id) => problems.push(format!("The entry point {id} is not in the graph")),
+        None if !expected.is_empty() => problems.push("The graph has no entry point".to_string()),
+        _ => {}
+    }
+    Ok(problems)
+}
+
+/// Drops the links to objects no longer in the graph of `index` from the
+/// neighbour list under `key`, if that is one.
+pub(crate) fn tidy(txn: &mut Transaction, index: &Index, key: &[u8], value: &[u8]) -> Result<Tidied> {
+    if key.first() != Some(&0x02) {
+        return Ok(Tidied::default());
+    }
+    let neighbours: Vec<Uuid> = value.chunks_exact(16).map(Uuid::from_slice).collect::<Result<_, _>>()?;
+    let mut kept = vec![];
+    {
+        let graph = graph(txn, index);
+        for id in neighbours.iter() {
+            if graph.node(*id)?.is_some() {
+                kept.push(*id);
+            }
+        }
+    }
+    if kept.len() < neighbours.len() {
+        txn.put(&index.tree(), key.to_vec(), encode_links(&kept));
+    }
+    Ok(Tidied {
+        seen: neighbours.len(),
+        dropped: neighbours.len() - kept.len(),
+    })
+}
+
+/// Moves an object in the graph of `index` as it goes from `old` to
+/// `new`.
+pub(crate) fn maintain(txn: &mut Transaction, index: &Index, old: Option<&Object>, new: Option<&Object>) -> Result<()> {
+    let field = &index.fields[0];
+    if old.and_then(|old| vector_of(old, field)) == new.and_then(|new| vector_of(new, field)) {
+        return Ok(());
+    }
+    if let Some(old) = old {
+        remove(txn, index, old.id)?;
+    }
+    if let Some(new) = new {
+        insert(txn, index, new)?;
+    }
+    Ok(())
+}
+
+/// The `k` objects of `type_name` nearest `target` by their vector
+/// `field`, that pass every filter; nearest first and with their distance.
+///
+/// Objects are compared one by one when the filters leave few of them, or
+/// while the index is being built, and searched for through the index
+/// otherwise. The latter is approximate: a near object is missed now and
+/// then.
+pub fn nearest(
+    txn: &(impl Snapshot + ?Sized),
+    schema: &Interner,
+    type_name: &str,
+    field: &str,
+    target: &[f32],
+    k: usize,
+    filters: &[Filter],
+) -> Result<Vec<(Object, f32)>> {
+    let (type_name, funk_ty) = object::resolve(schema, type_name)?;
+    funk_ty.check_field(field)?;
+    let Some(dimension) = funk_ty.dimensions.get(field) else {
+        bail!("`{type_name}.{field}` is not a vector");
+    };
+    if target.len() != *dimension {
+        bail!("`{type_name}.{field}` holds vector<{dimension}>, not one of {} numbers", target.len());
+    }
+    if !target.iter().all(|x| x.is_finite()) {
+        bail!("`{type_name}.{field}` holds finite numbers only, nothing is near {target:?}");
+    }
+    let found = index::declared(schema).into_iter().find(|index| {
+        matches!(index.kind, Kind::Nearest(_)) && index.fields[0] == field && index.covers(schema, &type_name)
+    });
+    let Some(index) = found else {
+        bail!("`{type_name}.{field}` has no vector index, declare one with `index hnsw::cosine on (.{field});`");
+    };
+    let Kind::Nearest(metric) = index.kind else { unreachable!() };
+
+    // Only live objects of `type_name` passing the filters are wanted,
+    // while the index may hold more.
+    let wanted = match filters.is_empty() && type_name == index.type_name && !expiry::expires(schema, &type_name) {
+        true => None,
+        false => Some(query::select(txn, schema, &type_name, filters)?),
+    };
+    let ready = index::state(txn, &index)? == Some(State::Ready);
+    if !ready || wanted.as_ref().is_some_and(|wanted| wanted.len() <= EXACT) {
+        let objects = match wanted {
+            Some(objects) => objects,
+            None => object::scan(txn, schema, &type_name)?,
+        };
+        let mut found: Vec<(Object, f32)> = objects
+            .into_iter()
+            .filter_map(|object| {
+                let distance = metric.distance(target, vector_of(&object, field)?);
+                Some((object, distance))
+            })
+            .collect();
+        found.sort_by(|(a, a_distance), (b, b_distance)| a_distance.total_cmp(b_distance).then(a.id.cmp(&b.id)));
+        found.truncate(k);
+        return Ok(found);
+    }
+
+    let wanted: Option<HashSet<Uuid>> = wanted.map(|objects| objects.iter().map(|object| object.id).collect());
+    let admit = |id: &Uuid| wanted.as_ref().is_none_or(|wanted| wanted.contains(id));
+    let graph = Graph {
+        txn,
+        tree: index.tree(),
+        metric,
+    };
+    search(&graph, target, k, &admit)?
+        .into_iter()
+        .map(|near| match object::get(txn, near.id)? {
+            Some(object) => Ok((object, near.distance)),
+            None => bail!("{index} has a node for {}, which does not exist", near.id),
+        })
+        .collect()
+}
+
+/// The `k` objects nearest `target` in `graph` that `admit` lets through.
+fn search<S: Snapshot + ?Sized>(
+    graph: &Graph<S>,
+    target: &[f32],
+    k: usize,
+    admit: &dyn Fn(&Uuid) -> bool,
+) -> Result<Vec<Near>> {
+    if k == 0 {
+        return Ok(vec![]);
+    }
+    let from = graph.descend(target, 0)?;
+    let mut found = graph.search_level(target, &from, k.max(EF_SEARCH), 0, admit)?;
+    found.truncate(k);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunkDb;
+
+    /// Deterministic vectors, so failures reproduce.
+    fn vectors(seed: u64, count: usize, dimension: usize) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        (0..count).map(|_| (0..dimension).map(|_| next()).collect()).collect()
+    }
+
+    #[test]
+    fn metrics_and_levels() -> Result<()> {
+        let (a, b) = ([1.0, 0.0], [0.0, 2.0]);
+        assert_eq!(Metric::Cosine.distance(&a, &b), 1.0);
+        assert_eq!(Metric::Cosine.distance(&a, &[3.0, 0.0]), 0.0);
+        assert_eq!(Metric::L2.distance(&[0.0, 3.0], &[4.0, 0.0]), 5.0);
+        assert_eq!(Metric::Dot.distance(&[1.0, 2.0], &[3.0, 4.0]), -11.0);
+        for metric in [Metric::Cosine, Metric::L2, Metric::Dot] {
+            assert_eq!(Metric::from_name(metric.name()), Some(metric));
+            let index = Index {
+                type_name: "default::Doc".into(),
+                fields: vec!["embedding".into()],
+                kind: Kind::Nearest(metric),
+            };
+            assert_eq!(Index::from_name(&index.name())?, index);
+        }
+        let levels: Vec<u8> = (0..4096).map(|_| level_for(Uuid::new_v4())).collect();
+        let above = levels.iter().filter(|level| **level > 0).count();
+        // One in `M` is expected above level 0.
+        assert!((128..400).contains(&above), "{above}");
+        Ok(())
+    }
+
+    #[test]
+    fn finds_nearest_neighbours() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(
+            "module default {
+                type Doc {
+                    n: int32;
+                    embedding: vector<8>;
+                    index hnsw::l2 on (.embedding);
+                    index on (.n);
+                }
+            }",
+        )?;
+        db.wait_for_indexes()?;
+        let mut ids = vec![];
+        for (n, vector) in vectors(7, 400, 8).into_iter().enumerate() {
+            ids.push(db.insert(Object::new("Doc").set("n", n as i32 % 7).set("embedding", vector))?);
+        }
+        let exact = |target: &[f32], k: usize, keep: &dyn Fn(&Object) -> bool| -> Result<Vec<Uuid>> {
+            let mut all: Vec<(f32, Uuid)> = db
+                .objects("Doc")?
+                .into_iter()
+                .filter(|object| keep(object))
+                .map(|object| (Metric::L2.distance(target, vector_of(&object, "embedding").unwrap()), object.id))
+                .collect();
+            all.sort_by(|a, b| a.0.total_cmp(&b.0));
+            Ok(all.into_iter().take(k).map(|(_, id)| id).collect())
+        };
+        let recall = |targets: &[Vec<f32>]| -> Result<f32> {
+            let mut hits = 0;
+            for target in targets {
+                let expected = exact(target, 10, &|_| true)?;
+                let found = db.nearest("Doc", "embedding", target, 10, &[])?;
+                assert!(found.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+                hits += found.iter().filter(|(object, _)| expected.contains(&object.id)).count();
+            }
+            Ok(hits as f32 / (targets.len() * 10) as f32)
+        };
+        let targets = vectors(99, 20, 8);
+        assert!(recall(&targets)? >= 0.9);
+
+        // Few enough objects pass `n = 3` to compare them all.
+        let odd = db.nearest("Doc", "embedding", &targets[0], 5, &[Filter::eq("n", 3)])?;
+        let expected = exact(&targets[0], 5, &|object| object.get("n") == Some(&3.into()))?;
+        assert_eq!(odd.iter().map(|(object, _)| object.id).collect::<Vec<_>>(), expected);
+        // Through the graph, only what the filter admits comes back.
+        let txn = db.begin();
+        let indexes = index::declared(&FunkDb::schema_in(&txn)?);
+        let graph = graph(&txn, indexes.iter().find(|index| index.kind == Kind::Nearest(Metric::L2)).unwrap());
+        let even: HashSet<Uuid> = ids.iter().step_by(2).copied().collect();
+        let found = search(&graph, &targets[1], 10, &|id| even.contains(id))?;
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|near| even.contains(&near.id)));
+        drop(txn);
+
+        // Removals keep the graph navigable, entry point included.
+        for id in ids.iter().skip(1).step_by(2) {
+            db.delete(*id)?;
+        }
+        assert!(recall(&targets)? >= 0.9);
+        let moved = db.object(ids[0])?.unwrap().set("embedding", targets[2].clone());
+        db.update(moved)?;
+        let found = db.nearest("Doc", "embedding", &targets[2], 1, &[])?;
+        assert_eq!((found[0].0.id, found[0].1), (ids[0], 0.0));
+
+        let err = db.insert(Object::new("Doc").set("embedding", vec![1.0_f32; 3])).unwrap_err();
+        assert!(err.to_string().contains("holds vector<8>, not one of 3 numbers"), "{err}");
+        let mut odd = vec![1.0_f32; 8];
+        for x in [f32::NAN, f32::INFINITY] {
+            odd[0] = x;
+            let err = db.insert(Object::new("Doc").set("embedding", odd.clone())).unwrap_err();
+            assert!(err.to_string().contains("finite numbers only"), "{err}");
+            assert!(db.nearest("Doc", "embedding", &odd, 1, &[]).is_err());
+        }
+        let err = db.nearest("Doc", "n", &targets[0], 1, &[]).unwrap_err();
+        assert!(err.to_string().contains("is not a vector"), "{err}");
+        Ok(())
+    }
+}
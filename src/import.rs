@@ -0,0 +1,476 @@
+//! Bulk loading rows into a type: `funkdb import`.
+//!
+//! Rows come as CSV, with a header naming the columns, or as JSON Lines,
+//! an object per line. Each column goes to the property or link of the
+//! same name, or to the one [`Import::map`] gives it; mapped to nothing,
+//! it is left out. Values take the kind the schema gives the property:
+//! CSV cells and JSON strings are read like [`FunkValue::parse`], other
+//! JSON like [`FunkValue::from_json`]. Empty cells and `null`s are no
+//! value at all. A multi property or link takes a JSON array, or a CSV
+//! cell with its values separated by [`SEPARATOR`].
+//!
+//! A column `id` gives the object its id. Links hold the ids of their
+//! targets, unless [`Import::keys`] names a property of the target type
+//! to look them up by, `name` say; an index on it keeps that fast.
+//!
+//! Rows go in [`Import::batch`] at a time, a transaction each, so an
+//! import stopped halfway keeps the batches before. A row that does not
+//! convert, or that the type does not accept, goes to the error file as
+//! JSON Lines, with its line and why, and the rest carry on:
+//!
+//! ```text
+//! {"line":7,"error":"`x` is not a valid int32","row":{"expires":"x"}}
+//! ```
+use crate::export::link_target;
+use crate::index::retrying;
+use crate::object::{self, Object};
+use crate::query::{self, Filter};
+use crate::storage::Transaction;
+use crate::value::FunkValue;
+use crate::{FunkDb, FunkTy, Interner};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use strum::{Display, EnumString};
+use typed_builder::TypedBuilder;
+use uuid::Uuid;
+
+/// Rows per transaction, unless told otherwise.
+pub const BATCH: usize = 1000;
+
+/// Separates the values of a multi property or link in a CSV cell.
+pub const SEPARATOR: char = '|';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+pub enum Format {
+    #[strum(serialize = "csv")]
+    Csv,
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+}
+
+impl Format {
+    /// The format the extension of `path` names: `.csv`, or `.jsonl` or
+    /// `.ndjson`.
+    pub fn of(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => Ok(Format::Csv),
+            Some("jsonl" | "ndjson") => Ok(Format::JsonLines),
+            _ => bail!("Cannot tell the format of {} from its extension, expected .csv or .jsonl", path.display()),
+        }
+    }
+}
+
+/// What to import, and how.
+#[derive(TypedBuilder, Debug, Clone)]
+pub struct Import {
+    /// The type every row becomes an object of.
+    #[builder(setter(into))]
+    pub type_name: String,
+    pub format: Format,
+    /// The property or link a column goes to, by column, when it is not
+    /// the one of the same name. An empty one leaves the column out.
+    #[builder(default)]
+    pub map: BTreeMap<String, String>,
+    /// The property of the target type to look targets up by, by link.
+    #[builder(default)]
+    pub keys: BTreeMap<String, String>,
+    /// Rows per transaction.
+    #[builder(default = BATCH)]
+    pub batch: usize,
+}
+
+impl Import {
+    fn field<'a>(&'a self, column: &'a str) -> &'a str {
+        self.map.get(column).map(String::as_str).unwrap_or(column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub imported: usize,
+    /// Rows written to the error file.
+    pub rejected: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "imported {} rows, rejected {}", self.imported, self.rejected)
+    }
+}
+
+struct Row {
+    line: usize,
+    fields: Vec<(String, Value)>,
+    // CSV cells, all text, with multi values in one.
+    is_text: bool,
+}
+
+impl Row {
+    fn to_json(&self) -> Value {
+        Value::Object(self.fields.iter().cloned().collect::<Map<_, _>>())
+    }
+}
+
+struct Rejected {
+    line: usize,
+    row: Value,
+    error: String,
+}
+
+type Rows<'r> = Box<dyn Iterator<Item = Result<Result<Row, Rejected>>> + 'r>;
+
+/// The columns the header names, none for JSON Lines, and every row of
+/// `input`, read or rejected. Failing to read `input` at all ends it.
+fn rows<'r>(format: Format, input: impl Read + 'r) -> Result<(Vec<String>, Rows<'r>)> {
+    match format {
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let columns: Vec<String> = reader.headers().context("Reading the CSV header")?.iter().map(String::from).collect();
+            let header = columns.clone();
+            let rows = reader.into_records().map(move |record| match record {
+                Ok(record) => {
+                    let fields = header
+                        .iter()
+                        .zip(record.iter())
+                        .filter(|(_, cell)| !cell.is_empty())
+                        .map(|(column, cell)| (column.clone(), Value::String(cell.to_string())))
+                        .collect();
+                    let line = record.position().map(|position| position.line() as usize).unwrap_or(0);
+                    Ok(Ok(Row { line, fields, is_text: true }))
+                }
+                Err(err) if err.is_io_error() => Err(err.into()),
+                Err(err) => {
+                    let line = err.position().map(|position| position.line() as usize).unwrap_or(0);
+                    Ok(Err(Rejected { line, row: Value::Null, error: err.to_string() }))
+                }
+            });
+            Ok((columns, Box::new(rows)))
+        }
+        Format::JsonLines => {
+            let lines = BufReader::new(input).lines().enumerate();
+            let rows = lines.filter_map(|(n, line)| {
+                let line = match line {
+                    Ok(line) if line.trim().is_empty() => return None,
+                    Ok(line) => line,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                let row = match serde_json::from_str::<Value>(&line) {
+                    Ok(Value::Object(fields)) => Ok(Row { line: n + 1, fields: fields.into_iter().collect(), is_text: false }),
+                    Ok(_) => Err(Rejected { line: n + 1, row: Value::String(line), error: "Expected an object".into() }),
+                    Err(err) => Err(Rejected { line: n + 1, row: Value::String(line), error: err.to_string() }),
+                };
+                Some(Ok(row))
+            });
+            Ok((vec![], Box::new(rows)))
+        }
+    }
+}
+
+/// Targets looked up by key, by link and the key's value.
+type Lookups = HashMap<(String, String), Uuid>;
+
+/// Turns rows into objects of one type.
+struct Converter<'a> {
+    import: &'a Import,
+    schema: &'a Interner<'static>,
+    type_name: &'a str,
+    funk_ty: &'a FunkTy<'a>,
+}
+
+impl Converter<'_> {
+    fn object(&self, txn: &Transaction, row: &Row, known: &Lookups, found: &mut Lookups) -> Result<Object> {
+        let mut object = Object::new(self.type_name);
+        for (column, value) in row.fields.iter() {
+            let field = self.import.field(column);
+            if field.is_empty() || value.is_null() {
+                continue;
+            }
+            let is_multi = match (self.funk_ty.properties.get(field), self.funk_ty.links.get(field)) {
+                (Some((_, _, is_multi)), _) | (_, Some((_, _, is_multi))) => *is_multi,
+                _ => false,
+            };
+            let items = match value {
+                Value::Array(items) => items.clone(),
+                Value::String(text) if row.is_text && is_multi => {
+                    text.split(SEPARATOR).map(|item| Value::String(item.trim().to_string())).collect()
+                }
+                value => vec![value.clone()],
+            };
+            for item in items.iter() {
+                object = self.set(txn, object, field, item, known, found).with_context(|| format!("Column `{column}`"))?;
+            }
+        }
+        Ok(object)
+    }
+
+    fn set(&self, txn: &Transaction, object: Object, field: &str, item: &Value, known: &Lookups, found: &mut Lookups) -> Result<Object> {
+        if field == "id" {
+            let Some(id) = item.as_str() else {
+                bail!("`{item}` is not an id");
+            };
+            return Ok(object.with_id(Uuid::parse_str(id)?));
+        }
+        if let Some((kind, _, _)) = self.funk_ty.properties.get(field) {
+            return Ok(object.push(field, value_of(*kind, item)?));
+        }
+        let Some((target, _, _)) = self.funk_ty.links.get(field) else {
+            self.funk_ty.check_field(field)?;
+            unreachable!("`{field}` is a property or a link");
+        };
+        let module = self.type_name.split_once("::").map(|(module, _)| module).unwrap_or("default");
+        let (target_module, target_name) = link_target(self.schema, module, target);
+        let target_type = format!("{target_module}::{target_name}");
+        let Some(key) = self.import.keys.get(field) else {
+            let Some(id) = item.as_str() else {
+                bail!("`{item}` is not an id");
+            };
+            return Ok(object.link(field, Uuid::parse_str(id)?));
+        };
+        let (_, target_ty) = object::resolve(self.schema, &target_type)?;
+        let Some((kind, _, _)) = target_ty.properties.get(key.as_str()) else {
+            bail!("`{target_type}` has no property `{key}` to look targets up by");
+        };
+        let value = value_of(*kind, item)?;
+        let lookup = (field.to_string(), value.to_string());
+        if let Some(id) = known.get(&lookup).or_else(|| found.get(&lookup)) {
+            return Ok(object.link(field, *id));
+        }
+        let targets = query::select(txn, self.schema, &target_type, &[Filter::eq(key, value.clone())])?;
+        match targets.as_slice() {
+            [target] => {
+                found.insert(lookup, target.id);
+                Ok(object.link(field, target.id))
+            }
+            [] => bail!("No `{target_type}` has `{key}` {value}"),
+            _ => bail!("{} `{target_type}` objects have `{key}` {value}, so it cannot tell them apart", targets.len()),
+        }
+    }
+}
+
+/// A value of `kind` from a CSV cell or JSON.
+fn value_of(kind: crate::funkstd, item: &Value) -> Result<FunkValue> {
+    match item {
+        Value::String(text) if kind != crate::funkstd::str => FunkValue::parse(kind, text.trim()),
+        item => FunkValue::from_json(kind, item),
+    }
+}
+
+/// Imports the rows of `input` into `db`, see the [module](self), and
+/// writes those it rejects to `errors`.
+pub fn import(db: &FunkDb, import: &Import, input: impl Read, mut errors: impl Write) -> Result<Summary> {
+    if db.is_read_only() {
+        bail!("Cannot import into a database opened read-only");
+    }
+    if import.batch == 0 {
+        bail!("Batches need at least one row");
+    }
+    let schema = db.schema()?;
+    let (type_name, funk_ty) = object::resolve(&schema, &import.type_name)?;
+    if funk_ty.is_abstract {
+        bail!("`{type_name}` is abstract, import into a type extending it");
+    }
+    for (link, key) in import.keys.iter() {
+        let Some((target, _, _)) = funk_ty.links.get(link.as_str()) else {
+            funk_ty.check_field(link)?;
+            bail!("`{type_name}.{link}` is a property, only links are looked up by key");
+        };
+        let module = type_name.split_once("::").map(|(module, _)| module).unwrap_or("default");
+        let (target_module, target_name) = link_target(&schema, module, target);
+        let (_, target_ty) = object::resolve(&schema, &format!("{target_module}::{target_name}"))?;
+        target_ty.check_field(key).with_context(|| format!("Looking up `{link}`"))?;
+    }
+    let (columns, mut rows) = rows(import.format, input)?;
+    for column in columns.iter() {
+        match import.field(column) {
+            "" | "id" => {}
+            field => funk_ty.check_field(field).with_context(|| format!("Column `{column}`"))?,
+        }
+    }
+    if import.format == Format::Csv {
+        if let Some(column) = import.map.keys().find(|column| !columns.contains(column)) {
+            bail!("There is no column `{column}` to map");
+        }
+    }
+
+    let mut summary = Summary::default();
+    let mut reject = |rejected: Rejected, summary: &mut Summary| -> Result<()> {
+        summary.rejected += 1;
+        writeln!(errors, "{}", json!({ "line": rejected.line, "error": rejected.error, "row": rejected.row }))?;
+        Ok(())
+    };
+    let mut lookups = Lookups::new();
+    let mut done = false;
+    while !done {
+        let mut batch = vec![];
+        while batch.len() < import.batch {
+            match rows.next() {
+                Some(row) => match row? {
+                    Ok(row) => batch.push(row),
+                    Err(rejected) => reject(rejected, &mut summary)?,
+                },
+                None => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        // Each attempt starts over, so lookups only count once committed.
+        let (imported, rejected, found) = retrying(&db.db, |txn| {
+            let schema = FunkDb::schema_in(txn)?;
+            let (type_name, funk_ty) = object::resolve(&schema, &import.type_name)?;
+            let converter = Converter { import, schema: &schema, type_name: &type_name, funk_ty };
+            let (mut imported, mut rejected, mut found) = (0, vec![], Lookups::new());
+            for row in batch.iter() {
+                let inserted = converter
+                    .object(txn, row, &lookups, &mut found)
+                    .and_then(|object| object::insert(txn, &schema, object));
+                match inserted {
+                    Ok(_) => imported += 1,
+                    Err(err) => rejected.push(Rejected { line: row.line, row: row.to_json(), error: format!("{err:#}") }),
+                }
+            }
+            Ok((imported, rejected, found))
+        })?;
+        summary.imported += imported;
+        for rejected in rejected {
+            reject(rejected, &mut summary)?;
+        }
+        lookups.extend(found);
+    }
+    errors.flush()?;
+    db.wait_for_indexes()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = "
+        module default {
+            type Person { required name: str; index on (.name); }
+            abstract type Named { name: str; }
+            type FunksGiven extending Named {
+                required expires: int32;
+                multi tags: str;
+                given: datetime;
+                owner: Person;
+                multi fans: Person;
+            }
+        }
+    ";
+
+    fn setup() -> Result<FunkDb> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(SCHEMA)?;
+        db.wait_for_indexes()?;
+        for name in ["ann", "bob", "bob"] {
+            db.insert(Object::new("Person").set("name", name))?;
+        }
+        Ok(db)
+    }
+
+    fn rejected(errors: &[u8]) -> Vec<(u64, String)> {
+        errors
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let json: Value = serde_json::from_slice(line).unwrap();
+                (json["line"].as_u64().unwrap(), json["error"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn imports_csv_in_batches_and_rejects_bad_rows() -> Result<()> {
+        let db = setup()?;
+        let csv = "\
+title,exp,tags,given,owner,notes
+first,1,a|b,2024-05-01T12:00:00Z,ann,skip me
+second,x,,,ann,
+third,3,,,nobody,
+fourth,4,c,,bob,
+fifth,5
+sixth,6,,,ann,
+";
+        let import = Import::builder()
+            .type_name("FunksGiven")
+            .format(Format::Csv)
+            .map(BTreeMap::from([("title", "name"), ("exp", "expires"), ("notes", "")].map(|(a, b)| (a.to_string(), b.to_string()))))
+            .keys(BTreeMap::from([("owner".to_string(), "name".to_string())]))
+            .batch(2)
+            .build();
+        let mut errors = vec![];
+        let summary = db.import(&import, csv.as_bytes(), &mut errors)?;
+        assert_eq!(summary, Summary { imported: 2, rejected: 4 });
+        let errors = rejected(&errors);
+        assert_eq!(errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(), [3, 4, 5, 6]);
+        assert!(errors[0].1.contains("`x` is not a valid int32"), "{errors:?}");
+        assert!(errors[1].1.contains("No `default::Person` has `name` nobody"), "{errors:?}");
+        assert!(errors[2].1.contains("cannot tell them apart"), "{errors:?}");
+        assert!(errors[3].1.contains("found record with 2 fields"), "{errors:?}");
+
+        let ann = db.select("Person", &[Filter::eq("name", "ann")])?[0].id;
+        let first = &db.select("FunksGiven", &[Filter::eq("name", "first")])?[0];
+        assert_eq!(first.properties["tags"], [FunkValue::from("a"), FunkValue::from("b")]);
+        assert_eq!(first.targets("owner"), [ann]);
+        assert!(first.properties["given"][0].as_time().is_some());
+        assert_eq!(db.select("FunksGiven", &[Filter::eq("name", "sixth")])?[0].targets("owner"), [ann]);
+        Ok(())
+    }
+
+    #[test]
+    fn imports_json_lines() -> Result<()> {
+        let db = setup()?;
+        let ann = db.select("Person", &[Filter::eq("name", "ann")])?[0].id;
+        let id = Uuid::new_v4();
+        let jsonl = format!(
+            r#"{{"id":"{id}","name":"one","expires":1,"tags":["a","b"],"fans":["{ann}"],"given":null}}
+
+{{"name":"two","expires":"2"}}
+not json
+{{"name":"three"}}
+{{"name":"four","expires":4,"fans":["{}"]}}
+"#,
+            Uuid::new_v4()
+        );
+        let import = Import::builder().type_name("default::FunksGiven").format(Format::JsonLines).build();
+        let mut errors = vec![];
+        let summary = super::import(&db, &import, jsonl.as_bytes(), &mut errors)?;
+        assert_eq!(summary, Summary { imported: 2, rejected: 3 });
+        let errors = rejected(&errors);
+        assert_eq!(errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(), [4, 5, 6]);
+        assert!(errors[1].1.contains("`default::FunksGiven.expires` is required"), "{errors:?}");
+        assert!(errors[2].1.contains("which does not exist"), "{errors:?}");
+        let one = db.object(id)?.unwrap();
+        assert_eq!(one.properties["tags"].len(), 2);
+        assert_eq!(one.targets("fans"), [ann]);
+        assert_eq!(db.select("FunksGiven", &[Filter::eq("name", "two")])?[0].get("expires"), Some(&FunkValue::int32(2)));
+
+        for (import, expected) in [
+            (Import::builder().type_name("Named").format(Format::JsonLines).build(), "abstract"),
+            (Import::builder().type_name("Nope").format(Format::JsonLines).build(), "Unknown type"),
+            (
+                Import::builder()
+                    .type_name("FunksGiven")
+                    .format(Format::JsonLines)
+                    .keys(BTreeMap::from([("owner".to_string(), "nmae".to_string())]))
+                    .build(),
+                "Did you mean `name`?",
+            ),
+        ] {
+            let err = super::import(&db, &import, &b""[..], &mut vec![]).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+        let csv = Import::builder().type_name("FunksGiven").format(Format::Csv).build();
+        let err = super::import(&db, &csv, &b"name,expirse\n"[..], &mut vec![]).unwrap_err();
+        assert!(format!("{err:#}").contains("Did you mean `expires`?"), "{err:#}");
+        assert_eq!(Format::of(Path::new("rows.ndjson"))?, Format::JsonLines);
+        assert!(Format::of(Path::new("rows.txt")).is_err());
+        Ok(())
+    }
+}
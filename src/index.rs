@@ -0,0 +1,580 @@
+//! Secondary indexes: `index on (.field)` and `index on ((.a, .b))`, and
+//! the bookkeeping they share with the full-text indexes of [`fts`] and
+//! the vector indexes of [`hnsw`].
+//!
+//! An index declared on a type covers the objects of that type and of
+//! every type extending it, and lives in the tree `indexes/<name>`, with
+//! one empty-valued key per entry:
+//!
+//! ```text
+//! component | component | ... | object id (16 bytes)
+//! ```
+//!
+//! where a component is `0x00` for a field that is not set, or `0x01`
+//! followed by the order-preserving form of [`codec`]. Link targets sort
+//! as the `uint128` of their id. A multi property or link adds an entry per
+//! value, so an object shows up under every value it holds. Full-text
+//! and vector indexes lay out their trees as [`fts`] and [`hnsw`] say.
+//!
+//! Every write keeps every declared index in step, ready or not. Whether an
+//! index may answer queries is kept in the [`STATES`] tree: an index added
+//! to a type that already has objects is `building` until [`backfill`],
+//! running in the background, has indexed them all, and a dropped index is
+//! `dropping` until [`clear`] has removed its entries. Both first wait for
+//! the transactions that began under the old schema, since those will not
+//! maintain the index the way the new schema says.
+use crate::codec;
+use crate::fts;
+use crate::hnsw::{self, Metric};
+use crate::object::{self, Object};
+use crate::storage::{is_retryable, Isolation, Mvcc, Snapshot, Transaction};
+use crate::value::FunkValue;
+use crate::{FunkDb, Interner, Named};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound;
+use uuid::Uuid;
+
+/// The state of every index, by name.
+pub const STATES: &str = "__indexes__";
+
+/// How many objects a backfill indexes, or entries [`tidy`] looks at,
+/// per transaction.
+const CHUNK: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Building,
+    Ready,
+    Dropping,
+}
+
+impl State {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            State::Building => b"building",
+            State::Ready => b"ready",
+            State::Dropping => b"dropping",
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            b"building" => Ok(State::Building),
+            b"ready" => Ok(State::Ready),
+            b"dropping" => Ok(State::Dropping),
+            _ => bail!("Unknown index state `{}`", String::from_utf8_lossy(bytes)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /// `index on (...)`, ordered by the field values.
+    Ordered,
+    /// `index fts::index on (.field)`, by the words of a `str`.
+    FullText,
+    /// `index hnsw::<metric> on (.field)`, by the distance between vectors.
+    Nearest(Metric),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Index {
+    /// The type declaring the index, as `module::Type`.
+    pub type_name: String,
+    pub fields: Vec<String>,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::Ordered => {}
+            Kind::FullText => write!(f, "fts::index ")?,
+            Kind::Nearest(metric) => write!(f, "{} ", metric.name())?,
+        }
+        write!(f, "{}({})", self.type_name, self.fields.join(", "))
+    }
+}
+
+impl Index {
+    pub fn name(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn tree(&self) -> String {
+        format!("indexes/{self}")
+    }
+
+    /// Parses what [`Index::name`] gives.
+    pub fn from_name(name: &str) -> Result<Self> {
+        let (kind, name) = match name.split_once(' ') {
+            Some(("fts::index", name)) => (Kind::FullText, name),
+            Some((method, name)) if Metric::from_name(method).is_some() => {
+                (Kind::Nearest(Metric::from_name(method).unwrap()), name)
+            }
+            _ => (Kind::Ordered, name),
+        };
+        let Some((type_name, fields)) = name.strip_suffix(')').and_then(|name| name.split_once('(')) else {
+            bail!("`{name}` does not name an index");
+        };
+        Ok(Self {
+            type_name: type_name.to_string(),
+            fields: fields.split(", ").map(str::to_string).collect(),
+            kind,
+        })
+    }
+
+    /// Whether objects of `type_name` are in this index.
+    pub fn covers(&self, schema: &Interner, type_name: &str) -> bool {
+        object::is_subtype(schema, type_name, &self.type_name)
+    }
+
+    /// Every entry `object` has in this index.
+    pub fn entries(&self, object: &Object) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        match self.kind {
+            Kind::Ordered => self.keys(object).into_iter().map(|key| (key, vec![])).collect(),
+            Kind::FullText => fts::entries(object, &self.fields[0]),
+            // Where an object goes in the graph depends on the others.
+            Kind::Nearest(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Every key `object` has in an ordered index.
+    pub fn keys(&self, object: &Object) -> BTreeSet<Vec<u8>> {
+        let mut keys = vec![vec![]];
+        for field in self.fields.iter() {
+            let values = values(object, field);
+            let components: Vec<Vec<u8>> = match values.is_empty() {
+                true => vec![vec![0x00]],
+                false => values.iter().map(component).collect(),
+            };
+            keys = keys
+                .iter()
+                .flat_map(|prefix| components.iter().map(move |component| [prefix.as_slice(), component].concat()))
+                .collect();
+        }
+        keys.into_iter()
+            .map(|mut key| {
+                key.extend(object.id.as_bytes());
+                key
+            })
+            .collect()
+    }
+}
+
+/// The values of `field` on `object` as an index sees them, link targets
+/// included.
+pub(crate) fn values(object: &Object, field: &str) -> Vec<FunkValue> {
+    match object.links.get(field) {
+        Some(targets) => targets.iter().map(|target| FunkValue::uint128(target.as_u128())).collect(),
+        None => object.get_all(field).to_vec(),
+    }
+}
+
+/// The key component of a set field.
+pub(crate) fn component(value: &FunkValue) -> Vec<u8> {
+    let mut out = vec![0x01];
+    codec::encode_key_into(&mut out, value);
+    out
+}
+
+/// The object id at the end of an index key.
+pub(crate) fn id_of(key: &[u8]) -> Result<Uuid> {
+    match key.len().checked_sub(16) {
+        Some(start) => Ok(Uuid::from_slice(&key[start..])?),
+        None => bail!("Index key too short for an object id"),
+    }
+}
+
+/// Every index the schema declares.
+pub fn declared(schema: &Interner) -> Vec<Index> {
+    let mut indexes = vec![];
+    for (module, funk_ty) in schema.types() {
+        let type_name = format!("{module}::{}", funk_ty.get_name().unwrap());
+        for fields in funk_ty.indexes.iter() {
+            indexes.push(Index {
+                type_name: type_name.clone(),
+                fields: fields.iter().map(|field| field.to_string()).collect(),
+                kind: Kind::Ordered,
+            });
+        }
+        for field in funk_ty.fts_indexes.iter() {
+            indexes.push(Index {
+                type_name: type_name.clone(),
+                fields: vec![field.to_string()],
+                kind: Kind::FullText,
+            });
+        }
+        for (field, metric) in funk_ty.vector_indexes.iter() {
+            indexes.push(Index {
+                type_name: type_name.clone(),
+                fields: vec![field.to_string()],
+                kind: Kind::Nearest(*metric),
+            });
+        }
+    }
+    indexes
+}
+
+pub fn state(txn: &(impl Snapshot + ?Sized), index: &Index) -> Result<Option<State>> {
+    match txn.get(STATES, index.name().as_bytes())? {
+        Some(bytes) => Ok(Some(State::from_bytes(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Every index with a recorded state.
+pub fn states(txn: &(impl Snapshot + ?Sized)) -> Result<Vec<(Index, State)>> {
+    txn.scan(STATES, (Bound::Unbounded, Bound::Unbounded))?
+        .into_iter()
+        .map(|(name, state)| Ok((Index::from_name(&String::from_utf8(name)?)?, State::from_bytes(&state)?)))
+        .collect()
+}
+
+/// Updates the index entries of an object going from `old` to `new`,
+/// either of which is absent for an insert or a delete.
+pub(crate) fn maintain(
+    txn: &mut Transaction,
+    schema: &Interner,
+    old: Option<&Object>,
+    new: Option<&Object>,
+) -> Result<()> {
+    let Some(type_name) = old.or(new).map(|object| object.type_name.as_str()) else {
+        return Ok(());
+    };
+    for index in declared(schema).iter().filter(|index| index.covers(schema, type_name)) {
+        if let Kind::Nearest(_) = index.kind {
+            hnsw::maintain(txn, index, old, new)?;
+            continue;
+        }
+        let old_entries = old.map(|object| index.entries(object)).unwrap_or_default();
+        let new_entries = new.map(|object| index.entries(object)).unwrap_or_default();
+        let tree = index.tree();
+        for key in old_entries.keys().filter(|key| !new_entries.contains_key(*key)) {
+            txn.delete(&tree, key.clone());
+        }
+        for (key, value) in new_entries.iter() {
+            if old_entries.get(key) != Some(value) {
+                txn.put(&tree, key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `object` to `index`, unless it is in there already.
+fn add(txn: &mut Transaction, index: &Index, object: &Object) -> Result<()> {
+    if let Kind::Nearest(_) = index.kind {
+        return hnsw::insert(txn, index, object);
+    }
+    for (key, value) in index.entries(object) {
+        txn.put(&index.tree(), key, value);
+    }
+    Ok(())
+}
+
+/// Records the indexes `new` adds or drops compared to `old`, and returns
+/// them, for [`backfill`] and [`clear`] to finish once committed.
+pub(crate) fn migrate(txn: &mut Transaction, old: &Interner, new: &Interner) -> Vec<(Index, State)> {
+    let old = declared(old);
+    let new = declared(new);
+    let mut jobs = vec![];
+    for index in new.iter().filter(|index| !old.contains(index)) {
+        jobs.push((index.clone(), State::Building));
+    }
+    for index in old.iter().filter(|index| !new.contains(index)) {
+        jobs.push((index.clone(), State::Dropping));
+    }
+    for (index, state) in jobs.iter() {
+        txn.put(STATES, index.name().into_bytes(), state.as_bytes().to_vec());
+    }
+    jobs
+}
+
+/// Runs `work` in a serializable transaction until it commits without a
+/// conflict.
+pub(crate) fn retrying<T>(db: &Mvcc, mut work: impl FnMut(&mut Transaction) -> Result<T>) -> Result<T> {
+    loop {
+        let mut txn = db.begin(Isolation::Serializable);
+        let done = work(&mut txn)?;
+        match txn.commit() {
+            Err(err) if is_retryable(&err) => continue,
+            result => return result.map(|_| done),
+        }
+    }
+}
+
+/// Indexes the objects that were there before `index` was declared at
+/// tick `since`, then marks it ready. Writes go on meanwhile: each chunk
+/// is a transaction of its own.
+pub fn backfill(db: &Mvcc, index: &Index, since: u64) -> Result<()> {
+    db.wait_for_older(since);
+    let ids: Vec<Uuid> = {
+        let txn = db.begin(Isolation::Snapshot);
+        let schema = FunkDb::schema_in(&txn)?;
+        let mut ids = vec![];
+        for type_name in object::subtypes(&schema, &index.type_name) {
+            for (key, _) in txn.scan(&object::tree(&type_name), (Bound::Unbounded, Bound::Unbounded))? {
+                ids.push(Uuid::from_slice(&key)?);
+            }
+        }
+        ids
+    };
+    for chunk in ids.chunks(CHUNK) {
+        let building = retrying(db, |txn| {
+            if state(txn, index)? != Some(State::Building) {
+                return Ok(false);
+            }
+            for id in chunk {
+                // Gone since, or changed since and indexed by that write.
+                if let Some(object) = object::stored(txn, *id)? {
+                    add(txn, index, &object)?;
+                }
+            }
+            Ok(true)
+        })?;
+        if !building {
+            return Ok(());
+        }
+    }
+    retrying(db, |txn| {
+        if state(txn, index)? == Some(State::Building) {
+            txn.put(STATES, index.name().into_bytes(), State::Ready.as_bytes().to_vec());
+        }
+        Ok(())
+    })
+}
+
+/// Removes every entry of `index`, dropped at tick `since`, then forgets it.
+pub fn clear(db: &Mvcc, index: &Index, since: u64) -> Result<()> {
+    db.wait_for_older(since);
+    let tree = index.tree();
+    loop {
+        let cleared = retrying(db, |txn| {
+            if state(txn, index)? != Some(State::Dropping) {
+                return Ok(true);
+            }
+            let keys = txn.scan(&tree, (Bound::Unbounded, Bound::Unbounded))?;
+            for (key, _) in keys.iter().take(CHUNK) {
+                txn.delete(&tree, key.clone());
+            }
+            if keys.len() <= CHUNK {
+                txn.delete(STATES, index.name().into_bytes());
+                return Ok(true);
+            }
+            Ok(false)
+        })?;
+        if cleared {
+            return Ok(());
+        }
+    }
+}
+
+/// What [`tidy`] went through, and what it dropped: entries, or the
+/// links of a nearest neighbour graph.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tidied {
+    pub seen: usize,
+    pub dropped: usize,
+}
+
+impl Tidied {
+    pub fn add(&mut self, other: Tidied) {
+        self.seen += other.seen;
+        self.dropped += other.dropped;
+    }
+
+    /// The share of what there was that went, 0 for an empty index.
+    pub fn fragmentation(&self) -> f64 {
+        match self.seen {
+            0 => 0.0,
+            seen => self.dropped as f64 / seen as f64,
+        }
+    }
+}
+
+/// Drops the entries of a ready `index` that no longer match a stored
+/// object, a chunk at a time. Writes keep entries in step, so these are
+/// what a crash or an older version left behind, and the links a nearest
+/// neighbour graph keeps to objects removed since.
+pub fn tidy(db: &Mvcc, index: &Index) -> Result<Tidied> {
+    let tree = index.tree();
+    let mut after: Option<Vec<u8>> = None;
+    let mut tidied = Tidied::default();
+    loop {
+        let (last, count) = retrying(db, |txn| {
+            if state(txn, index)? != Some(State::Ready) {
+                return Ok((None, Tidied::default()));
+            }
+            let schema = FunkDb::schema_in(txn)?;
+            let start = match &after {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let pairs = txn.scan(&tree, (start, Bound::Unbounded))?;
+            let mut count = Tidied::default();
+            for (key, value) in pairs.iter().take(CHUNK) {
+                if let Kind::Nearest(_) = index.kind {
+                    count.add(hnsw::tidy(txn, index, key, value)?);
+                    continue;
+                }
+                count.seen += 1;
+                let live = match object::stored(txn, id_of(key)?)? {
+                    Some(object) => index.covers(&schema, &object.type_name) && index.entries(&object).contains_key(key),
+                    None => false,
+                };
+                if !live {
+                    txn.delete(&tree, key.clone());
+                    count.dropped += 1;
+                }
+            }
+            let last = pairs.get(CHUNK).map(|_| pairs[CHUNK - 1].0.clone());
+            Ok((last, count))
+        })?;
+        tidied.add(count);
+        match last {
+            Some(key) => after = Some(key),
+            None => return Ok(tidied),
+        }
+    }
+}
+
+/// What is wrong with the entries of `index`, given the `objects` it
+/// covers: entries missing or out of date, and entries no object has.
+pub fn verify(txn: &(impl Snapshot + ?Sized), index: &Index, objects: &[Object]) -> Result<Vec<String>> {
+    if let Kind::Nearest(_) = index.kind {
+        return hnsw::verify(txn, index, objects);
+    }
+    let expected: BTreeMap<Vec<u8>, Vec<u8>> = objects.iter().flat_map(|object| index.entries(object)).collect();
+    let actual: BTreeMap<Vec<u8>, Vec<u8>> = txn.scan(&index.tree(), (Bound::Unbounded, Bound::Unbounded))?.into_iter().collect();
+    let mut problems = BTreeSet::new();
+    for (key, value) in expected.iter() {
+        match actual.get(key) {
+            None => problems.insert(format!("{} is missing an entry", id_of(key)?)),
+            Some(stored) if stored != value => problems.insert(format!("{} has an entry out of date", id_of(key)?)),
+            Some(_) => false,
+        };
+    }
+    for key in actual.keys().filter(|key| !expected.contains_key(*key)) {
+        match id_of(key) {
+            Ok(id) => problems.insert(format!("{id} has an entry it should not")),
+            Err(_) => problems.insert(format!("The entry {key:02x?} is malformed")),
+        };
+    }
+    Ok(problems.into_iter().collect())
+}
+
+/// Empties `index` and indexes every object afresh, for when its entries
+/// no longer agree with the objects. Queries pass it over meanwhile, as
+/// they do while an index is first built.
+pub fn rebuild(db: &Mvcc, index: &Index) -> Result<()> {
+    retrying(db, |txn| {
+        txn.put(STATES, index.name().into_bytes(), State::Building.as_bytes().to_vec());
+        Ok(())
+    })?;
+    let tree = index.tree();
+    // Writes meanwhile may add entries that go too, but the backfill
+    // starts after and indexes their objects again.
+    loop {
+        let emptied = retrying(db, |txn| {
+            let keys = txn.scan(&tree, (Bound::Unbounded, Bound::Unbounded))?;
+            for (key, _) in keys.iter().take(CHUNK) {
+                txn.delete(&tree, key.clone());
+            }
+            Ok(keys.len() <= CHUNK)
+        })?;
+        if emptied {
+            return backfill(db, index, db.clock());
+        }
+    }
+}
+
+/// Finishes whatever `job` an index was left in.
+pub fn finish(db: &Mvcc, index: &Index, state: State, since: u64) -> Result<()> {
+    match state {
+        State::Building => backfill(db, index, since),
+        State::Dropping => clear(db, index, since),
+        State::Ready => Ok(()),
+    }
+}
+
+/// The indexes of a freshly opened database that are still being built or
+/// dropped, say because the process stopped halfway through.
+pub fn unfinished(db: &Mvcc) -> Result<Vec<(Index, State)>> {
+    let txn = db.begin(Isolation::Snapshot);
+    Ok(states(&txn)?.into_iter().filter(|(_, state)| *state != State::Ready).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+
+    #[test]
+    fn keys_sort_by_field_values() -> Result<()> {
+        let index = Index {
+            type_name: "default::A".into(),
+            fields: vec!["n".into(), "tags".into()],
+            kind: Kind::Ordered,
+        };
+        assert_eq!(Index::from_name(&index.name())?, index);
+        let object = Object::new("default::A").set("n", 2).push("tags", "x").push("tags", "y");
+        let keys: Vec<_> = index.keys(&object).into_iter().collect();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| id_of(key).unwrap() == object.id));
+        let unset = index.keys(&Object::new("default::A"));
+        let smaller = index.keys(&Object::new("default::A").set("n", -5));
+        assert!(unset.first() < smaller.first() && smaller.first() < keys.first());
+
+        let schema = sdl::parse(
+            "module default {
+                abstract type Named { name: str; index on (.name); }
+                type A extending Named { n: int32; index on ((.n, .name)); }
+            }",
+        )?;
+        let indexes: Vec<String> = declared(&schema).iter().map(Index::name).collect();
+        assert_eq!(indexes, ["default::A(n, name)", "default::Named(name)"]);
+        assert!(declared(&schema).iter().all(|index| index.covers(&schema, "default::A")));
+        Ok(())
+    }
+
+    #[test]
+    fn backfills_and_drops_run_alongside_writes() -> Result<()> {
+        use crate::query::Filter;
+        use crate::FunkDb;
+        const PLAIN: &str = "module default { type A { n: int32; } }";
+        const INDEXED: &str = "module default { type A { n: int32; index on (.n); } }";
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(PLAIN)?;
+        let ids = (0..600).map(|n| db.insert(Object::new("A").set("n", n % 7))).collect::<Result<Vec<_>>>()?;
+
+        let index = &declared(&sdl::parse(INDEXED)?)[0];
+        let reader = db.begin();
+        db.set_catalog(INDEXED)?;
+        // The backfill waits for `reader`, which began under the old schema,
+        // and writes go on meanwhile.
+        assert_eq!(state(&db.begin(), index)?, Some(State::Building));
+        assert!(db.plan("A", &[Filter::eq("n", 3)])?.to_string().starts_with("scan"));
+        db.delete(ids[3])?;
+        db.update(db.object(ids[10])?.unwrap().set("n", 3))?;
+        db.insert(Object::new("A").set("n", 3))?;
+        drop(reader);
+        db.wait_for_indexes()?;
+
+        assert_eq!(state(&db.begin(), index)?, Some(State::Ready));
+        assert_eq!(db.plan("A", &[Filter::eq("n", 3)])?.to_string(), "index default::A(n) by equal on n");
+        let expected = db.objects("A")?.into_iter().filter(|object| object.get("n") == Some(&3.into())).count();
+        assert_eq!(db.select("A", &[Filter::eq("n", 3)])?.len(), expected);
+        let entries = db.begin().scan(&index.tree(), (Bound::Unbounded, Bound::Unbounded))?.len();
+        assert_eq!(entries, 600);
+
+        db.set_catalog(PLAIN)?;
+        db.wait_for_indexes()?;
+        let txn = db.begin();
+        assert!(states(&txn)?.is_empty());
+        assert!(txn.scan(&index.tree(), (Bound::Unbounded, Bound::Unbounded))?.is_empty());
+        Ok(())
+    }
+}
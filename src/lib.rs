@@ -1,18 +1,46 @@
 #![allow(unused_imports, non_snake_case, non_camel_case_types, dead_code)]
 use anyhow::{self as ah, anyhow, bail, Error, Result};
-use std::borrow::{Cow, BorrowMut};
+use std::borrow::Cow;
 use std::cell::{RefCell, RefMut};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
 #[cfg(any(unix, target_os = "wasi"))]
 use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use strum::{EnumIter, IntoEnumIterator};
+use storage::{Access, AsOf, Backend, FileLock, Isolation, Mvcc, StorageEngine, Transaction};
+use periodic::Periodic;
 use typed_builder::TypedBuilder;
 
+pub mod backup;
+pub mod changes;
+pub mod codec;
+pub mod diagram;
+pub mod docs;
+pub mod dump;
+pub mod expiry;
+pub mod export;
+pub mod fsck;
+pub mod fts;
+pub mod hnsw;
+pub mod import;
+pub mod index;
+pub mod lint;
+pub mod object;
+mod periodic;
+pub mod query;
+pub mod sdl;
+pub mod stats;
+pub mod storage;
+pub mod suggest;
+pub mod vacuum;
+pub mod value;
+
 // Note: Module<'a> automatically implements ToOwned and Borrow<Self<'_>>
 #[derive(Debug, Clone)]
 struct Module<'a> {
@@ -94,7 +122,7 @@ struct Namespace<'a> {
 }
 
 impl<'a> Namespace<'a> {
-    fn register_module(&'a mut self, new_module: &'a mut Module<'a>) -> anyhow::Result<()> {
+    fn register_module(&mut self, new_module: &mut Module<'a>) -> anyhow::Result<()> {
         if let Some(_found) = self
             .modules
             .iter()
@@ -102,14 +130,8 @@ impl<'a> Namespace<'a> {
         {
             bail!("Module registration occurred twice!");
         } else {
-            self.modules.push(Cow::Borrowed(new_module));
-            Box::leak(
-                Box::new(
-                    self.interner
-                        .borrow_mut()
-                        .take()
-                )
-            ).commit_module(&new_module);
+            self.modules.push(Cow::Owned(new_module.clone()));
+            self.interner.borrow_mut().commit_module(new_module);
         }
         Ok(())
     }
@@ -117,56 +139,142 @@ impl<'a> Namespace<'a> {
         &mut self,
         commits: &Vec<(Module<'a>, Vec<FunkData<'a>>)>,
     ) -> anyhow::Result<()> {
-        use std::ops::Deref;
         // For each of the modules we want to verify the type submission
         // as a unique entry. If a single type submission cannot be completed,
         // yeet the error back to the caller.
-        // 
-        // If the type has an external property or link outside of the current 
+        //
+        // If the type has an external property or link outside of the current
         // module, check the interner for name resolution.
         let mut errors = vec![];
-        
+        let mut pending: Vec<(&str, &str)> = vec![];
+
         for commit in commits {
             let (module, submissions): &(Module, Vec<FunkData>) = commit;
 
-            // First we need to verify that a given module has not been taken
+            // First we need to verify that a given module has been registered
             // before introspecting the module-level types, abstract types,
             // triggers, mutations, traits, or even properties and links on those types.
-            let module_name = module.get_name().to_owned();
-
-            if !self.interner.borrow().is_name_available(
-                    Some(module_name.as_ref()), 
-                    None, 
-                    None) 
-            {
-                errors.push(anyhow!("Module {0}: already defined!", module_name.as_ref()));
+            let module_name = module.get_name().as_ref();
+
+            if self.interner.borrow().is_name_available(Some(module_name), None, None) {
+                errors.push(anyhow!(
+                    "Module {0}: not registered!{1}",
+                    module_name,
+                    self.interner.borrow().suggest_module(module_name)
+                ));
                 continue;
             }
-            
+
             for funkdata in submissions {
-                let type_name = funkdata.get_name().unwrap().to_owned();
-                if !self.interner.borrow().is_name_available(
-                        Some(module_name.as_ref()), 
-                        Some(type_name.as_ref()), 
-                        None) 
+                let type_name = funkdata.get_name().unwrap();
+                if pending.contains(&(module_name, type_name))
+                    || !self.interner.borrow().is_name_available(
+                        Some(module_name),
+                        Some(type_name),
+                        None,
+                    )
                 {
-                    errors.push(anyhow!("Schema level name `{0}::{1}` was defined more than once.", 
-                        module_name.as_ref(), 
+                    errors.push(anyhow!(
+                        "Schema level name `{0}::{1}` was defined more than once.",
+                        module_name,
                         type_name
                     ));
                     continue;
-                } else {
-                    
+                }
+                pending.push((module_name, type_name));
+            }
+        }
+
+        // Links may point at anything else in this submission, or at
+        // anything the interner already knows about.
+        for (module, submissions) in commits {
+            let module_name = module.get_name().as_ref();
+            for funkdata in submissions {
+                let FunkData::custom(funk_ty) = funkdata else {
+                    continue;
+                };
+                for (link_name, (target, _, _)) in funk_ty.links.iter() {
+                    let target_name = target.get_name().unwrap();
+                    let is_pending = match target_name.rsplit_once("::") {
+                        Some(qualified) => pending.contains(&qualified),
+                        None => pending.iter().any(|(_, name)| *name == target_name),
+                    };
+                    let is_known = self
+                        .interner
+                        .borrow()
+                        .resolve_type(module_name, target_name)
+                        .is_some();
+                    if !is_pending && !is_known {
+                        let mut candidates = self.interner.borrow().type_candidates(target_name);
+                        candidates.extend(pending.iter().map(|(r#mod, name)| match target_name.contains("::") {
+                            true => format!("{mod}::{name}"),
+                            false => name.to_string(),
+                        }));
+                        errors.push(anyhow!(
+                            "Link `{0}::{1}.{2}` points at unknown type `{3}`.{4}",
+                            module_name,
+                            funk_ty.get_name().unwrap(),
+                            link_name,
+                            target_name,
+                            suggest::did_you_mean(target_name, candidates.iter().map(String::as_str))
+                        ));
+                    }
                 }
             }
         }
+
+        if !errors.is_empty() {
+            let report: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            bail!("{}", report.join("\n"));
+        }
+
+        for (module, submissions) in commits {
+            let mut interner = self.interner.borrow_mut();
+            for funkdata in submissions {
+                interner.commit_member(module.get_name().as_ref(), funkdata.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Attaches annotations to something that has already been committed:
+    /// a module, a `module::Type`, or a property or link on that type.
+    fn annotate(
+        &mut self,
+        module_name: &str,
+        identity: Option<&str>,
+        field: Option<&str>,
+        annotations: Annotations<'a>,
+    ) -> anyhow::Result<()> {
+        let mut interner = self.interner.borrow_mut();
+        if interner.is_name_available(Some(module_name), None, None) {
+            bail!("Module {0}: not registered!{1}", module_name, interner.suggest_module(module_name));
+        }
+        if let Some(type_name) = identity {
+            let Some((_, funk_ty)) = interner.resolve_type(module_name, type_name) else {
+                bail!(
+                    "Cannot annotate unknown type `{module_name}::{type_name}`.{}",
+                    interner.suggest_type(type_name)
+                );
+            };
+            if let Some(field) = field {
+                funk_ty.check_field(field)?;
+            }
+        }
+        let key = (
+            Some(Cow::Owned(module_name.to_string())),
+            identity.map(|identity| Cow::Owned(identity.to_string())),
+            field.map(|field| Cow::Owned(field.to_string())),
+        );
+        interner.annotations.insert(key, annotations);
         Ok(())
     }
 }
 
 #[derive(Default, Debug, Clone)]
-pub(crate) struct Interner<'interner> {
+pub struct Interner<'interner> {
     pub metadata: MetaMap<'interner>,
+    pub annotations: AnnotationMap<'interner>,
 }
 
 pub type MetaMap<'a> = BTreeMap<
@@ -178,6 +286,47 @@ pub type MetaMap<'a> = BTreeMap<
     FunkData<'a>,
 >;
 
+/// Annotations live next to the metadata they describe, under the very
+/// same key, so a `(module, None, None)` entry annotates a module and a
+/// `(module, type, field)` entry annotates a single property or link.
+pub type AnnotationMap<'a> = BTreeMap<
+    (
+        /* module_name = */ Option<Cow<'a, str>>,
+        /*    identity = */ Option<Cow<'a, str>>,
+        /*  assignment = */ Option<Cow<'a, str>>,
+    ),
+    Annotations<'a>,
+>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Annotations<'a> {
+    pub title: Option<Cow<'a, str>>,
+    pub description: Option<Cow<'a, str>>,
+    pub deprecated: Option<Cow<'a, str>>,
+}
+
+impl<'a> Annotations<'a> {
+    pub const NAMES: [&'static str; 3] = ["title", "description", "deprecated"];
+
+    pub fn set<T: Into<Cow<'a, str>>>(&mut self, name: &str, value: T) -> anyhow::Result<()> {
+        let slot = match name {
+            "title" => &mut self.title,
+            "description" => &mut self.description,
+            "deprecated" => &mut self.deprecated,
+            _ => bail!("Unknown annotation `{name}`.{}", suggest::did_you_mean(name, Self::NAMES)),
+        };
+        if slot.is_some() {
+            bail!("Annotation `{name}` was given more than once.");
+        }
+        *slot = Some(value.into());
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
 #[doc(hidden)]
 #[derive(Default)]
 struct Key<'a> {
@@ -202,8 +351,10 @@ impl<'interner> Interner<'interner> {
     pub fn new() -> Self {
         Interner {
             metadata: MetaMap::new(),
+            annotations: AnnotationMap::new(),
         }
     }
+    #[allow(clippy::needless_update)]
     pub fn is_name_available(
         &self,
         module_name: Option<&str>,
@@ -221,18 +372,18 @@ impl<'interner> Interner<'interner> {
                     identity = ident,
                     assignment = link_or_prop,
                 };
-                self.metadata.get(&(k.r#mod, k.identity, k.assignment)).is_none()
+                !self.metadata.contains_key(&(k.r#mod, k.identity, k.assignment))
             }
             (Some(module), Some(ident), None) => {
                 let k = key!{
                     r#mod = module,
                     identity = ident,
                 };
-                self.metadata.get(&(k.r#mod, k.identity, k.assignment)).is_none()
+                !self.metadata.contains_key(&(k.r#mod, k.identity, k.assignment))
             }
             (Some(module), None, None) => {
                 let k = key!{ r#mod = module };
-                self.metadata.get(&(k.r#mod, k.identity, k.assignment)).is_none()
+                !self.metadata.contains_key(&(k.r#mod, k.identity, k.assignment))
             }
             _ => {
                 panic!("Verify the arguments passed to is_name_available");
@@ -240,12 +391,12 @@ impl<'interner> Interner<'interner> {
         }
     }
 
-    fn commit_module(&'interner mut self, module: &Module<'interner>) {
+    fn commit_module(&mut self, module: &Module<'interner>) {
         let r#mod = FunkData::nil;
-        assert!(self.metadata.insert((Some(module.name.to_owned()), None, None), r#mod).is_none());
+        assert!(self.metadata.insert((Some(module.name.clone()), None, None), r#mod).is_none());
     }
 
-    fn commit_member(&'interner mut self, entry: FunkData<'interner>) {
+    fn commit_member(&mut self, module_name: &str, entry: FunkData<'interner>) {
         match entry {
             FunkData::primitive(funk_std) => {
                 let r#mod = Some(Cow::Owned("std".to_string()));
@@ -253,15 +404,94 @@ impl<'interner> Interner<'interner> {
                 assert!(self.metadata.insert((r#mod, member, None), entry).is_none());
             }
             FunkData::custom(ref funk_ty) => {
-                assert!(self.metadata.insert((funk_ty.type_name.clone(), None, None), entry).is_none());
+                let r#mod = Some(Cow::Owned(module_name.to_string()));
+                let member = funk_ty.type_name.clone();
+                assert!(self.metadata.insert((r#mod, member, None), entry).is_none());
             }
             FunkData::nil => { panic!("Incorrect usage of `commit_member`. Use `commit_module` instead."); }
-        }        
+        }
+    }
+
+    /// Every committed module name, `std` included.
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.metadata.iter().filter_map(|((r#mod, identity, assignment), _)| {
+            match (r#mod, identity, assignment) {
+                (Some(r#mod), None, None) => Some(r#mod.as_ref()),
+                _ => None,
+            }
+        })
     }
 
-    
+    /// Every committed user-defined type alongside the module it lives in,
+    /// ordered by `(module, type)`.
+    pub fn types(&self) -> impl Iterator<Item = (&str, &FunkTy<'interner>)> {
+        self.metadata.iter().filter_map(|((r#mod, identity, assignment), data)| {
+            match (r#mod, identity, assignment, data) {
+                (Some(r#mod), Some(_), None, FunkData::custom(funk_ty)) => {
+                    Some((r#mod.as_ref(), funk_ty))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Looks up a type by name as seen from inside `module_name`.
+    ///
+    /// A `module::Type` name is looked up verbatim, while a bare name is
+    /// first looked up in `module_name` and then in every other module.
+    pub fn resolve_type(&self, module_name: &str, name: &str) -> Option<(&str, &FunkTy<'interner>)> {
+        if let Some((r#mod, ident)) = name.rsplit_once("::") {
+            return self.types().find(|(m, ty)| *m == r#mod && ty.get_name() == Some(ident));
+        }
+        self.types()
+            .find(|(m, ty)| *m == module_name && ty.get_name() == Some(name))
+            .or_else(|| self.types().find(|(_, ty)| ty.get_name() == Some(name)))
+    }
+
+    /// A "did you mean" hint for a module name that failed to resolve.
+    pub fn suggest_module(&self, name: &str) -> String {
+        suggest::did_you_mean(name, self.modules())
+    }
+
+    /// A "did you mean" hint for a type name that failed to resolve. Scalars
+    /// are candidates too, since a misspelled scalar reads like a link.
+    pub fn suggest_type(&self, name: &str) -> String {
+        let candidates = self.type_candidates(name);
+        suggest::did_you_mean(name, candidates.iter().map(String::as_str))
+    }
+
+    // Qualified candidates for a qualified name, bare ones otherwise.
+    fn type_candidates(&self, name: &str) -> Vec<String> {
+        let is_qualified = name.contains("::");
+        let types = self.types().map(|(r#mod, funk_ty)| match is_qualified {
+            true => format!("{mod}::{}", funk_ty.get_name().unwrap()),
+            false => funk_ty.get_name().unwrap().to_string(),
+        });
+        let scalars = funkstd::iter().map(|scalar| match is_qualified {
+            true => format!("std::{}", scalar.get_name().unwrap()),
+            false => scalar.get_name().unwrap().to_string(),
+        });
+        types.chain(scalars).collect()
+    }
+
+    /// Annotations on a module (`identity` and `field` are `None`), a type
+    /// (`field` is `None`) or a property or link.
+    pub fn annotations_of(
+        &self,
+        module_name: &str,
+        identity: Option<&str>,
+        field: Option<&str>,
+    ) -> Option<&Annotations<'interner>> {
+        self.annotations.get(&(
+            Some(Cow::Owned(module_name.to_string())),
+            identity.map(|identity| Cow::Owned(identity.to_string())),
+            field.map(|field| Cow::Owned(field.to_string())),
+        ))
+    }
 }
 
+// Types are few and long-lived, boxing them would buy nothing.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum FunkData<'interner> {
     primitive(funkstd),
@@ -307,6 +537,11 @@ pub enum funkstd {
     uint32,
     uint64,
     uint128,
+    // `vector<N>`: N `f32`s, the dimension being kept by the type with
+    // the property, see [`FunkTy::dimensions`].
+    vector,
+    // A point in time, to the microsecond, written in RFC 3339.
+    datetime,
 }
 
 impl Named<'_> for funkstd {
@@ -324,7 +559,8 @@ impl Named<'_> for funkstd {
             Self::uint64 => Some("uint64"),
             Self::uint128 => Some("uint128"),
             Self::str => Some("str"),
-            Self::bool => Some("bool"),
+            Self::vector => Some("vector"),
+            Self::datetime => Some("datetime"),
         }
     }
 }
@@ -352,13 +588,89 @@ pub struct FunkTy<'a> {
     pub type_name: Option<Cow<'a, str>>,
     pub properties: FunkPropMap<'a>,
     pub links: FunkLinkMap<'a>,
+    // Abstract types never hold objects of their own, they only lend
+    // their properties and links to the types `extending` them.
+    pub is_abstract: bool,
+    pub extending: Vec<Cow<'a, str>>,
+    // One entry per `index on (...)`, naming the properties or links
+    // that make up the index key, in order.
+    pub indexes: Vec<Vec<Cow<'a, str>>>,
+    // One entry per `index fts::index on (...)`, naming the `str`
+    // property searched through it.
+    pub fts_indexes: Vec<Cow<'a, str>>,
+    // The dimension of every `vector<N>` property.
+    pub dimensions: BTreeMap<Cow<'a, str>, usize>,
+    // One entry per `index hnsw::<metric> on (...)`, naming the vector
+    // property searched through it.
+    pub vector_indexes: Vec<(Cow<'a, str>, hnsw::Metric)>,
+    // How long objects live, from `expire after '30d';` or
+    // `expire on (.field);`, see [`expiry`].
+    pub ttl: Option<expiry::Ttl>,
+    // How records are compressed, from `compress lz4;`, see
+    // [`storage::compress`].
+    pub compression: Option<storage::Compression>,
 }
 
 impl<'a> FunkTy<'a> {
     pub fn r#type<T: Into<Cow<'a, str>>>(name: T) -> FunkTy<'a> {
-        let mut this = FunkTy::default();
-        this.type_name = Some(name.into());
-        this
+        FunkTy {
+            type_name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Fails with a "did you mean" hint unless `name` is one of this
+    /// type's properties or links.
+    pub fn check_field(&self, name: &str) -> anyhow::Result<()> {
+        if self.properties.contains_key(name) || self.links.contains_key(name) {
+            return Ok(());
+        }
+        let candidates = self
+            .properties
+            .keys()
+            .chain(self.links.keys())
+            .map(|field| field.as_ref());
+        bail!(
+            "`{}` has no property or link `{name}`.{}",
+            self.type_name.as_deref().unwrap_or("{unknown}"),
+            suggest::did_you_mean(name, candidates)
+        );
+    }
+
+    /// How the property `name` is spelled in a schema, `vector<N>`
+    /// included.
+    pub fn property_type(&self, name: &str) -> Option<String> {
+        let (kind, _, _) = self.properties.get(name)?;
+        match self.dimensions.get(name) {
+            Some(dimension) => Some(format!("vector<{dimension}>")),
+            None => Some(kind.get_name().unwrap().to_string()),
+        }
+    }
+
+    fn r#abstract(mut self) -> Self {
+        self.is_abstract = true;
+        self
+    }
+
+    fn extending<T: Into<Cow<'a, str>>>(mut self, base: T) -> Self {
+        self.extending.push(base.into());
+        self
+    }
+
+    fn add_index<T: Into<Cow<'a, str>>>(mut self, fields: Vec<T>) -> Self {
+        self.indexes
+            .push(fields.into_iter().map(Into::into).collect());
+        self
+    }
+
+    fn add_fts_index<T: Into<Cow<'a, str>>>(mut self, field: T) -> Self {
+        self.fts_indexes.push(field.into());
+        self
+    }
+
+    fn add_vector_index<T: Into<Cow<'a, str>>>(mut self, field: T, metric: hnsw::Metric) -> Self {
+        self.vector_indexes.push((field.into(), metric));
+        self
     }
 
     fn add_property<T: Into<Cow<'a, str>>>(mut self, prop: (T, funkstd)) -> Self {
@@ -455,6 +767,456 @@ impl<'interner> Named<'interner> for FunkTy<'interner> {
     }
 }
 
+#[derive(TypedBuilder, Debug, Clone, Default)]
+pub struct FunkDbOptions {
+    /// Overrides the backend a plain path would get. A URL scheme in the
+    /// location has to agree with it.
+    #[builder(default, setter(strip_option))]
+    pub backend: Option<Backend>,
+    /// What [`FunkDb::begin`] gives transactions.
+    #[builder(default)]
+    pub isolation: Isolation,
+    /// Opens the database for reading only, sharing it with other readers,
+    /// except for sled, which only one process opens at a time. Otherwise
+    /// the opening process is its only user until it closes it.
+    #[builder(setter(strip_bool))]
+    pub read_only: bool,
+    /// How often expired objects are deleted for good, by default every
+    /// [`expiry::SWEEP_EVERY`]. They are invisible from the moment they
+    /// expire either way.
+    #[builder(default, setter(strip_option))]
+    pub sweep_every: Option<std::time::Duration>,
+    /// How long to keep what commits replace, so the database can be read
+    /// as of any transaction in that window, see [`FunkDb::as_of`]. The
+    /// window is saved with the database, so opening it without one keeps
+    /// the last; a zero window stops keeping history and drops what there
+    /// is.
+    #[builder(default, setter(strip_option))]
+    pub retain: Option<std::time::Duration>,
+    /// How often to [vacuum](FunkDb::vacuum) in the background, if at all.
+    #[builder(default, setter(strip_option))]
+    pub vacuum_every: Option<std::time::Duration>,
+    /// Encrypts a new `.funk` file, and opens an encrypted one; without
+    /// it, [`storage::Key::from_env`] may provide one. See [`storage::crypt`].
+    #[builder(default, setter(strip_option))]
+    pub key: Option<storage::Key>,
+    /// Compresses the pages of a `.funk` file from now on; without it,
+    /// they stay as they are. Types compress their records with
+    /// `compress lz4;` in the schema instead, see [`storage::compress`].
+    #[builder(default, setter(strip_option))]
+    pub compression: Option<storage::Compression>,
+}
+
+#[allow(dead_code)]
+pub struct FunkDb {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    db: Arc<Mvcc>,
+    isolation: Isolation,
+    lock: Option<FileLock>,
+    /// Index backfills and drops, and rekeying, running in the background.
+    jobs: Mutex<Vec<thread::JoinHandle<anyhow::Result<()>>>>,
+    /// Deletes expired objects, unless the database is read-only.
+    sweeper: Option<Periodic>,
+    /// Vacuums, if asked to.
+    vacuumer: Option<Periodic>,
+}
+
+impl FunkDb {
+    pub fn new<F: IntoRawFd>(path: PathBuf, fileno: Option<F>, store: Box<dyn StorageEngine>) -> anyhow::Result<Self> {
+        let stream = match fileno {
+            Some(f) => {
+                let fd = f.into_raw_fd();
+                Some(unsafe { <UnixStream as FromRawFd>::from_raw_fd(fd) })
+            }
+            None => None,
+        };
+        Ok(Self {
+            path,
+            stream,
+            db: Arc::new(Mvcc::new(store)?),
+            isolation: Isolation::default(),
+            lock: None,
+            jobs: Mutex::default(),
+            sweeper: None,
+            vacuumer: None,
+        })
+    }
+    /// Opens the database at `location`, which is either a path to a
+    /// `.funk` file or a URL naming the backend, see [`storage`].
+    pub fn open(location: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::open_with(location, FunkDbOptions::default())
+    }
+    pub fn open_with(location: impl AsRef<Path>, options: FunkDbOptions) -> anyhow::Result<Self> {
+        let location = location.as_ref().to_string_lossy();
+        let access = match options.read_only {
+            true => Access::ReadOnly,
+            false => Access::ReadWrite,
+        };
+        let (backend, path) = storage::parse_location(&location)?;
+        // Lock before the engine so much as reads, since opening may replay
+        // and truncate a log.
+        let backend = options.backend.unwrap_or(backend);
+        let lock = match backend {
+            Backend::Memory => None,
+            Backend::Sled => Some(FileLock::acquire_alone(&path, access)?),
+            _ => Some(FileLock::acquire(&path, access)?),
+        };
+        let key = match options.key {
+            Some(key) => Some(key),
+            None if backend == Backend::File => storage::Key::from_env()?,
+            None => None,
+        };
+        let (path, store) = storage::open(&location, options.backend, access, key.as_ref(), options.compression)?;
+        let mut db = Self::new(path, Option::<UnixStream>::None, store)?;
+        db.isolation = options.isolation;
+        db.lock = lock;
+        if options.read_only {
+            Arc::get_mut(&mut db.db).expect("not shared yet").set_read_only();
+            return Ok(db);
+        }
+        if let Some(window) = options.retain {
+            let window = Some(window).filter(|window| !window.is_zero());
+            Arc::get_mut(&mut db.db).expect("not shared yet").set_retention(window)?;
+        }
+        let since = db.db.clock();
+        for (index, state) in index::unfinished(&db.db)? {
+            db.spawn(index, state, since);
+        }
+        let every = options.sweep_every.unwrap_or(expiry::SWEEP_EVERY);
+        let store = Arc::clone(&db.db);
+        db.sweeper = Some(Periodic::start("Sweeping expired objects", every, move || expiry::sweep(&store).map(drop)));
+        if let Some(every) = options.vacuum_every {
+            let store = Arc::clone(&db.db);
+            db.vacuumer = Some(Periodic::start("Vacuuming", every, move || vacuum::vacuum(&store).map(drop)));
+        }
+        Ok(db)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn backend(&self) -> Backend {
+        self.db.store().backend()
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.db.is_read_only()
+    }
+    /// Begins a transaction with the isolation the database was opened
+    /// with. Any number of them may run at once, from any thread.
+    pub fn begin(&self) -> Transaction<'_> {
+        self.db.begin(self.isolation)
+    }
+    pub fn begin_with(&self, isolation: Isolation) -> Transaction<'_> {
+        self.db.begin(isolation)
+    }
+    /// How many replaced values are kept around for open transactions.
+    pub fn versions(&self) -> usize {
+        self.db.versions()
+    }
+    /// The SDL source of the committed schema, if one was ever stored.
+    pub fn catalog(&self) -> anyhow::Result<Option<String>> {
+        storage::catalog(&self.begin())
+    }
+    /// Replaces the committed schema with `source`, provided it parses
+    /// and the backend could migrate its data to it.
+    ///
+    /// Indexes the new schema adds are built, and those it drops removed,
+    /// in the background; see [`FunkDb::wait_for_indexes`].
+    pub fn set_catalog(&self, source: &str) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            bail!("Cannot change the schema of a database opened read-only");
+        }
+        let schema = sdl::parse(source)?;
+        self.db.store().migrate(&schema)?;
+        let mut txn = self.begin();
+        let old = Self::schema_in(&txn)?;
+        let jobs = index::migrate(&mut txn, &old, &schema);
+        txn.put(storage::CATALOG, b"schema".to_vec(), source.as_bytes().to_vec());
+        txn.commit()?;
+        let since = self.db.clock();
+        for (index, state) in jobs {
+            self.spawn(index, state, since);
+        }
+        Ok(())
+    }
+    fn spawn(&self, index: index::Index, state: index::State, since: u64) {
+        let db = Arc::clone(&self.db);
+        let job = thread::spawn(move || index::finish(&db, &index, state, since));
+        self.jobs.lock().unwrap().push(job);
+    }
+    /// Blocks until every index is built or dropped, and a [rekey] is
+    /// done. Must not be called with a transaction open on this thread,
+    /// since building waits for the transactions that began before it.
+    ///
+    /// [rekey]: FunkDb::rekey
+    pub fn wait_for_indexes(&self) -> anyhow::Result<()> {
+        let jobs = std::mem::take(&mut *self.jobs.lock().unwrap());
+        for job in jobs {
+            job.join().map_err(|_| anyhow!("A background job panicked"))??;
+        }
+        Ok(())
+    }
+    /// The committed schema, or just `std` when there is none yet.
+    pub fn schema(&self) -> anyhow::Result<Interner<'static>> {
+        Self::schema_in(&self.begin())
+    }
+    /// The schema as `txn` sees it.
+    pub fn schema_in(txn: &(impl storage::Snapshot + ?Sized)) -> anyhow::Result<Interner<'static>> {
+        sdl::parse(&storage::catalog(txn)?.unwrap_or_default())
+    }
+    /// The database as it was right after transaction `txn` committed, to
+    /// read through [`object`], [`query`] and the rest like a transaction,
+    /// its schema included. Transaction ids are the ticks in the change
+    /// feed, see [`changes::Change::txn`]. History has to be kept back that
+    /// far, see [`FunkDbOptions::retain`].
+    pub fn as_of(&self, txn: u64) -> anyhow::Result<AsOf<'_>> {
+        self.db.as_of(txn)
+    }
+    /// The database as it was at `time`.
+    pub fn as_of_time(&self, time: std::time::SystemTime) -> anyhow::Result<AsOf<'_>> {
+        let value::FunkValue::datetime(micros) = value::FunkValue::from(time) else {
+            unreachable!("times convert to datetimes")
+        };
+        self.db.as_of_time(micros)
+    }
+    pub fn insert(&self, object: object::Object) -> anyhow::Result<uuid::Uuid> {
+        let mut txn = self.begin();
+        let schema = Self::schema_in(&txn)?;
+        let id = object::insert(&mut txn, &schema, object)?;
+        txn.commit()?;
+        Ok(id)
+    }
+    pub fn object(&self, id: uuid::Uuid) -> anyhow::Result<Option<object::Object>> {
+        object::get(&self.begin(), id)
+    }
+    pub fn update(&self, object: object::Object) -> anyhow::Result<()> {
+        let mut txn = self.begin();
+        let schema = Self::schema_in(&txn)?;
+        object::update(&mut txn, &schema, object)?;
+        txn.commit()
+    }
+    pub fn delete(&self, id: uuid::Uuid) -> anyhow::Result<()> {
+        let mut txn = self.begin();
+        let schema = Self::schema_in(&txn)?;
+        object::delete(&mut txn, &schema, id)?;
+        txn.commit()
+    }
+    /// Deletes every expired object now rather than waiting for the
+    /// sweeper, and returns how many went.
+    pub fn sweep(&self) -> anyhow::Result<usize> {
+        if self.is_read_only() {
+            bail!("Cannot sweep a database opened read-only");
+        }
+        expiry::sweep(&self.db)
+    }
+    /// Takes the last failure of the sweeper or of the vacuumer, if either
+    /// failed since last asked. Both try again next time round rather than
+    /// stop.
+    pub fn background_error(&self) -> Option<anyhow::Error> {
+        [&self.sweeper, &self.vacuumer]
+            .into_iter()
+            .flatten()
+            .find_map(|periodic| periodic.take_failure())
+    }
+    /// Checks the database end to end, see [`fsck`]. With `repair`, also
+    /// rebuilds the indexes found wanting, then checks again.
+    pub fn fsck(&self, repair: bool) -> anyhow::Result<fsck::Report> {
+        let report = fsck::check(self)?;
+        if !repair || report.problems.iter().all(|problem| problem.check != fsck::Check::Index) {
+            return Ok(report);
+        }
+        if self.is_read_only() {
+            bail!("Cannot repair a database opened read-only");
+        }
+        let repaired = fsck::repair(self, &report)?;
+        Ok(fsck::Report {
+            repaired,
+            ..fsck::check(self)?
+        })
+    }
+    pub fn is_encrypted(&self) -> bool {
+        self.db.store().is_encrypted()
+    }
+    /// Re-encrypts the `.funk` file and its log with `key` in the
+    /// background, or decrypts them given none; see
+    /// [`FunkDb::wait_for_indexes`]. The file is rewritten from a snapshot
+    /// while reads and commits go on; they wait only while the commits made
+    /// meanwhile are caught up and the file and log renamed in place. From
+    /// then on the database opens with the new key only.
+    pub fn rekey(&self, key: Option<storage::Key>) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            bail!("Cannot encrypt a database opened read-only");
+        }
+        if self.backend() != Backend::File {
+            bail!("Only .funk files can be encrypted, not {} databases", self.backend());
+        }
+        let db = Arc::clone(&self.db);
+        let job = thread::spawn(move || db.rekey(key.as_ref()));
+        self.jobs.lock().unwrap().push(job);
+        Ok(())
+    }
+    /// Imports the rows of `input` in batches, see [`import`], and writes
+    /// those it rejects to `errors`.
+    pub fn import(&self, import: &import::Import, input: impl std::io::Read, errors: impl Write) -> anyhow::Result<import::Summary> {
+        import::import(self, import, input, errors)
+    }
+    /// How much room the database takes up, and how well it compresses,
+    /// see [`stats`].
+    pub fn stats(&self) -> anyhow::Result<stats::Stats> {
+        stats::stats(self)
+    }
+    /// Reclaims the space deleted and expired objects, old history and
+    /// stale index entries take up, see [`vacuum`]. Reads and writes go on
+    /// meanwhile.
+    pub fn vacuum(&self) -> anyhow::Result<vacuum::Report> {
+        if self.is_read_only() {
+            bail!("Cannot vacuum a database opened read-only");
+        }
+        vacuum::vacuum(&self.db)
+    }
+    /// Up to `limit` committed changes to objects of `type_name` and of
+    /// types extending it, after `after` if given, see [`changes`]. The
+    /// position of the last one is where to carry on from.
+    pub fn changes(
+        &self,
+        type_name: &str,
+        after: Option<changes::Position>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<changes::Change>> {
+        let txn = self.begin();
+        changes::read(&txn, &Self::schema_in(&txn)?, type_name, after, limit)
+    }
+    /// Every object of `type_name`, including those of types extending it.
+    pub fn objects(&self, type_name: &str) -> anyhow::Result<Vec<object::Object>> {
+        let txn = self.begin();
+        object::scan(&txn, &Self::schema_in(&txn)?, type_name)
+    }
+    /// The objects of `type_name` passing every filter, see [`query`].
+    pub fn select(&self, type_name: &str, filters: &[query::Filter]) -> anyhow::Result<Vec<object::Object>> {
+        let txn = self.begin();
+        query::select(&txn, &Self::schema_in(&txn)?, type_name, filters)
+    }
+    pub fn plan(&self, type_name: &str, filters: &[query::Filter]) -> anyhow::Result<query::Plan> {
+        let txn = self.begin();
+        query::plan(&txn, &Self::schema_in(&txn)?, type_name, filters)
+    }
+    /// The objects of `type_name` matching a full-text `query`, best first,
+    /// see [`fts::search`].
+    pub fn search(&self, type_name: &str, query: &str) -> anyhow::Result<Vec<(object::Object, f64)>> {
+        let txn = self.begin();
+        fts::search(&txn, &Self::schema_in(&txn)?, type_name, query)
+    }
+    /// The `k` objects of `type_name` nearest `target` by their vector
+    /// `field` and passing every filter, see [`hnsw::nearest`].
+    pub fn nearest(
+        &self,
+        type_name: &str,
+        field: &str,
+        target: &[f32],
+        k: usize,
+        filters: &[query::Filter],
+    ) -> anyhow::Result<Vec<(object::Object, f32)>> {
+        let txn = self.begin();
+        hnsw::nearest(&txn, &Self::schema_in(&txn)?, type_name, field, target, k, filters)
+    }
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if self.stream.is_some() {
+            bail!("`save` not implemented!");
+        }
+        self.db.store().flush()
+    }
+}
+
+impl Drop for FunkDb {
+    fn drop(&mut self) {
+        // Jobs, the sweeper and the vacuumer hold on to the engine, and
+        // have to be done with it before the lock goes.
+        self.sweeper = None;
+        self.vacuumer = None;
+        let _ = self.wait_for_indexes();
+    }
+}
+
+/// Serves a [`FunkDb`] over a Unix domain socket, a thread per client.
+///
+/// Clients send one JSON request per line and get one JSON response per
+/// line back, or `{"error": "..."}` if the request failed. For now the
+/// only request reads the change feed, see [`FunkDb::changes`]:
+///
+/// ```text
+/// > {"changes": "default::FunksGiven", "after": "12.0", "limit": 100}
+/// < {"changes": [{"position": "13.0", "txn": 13, "op": "update", ...}], "cursor": "13.0"}
+/// ```
+///
+/// `after` and `limit` may be left out. `cursor` is the `after` of the
+/// next request, and stays what it was while nothing new is committed.
+pub struct FunkDbServer {
+    listener: UnixListener,
+    db: Arc<FunkDb>,
+}
+
+impl FunkDbServer {
+    /// How many changes a request gets at most unless it says otherwise.
+    pub const CHANGES_LIMIT: usize = 1000;
+
+    /// Listens on `server_path`, which is distinct from the database's
+    /// own file.
+    pub fn bind(server_path: impl AsRef<Path>, db: Arc<FunkDb>) -> anyhow::Result<Self> {
+        let listener = UnixListener::bind(server_path.as_ref())
+            .map_err(|e| anyhow!("Cannot listen on {}: {e}", server_path.as_ref().display()))?;
+        Ok(Self { listener, db })
+    }
+
+    /// Accepts clients until the listener fails.
+    pub fn serve(&self) -> anyhow::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let db = Arc::clone(&self.db);
+            thread::spawn(move || Self::serve_client(&db, stream));
+        }
+        Ok(())
+    }
+
+    fn serve_client(db: &FunkDb, stream: UnixStream) -> anyhow::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = Self::respond(db, &line).unwrap_or_else(|err| serde_json::json!({ "error": format!("{err:#}") }));
+            writeln!(out, "{response}")?;
+        }
+        Ok(())
+    }
+
+    fn respond(db: &FunkDb, line: &str) -> anyhow::Result<serde_json::Value> {
+        use serde_json::Value;
+        let request: Value = serde_json::from_str(line)?;
+        let Some(type_name) = request.get("changes").and_then(Value::as_str) else {
+            bail!("Unknown request, expected one like {{\"changes\": \"default::FunksGiven\"}}");
+        };
+        let after = match request.get("after") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(position)) => Some(position.parse::<changes::Position>()?),
+            Some(other) => bail!("`after` is a position like \"12.0\", not {other}"),
+        };
+        let limit = match request.get("limit") {
+            None | Some(Value::Null) => Self::CHANGES_LIMIT,
+            Some(limit) => match limit.as_u64() {
+                Some(limit) => usize::try_from(limit)?,
+                None => bail!("`limit` is a count of changes, not {limit}"),
+            },
+        };
+        let changes = db.changes(type_name, after, limit)?;
+        let cursor = changes.last().map(|change| change.position).or(after);
+        Ok(serde_json::json!({
+            "changes": changes.iter().map(changes::Change::to_json).collect::<Vec<_>>(),
+            "cursor": cursor.map(|position| position.to_string()),
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -464,7 +1226,227 @@ mod tests {
         let path = "tmp.funk";
         let mut db = FunkDb::open(path).expect("it works in testing");
         db.save()?;
-        assert!(fs::remove_file(db.path).is_ok());
+        assert!(fs::remove_file(&db.path).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn open_by_url_and_keep_the_catalog() -> anyhow::Result<()> {
+        let db = FunkDb::open("mem://")?;
+        assert_eq!(db.backend(), Backend::Memory);
+        assert!(db.catalog()?.is_none());
+        assert!(db.set_catalog("module default { type A { b: nope; } }").is_err());
+        db.set_catalog("module default { type A { b: str; } }")?;
+        assert!(db.schema()?.resolve_type("default", "A").is_some());
+
+        let path = storage::scratch_path("catalog.db");
+        let options = FunkDbOptions::builder().backend(Backend::Sqlite).build();
+        let db = FunkDb::open_with(&path, options.clone())?;
+        db.set_catalog("module default { type A { b: str; } }")?;
+        drop(db);
+        let db = FunkDb::open_with(&path, options)?;
+        assert_eq!(db.backend(), Backend::Sqlite);
+        assert!(db.catalog()?.unwrap().contains("type A"));
+        drop(db);
+        let tables = storage::SqliteStore::open(&path)?.connection().query_row(
+            "SELECT count(*) FROM sqlite_master WHERE name = 'default::A'",
+            [],
+            |row| row.get::<_, i64>(0),
+        )?;
+        assert_eq!(tables, 1);
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn objects_on_every_backend() -> anyhow::Result<()> {
+        use object::Object;
+        const SCHEMA: &str = "module default {
+            type FunksGiven { required expires: int32; multi tags: str; }
+            type ReasonForLiving { multi funks: FunksGiven; }
+        }";
+        for scheme in ["mem", "sled", "sqlite"] {
+            let path = storage::scratch_path(scheme);
+            let location = match scheme {
+                "mem" => "mem://".to_string(),
+                _ => format!("{scheme}://{}", path.display()),
+            };
+            let mut db = FunkDb::open(&location)?;
+            db.set_catalog(SCHEMA)?;
+            let given = db.insert(Object::new("FunksGiven").set("expires", 3).push("tags", "a"))?;
+            let reason = db.insert(Object::new("ReasonForLiving").link("funks", given))?;
+            assert_eq!(db.object(reason)?.unwrap().targets("funks"), [given]);
+            assert_eq!(db.objects("FunksGiven")?.len(), 1);
+            db.delete(given)?;
+            assert!(db.object(reason)?.unwrap().targets("funks").is_empty());
+            let again = db.insert(Object::new("FunksGiven").set("expires", 4).push("tags", "b"))?;
+            db.update(db.object(reason)?.unwrap().link("funks", again))?;
+            db.save()?;
+
+            match scheme {
+                "sled" => {
+                    drop(db);
+                    let store = storage::SledStore::open(&path)?;
+                    assert!(store.trees()?.contains(&"objects/default::FunksGiven".to_string()));
+                    assert!(store.get("objects/default::FunksGiven", again.as_bytes())?.is_some());
+                }
+                "sqlite" => {
+                    drop(db);
+                    let store = storage::SqliteStore::open(&path)?;
+                    let conn = store.connection();
+                    let row: (String, i64) = conn.query_row(
+                        "SELECT f.id, f.expires FROM \"default::ReasonForLiving.funks\" AS j
+                         JOIN \"default::FunksGiven\" AS f ON f.id = j.target",
+                        [],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )?;
+                    assert_eq!(row, (again.to_string(), 4));
+                    let tags: i64 =
+                        conn.query_row("SELECT count(*) FROM \"default::FunksGiven.tags\"", [], |row| row.get(0))?;
+                    assert_eq!(tags, 1);
+                }
+                _ => {}
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn one_writer_or_many_readers() -> anyhow::Result<()> {
+        let read_only = || FunkDbOptions::builder().read_only().build();
+        for scheme in ["file", "sqlite"] {
+            let path = storage::scratch_path(scheme);
+            let location = match scheme {
+                "file" => path.display().to_string(),
+                _ => format!("{scheme}://{}", path.display()),
+            };
+            assert!(FunkDb::open_with(&location, read_only()).is_err());
+            let db = FunkDb::open(&location)?;
+            db.set_catalog("module default { type A { n: int32; } }")?;
+            let id = db.insert(object::Object::new("A").set("n", 1))?;
+            let err = FunkDb::open(&location).err().unwrap().to_string();
+            assert!(err.contains(&format!("locked by process {}", std::process::id())), "{err}");
+            assert!(FunkDb::open_with(&location, read_only()).is_err());
+            drop(db);
+
+            let reader = FunkDb::open_with(&location, read_only())?;
+            let other = FunkDb::open_with(&location, read_only())?;
+            assert!(reader.is_read_only());
+            assert_eq!(other.object(id)?.unwrap().get("n"), Some(&value::FunkValue::int32(1)));
+            assert!(reader.insert(object::Object::new("A")).is_err());
+            assert!(reader.set_catalog("module default { }").is_err());
+            let err = FunkDb::open(&location).err().unwrap().to_string();
+            assert!(err.contains("open read-only in another process"), "{err}");
+            drop((reader, other));
+            FunkDb::open(&location)?.save()?;
+            assert!(!storage::lock::path_for(&path).exists());
+            fs::remove_file(&path)?;
+        }
+
+        // Sled has a reader to itself, and says whose it is.
+        let path = storage::scratch_path("sled");
+        let location = format!("sled://{}", path.display());
+        FunkDb::open(&location)?.save()?;
+        let reader = FunkDb::open_with(&location, read_only())?;
+        let pid = std::process::id();
+        for options in [read_only(), FunkDbOptions::default()] {
+            let err = FunkDb::open_with(&location, options).err().unwrap().to_string();
+            assert!(err.contains(&format!("locked by process {pid}, which has it open read-only")), "{err}");
+        }
+        drop(reader);
+        assert!(!storage::lock::path_for(&path).exists());
+        fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_at_rest_and_rekeyed_in_the_background() -> anyhow::Result<()> {
+        let path = storage::scratch_path("rekey.funk");
+        let (key, other) = (storage::Key::generate(), storage::Key::generate());
+        let with = |key: &storage::Key| FunkDbOptions::builder().key(key.clone()).build();
+        let db = FunkDb::open_with(&path, with(&key))?;
+        assert!(db.is_encrypted());
+        db.set_catalog("module default { type A { n: int32; } }")?;
+        let id = db.insert(object::Object::new("A").set("n", 1))?;
+        db.rekey(Some(other.clone()))?;
+        db.insert(object::Object::new("A").set("n", 2))?;
+        db.wait_for_indexes()?;
+        db.insert(object::Object::new("A").set("n", 3))?;
+        drop(db);
+
+        let err = format!("{:#}", FunkDb::open_with(&path, with(&key)).err().unwrap());
+        assert!(err.contains("encrypted with another key"), "{err}");
+        let db = FunkDb::open_with(&path, with(&other))?;
+        assert_eq!(db.objects("A")?.len(), 3);
+        assert_eq!(db.object(id)?.unwrap().get("n"), Some(&value::FunkValue::int32(1)));
+        assert!(FunkDb::open("mem://")?.rekey(Some(key)).is_err());
+        drop(db);
+        storage::remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn serves_the_change_feed() -> anyhow::Result<()> {
+        let db = Arc::new(FunkDb::open("mem://")?);
+        db.set_catalog("module default { type A { n: int32; } }")?;
+        let id = db.insert(object::Object::new("A").set("n", 1))?;
+        db.update(db.object(id)?.unwrap().set("n", 2))?;
+        let path = storage::scratch_path("server.sock");
+        let server = FunkDbServer::bind(&path, Arc::clone(&db))?;
+        thread::spawn(move || server.serve());
+
+        let stream = UnixStream::connect(&path)?;
+        let mut out = stream.try_clone()?;
+        let mut responses = BufReader::new(stream).lines();
+        let mut ask = |request: &str| -> anyhow::Result<serde_json::Value> {
+            writeln!(out, "{request}")?;
+            Ok(serde_json::from_str(&responses.next().unwrap()?)?)
+        };
+        let first = ask(r#"{"changes": "A", "limit": 1}"#)?;
+        assert_eq!(first["changes"][0]["op"], "insert");
+        assert_eq!(first["changes"][0]["new"]["n"], serde_json::json!([1]));
+        let cursor = first["cursor"].as_str().unwrap().to_string();
+        let rest = ask(&format!(r#"{{"changes": "A", "after": "{cursor}"}}"#))?;
+        assert_eq!(rest["changes"][0]["op"], "update");
+        assert_eq!(rest["changes"][0]["old"]["n"], serde_json::json!([1]));
+        let cursor = rest["cursor"].as_str().unwrap().to_string();
+        let idle = ask(&format!(r#"{{"changes": "A", "after": "{cursor}"}}"#))?;
+        assert_eq!((idle["changes"].as_array().unwrap().len(), idle["cursor"].as_str()), (0, Some(cursor.as_str())));
+        assert!(ask(r#"{"changes": "B"}"#)?["error"].as_str().unwrap().contains("Unknown type `B`"));
+        assert!(ask("{}")?["error"].as_str().unwrap().contains("Unknown request"));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn transactions_see_a_snapshot() -> anyhow::Result<()> {
+        use object::Object;
+        let options = FunkDbOptions::builder().isolation(Isolation::Serializable).build();
+        let db = FunkDb::open_with("mem://", options)?;
+        db.set_catalog("module default { type A { n: int32; } }")?;
+        let id = db.insert(Object::new("A").set("n", 1))?;
+
+        let reader = db.begin();
+        let mut writer = db.begin();
+        db.set_catalog("module default { type A { n: int32; } type B { } }")?;
+        db.update(db.object(id)?.unwrap().set("n", 2))?;
+        let schema = FunkDb::schema_in(&reader)?;
+        assert!(schema.resolve_type("default", "B").is_none());
+        let read = object::get(&reader, id)?.unwrap();
+        assert_eq!(read.get("n"), Some(&value::FunkValue::int32(1)));
+
+        object::update(&mut writer, &schema, read.set("n", 3))?;
+        assert!(storage::is_retryable(&writer.commit().unwrap_err()));
+        // The catalog, the record and its id, and the change recorded.
+        assert_eq!(db.versions(), 4);
+        drop(reader);
+        assert_eq!(db.versions(), 0);
+        assert!(db.schema()?.resolve_type("default", "B").is_some());
         Ok(())
     }
 
@@ -536,7 +1518,7 @@ mod tests {
             .add_required_property(("online", funkstd::r#bool))
             .add_multi_link(("funks", Rc::clone(&FunksGiven)));
 
-        let FunksGiven = Rc::into_inner(FunksGiven).unwrap();
+        let FunksGiven = Rc::unwrap_or_clone(FunksGiven);
 
         let commits = vec![(
             default,
@@ -573,74 +1555,3 @@ mod tests {
         todo!("Create new insert query and apply it");
     }
 }
-
-#[allow(dead_code)]
-pub struct FunkDb {
-    path: PathBuf,
-    stream: Option<UnixStream>,
-    file: File,
-}
-
-impl FunkDb {
-    pub fn new<F: IntoRawFd>(path: PathBuf, fileno: Option<F>, file: File) -> Self {
-        let stream = match fileno {
-            Some(f) => {
-                let fd = f.into_raw_fd();
-                Some(unsafe { <UnixStream as FromRawFd>::from_raw_fd(fd) })
-            }
-            None => None,
-        };
-        Self { path, stream, file }
-    }
-    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let path = PathBuf::from(path.as_ref());
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
-        Ok(Self::new(path, Option::<UnixStream>::None, file))
-    }
-    #[allow(dead_code)]
-    fn new_server(
-        &mut self,
-        server_path: impl AsRef<Path>,
-        db_path: impl AsRef<Path>,
-    ) -> anyhow::Result<()> {
-        let _sfd = FunkDbServer::bind(server_path, db_path)?;
-        todo!("`new_server` Not implemented");
-    }
-    pub fn save(&mut self) -> anyhow::Result<()> {
-        if self.stream.is_some() {
-            bail!("`save` not implemented!");
-        }
-        self.file.sync_all()?;
-        Ok(())
-    }
-}
-
-pub struct FunkDbServer {}
-
-impl FunkDbServer {
-    /// Returns the result of the bind op
-    /// which, assuming the socket path wasn't already taken, should be Ok(i32).
-    ///
-    /// With the unwrapped return value, the caller can assume
-    /// that there is a unix domain socket at [`path`] which
-    /// is a [`UnixListener`].
-    ///
-    /// The listener will be used to accept client connections to the database
-    /// so that prepared statements can be executed, queries against the
-    /// database can be ran, and transactions to update the schema can be made.
-    ///
-    /// Note that [`bind`]'s argument, [`path`], is distinct from the actual
-    /// database file.
-    #[allow(dead_code, unused_variables)]
-    pub fn bind(server_path: impl AsRef<Path>, db_path: impl AsRef<Path>) -> anyhow::Result<RawFd> {
-        let path = server_path.as_ref().to_string_lossy();
-        let stream = db_path.as_ref().to_string_lossy();
-        let server = UnixListener::bind(db_path)?.set_nonblocking(true);
-
-        bail!("This is not yet implemented");
-    }
-}
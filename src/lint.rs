@@ -0,0 +1,307 @@
+//! Warnings about schemas that are legal but probably not what was meant.
+//!
+//! Unlike the hard errors raised by [`crate::sdl::parse`] and
+//! `Namespace::try_commit`, nothing here stops a schema from being
+//! committed. Each rule can be allowed, warned about (the default) or
+//! denied through a [`LintConfig`].
+use crate::{funkstd, Interner, Named};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, EnumString, EnumIter)]
+pub enum LintRule {
+    #[strum(serialize = "type-name-case")]
+    TypeNameCase,
+    #[strum(serialize = "shadows-std")]
+    ShadowsStd,
+    #[strum(serialize = "multi-link-without-index")]
+    MultiLinkWithoutIndex,
+    #[strum(serialize = "required-link-cycle")]
+    RequiredLinkCycle,
+    #[strum(serialize = "unused-abstract-type")]
+    UnusedAbstractType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display)]
+pub enum LintLevel {
+    #[strum(serialize = "allow")]
+    Allow,
+    #[default]
+    #[strum(serialize = "warning")]
+    Warn,
+    #[strum(serialize = "error")]
+    Deny,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    levels: BTreeMap<LintRule, LintLevel>,
+}
+
+impl LintConfig {
+    pub fn allow(mut self, rule: LintRule) -> Self {
+        self.levels.insert(rule, LintLevel::Allow);
+        self
+    }
+
+    pub fn deny(mut self, rule: LintRule) -> Self {
+        self.levels.insert(rule, LintLevel::Deny);
+        self
+    }
+
+    pub fn level(&self, rule: LintRule) -> LintLevel {
+        self.levels.get(&rule).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub rule: LintRule,
+    pub level: LintLevel,
+    // What the finding is about, as `module::Type` or `module::Type.field`.
+    pub subject: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}] {}: {}", self.level, self.rule, self.subject, self.message)
+    }
+}
+
+pub fn lint(interner: &Interner, config: &LintConfig) -> Vec<Finding> {
+    let mut findings = vec![];
+    for rule in LintRule::iter() {
+        let level = config.level(rule);
+        if level == LintLevel::Allow {
+            continue;
+        }
+        let found = match rule {
+            LintRule::TypeNameCase => type_name_case(interner),
+            LintRule::ShadowsStd => shadows_std(interner),
+            LintRule::MultiLinkWithoutIndex => multi_link_without_index(interner),
+            LintRule::RequiredLinkCycle => required_link_cycle(interner),
+            LintRule::UnusedAbstractType => unused_abstract_type(interner),
+        };
+        findings.extend(found.into_iter().map(|(subject, message)| Finding {
+            rule,
+            level,
+            subject,
+            message,
+        }));
+    }
+    findings
+}
+
+fn is_pascal_case(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase()) && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn to_pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+fn type_name_case(interner: &Interner) -> Vec<(String, String)> {
+    interner
+        .types()
+        .filter_map(|(module, funk_ty)| {
+            let name = funk_ty.get_name().unwrap();
+            (!is_pascal_case(name)).then(|| {
+                (
+                    format!("{module}::{name}"),
+                    format!("type names should be PascalCase, like `{}`", to_pascal_case(name)),
+                )
+            })
+        })
+        .collect()
+}
+
+fn shadows_std(interner: &Interner) -> Vec<(String, String)> {
+    let std_names: BTreeSet<String> = funkstd::iter()
+        .map(|kind| kind.get_name().unwrap().to_string())
+        .collect();
+    let mut found = vec![];
+    for (module, funk_ty) in interner.types() {
+        let type_name = funk_ty.get_name().unwrap();
+        let fields = funk_ty.properties.keys().chain(funk_ty.links.keys());
+        for field in fields.filter(|field| std_names.contains(field.as_ref())) {
+            found.push((
+                format!("{module}::{type_name}.{field}"),
+                format!("`{field}` shadows `std::{field}`"),
+            ));
+        }
+    }
+    found
+}
+
+fn multi_link_without_index(interner: &Interner) -> Vec<(String, String)> {
+    let mut found = vec![];
+    for (module, funk_ty) in interner.types() {
+        let type_name = funk_ty.get_name().unwrap();
+        for (link, (_, _, is_multi)) in funk_ty.links.iter() {
+            let is_indexed = funk_ty
+                .indexes
+                .iter()
+                .any(|index| index.first() == Some(link));
+            if *is_multi && !is_indexed {
+                found.push((
+                    format!("{module}::{type_name}.{link}"),
+                    format!("multi link has no index, consider `index on (.{link});`"),
+                ));
+            }
+        }
+    }
+    found
+}
+
+fn required_link_cycle(interner: &Interner) -> Vec<(String, String)> {
+    // Every object in a cycle of required links needs another one from
+    // the cycle to exist first, so none of them can ever be inserted.
+    let mut edges: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (module, funk_ty) in interner.types() {
+        let from = format!("{module}::{}", funk_ty.get_name().unwrap());
+        let targets = edges.entry(from).or_default();
+        for (target, required, _) in funk_ty.links.values() {
+            if !required {
+                continue;
+            }
+            if let Some((target_module, target_ty)) = interner.resolve_type(module, target.get_name().unwrap()) {
+                targets.insert(format!("{target_module}::{}", target_ty.get_name().unwrap()));
+            }
+        }
+    }
+
+    let reachable = |start: &String| -> BTreeSet<String> {
+        let mut seen = BTreeSet::new();
+        let mut stack: Vec<&String> = edges[start].iter().collect();
+        while let Some(node) = stack.pop() {
+            if seen.insert(node.clone()) {
+                stack.extend(edges.get(node).into_iter().flatten());
+            }
+        }
+        seen
+    };
+    let reach: BTreeMap<&String, BTreeSet<String>> =
+        edges.keys().map(|node| (node, reachable(node))).collect();
+
+    let mut reported = BTreeSet::new();
+    let mut found = vec![];
+    for (node, reachable) in reach.iter() {
+        if !reachable.contains(*node) || reported.contains(*node) {
+            continue;
+        }
+        let cycle: Vec<&String> = reachable
+            .iter()
+            .filter(|other| reach[other].contains(*node))
+            .collect();
+        reported.extend(cycle.iter().map(|other| (*other).clone()));
+        let names: Vec<&str> = cycle.iter().map(|other| other.as_str()).collect();
+        found.push((
+            (*node).clone(),
+            format!(
+                "required links form a cycle through {}, so none of them can be inserted first",
+                names.join(", ")
+            ),
+        ));
+    }
+    found
+}
+
+fn unused_abstract_type(interner: &Interner) -> Vec<(String, String)> {
+    let mut used = BTreeSet::new();
+    for (module, funk_ty) in interner.types() {
+        let targets = funk_ty
+            .extending
+            .iter()
+            .map(|base| base.as_ref())
+            .chain(funk_ty.links.values().map(|(target, _, _)| target.get_name().unwrap()));
+        for target in targets {
+            if let Some((target_module, target_ty)) = interner.resolve_type(module, target) {
+                used.insert(format!("{target_module}::{}", target_ty.get_name().unwrap()));
+            }
+        }
+    }
+    interner
+        .types()
+        .filter(|(_, funk_ty)| funk_ty.is_abstract)
+        .map(|(module, funk_ty)| format!("{module}::{}", funk_ty.get_name().unwrap()))
+        .filter(|name| !used.contains(name))
+        .map(|name| (name, "abstract type is neither extended nor linked to".to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+
+    const SCHEMA: &str = "
+        module default {
+            abstract type Lonely { note: str; }
+            abstract type Named { required name: str; }
+            type funks_given extending Named {
+                int32: int32;
+                required owner: Owner;
+            }
+            type Owner {
+                required pet: Pet;
+                multi funks: funks_given;
+            }
+            type Pet {
+                required owner: Owner;
+                multi toys: Pet;
+                index on (.toys);
+            }
+        }
+    ";
+
+    fn rules(findings: &[Finding]) -> Vec<(LintRule, &str)> {
+        findings
+            .iter()
+            .map(|finding| (finding.rule, finding.subject.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn every_rule_fires() -> anyhow::Result<()> {
+        let findings = lint(&sdl::parse(SCHEMA)?, &LintConfig::default());
+        assert_eq!(
+            rules(&findings),
+            vec![
+                (LintRule::TypeNameCase, "default::funks_given"),
+                (LintRule::ShadowsStd, "default::funks_given.int32"),
+                (LintRule::MultiLinkWithoutIndex, "default::Owner.funks"),
+                (LintRule::RequiredLinkCycle, "default::Owner"),
+                (LintRule::UnusedAbstractType, "default::Lonely"),
+            ]
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "warning[type-name-case] default::funks_given: type names should be PascalCase, like `FunksGiven`"
+        );
+        assert!(findings[3].message.contains("default::Owner, default::Pet"), "{}", findings[3]);
+        Ok(())
+    }
+
+    #[test]
+    fn allow_and_deny() -> anyhow::Result<()> {
+        let config = LintConfig::default()
+            .allow(LintRule::TypeNameCase)
+            .allow(LintRule::ShadowsStd)
+            .deny(LintRule::RequiredLinkCycle);
+        let findings = lint(&sdl::parse(SCHEMA)?, &config);
+        assert_eq!(findings.len(), 3);
+        assert!(findings
+            .iter()
+            .all(|finding| (finding.level == LintLevel::Deny) == (finding.rule == LintRule::RequiredLinkCycle)));
+        Ok(())
+    }
+}
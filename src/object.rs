@@ -0,0 +1,641 @@
+//! Objects: instances of a committed [`FunkTy`], stored per type.
+//!
+//! Every object has an implicit `id`, a random UUID, and an implicit
+//! `__type__` link naming the type it was inserted as. Objects of
+//! `module::Type` are kept in the tree `objects/module::Type`, keyed by
+//! the 16 bytes of their id, and the [`IDS`] tree maps every id back to
+//! its type, so an object can be read by id alone and a read through an
+//! abstract base still tells each object's concrete type.
+//!
+//! Multi links are ordered id sets: insertion order is kept and a target
+//! appears at most once.
+//!
+//! Records of a type that says `compress lz4;` or `compress zstd;` are
+//! compressed once they are [`LARGE`], see [`Object::encode_with`].
+//!
+//! An object of a type with an `expire` clause is gone for every read
+//! once its deadline passed, even before [`expiry`] deletes it.
+use crate::changes::{self, Op};
+use crate::codec::{self, Cursor};
+use crate::expiry;
+use crate::export::link_target;
+use crate::index;
+use crate::storage::compress::{Compression, LARGE};
+use crate::storage::{Snapshot, Transaction};
+use crate::value::FunkValue;
+use crate::{FunkTy, Interner, Named};
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+/// Maps every object id to its `__type__`.
+pub const IDS: &str = "__ids__";
+
+/// Starts a compressed record, then the tag of its [`Compression`]. A
+/// plain one starts with the length of its `__type__`, which is never 0.
+const COMPRESSED: u8 = 0x00;
+
+/// Fields every type has without declaring them.
+pub const IMPLICIT_FIELDS: [&str; 2] = ["id", "__type__"];
+
+pub fn tree(type_name: &str) -> String {
+    format!("objects/{type_name}")
+}
+
+/// Deleting an object would leave a required link empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Required {
+    pub id: Uuid,
+    /// The link, as `module::Type.link`.
+    pub link: String,
+    pub referrer: Uuid,
+}
+
+impl fmt::Display for Required {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} cannot be deleted, `{}` of {} requires it", self.id, self.link, self.referrer)
+    }
+}
+
+impl std::error::Error for Required {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub id: Uuid,
+    /// The `__type__` link, as `module::Type`.
+    pub type_name: String,
+    pub properties: BTreeMap<String, Vec<FunkValue>>,
+    pub links: BTreeMap<String, Vec<Uuid>>,
+}
+
+impl Object {
+    /// A new object of `type_name` with a fresh id.
+    pub fn new(type_name: impl Into<String>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            type_name: type_name.into(),
+            properties: BTreeMap::new(),
+            links: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Sets a single property, replacing whatever it held.
+    pub fn set<V: Into<FunkValue>>(mut self, name: &str, value: V) -> Self {
+        self.properties.insert(name.to_string(), vec![value.into()]);
+        self
+    }
+
+    /// Appends to a multi property.
+    pub fn push<V: Into<FunkValue>>(mut self, name: &str, value: V) -> Self {
+        self.properties.entry(name.to_string()).or_default().push(value.into());
+        self
+    }
+
+    /// Appends `target` to a link, unless it is already there.
+    pub fn link(mut self, name: &str, target: Uuid) -> Self {
+        let targets = self.links.entry(name.to_string()).or_default();
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+        self
+    }
+
+    pub fn unset(mut self, name: &str) -> Self {
+        self.properties.remove(name);
+        self.links.remove(name);
+        self
+    }
+
+    /// The value of a single property.
+    pub fn get(&self, name: &str) -> Option<&FunkValue> {
+        self.properties.get(name).and_then(|values| values.first())
+    }
+
+    pub fn get_all(&self, name: &str) -> &[FunkValue] {
+        self.properties.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn targets(&self, name: &str) -> &[Uuid] {
+        self.links.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The object as JSON: its `id` and `__type__`, then every property
+    /// and link as an array of values or target ids, single or not.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut fields = serde_json::Map::new();
+        fields.insert("id".to_string(), self.id.to_string().into());
+        fields.insert("__type__".to_string(), self.type_name.clone().into());
+        for (name, values) in self.properties.iter() {
+            fields.insert(name.clone(), values.iter().map(FunkValue::to_json).collect());
+        }
+        for (name, targets) in self.links.iter() {
+            fields.insert(name.clone(), targets.iter().map(Uuid::to_string).collect());
+        }
+        fields.into()
+    }
+
+    /// The stored record: the `__type__`, then every property and link,
+    /// with values in the compact form of [`codec`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        put_str(&mut out, &self.type_name);
+        put_len(&mut out, self.properties.len());
+        for (name, values) in self.properties.iter() {
+            put_str(&mut out, name);
+            put_len(&mut out, values.len());
+            for value in values {
+                codec::encode_into(&mut out, value);
+            }
+        }
+        put_len(&mut out, self.links.len());
+        for (name, targets) in self.links.iter() {
+            put_str(&mut out, name);
+            put_len(&mut out, targets.len());
+            for target in targets {
+                out.extend(target.as_bytes());
+            }
+        }
+        out
+    }
+
+    /// The record, compressed with `compression` if it is [`LARGE`] and
+    /// that makes it smaller.
+    pub fn encode_with(&self, compression: Compression) -> Vec<u8> {
+        let record = self.encode();
+        if compression == Compression::None || record.len() < LARGE {
+            return record;
+        }
+        let compressed = compression.compress(&record);
+        if compressed.len() + 2 >= record.len() {
+            return record;
+        }
+        [&[COMPRESSED, compression.tag()], compressed.as_slice()].concat()
+    }
+
+    /// Reads a record either [`encode`](Self::encode) or
+    /// [`encode_with`](Self::encode_with) wrote.
+    pub fn decode(id: Uuid, bytes: &[u8]) -> Result<Self> {
+        let bytes = unpack(bytes)?;
+        let mut cursor = Cursor::new(&bytes);
+        let mut object = Object::new(take_str(&mut cursor)?).with_id(id);
+        for _ in 0..cursor.varint()? {
+            let name = take_str(&mut cursor)?;
+            let values = (0..cursor.varint()?)
+                .map(|_| codec::decode_from(&mut cursor))
+                .collect::<Result<_>>()?;
+            object.properties.insert(name, values);
+        }
+        for _ in 0..cursor.varint()? {
+            let name = take_str(&mut cursor)?;
+            let targets = (0..cursor.varint()?)
+                .map(|_| Ok(Uuid::from_slice(cursor.take(16)?)?))
+                .collect::<Result<_>>()?;
+            object.links.insert(name, targets);
+        }
+        if !cursor.is_empty() {
+            bail!("Trailing bytes after object {id}");
+        }
+        Ok(object)
+    }
+}
+
+/// The record in `bytes` as [`Object::encode`] wrote it, decompressed if
+/// it was compressed.
+pub fn unpack(bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match bytes {
+        [COMPRESSED, tag, compressed @ ..] => Ok(Cow::Owned(Compression::from_tag(*tag)?.decompress(compressed)?)),
+        _ => Ok(Cow::Borrowed(bytes)),
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    codec::put_varint(out, len as u128);
+}
+
+fn put_str(out: &mut Vec<u8>, text: &str) {
+    put_len(out, text.len());
+    out.extend(text.as_bytes());
+}
+
+fn take_str(cursor: &mut Cursor) -> Result<String> {
+    let len = usize::try_from(cursor.varint()?)?;
+    Ok(String::from_utf8(cursor.take(len)?.to_vec())?)
+}
+
+/// Finds `name`, bare names being looked up in `default` first, and
+/// returns it qualified along with its definition.
+pub fn resolve<'s>(schema: &'s Interner, name: &str) -> Result<(String, &'s FunkTy<'s>)> {
+    match schema.resolve_type("default", name) {
+        Some((module, funk_ty)) => Ok((format!("{module}::{}", funk_ty.get_name().unwrap()), funk_ty)),
+        None => bail!("Unknown type `{name}`.{}", schema.suggest_type(name)),
+    }
+}
+
+/// Whether objects of `type_name` may stand in for `base`: the same type
+/// or one that extends it, directly or not.
+pub fn is_subtype(schema: &Interner, type_name: &str, base: &str) -> bool {
+    if type_name == base {
+        return true;
+    }
+    let Some((module, funk_ty)) = schema.resolve_type("default", type_name) else {
+        return false;
+    };
+    funk_ty.extending.iter().any(|parent| {
+        schema
+            .resolve_type(module, parent)
+            .is_some_and(|(parent_module, parent_ty)| {
+                is_subtype(schema, &format!("{parent_module}::{}", parent_ty.get_name().unwrap()), base)
+            })
+    })
+}
+
+/// Every committed type `base` stands for, itself included.
+pub fn subtypes(schema: &Interner, base: &str) -> Vec<String> {
+    schema
+        .types()
+        .map(|(module, funk_ty)| format!("{module}::{}", funk_ty.get_name().unwrap()))
+        .filter(|type_name| is_subtype(schema, type_name, base))
+        .collect()
+}
+
+/// The type an object id belongs to, if it exists.
+pub fn type_of(txn: &(impl Snapshot + ?Sized), id: Uuid) -> Result<Option<String>> {
+    match txn.get(IDS, id.as_bytes())? {
+        Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks `object` against its type and puts it in canonical form. With
+/// `links_later`, required links may still be missing.
+pub(crate) fn validate(
+    txn: &(impl Snapshot + ?Sized),
+    schema: &Interner,
+    object: &mut Object,
+    links_later: bool,
+) -> Result<()> {
+    let (type_name, funk_ty) = resolve(schema, &object.type_name)?;
+    if funk_ty.is_abstract {
+        bail!("`{type_name}` is abstract and cannot be instantiated");
+    }
+    object.type_name = type_name.clone();
+    let module = type_name.rsplit_once("::").unwrap().0;
+
+    for (name, values) in object.properties.iter() {
+        funk_ty.check_field(name)?;
+        let Some((kind, _, is_multi)) = funk_ty.properties.get(name.as_str()) else {
+            bail!("`{type_name}.{name}` is a link, not a property");
+        };
+        if !is_multi && values.len() > 1 {
+            bail!("`{type_name}.{name}` is single, but was given {} values", values.len());
+        }
+        if let Some(value) = values.iter().find(|value| value.kind() != *kind) {
+            bail!(
+                "`{type_name}.{name}` holds {}, not {} like `{value}`",
+                funk_ty.property_type(name).unwrap(),
+                value.kind().get_name().unwrap()
+            );
+        }
+        for value in values {
+            let FunkValue::vector(v) = value else {
+                continue;
+            };
+            if let Some(dimension) = funk_ty.dimensions.get(name.as_str()).filter(|dimension| **dimension != v.len()) {
+                bail!("`{type_name}.{name}` holds vector<{dimension}>, not one of {} numbers", v.len());
+            }
+            // Nothing could read them back, and distances to them are NaN.
+            if !v.iter().all(|x| x.is_finite()) {
+                bail!("`{type_name}.{name}` holds finite numbers only, not `{value}`");
+            }
+        }
+    }
+    for (name, targets) in object.links.iter_mut() {
+        funk_ty.check_field(name)?;
+        let Some((target_ty, _, is_multi)) = funk_ty.links.get(name.as_str()) else {
+            bail!("`{type_name}.{name}` is a property, not a link");
+        };
+        let mut seen = vec![];
+        targets.retain(|target| {
+            let first = !seen.contains(target);
+            seen.push(*target);
+            first
+        });
+        if !is_multi && targets.len() > 1 {
+            bail!("`{type_name}.{name}` is single, but was given {} targets", targets.len());
+        }
+        let (target_module, target_name) = link_target(schema, module, target_ty);
+        let expected = format!("{target_module}::{target_name}");
+        for target in targets.iter() {
+            let Some(actual) = type_of(txn, *target)? else {
+                bail!("`{type_name}.{name}` points at {target}, which does not exist");
+            };
+            if !is_subtype(schema, &actual, &expected) {
+                bail!("`{type_name}.{name}` expects `{expected}`, but {target} is a `{actual}`");
+            }
+        }
+    }
+    object.properties.retain(|_, values| !values.is_empty());
+    object.links.retain(|_, targets| !targets.is_empty());
+
+    let missing = funk_ty
+        .properties
+        .iter()
+        .filter(|(_, (_, required, _))| *required)
+        .map(|(name, _)| name)
+        .chain(funk_ty.links.iter().filter(|(_, (_, required, _))| *required && !links_later).map(|(name, _)| name))
+        .find(|name| !object.properties.contains_key(name.as_ref()) && !object.links.contains_key(name.as_ref()));
+    if let Some(name) = missing {
+        bail!("`{type_name}.{name}` is required");
+    }
+    Ok(())
+}
+
+/// Stores `object` over `old`, if it replaces one, and updates the
+/// indexes, its deadline and the change feed.
+fn write(txn: &mut Transaction, schema: &Interner, old: Option<&Object>, object: &Object) -> Result<()> {
+    txn.put(IDS, object.id.as_bytes().to_vec(), object.type_name.as_bytes().to_vec());
+    let compression = resolve(schema, &object.type_name)?.1.compression.unwrap_or_default();
+    txn.put(&tree(&object.type_name), object.id.as_bytes().to_vec(), object.encode_with(compression));
+    expiry::arm(txn, schema, object)?;
+    let op = match old {
+        Some(_) => Op::Update,
+        None => Op::Insert,
+    };
+    changes::record(txn, op, old, Some(object));
+    index::maintain(txn, schema, old, Some(object))
+}
+
+pub fn insert(txn: &mut Transaction, schema: &Interner, mut object: Object) -> Result<Uuid> {
+    if let Some(existing) = type_of(txn, object.id)? {
+        bail!("An object with id {} already exists, it is a `{existing}`", object.id);
+    }
+    validate(txn, schema, &mut object, false)?;
+    write(txn, schema, None, &object)?;
+    Ok(object.id)
+}
+
+/// Inserts `object` without its links, for loading objects that may link
+/// to ones not loaded yet. Its required links are only checked once an
+/// [`update`] sets them.
+pub(crate) fn insert_unlinked(txn: &mut Transaction, schema: &Interner, mut object: Object) -> Result<Uuid> {
+    if let Some(existing) = type_of(txn, object.id)? {
+        bail!("An object with id {} already exists, it is a `{existing}`", object.id);
+    }
+    object.links.clear();
+    validate(txn, schema, &mut object, true)?;
+    write(txn, schema, None, &object)?;
+    Ok(object.id)
+}
+
+pub fn get(txn: &(impl Snapshot + ?Sized), id: Uuid) -> Result<Option<Object>> {
+    if expiry::is_expired(txn, id)? {
+        return Ok(None);
+    }
+    stored(txn, id)
+}
+
+/// The object stored under `id`, expired or not.
+pub(crate) fn stored(txn: &(impl Snapshot + ?Sized), id: Uuid) -> Result<Option<Object>> {
+    let Some(type_name) = type_of(txn, id)? else {
+        return Ok(None);
+    };
+    let bytes = txn
+        .get(&tree(&type_name), id.as_bytes())?
+        .ok_or_else(|| anyhow!("{id} is a `{type_name}`, but its record is missing"))?;
+    Ok(Some(Object::decode(id, &bytes)?))
+}
+
+/// Replaces a stored object with `object`. The `__type__` cannot change.
+pub fn update(txn: &mut Transaction, schema: &Interner, mut object: Object) -> Result<()> {
+    let Some(existing) = get(txn, object.id)? else {
+        bail!("There is no object with id {}", object.id);
+    };
+    validate(txn, schema, &mut object, false)?;
+    if existing.type_name != object.type_name {
+        bail!("{} is a `{}` and cannot become a `{}`", object.id, existing.type_name, object.type_name);
+    }
+    write(txn, schema, Some(&existing), &object)
+}
+
+/// Deletes an object and drops it from every link pointing at it. Fails
+/// if that would leave a required link empty.
+pub fn delete(txn: &mut Transaction, schema: &Interner, id: Uuid) -> Result<()> {
+    let Some(deleted) = get(txn, id)? else {
+        bail!("There is no object with id {id}");
+    };
+    remove(txn, schema, deleted, Op::Delete)
+}
+
+/// Deletes `id` if it expired, and tells whether it did. It may have been
+/// written since the sweeper found it due.
+pub(crate) fn expire(txn: &mut Transaction, schema: &Interner, id: Uuid) -> Result<bool> {
+    let Some(expired) = stored(txn, id)? else {
+        return Ok(false);
+    };
+    if !expiry::is_expired(txn, id)? {
+        return Ok(false);
+    }
+    remove(txn, schema, expired, Op::Expire)?;
+    Ok(true)
+}
+
+fn remove(txn: &mut Transaction, schema: &Interner, deleted: Object, op: Op) -> Result<()> {
+    let id = deleted.id;
+    let type_name = deleted.type_name.clone();
+    for (module, funk_ty) in schema.types() {
+        let source = format!("{module}::{}", funk_ty.get_name().unwrap());
+        let pointing: Vec<&str> = funk_ty
+            .links
+            .iter()
+            .filter(|(_, (target, _, _))| {
+                let (target_module, target_name) = link_target(schema, module, target);
+                is_subtype(schema, &type_name, &format!("{target_module}::{target_name}"))
+            })
+            .map(|(name, _)| name.as_ref())
+            .collect();
+        if pointing.is_empty() {
+            continue;
+        }
+        for original in scan_exact(txn, &source)? {
+            let mut referrer = original.clone();
+            let mut changed = false;
+            for name in pointing.iter() {
+                let Some(targets) = referrer.links.get_mut(*name) else {
+                    continue;
+                };
+                if !targets.contains(&id) {
+                    continue;
+                }
+                targets.retain(|target| *target != id);
+                changed = true;
+                if targets.is_empty() && funk_ty.links[*name].1 {
+                    return Err(Required {
+                        id,
+                        link: format!("{source}.{name}"),
+                        referrer: referrer.id,
+                    }
+                    .into());
+                }
+            }
+            if changed {
+                referrer.links.retain(|_, targets| !targets.is_empty());
+                write(txn, schema, Some(&original), &referrer)?;
+            }
+        }
+    }
+    txn.delete(IDS, id.as_bytes().to_vec());
+    txn.delete(&tree(&type_name), id.as_bytes().to_vec());
+    expiry::disarm(txn, id)?;
+    changes::record(txn, op, Some(&deleted), None);
+    index::maintain(txn, schema, Some(&deleted), None)
+}
+
+fn scan_exact(txn: &(impl Snapshot + ?Sized), type_name: &str) -> Result<Vec<Object>> {
+    use std::ops::Bound;
+    txn.scan(&tree(type_name), (Bound::Unbounded, Bound::Unbounded))?
+        .into_iter()
+        .map(|(key, bytes)| Object::decode(Uuid::from_slice(&key)?, &bytes))
+        .collect()
+}
+
+/// Every object of `type_name` or of a type extending it.
+pub fn scan(txn: &(impl Snapshot + ?Sized), schema: &Interner, type_name: &str) -> Result<Vec<Object>> {
+    let (type_name, _) = resolve(schema, type_name)?;
+    let mut objects = vec![];
+    for subtype in subtypes(schema, &type_name) {
+        let found = scan_exact(txn, &subtype)?;
+        if !expiry::expires(schema, &subtype) {
+            objects.extend(found);
+            continue;
+        }
+        for object in found {
+            if !expiry::is_expired(txn, object.id)? {
+                objects.push(object);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+    use crate::storage::{Isolation, MemoryStore, Mvcc, CLOCK};
+
+    const SCHEMA: &str = "
+        module default {
+            abstract type Named { required name: str; }
+            type FunksGiven extending Named {
+                required expires: int32;
+                multi tags: str;
+            }
+            type ReasonForLiving {
+                required online: bool;
+                multi funks: FunksGiven;
+                best: Named;
+            }
+            type Owner {
+                required reason: ReasonForLiving;
+            }
+        }
+    ";
+
+    #[test]
+    fn records_round_trip() -> Result<()> {
+        let object = Object::new("default::FunksGiven")
+            .set("expires", 7)
+            .set("name", "x")
+            .push("tags", "a")
+            .push("tags", u128::MAX)
+            .link("funks", Uuid::new_v4());
+        assert_eq!(Object::decode(object.id, &object.encode())?, object);
+        assert!(Object::decode(object.id, &object.encode()[1..]).is_err());
+
+        // Small records stay as they are; large ones shrink, and say how.
+        assert_eq!(object.encode_with(Compression::Zstd), object.encode());
+        let large = object.set("name", "funk ".repeat(200));
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let record = large.encode_with(compression);
+            assert!(record.len() < large.encode().len() / 4, "{compression}");
+            assert_eq!(Object::decode(large.id, &record)?, large);
+            assert_eq!(unpack(&record)?, large.encode());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn insert_read_update_delete() -> Result<()> {
+        let schema = sdl::parse(SCHEMA)?;
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        let mut txn = db.begin(Isolation::Snapshot);
+
+        let given = Object::new("FunksGiven").set("name", "rent").set("expires", 30).push("tags", "a");
+        let given = insert(&mut txn, &schema, given)?;
+        let reason = Object::new("ReasonForLiving")
+            .set("online", true)
+            .link("funks", given)
+            .link("funks", given)
+            .link("best", given);
+        let reason = insert(&mut txn, &schema, reason)?;
+        let owner = insert(&mut txn, &schema, Object::new("Owner").link("reason", reason))?;
+
+        let read = get(&txn, reason)?.unwrap();
+        assert_eq!(read.type_name, "default::ReasonForLiving");
+        assert_eq!(read.targets("funks"), [given]);
+        assert_eq!(get(&txn, given)?.unwrap().get("expires"), Some(&FunkValue::int32(30)));
+
+        // Reading through the abstract base still tells the concrete type.
+        let named = scan(&txn, &schema, "Named")?;
+        assert_eq!(named.len(), 1);
+        assert_eq!(named[0].type_name, "default::FunksGiven");
+
+        update(&mut txn, &schema, read.clone().set("online", false))?;
+        assert_eq!(get(&txn, reason)?.unwrap().get("online"), Some(&FunkValue::bool(false)));
+
+        delete(&mut txn, &schema, given)?;
+        let read = get(&txn, reason)?.unwrap();
+        assert!(read.targets("funks").is_empty() && read.targets("best").is_empty());
+        assert!(delete(&mut txn, &schema, reason).is_err());
+        delete(&mut txn, &schema, owner)?;
+        delete(&mut txn, &schema, reason)?;
+        txn.commit()?;
+        // Only the clock and the change feed remember anything was there.
+        let trees = db.store().trees()?;
+        assert!(trees.iter().all(|tree| tree == CLOCK.0 || tree.starts_with("changes/")), "{trees:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn objects_must_fit_their_type() -> Result<()> {
+        let schema = sdl::parse(SCHEMA)?;
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        let mut txn = db.begin(Isolation::Snapshot);
+        let given = Object::new("FunksGiven").set("name", "rent").set("expires", 30);
+        let rejected = [
+            (Object::new("Named").set("name", "x"), "abstract"),
+            (Object::new("FunksGivn"), "Did you mean `FunksGiven`?"),
+            (given.clone().set("expires", 30_i64), "holds int32, not int64"),
+            (given.clone().set("expirse", 1), "Did you mean `expires`?"),
+            (given.clone().push("expires", 1), "is single"),
+            (given.clone().unset("name"), "`default::FunksGiven.name` is required"),
+            (given.clone().link("tags", Uuid::new_v4()), "is a property"),
+            (Object::new("ReasonForLiving").set("online", true).link("best", Uuid::new_v4()), "does not exist"),
+        ];
+        for (object, expected) in rejected {
+            let err = insert(&mut txn, &schema, object).unwrap_err().to_string();
+            assert!(err.contains(expected), "{err}");
+        }
+        let reason = insert(&mut txn, &schema, Object::new("ReasonForLiving").set("online", true))?;
+        let err = insert(&mut txn, &schema, Object::new("ReasonForLiving").set("online", true).link("funks", reason));
+        assert!(err.unwrap_err().to_string().contains("expects `default::FunksGiven`"));
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! Background work that runs every so often for as long as a database is
+//! open: the sweep of [`expiry`](crate::expiry) and the
+//! [vacuum](crate::vacuum).
+use anyhow::Result;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Runs a job on a thread of its own every so often, until dropped.
+pub(crate) struct Periodic {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// The last failure not taken yet.
+    failed: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl Periodic {
+    /// Runs `job` every `every`, keeping failures as `what` failing for
+    /// [`take_failure`](Self::take_failure) and trying again next time.
+    pub(crate) fn start(what: &'static str, every: Duration, mut job: impl FnMut() -> Result<()> + Send + 'static) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let failed = Arc::new(Mutex::new(None));
+        let handle = thread::spawn({
+            let stop = Arc::clone(&stop);
+            let failed = Arc::clone(&failed);
+            move || {
+                let (stopped, wake) = &*stop;
+                loop {
+                    let stopped = wake.wait_timeout_while(stopped.lock().unwrap(), every, |stopped| !*stopped);
+                    if *stopped.unwrap().0 {
+                        return;
+                    }
+                    if let Err(err) = job() {
+                        *failed.lock().unwrap() = Some(err.context(format!("{what} failed")));
+                    }
+                }
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+            failed,
+        }
+    }
+
+    /// The last time the job failed, if it has since it was last asked.
+    pub(crate) fn take_failure(&self) -> Option<anyhow::Error> {
+        self.failed.lock().unwrap().take()
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[test]
+    fn keeps_the_last_failure() {
+        let mut runs = 0;
+        let periodic = Periodic::start("Counting", Duration::from_millis(5), move || {
+            runs += 1;
+            bail!("run {runs}")
+        });
+        let mut failure = None;
+        for _ in 0..200 {
+            failure = periodic.take_failure();
+            if failure.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let failure = format!("{:#}", failure.expect("never ran"));
+        assert!(failure.starts_with("Counting failed: run "), "{failure}");
+        drop(periodic);
+    }
+}
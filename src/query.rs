@@ -0,0 +1,292 @@
+//! Reading objects by the values of their fields.
+//!
+//! [`select`] takes equality and range [`Filter`]s, and [`plan`] picks the
+//! ready index whose leading fields the filters pin down best: as many
+//! equalities as possible, then one range. Whatever the index does not
+//! cover is checked on the objects it yields. Without a fitting index every
+//! object of the type is read.
+use crate::expiry;
+use crate::index::{self, Index, Kind, State};
+use crate::object::{self, Object};
+use crate::storage::{prefix_end, Snapshot};
+use crate::value::FunkValue;
+use crate::{funkstd, Interner, Named};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Bound;
+use uuid::Uuid;
+
+/// Holds for an object if any value of `field` lies within the bounds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub field: String,
+    pub start: Bound<FunkValue>,
+    pub end: Bound<FunkValue>,
+}
+
+impl Filter {
+    pub fn eq(field: &str, value: impl Into<FunkValue>) -> Self {
+        let value = value.into();
+        Self::range(field, Bound::Included(value.clone()), Bound::Included(value))
+    }
+
+    pub fn range(field: &str, start: Bound<FunkValue>, end: Bound<FunkValue>) -> Self {
+        Self {
+            field: field.to_string(),
+            start,
+            end,
+        }
+    }
+
+    /// Holds for objects whose link `field` points at `id`.
+    pub fn target(field: &str, id: Uuid) -> Self {
+        Self::eq(field, FunkValue::uint128(id.as_u128()))
+    }
+
+    fn equals(&self) -> Option<&FunkValue> {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) if start == end => Some(start),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, value: &FunkValue) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(start) => value >= start,
+            Bound::Excluded(start) => value > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => value <= end,
+            Bound::Excluded(end) => value < end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    pub fn matches(&self, object: &Object) -> bool {
+        index::values(object, &self.field).iter().any(|value| self.contains(value))
+    }
+
+    fn bounds(&self) -> impl Iterator<Item = &FunkValue> {
+        [&self.start, &self.end].into_iter().filter_map(|bound| match bound {
+            Bound::Included(value) | Bound::Excluded(value) => Some(value),
+            Bound::Unbounded => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// Read every object of the type.
+    Scan { type_name: String },
+    /// Read the entries of `index` whose first `equal` fields are pinned
+    /// down, and whose next one is bounded if `range`.
+    Index { index: Index, equal: usize, range: bool },
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Plan::Scan { type_name } => write!(f, "scan {type_name}"),
+            Plan::Index { index, equal, range } => {
+                let mut steps = vec![];
+                if *equal > 0 {
+                    steps.push(format!("equal on {}", index.fields[..*equal].join(", ")));
+                }
+                if *range {
+                    steps.push(format!("range on {}", index.fields[*equal]));
+                }
+                write!(f, "index {index} by {}", steps.join(" then "))
+            }
+        }
+    }
+}
+
+/// Fails unless every filter names a field of the type and bounds it with
+/// values of the field's kind.
+fn check(schema: &Interner, type_name: &str, filters: &[Filter]) -> Result<()> {
+    let (type_name, funk_ty) = object::resolve(schema, type_name)?;
+    for filter in filters {
+        funk_ty.check_field(&filter.field)?;
+        let kind = match funk_ty.properties.get(filter.field.as_str()) {
+            Some((kind, _, _)) => *kind,
+            None => funkstd::uint128,
+        };
+        if let Some(value) = filter.bounds().find(|value| value.kind() != kind) {
+            bail!(
+                "`{type_name}.{}` holds {}, it cannot be compared with `{value}`",
+                filter.field,
+                kind.get_name().unwrap()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How [`select`] would go about `filters` on objects of `type_name`.
+pub fn plan(txn: &(impl Snapshot + ?Sized), schema: &Interner, type_name: &str, filters: &[Filter]) -> Result<Plan> {
+    let (type_name, _) = object::resolve(schema, type_name)?;
+    let mut best = Plan::Scan {
+        type_name: type_name.clone(),
+    };
+    let mut best_score = (0, false);
+    for index in index::declared(schema) {
+        if index.kind != Kind::Ordered
+            || !index.covers(schema, &type_name)
+            || index::state(txn, &index)? != Some(State::Ready)
+        {
+            continue;
+        }
+        let mut score = (0, false);
+        for field in index.fields.iter() {
+            let on_field = || filters.iter().filter(|filter| &filter.field == field);
+            if on_field().any(|filter| filter.equals().is_some()) {
+                score.0 += 1;
+                continue;
+            }
+            score.1 = on_field().next().is_some();
+            break;
+        }
+        if score > best_score {
+            best_score = score;
+            best = Plan::Index {
+                index,
+                equal: score.0,
+                range: score.1,
+            };
+        }
+    }
+    Ok(best)
+}
+
+/// The objects of `type_name`, or of types extending it, that pass every
+/// filter. Read through an index they come in its order.
+pub fn select(txn: &(impl Snapshot + ?Sized), schema: &Interner, type_name: &str, filters: &[Filter]) -> Result<Vec<Object>> {
+    check(schema, type_name, filters)?;
+    let passes = |object: &Object| filters.iter().all(|filter| filter.matches(object));
+    let (index, equal, range) = match plan(txn, schema, type_name, filters)? {
+        Plan::Scan { .. } => {
+            let objects = object::scan(txn, schema, type_name)?;
+            return Ok(objects.into_iter().filter(passes).collect());
+        }
+        Plan::Index { index, equal, range } => (index, equal, range),
+    };
+    let mut prefix = vec![];
+    for field in index.fields[..equal].iter() {
+        let value = filters
+            .iter()
+            .find_map(|filter| filter.equals().filter(|_| &filter.field == field))
+            .unwrap();
+        prefix.extend(index::component(value));
+    }
+    let after = |key: Vec<u8>| prefix_end(&key).map_or(Bound::Unbounded, Bound::Excluded);
+    let bounded = match range {
+        true => filters.iter().find(|filter| filter.field == index.fields[equal]),
+        false => None,
+    };
+    let (start, end) = match bounded {
+        Some(filter) => {
+            let at = |value: &FunkValue| [prefix.as_slice(), &index::component(value)].concat();
+            let start = match &filter.start {
+                Bound::Included(value) => Bound::Included(at(value)),
+                Bound::Excluded(value) => after(at(value)),
+                Bound::Unbounded => Bound::Included([prefix.as_slice(), &[0x01]].concat()),
+            };
+            let end = match &filter.end {
+                Bound::Included(value) => after(at(value)),
+                Bound::Excluded(value) => Bound::Excluded(at(value)),
+                Bound::Unbounded => after([prefix.as_slice(), &[0x01]].concat()),
+            };
+            (start, end)
+        }
+        None => (Bound::Included(prefix.clone()), after(prefix.clone())),
+    };
+    let (type_name, _) = object::resolve(schema, type_name)?;
+    let mut seen = HashSet::new();
+    let mut objects = vec![];
+    for (key, _) in txn.scan(&index.tree(), (start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice)))? {
+        let id = index::id_of(&key)?;
+        if !seen.insert(id) {
+            continue;
+        }
+        let Some(object) = object::get(txn, id)? else {
+            if expiry::is_expired(txn, id)? {
+                continue;
+            }
+            bail!("{index} has an entry for {id}, which does not exist");
+        };
+        if object::is_subtype(schema, &object.type_name, &type_name) && passes(&object) {
+            objects.push(object);
+        }
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunkDb;
+
+    const SCHEMA: &str = "module default {
+        abstract type Named { name: str; index on (.name); }
+        type Person extending Named {
+            age: uint8;
+            multi friends: Person;
+            index on ((.age, .name));
+            index on (.friends);
+        }
+    }";
+
+    fn names(objects: Vec<Object>) -> Vec<String> {
+        let mut names: Vec<String> = objects.iter().map(|object| object.get("name").unwrap().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn filters_use_the_best_index() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(SCHEMA)?;
+        db.wait_for_indexes()?;
+        let ann = db.insert(Object::new("Person").set("name", "ann").set("age", 30_u8))?;
+        for (name, age) in [("bob", 30_u8), ("cat", 41), ("dan", 30), ("eve", 17)] {
+            db.insert(Object::new("Person").set("name", name).set("age", age).link("friends", ann))?;
+        }
+        db.insert(Object::new("Person").set("name", "nil"))?;
+
+        let cases = [
+            (vec![Filter::eq("name", "cat")], "index default::Named(name) by equal on name", vec!["cat"]),
+            (
+                vec![Filter::eq("age", 30_u8), Filter::range("name", Bound::Excluded("ann".into()), Bound::Unbounded)],
+                "index default::Person(age, name) by equal on age then range on name",
+                vec!["bob", "dan"],
+            ),
+            (
+                vec![Filter::range("age", Bound::Included(17_u8.into()), Bound::Excluded(41_u8.into()))],
+                "index default::Person(age, name) by range on age",
+                vec!["ann", "bob", "dan", "eve"],
+            ),
+            (
+                vec![Filter::target("friends", ann), Filter::range("age", Bound::Included(30_u8.into()), Bound::Unbounded)],
+                "index default::Person(friends) by equal on friends",
+                vec!["bob", "cat", "dan"],
+            ),
+            (vec![], "scan default::Person", vec!["ann", "bob", "cat", "dan", "eve", "nil"]),
+        ];
+        for (filters, expected_plan, expected) in cases {
+            let txn = db.begin();
+            let schema = FunkDb::schema_in(&txn)?;
+            let got = plan(&txn, &schema, "Person", &filters)?;
+            assert_eq!(got.to_string(), expected_plan);
+            assert_eq!(names(select(&txn, &schema, "Person", &filters)?), expected);
+        }
+
+        // Going through the abstract base reads the same entries.
+        assert_eq!(names(db.select("Named", &[Filter::eq("name", "eve")])?), ["eve"]);
+        let err = db.select("Person", &[Filter::eq("age", 30_i64)]).unwrap_err();
+        assert!(err.to_string().contains("holds uint8"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,911 @@
+//! A small reader for the schema definition language.
+//!
+//! Only the subset that FunkDB can represent today is understood:
+//!
+//! ```text
+//! module default {
+//!     type FunksGiven {
+//!         required expires: int32;
+//!         significance: str;
+//!     }
+//!     type ReasonForLiving {
+//!         required online: bool;
+//!         multi funks: FunksGiven;
+//!     }
+//! }
+//! ```
+//!
+//! A member whose target is a `std` scalar becomes a property, anything
+//! else becomes a link. The EdgeDB spelling (`required multi link funks ->
+//! FunksGiven;`) is accepted too.
+//!
+//! Modules, types, properties and links may carry annotations:
+//!
+//! ```text
+//! type FunksGiven {
+//!     annotation title := 'Funks given';
+//!     required expires: int32 {
+//!         annotation deprecated := 'Use `valid_until` instead.';
+//!     };
+//! }
+//! ```
+//!
+//! and may say how long their objects live, see [`expiry`]:
+//!
+//! ```text
+//! type Session { expire after '30d'; }
+//! type Invite { required expires: datetime; expire on (.expires); }
+//! ```
+//!
+//! and how to compress their records, see [`compress`]:
+//!
+//! ```text
+//! type Article { body: str; compress zstd; }
+//! ```
+//!
+//! [`compress`]: crate::storage::compress
+use crate::expiry::{self, Ttl};
+use crate::storage::Compression;
+use crate::{funkstd, hnsw, object, suggest, Annotations, FunkData, FunkTy, Interner, Module, Named, Namespace};
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use strum::IntoEnumIterator;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    Ident(String),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Lexeme {
+    pub token: Token,
+    pub line: usize,
+}
+
+const PUNCTUATION: [&str; 12] = ["->", ":=", "{", "}", ";", ",", ":", "(", ")", ".", "<", ">"];
+
+pub(crate) fn tokenize(src: &str) -> Result<Vec<Lexeme>> {
+    let mut lexemes = vec![];
+    let mut line = 1;
+    let mut rest = src;
+
+    'outer: while let Some(c) = rest.chars().next() {
+        if c == '\n' {
+            line += 1;
+            rest = &rest[1..];
+            continue;
+        }
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        if c == '#' {
+            rest = rest.find('\n').map_or("", |end| &rest[end..]);
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            let Some(end) = rest[1..].find(c) else {
+                bail!("line {line}: unterminated string literal");
+            };
+            let literal = &rest[1..end + 1];
+            line += literal.matches('\n').count();
+            lexemes.push(Lexeme { token: Token::Str(literal.to_string()), line });
+            rest = &rest[end + 2..];
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' {
+            // `::` glues path segments together, so `std::int32` is one name.
+            let mut end = 0;
+            loop {
+                end += rest[end..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len() - end);
+                let tail = &rest[end..];
+                if tail.starts_with("::")
+                    && tail[2..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+                {
+                    end += 2;
+                    continue;
+                }
+                break;
+            }
+            lexemes.push(Lexeme { token: Token::Ident(rest[..end].to_string()), line });
+            rest = &rest[end..];
+            continue;
+        }
+        for punct in PUNCTUATION {
+            if rest.starts_with(punct) {
+                lexemes.push(Lexeme { token: Token::Punct(punct), line });
+                rest = &rest[punct.len()..];
+                continue 'outer;
+            }
+        }
+        bail!("line {line}: unexpected character `{c}`");
+    }
+    Ok(lexemes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemberKind {
+    Property,
+    Link,
+    // Decided by looking at the target name.
+    Inferred,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MemberDecl {
+    pub name: String,
+    pub target: String,
+    pub kind: MemberKind,
+    pub required: bool,
+    pub is_multi: bool,
+    // The `N` of `vector<N>`.
+    pub dimension: Option<usize>,
+    pub annotations: Vec<AnnotationDecl>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TypeDecl {
+    pub name: String,
+    pub is_abstract: bool,
+    pub extending: Vec<String>,
+    pub members: Vec<MemberDecl>,
+    pub indexes: Vec<IndexDecl>,
+    pub annotations: Vec<AnnotationDecl>,
+    // `expire after '30d';` or `expire on (.expires);`, and its line.
+    pub expire: Option<(Ttl, usize)>,
+    // `compress lz4;`, and its line.
+    pub compress: Option<(Compression, usize)>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IndexDecl {
+    pub fields: Vec<String>,
+    // `fts::index` or `hnsw::cosine` in `index fts::index on (.field)`,
+    // for anything but an ordered index.
+    pub method: Option<String>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ModuleDecl {
+    pub name: String,
+    pub types: Vec<TypeDecl>,
+    pub annotations: Vec<AnnotationDecl>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AnnotationDecl {
+    pub name: String,
+    pub value: String,
+    pub line: usize,
+}
+
+pub(crate) struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(src: &str) -> Result<Self> {
+        Ok(Self { lexemes: tokenize(src)?, pos: 0 })
+    }
+
+    fn line(&self) -> usize {
+        self.lexemes
+            .get(self.pos)
+            .or(self.lexemes.last())
+            .map_or(1, |lexeme| lexeme.line)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.lexemes.get(self.pos).map(|lexeme| &lexeme.token)
+    }
+
+    fn describe(token: Option<&Token>) -> String {
+        match token {
+            Some(Token::Ident(ident)) => format!("`{ident}`"),
+            Some(Token::Str(literal)) => format!("'{literal}'"),
+            Some(Token::Punct(punct)) => format!("`{punct}`"),
+            None => "end of input".to_string(),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<()> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Punct(found)) if found == punct => Ok(()),
+            found => bail!("line {line}: expected `{punct}`, found {}", Self::describe(found.as_ref())),
+        }
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Ident(ident)) => Ok(ident),
+            found => bail!("line {line}: expected a name, found {}", Self::describe(found.as_ref())),
+        }
+    }
+
+    pub fn parse_schema(&mut self) -> Result<Vec<ModuleDecl>> {
+        let mut modules = vec![];
+        while self.peek().is_some() {
+            let line = self.line();
+            if !self.eat_keyword("module") {
+                bail!("line {line}: expected `module`, found {}", Self::describe(self.peek()));
+            }
+            modules.push(self.parse_module()?);
+        }
+        Ok(modules)
+    }
+
+    fn parse_module(&mut self) -> Result<ModuleDecl> {
+        let name = self.ident()?;
+        self.expect("{")?;
+        let mut types = vec![];
+        let mut annotations = vec![];
+        while !self.eat("}") {
+            let line = self.line();
+            if self.eat_modifier("annotation") {
+                annotations.push(self.parse_annotation(line)?);
+                continue;
+            }
+            let is_abstract = self.eat_keyword("abstract");
+            if !self.eat_keyword("type") {
+                bail!("line {line}: expected `type` or `}}`, found {}", Self::describe(self.peek()));
+            }
+            types.push(self.parse_type(line, is_abstract)?);
+        }
+        Ok(ModuleDecl { name, types, annotations })
+    }
+
+    fn parse_type(&mut self, line: usize, is_abstract: bool) -> Result<TypeDecl> {
+        let name = self.ident()?;
+        let mut extending = vec![];
+        if self.eat_keyword("extending") {
+            extending.push(self.ident()?);
+            while self.eat(",") {
+                extending.push(self.ident()?);
+            }
+        }
+        self.expect("{")?;
+        let mut members = vec![];
+        let mut indexes = vec![];
+        let mut annotations = vec![];
+        let mut expire = None;
+        let mut compress = None;
+        while !self.eat("}") {
+            let line = self.line();
+            if self.eat_modifier("compress") {
+                if compress.is_some() {
+                    bail!("line {line}: `{name}` says how to compress its objects more than once");
+                }
+                compress = Some((self.parse_compress(line)?, line));
+                continue;
+            }
+            if self.eat_modifier("expire") {
+                if expire.is_some() {
+                    bail!("line {line}: `{name}` says when its objects expire more than once");
+                }
+                expire = Some((self.parse_expire(line)?, line));
+                continue;
+            }
+            if self.eat_modifier("annotation") {
+                annotations.push(self.parse_annotation(line)?);
+                continue;
+            }
+            if self.eat_modifier("index") {
+                indexes.push(self.parse_index(line)?);
+                continue;
+            }
+            members.push(self.parse_member()?);
+        }
+        Ok(TypeDecl { name, is_abstract, extending, members, indexes, annotations, expire, compress, line })
+    }
+
+    // `compress lz4;`, `compress zstd;` or `compress none;`, with
+    // `compress` already eaten.
+    fn parse_compress(&mut self, line: usize) -> Result<Compression> {
+        let name = self.ident()?;
+        let Ok(compression) = name.parse() else {
+            bail!(
+                "line {line}: unknown compression `{name}`.{}",
+                suggest::did_you_mean(&name, ["lz4", "zstd", "none"])
+            );
+        };
+        self.expect(";")?;
+        Ok(compression)
+    }
+
+    // `expire after '30d';` or `expire on (.expires);`, with `expire`
+    // already eaten.
+    fn parse_expire(&mut self, line: usize) -> Result<Ttl> {
+        if self.eat_keyword("after") {
+            let ttl = match self.next() {
+                Some(Token::Str(text)) => expiry::parse_duration(&text).map_err(|e| anyhow!("line {line}: {e}"))?,
+                found => bail!("line {line}: expected a duration like '30d', found {}", Self::describe(found.as_ref())),
+            };
+            self.expect(";")?;
+            return Ok(Ttl::After(ttl));
+        }
+        if !self.eat_keyword("on") {
+            bail!("line {line}: expected `after` or `on`, found {}", Self::describe(self.peek()));
+        }
+        self.expect("(")?;
+        self.expect(".")?;
+        let field = self.ident()?;
+        self.expect(")")?;
+        self.expect(";")?;
+        Ok(Ttl::On(field))
+    }
+
+    // `index on (.significance);`, `index on ((.expires, .significance));`
+    // or `index fts::index on (.significance);`, with `index` already eaten.
+    fn parse_index(&mut self, line: usize) -> Result<IndexDecl> {
+        let method = match self.peek() {
+            Some(Token::Ident(ident)) if ident != "on" => Some(self.ident()?),
+            _ => None,
+        };
+        if !self.eat_keyword("on") {
+            bail!("line {line}: expected `on`, found {}", Self::describe(self.peek()));
+        }
+        self.expect("(")?;
+        let is_tuple = method.is_none() && self.eat("(");
+        let mut fields = vec![];
+        loop {
+            self.expect(".")?;
+            fields.push(self.ident()?);
+            if !is_tuple || !self.eat(",") {
+                break;
+            }
+        }
+        if is_tuple {
+            self.expect(")")?;
+        }
+        self.expect(")")?;
+        self.expect(";")?;
+        Ok(IndexDecl { fields, method, line })
+    }
+
+    // `annotation title := 'Funks given';`, with `annotation` already eaten.
+    fn parse_annotation(&mut self, line: usize) -> Result<AnnotationDecl> {
+        let name = self.ident()?;
+        self.expect(":=")?;
+        let value = match self.next() {
+            Some(Token::Str(value)) => value,
+            found => bail!("line {line}: expected a string literal, found {}", Self::describe(found.as_ref())),
+        };
+        self.expect(";")?;
+        Ok(AnnotationDecl { name, value, line })
+    }
+
+    // Modifiers are only keywords when a name follows them, so a
+    // property may still be called `required` or `link`.
+    fn eat_modifier(&mut self, keyword: &str) -> bool {
+        let is_modifier = matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword)
+            && matches!(
+                self.lexemes.get(self.pos + 1).map(|lexeme| &lexeme.token),
+                Some(Token::Ident(_))
+            );
+        if is_modifier {
+            self.pos += 1;
+        }
+        is_modifier
+    }
+
+    fn parse_member(&mut self) -> Result<MemberDecl> {
+        let line = self.line();
+        let required = self.eat_modifier("required");
+        let is_multi = self.eat_modifier("multi");
+        if !is_multi {
+            self.eat_modifier("single");
+        }
+        let kind = if self.eat_modifier("property") {
+            MemberKind::Property
+        } else if self.eat_modifier("link") {
+            MemberKind::Link
+        } else {
+            MemberKind::Inferred
+        };
+        let name = self.ident()?;
+        if !self.eat(":") && !self.eat("->") {
+            bail!("line {}: expected `:` or `->`, found {}", self.line(), Self::describe(self.peek()));
+        }
+        let target = self.ident()?;
+        let mut dimension = None;
+        if self.eat("<") {
+            let line = self.line();
+            let n = self.ident()?;
+            let Ok(n) = n.parse() else {
+                bail!("line {line}: expected a dimension, found `{n}`");
+            };
+            dimension = Some(n);
+            self.expect(">")?;
+        }
+        let mut annotations = vec![];
+        if self.eat("{") {
+            while !self.eat("}") {
+                let line = self.line();
+                if !self.eat_modifier("annotation") {
+                    bail!("line {line}: expected `annotation` or `}}`, found {}", Self::describe(self.peek()));
+                }
+                annotations.push(self.parse_annotation(line)?);
+            }
+        }
+        if !self.eat(";") && !self.eat(",") && self.peek() != Some(&Token::Punct("}")) {
+            bail!("line {}: expected `;`, found {}", self.line(), Self::describe(self.peek()));
+        }
+        Ok(MemberDecl { name, target, kind, required, is_multi, dimension, annotations, line })
+    }
+}
+
+/// Resolves a scalar name, with or without its `std::` prefix.
+pub(crate) fn scalar(name: &str) -> Option<funkstd> {
+    let name = name.strip_prefix("std::").unwrap_or(name);
+    funkstd::iter().find(|scalar| scalar.get_name() == Some(name))
+}
+
+/// Parses `src` and commits every module it declares into a fresh
+/// [`Interner`], with `std` and its builtins already in place.
+pub fn parse(src: &str) -> Result<Interner<'static>> {
+    let decls = Parser::new(src)?.parse_schema()?;
+
+    let interner = Rc::new(RefCell::new(Interner::new()));
+    let mut ns = Namespace::builder()
+        .interner(Rc::clone(&interner))
+        .modules(vec![])
+        .build();
+
+    let mut funk_std = Module::builder()
+        .name("std")
+        .interner(Rc::clone(&interner))
+        .build();
+    ns.register_module(&mut funk_std)?;
+    let builtins: Vec<FunkData> = funkstd::iter().map(FunkData::primitive).collect();
+    ns.try_commit(&vec![(funk_std, builtins)])?;
+
+    let mut commits = vec![];
+    for decl in decls.iter() {
+        let mut module = Module::builder()
+            .name(decl.name.clone())
+            .interner(Rc::clone(&interner))
+            .build();
+        ns.register_module(&mut module)?;
+
+        // Earlier types are linked by value, later ones (and anything in
+        // another module) by a name-only stand-in; `try_commit` checks that
+        // every stand-in resolves.
+        let mut built: BTreeMap<String, Rc<FunkTy<'static>>> = BTreeMap::new();
+        let mut submissions = vec![];
+        for ty in decl.types.iter() {
+            let funk_ty = build_type(ty, &built)?;
+            built.insert(ty.name.clone(), Rc::new(funk_ty.clone()));
+            submissions.push(FunkData::custom(funk_ty));
+        }
+        commits.push((module, submissions));
+    }
+    ns.try_commit(&commits)?;
+
+    for decl in decls.iter() {
+        let module = decl.name.as_str();
+        let mut annotated = vec![(None, None, &decl.annotations)];
+        for ty in decl.types.iter() {
+            annotated.push((Some(ty.name.as_str()), None, &ty.annotations));
+            for member in ty.members.iter() {
+                annotated.push((Some(ty.name.as_str()), Some(member.name.as_str()), &member.annotations));
+            }
+        }
+        for (identity, field, decls) in annotated {
+            if !decls.is_empty() {
+                ns.annotate(module, identity, field, annotations(decls)?)?;
+            }
+        }
+    }
+
+    let interner = interner.borrow().clone();
+    Ok(interner)
+}
+
+fn annotations(decls: &[AnnotationDecl]) -> Result<Annotations<'static>> {
+    let mut annotations = Annotations::default();
+    for decl in decls {
+        annotations
+            .set(&decl.name, decl.value.clone())
+            .map_err(|e| anyhow!("line {}: {e}", decl.line))?;
+    }
+    Ok(annotations)
+}
+
+fn build_type(
+    decl: &TypeDecl,
+    built: &BTreeMap<String, Rc<FunkTy<'static>>>,
+) -> Result<FunkTy<'static>> {
+    let mut funk_ty = FunkTy::r#type(decl.name.clone());
+    if decl.is_abstract {
+        funk_ty = funk_ty.r#abstract();
+    }
+    // Bases are flattened into the type, so nothing downstream has to
+    // chase `extending` to find an inherited property or link.
+    for base in decl.extending.iter() {
+        let Some(base_ty) = built.get(base) else {
+            bail!(
+                "line {}: `{}` extends `{base}`, which has to be declared earlier in the same module.{}",
+                decl.line,
+                decl.name,
+                suggest::did_you_mean(base, built.keys().map(String::as_str))
+            );
+        };
+        for (name, prop) in base_ty.properties.iter() {
+            funk_ty.properties.insert(name.clone(), *prop);
+        }
+        for (name, link) in base_ty.links.iter() {
+            funk_ty.links.insert(name.clone(), link.clone());
+        }
+        for (name, dimension) in base_ty.dimensions.iter() {
+            funk_ty.dimensions.insert(name.clone(), *dimension);
+        }
+        if funk_ty.ttl.is_none() {
+            funk_ty.ttl = base_ty.ttl.clone();
+        }
+        if funk_ty.compression.is_none() {
+            funk_ty.compression = base_ty.compression;
+        }
+        funk_ty = funk_ty.extending(base.clone());
+    }
+    for member in decl.members.iter() {
+        let name = Cow::Owned(member.name.clone());
+        if object::IMPLICIT_FIELDS.contains(&member.name.as_str()) {
+            bail!(
+                "line {}: `{}.{}` is implicit on every type and cannot be declared.",
+                member.line,
+                decl.name,
+                member.name
+            );
+        }
+        if funk_ty.properties.contains_key(&name) || funk_ty.links.contains_key(&name) {
+            bail!(
+                "line {}: `{}.{}` was defined more than once.",
+                member.line,
+                decl.name,
+                member.name
+            );
+        }
+        let scalar = scalar(&member.target);
+        match (scalar, member.dimension) {
+            (Some(funkstd::vector), None) => {
+                bail!("line {}: `vector` needs a dimension, like `vector<768>`", member.line)
+            }
+            (Some(funkstd::vector), Some(0)) => bail!("line {}: vectors need at least one dimension", member.line),
+            (Some(funkstd::vector), Some(_)) if member.is_multi => {
+                bail!("line {}: `{}.{}` cannot be a multi vector", member.line, decl.name, member.name)
+            }
+            (Some(funkstd::vector), Some(dimension)) => {
+                funk_ty.dimensions.insert(name.clone(), dimension);
+            }
+            (_, Some(_)) => bail!("line {}: only `vector` takes a dimension", member.line),
+            (_, None) => {}
+        }
+        funk_ty = match (member.kind, scalar) {
+            (MemberKind::Link, Some(_)) => {
+                bail!("line {}: links must point at an object type, not `{}`", member.line, member.target)
+            }
+            (MemberKind::Property, None) => {
+                let scalars: Vec<String> = funkstd::iter()
+                    .map(|scalar| scalar.get_name().unwrap().to_string())
+                    .collect();
+                bail!(
+                    "line {}: unknown scalar type `{}`.{}",
+                    member.line,
+                    member.target,
+                    suggest::did_you_mean(
+                        member.target.trim_start_matches("std::"),
+                        scalars.iter().map(String::as_str)
+                    )
+                )
+            }
+            (_, Some(kind)) => match (member.required, member.is_multi) {
+                (false, false) => funk_ty.add_property((name, kind)),
+                (false, true) => funk_ty.add_multi_property((name, kind)),
+                (true, false) => funk_ty.add_required_property((name, kind)),
+                (true, true) => funk_ty.add_required_multi_property((name, kind)),
+            },
+            (_, None) => {
+                let target = built
+                    .get(&member.target)
+                    .cloned()
+                    .unwrap_or_else(|| Rc::new(FunkTy::r#type(member.target.clone())));
+                match (member.required, member.is_multi) {
+                    (false, false) => funk_ty.add_link((name, target)),
+                    (false, true) => funk_ty.add_multi_link((name, target)),
+                    (true, false) => funk_ty.add_required_link((name, target)),
+                    (true, true) => funk_ty.add_required_multi_link((name, target)),
+                }
+            }
+        };
+    }
+    if let Some((ttl, line)) = &decl.expire {
+        if let Ttl::On(field) = ttl {
+            funk_ty
+                .check_field(field)
+                .map_err(|e| anyhow!("line {line}: cannot expire on an unknown field. {e}"))?;
+            if funk_ty.properties.get(field.as_str()).map(|(kind, _, is_multi)| (*kind, *is_multi)) != Some((funkstd::datetime, false)) {
+                bail!("line {line}: `expire on` needs a single `datetime` property, `{field}` is not one");
+            }
+        }
+        funk_ty.ttl = Some(ttl.clone());
+    }
+    if let Some((compression, _)) = decl.compress {
+        funk_ty.compression = Some(compression);
+    }
+    for index in decl.indexes.iter() {
+        for field in index.fields.iter() {
+            funk_ty
+                .check_field(field)
+                .map_err(|e| anyhow!("line {}: cannot index unknown field. {e}", index.line))?;
+        }
+        let field = &index.fields[0];
+        let kind = funk_ty.properties.get(field.as_str()).map(|(kind, _, _)| *kind);
+        let Some(method) = index.method.as_deref() else {
+            if let Some(field) = index.fields.iter().find(|field| funk_ty.dimensions.contains_key(field.as_str())) {
+                bail!("line {}: `{field}` is a vector, index it with `hnsw::cosine`, `hnsw::l2` or `hnsw::dot`", index.line);
+            }
+            funk_ty = funk_ty.add_index(index.fields.clone());
+            continue;
+        };
+        funk_ty = match (method, hnsw::Metric::from_name(method), kind) {
+            ("fts::index", _, Some(funkstd::str)) => funk_ty.add_fts_index(field.clone()),
+            ("fts::index", _, _) => {
+                bail!("line {}: `fts::index` needs a `str` property, `{field}` is not one", index.line)
+            }
+            (_, Some(metric), Some(funkstd::vector)) => funk_ty.add_vector_index(field.clone(), metric),
+            (_, Some(_), _) => bail!("line {}: `{method}` needs a vector property, `{field}` is not one", index.line),
+            (_, None, _) => bail!(
+                "line {}: unknown index `{method}`.{}",
+                index.line,
+                suggest::did_you_mean(method, ["fts::index", "hnsw::cosine", "hnsw::l2", "hnsw::dot"])
+            ),
+        };
+    }
+    Ok(funk_ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) const SCHEMA: &str = r#"
+        # The schema from the `create_db_schema_and_apply_it` test.
+        module default {
+            type FunksGiven {
+                required expires: int32;
+                significance: str,
+            }
+            type ReasonForLiving {
+                required online: bool;
+                multi funks: FunksGiven;
+            }
+        }
+    "#;
+
+    #[test]
+    fn parses_default_module() -> anyhow::Result<()> {
+        let interner = parse(SCHEMA)?;
+        let (module, funks_given) = interner.resolve_type("default", "FunksGiven").unwrap();
+        assert_eq!(module, "default");
+        assert_eq!(
+            funks_given.properties.get("expires"),
+            Some(&(funkstd::int32, true, false))
+        );
+        let (_, reason) = interner.resolve_type("default", "ReasonForLiving").unwrap();
+        let (target, required, is_multi) = reason.links.get("funks").unwrap();
+        assert_eq!(target.get_name(), Some("FunksGiven"));
+        assert!(!required && *is_multi);
+        Ok(())
+    }
+
+    #[test]
+    fn edgedb_spelling_and_forward_links() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                type A { required link b -> B; property n -> std::uint8; }
+                type B { multi link a -> default::A; }
+            }",
+        )?;
+        let (_, a) = interner.resolve_type("default", "A").unwrap();
+        assert!(a.links.contains_key("b"));
+        assert_eq!(a.properties.get("n"), Some(&(funkstd::uint8, false, false)));
+        Ok(())
+    }
+
+    #[test]
+    fn annotations_everywhere() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                annotation title := 'Everything';
+                type FunksGiven {
+                    annotation description := \"Funks, given.\";
+                    required expires: int32 {
+                        annotation deprecated := 'Use valid_until';
+                    };
+                }
+            }",
+        )?;
+        let module = interner.annotations_of("default", None, None).unwrap();
+        assert_eq!(module.title.as_deref(), Some("Everything"));
+        let ty = interner.annotations_of("default", Some("FunksGiven"), None).unwrap();
+        assert_eq!(ty.description.as_deref(), Some("Funks, given."));
+        let field = interner
+            .annotations_of("default", Some("FunksGiven"), Some("expires"))
+            .unwrap();
+        assert_eq!(field.deprecated.as_deref(), Some("Use valid_until"));
+
+        let err = parse("module default { annotation colour := 'red'; }").unwrap_err();
+        assert!(err.to_string().contains("Unknown annotation `colour`"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn abstract_types_and_indexes() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                abstract type Named { required name: str; }
+                type User extending Named {
+                    age: uint8;
+                    index on (.name);
+                    index on ((.age, .name));
+                }
+            }",
+        )?;
+        let (_, named) = interner.resolve_type("default", "Named").unwrap();
+        assert!(named.is_abstract);
+        let (_, user) = interner.resolve_type("default", "User").unwrap();
+        assert!(!user.is_abstract);
+        assert_eq!(user.extending, vec!["Named"]);
+        assert_eq!(user.properties.get("name"), Some(&(funkstd::str, true, false)));
+        assert_eq!(user.indexes, vec![vec!["name"], vec!["age", "name"]]);
+
+        let interner = parse("module default { type A { n: int32; bio: str; index fts::index on (.bio); } }")?;
+        let (_, a) = interner.resolve_type("default", "A").unwrap();
+        assert_eq!(a.fts_indexes, vec!["bio"]);
+        assert!(a.indexes.is_empty());
+        let err = parse("module default { type A { n: int32; index fts::index on (.n); } }").unwrap_err();
+        assert!(err.to_string().contains("`n` is not one"), "{err}");
+
+        let err = parse("module default { type A { index on (.nope); } }").unwrap_err();
+        assert!(err.to_string().contains("`A` has no property or link `nope`."), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn vectors_and_their_indexes() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                abstract type Embedded { embedding: vector<3>; index hnsw::cosine on (.embedding); }
+                type Doc extending Embedded { required title: str; }
+            }",
+        )?;
+        let (_, doc) = interner.resolve_type("default", "Doc").unwrap();
+        assert_eq!(doc.property_type("embedding").as_deref(), Some("vector<3>"));
+        let (_, embedded) = interner.resolve_type("default", "Embedded").unwrap();
+        assert_eq!(embedded.vector_indexes, vec![("embedding".into(), hnsw::Metric::Cosine)]);
+
+        for (sdl, expected) in [
+            ("type A { v: vector; }", "`vector` needs a dimension"),
+            ("type A { v: int32<3>; }", "only `vector` takes a dimension"),
+            ("type A { multi v: vector<3>; }", "cannot be a multi vector"),
+            ("type A { v: vector<3>; index on (.v); }", "index it with `hnsw::cosine`"),
+            ("type A { n: int32; index hnsw::l2 on (.n); }", "needs a vector property"),
+            ("type A { v: vector<3>; index hnsw::cosin on (.v); }", "Did you mean `hnsw::cosine`?"),
+        ] {
+            let err = parse(&format!("module default {{ {sdl} }}")).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn types_that_expire() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                abstract type Session { expire after '1d12h'; }
+                type Login extending Session { user: str; }
+                type Invite { required expires: datetime; expire on (.expires); }
+            }",
+        )?;
+        let (_, login) = interner.resolve_type("default", "Login").unwrap();
+        assert_eq!(login.ttl, Some(Ttl::After(std::time::Duration::from_secs(36 * 60 * 60))));
+        let (_, invite) = interner.resolve_type("default", "Invite").unwrap();
+        assert_eq!(invite.ttl, Some(Ttl::On("expires".to_string())));
+
+        for (sdl, expected) in [
+            ("type A { expire after 30; }", "expected a duration like '30d'"),
+            ("type A { expire after '30y'; }", "Unknown unit `y`"),
+            ("type A { expire on (.at); }", "cannot expire on an unknown field"),
+            ("type A { at: int64; expire on (.at); }", "needs a single `datetime` property"),
+            ("type A { expire after '1d'; expire after '2d'; }", "more than once"),
+        ] {
+            let err = parse(&format!("module default {{ {sdl} }}")).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn types_that_compress() -> anyhow::Result<()> {
+        let interner = parse(
+            "module default {
+                abstract type Document { body: str; compress zstd; }
+                type Article extending Document { title: str; }
+                type Draft extending Document { compress none; }
+                type Note { compress lz4; }
+            }",
+        )?;
+        let compression = |name| interner.resolve_type("default", name).unwrap().1.compression;
+        assert_eq!(compression("Article"), Some(Compression::Zstd));
+        assert_eq!(compression("Draft"), Some(Compression::None));
+        assert_eq!(compression("Note"), Some(Compression::Lz4));
+
+        for (sdl, expected) in [
+            ("type A { compress lz5; }", "Did you mean `lz4`?"),
+            ("type A { compress lz4; compress zstd; }", "more than once"),
+        ] {
+            let err = parse(&format!("module default {{ {sdl} }}")).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_link_target() {
+        let err = parse("module default { type A { b: Nope; } }").unwrap_err();
+        assert!(err.to_string().contains("unknown type `Nope`"), "{err}");
+        let err = parse("module default { type A { id: str; } }").unwrap_err();
+        assert!(err.to_string().contains("`A.id` is implicit"), "{err}");
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        let err = parse("module default { type FunksGiven {} type A { b: FunksGivn; } }").unwrap_err();
+        assert!(err.to_string().ends_with("Did you mean `FunksGiven`?"), "{err}");
+        let err = parse("module default { type A { n: int23; } }").unwrap_err();
+        assert!(err.to_string().ends_with("Did you mean `int32`?"), "{err}");
+        let err = parse("module default { type A { property n -> strr; } }").unwrap_err();
+        assert!(err.to_string().ends_with("Did you mean `str`?"), "{err}");
+        let err = parse("module default { type A { n: str; index on (.m); } }").unwrap_err();
+        assert!(err.to_string().ends_with("Did you mean `n`?"), "{err}");
+        let err = parse("module default { annotation titel := 'x'; }").unwrap_err();
+        assert!(err.to_string().ends_with("Did you mean `title`?"), "{err}");
+    }
+}
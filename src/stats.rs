@@ -0,0 +1,158 @@
+//! How much room a database takes up, and how well it compresses:
+//! `funkdb stats`.
+//!
+//! [`stats`] reports the pages of a `.funk` file as of its last
+//! checkpoint, see [`StorageEngine::page_compression`], and goes through
+//! every object record to compare its size as stored with its size
+//! decompressed, per type, see [`compress`].
+//!
+//! [`StorageEngine::page_compression`]: crate::storage::StorageEngine::page_compression
+//! [`compress`]: crate::storage::compress
+use crate::object;
+use crate::storage::{Backend, Compression, Ratio, Snapshot};
+use crate::FunkDb;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub objects: usize,
+    /// What the schema says to compress new records with.
+    pub compression: Compression,
+    pub records: Ratio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub backend: Backend,
+    /// Bytes on disk, 0 in memory.
+    pub size: u64,
+    pub encrypted: bool,
+    pub page_compression: Compression,
+    pub pages: Ratio,
+    /// By type, for every type with objects.
+    pub types: BTreeMap<String, TypeStats>,
+}
+
+impl Stats {
+    /// The records of every type together.
+    pub fn records(&self) -> Ratio {
+        let mut records = Ratio::default();
+        for stats in self.types.values() {
+            records.raw += stats.records.raw;
+            records.stored += stats.records.stored;
+        }
+        records
+    }
+
+    pub fn to_json(&self) -> Value {
+        let types: serde_json::Map<String, Value> = self
+            .types
+            .iter()
+            .map(|(type_name, stats)| {
+                let mut json = ratio_json(&stats.records);
+                json["objects"] = json!(stats.objects);
+                json["compression"] = json!(stats.compression.to_string());
+                (type_name.clone(), json)
+            })
+            .collect();
+        let mut pages = ratio_json(&self.pages);
+        pages["compression"] = json!(self.page_compression.to_string());
+        json!({
+            "backend": self.backend.to_string(),
+            "size": self.size,
+            "encrypted": self.encrypted,
+            "pages": pages,
+            "records": ratio_json(&self.records()),
+            "types": types,
+        })
+    }
+}
+
+fn ratio_json(ratio: &Ratio) -> Value {
+    json!({
+        "raw": ratio.raw,
+        "stored": ratio.stored,
+        "ratio": (ratio.ratio() * 100.0).round() / 100.0,
+    })
+}
+
+/// Measures `db`, see the [module](self).
+pub fn stats(db: &FunkDb) -> Result<Stats> {
+    let (backend, size, encrypted, (page_compression, pages), trees) = {
+        let store = db.db.store();
+        (store.backend(), store.size_on_disk()?, store.is_encrypted(), store.page_compression(), store.trees()?)
+    };
+    let txn = db.begin();
+    let schema = FunkDb::schema_in(&txn)?;
+    let mut types = BTreeMap::new();
+    for type_name in trees.iter().filter_map(|tree| tree.strip_prefix("objects/")) {
+        let mut stats = TypeStats {
+            compression: object::resolve(&schema, type_name)
+                .map(|(_, funk_ty)| funk_ty.compression.unwrap_or_default())
+                .unwrap_or_default(),
+            ..TypeStats::default()
+        };
+        for (_, record) in txn.scan(&object::tree(type_name), (Bound::Unbounded, Bound::Unbounded))? {
+            stats.objects += 1;
+            stats.records.add(object::unpack(&record)?.len(), record.len());
+        }
+        types.insert(type_name.to_string(), stats);
+    }
+    Ok(Stats {
+        backend,
+        size,
+        encrypted,
+        page_compression,
+        pages,
+        types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+    use crate::storage::{remove_scratch, scratch_path};
+    use crate::FunkDbOptions;
+
+    #[test]
+    fn reports_how_well_pages_and_records_compress() -> Result<()> {
+        let path = scratch_path("stats.funk");
+        let mut db = FunkDb::open(&path)?;
+        db.set_catalog("module default { type Article { body: str; compress zstd; } type Note { body: str; } }")?;
+        for n in 0..20 {
+            db.insert(Object::new("Article").set("body", format!("{n} {}", "funk ".repeat(100))))?;
+            db.insert(Object::new("Note").set("body", format!("{n} {}", "funk ".repeat(100))))?;
+        }
+        db.save()?;
+        let stats = db.stats()?;
+        assert_eq!(stats.page_compression, Compression::None);
+        assert!(stats.pages.ratio() < 1.01);
+        let article = stats.types["default::Article"];
+        assert_eq!((article.objects, article.compression), (20, Compression::Zstd));
+        assert!(article.records.ratio() > 4.0, "{}", article.records);
+        assert_eq!(stats.types["default::Note"].records.ratio(), 1.0);
+        drop(db);
+
+        // The header says how pages are compressed, so a reader needs no
+        // option to decode them.
+        let options = FunkDbOptions::builder().compression(Compression::Lz4).build();
+        let size = std::fs::metadata(&path)?.len();
+        drop(FunkDb::open_with(&path, options)?);
+        assert!(std::fs::metadata(&path)?.len() < size / 2);
+        let db = FunkDb::open_with(&path, FunkDbOptions::builder().read_only().build())?;
+        let stats = db.stats()?;
+        assert_eq!(stats.page_compression, Compression::Lz4);
+        assert!(stats.pages.ratio() > 2.0, "{}", stats.pages);
+        assert_eq!(db.objects("Note")?.len(), 20);
+        let json = stats.to_json();
+        assert_eq!(json["pages"]["compression"], "lz4");
+        assert_eq!(json["types"]["default::Article"]["compression"], "zstd");
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Compression of `.funk` pages and large object records.
+//!
+//! A `.funk` file compresses its checkpoint a page at a time with the
+//! [`Compression`] of the database, given by
+//! [`FunkDbOptions::compression`](crate::FunkDbOptions::compression) and
+//! kept in the file header from then on, so any reader can decode it.
+//! Pages are compressed before they are sealed, see [`crypt`](super::crypt).
+//!
+//! Types compress their records instead, on every backend, with
+//! `compress lz4;` or `compress zstd;` in the schema; records shorter than
+//! [`LARGE`] are left as they are. A compressed record says how it was
+//! compressed, so changing the schema leaves the records already written
+//! readable.
+//!
+//! How well either does is a [`Ratio`], see [`FunkDb::stats`](crate::FunkDb::stats).
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use strum::{Display, EnumIter, EnumString};
+
+/// Records shorter than this many bytes are not worth compressing.
+pub const LARGE: usize = 256;
+
+/// The zstd level: fast, yet well ahead of LZ4 on repetitive text.
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, EnumIter)]
+pub enum Compression {
+    #[default]
+    #[strum(serialize = "none")]
+    None,
+    #[strum(serialize = "lz4")]
+    Lz4,
+    #[strum(serialize = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// How files and records name the compression.
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => bail!("Unknown compression {tag}"),
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(bytes),
+            Compression::Zstd => zstd::encode_all(bytes, ZSTD_LEVEL).expect("compressing in memory cannot fail"),
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(bytes).map_err(|err| anyhow!("Does not decompress: {err}")),
+            Compression::Zstd => zstd::decode_all(bytes).map_err(|err| anyhow!("Does not decompress: {err}")),
+        }
+    }
+}
+
+/// Bytes before and after compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ratio {
+    pub raw: u64,
+    pub stored: u64,
+}
+
+impl Ratio {
+    pub fn add(&mut self, raw: usize, stored: usize) {
+        self.raw += raw as u64;
+        self.stored += stored as u64;
+    }
+
+    /// How many times smaller compression made things, 1 with nothing
+    /// to compress.
+    pub fn ratio(&self) -> f64 {
+        match self.stored {
+            0 => 1.0,
+            stored => self.raw as f64 / stored as f64,
+        }
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes stored in {} ({:.2}x)", self.raw, self.stored, self.ratio())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn round_trips() -> Result<()> {
+        let text = "funk ".repeat(1000);
+        for compression in Compression::iter() {
+            let compressed = compression.compress(text.as_bytes());
+            assert_eq!(compression.decompress(&compressed)?, text.as_bytes());
+            assert_eq!(Compression::from_tag(compression.tag())?, compression);
+            assert_eq!(compression.to_string().parse::<Compression>()?, compression);
+            if compression != Compression::None {
+                assert!(compressed.len() < text.len() / 10, "{compression}");
+                assert!(compression.decompress(&compressed[..compressed.len() / 2]).is_err(), "{compression}");
+            }
+        }
+        let mut ratio = Ratio::default();
+        assert_eq!(ratio.ratio(), 1.0);
+        ratio.add(1000, 250);
+        assert_eq!(ratio.to_string(), "1000 bytes stored in 250 (4.00x)");
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Encryption at rest for `.funk` files.
+//!
+//! Given a [`Key`], a `.funk` file is sealed a page at a time and every
+//! record of its log on its own, with ChaCha20-Poly1305: each sealed unit
+//! is a fresh random nonce followed by the ciphertext and its tag, and is
+//! bound to where it belongs through the associated data, so pages and
+//! records cannot be swapped, moved between files or cut off unnoticed.
+//!
+//! The file header names the key it was sealed with by its [`Cipher::id`],
+//! which reveals nothing about the key, so opening with another key fails
+//! right away rather than deep in the data. Keys come from
+//! [`FunkDbOptions::key`](crate::FunkDbOptions::key) or, failing that,
+//! from the environment, see [`Key::from_env`].
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::fmt;
+use std::path::Path;
+
+/// The environment variable naming a key file.
+pub const KEY_FILE_ENV: &str = "FUNKDB_KEY_FILE";
+/// The environment variable holding a key, as hex.
+pub const KEY_ENV: &str = "FUNKDB_KEY";
+
+const NONCE: usize = 12;
+const TAG: usize = 16;
+
+/// A 256-bit key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn generate() -> Self {
+        Self(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// Reads a key from 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            bail!("A key is 64 hex digits");
+        }
+        let mut key = [0; 32];
+        for (n, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * n..2 * n + 2], 16).context("A key is 64 hex digits")?;
+        }
+        Ok(Self(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Reads the key in the file at `path`: 32 bytes, or 64 hex digits.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("Reading the key file {}", path.display()))?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => Self::from_hex(&String::from_utf8_lossy(&bytes)).with_context(|| format!("Reading the key file {}", path.display())),
+        }
+    }
+
+    /// The key in the file [`KEY_FILE_ENV`] names, or else the one in
+    /// [`KEY_ENV`], if either is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        if let Some(path) = std::env::var_os(KEY_FILE_ENV) {
+            return Self::from_file(path).map(Some);
+        }
+        match std::env::var(KEY_ENV) {
+            Ok(hex) => Self::from_hex(&hex).with_context(|| format!("Reading the key in `{KEY_ENV}`")).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+// Keys stay out of logs and panics.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Seals and opens with one [`Key`].
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    id: [u8; 8],
+}
+
+impl Cipher {
+    pub fn new(key: &Key) -> Self {
+        let aead = ChaCha20Poly1305::new(&key.0.into());
+        // The tag of nothing under a fixed nonce tells keys apart without
+        // giving them away.
+        let check = aead
+            .encrypt(&Nonce::default(), Payload { msg: b"", aad: b"funkdb key id" })
+            .expect("sealing nothing cannot fail");
+        Self {
+            aead,
+            id: check[..8].try_into().unwrap(),
+        }
+    }
+
+    /// Names the key, for telling whether it is the one a file was sealed
+    /// with.
+    pub fn id(&self) -> [u8; 8] {
+        self.id
+    }
+
+    /// `plaintext` sealed under a fresh nonce, bound to `aad`.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .aead
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .expect("the plaintext fits the cipher");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    /// What [`seal`](Self::seal) sealed, if it was sealed with this key,
+    /// bound to `aad` and not changed since.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE + TAG {
+            bail!("Too short to be sealed");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE);
+        match self.aead.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => bail!("Fails authentication"),
+        }
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_and_tells_keys_apart() -> Result<()> {
+        let key = Key::generate();
+        let cipher = Cipher::new(&key);
+        let sealed = cipher.seal(b"page 1", b"hello");
+        assert_eq!(cipher.open(b"page 1", &sealed)?, b"hello");
+        assert!(cipher.open(b"page 2", &sealed).is_err());
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(cipher.open(b"page 1", &flipped).is_err());
+
+        let other = Cipher::new(&Key::generate());
+        assert_ne!(other.id(), cipher.id());
+        assert!(other.open(b"page 1", &sealed).is_err());
+        assert_eq!(Cipher::new(&Key::from_hex(&key.to_hex())?).id(), cipher.id());
+        assert!(Key::from_hex("abc").is_err());
+        assert_eq!(format!("{key:?}"), "Key(..)");
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+//! Versions kept on disk for reading the database as of an earlier commit.
+//!
+//! With a retention window set, see [`Mvcc::set_retention`], each commit
+//! also writes what it replaced: the value a key held before the commit at
+//! tick `t` goes to [`VERSIONS`] under
+//!
+//! ```text
+//! tree (escaped) | key (escaped) | t (8 bytes, big-endian)
+//! ```
+//!
+//! as `0x00` if the key did not exist and `0x01` then the value if it did.
+//! Escaping turns `0x00` into `0x00 0xff` and ends with `0x00 0x01`, so
+//! versions sort by tree, key and tick. An [`AsOf`] view at tick `T` reads
+//! a key from its first version after `T` when there is one, and from the
+//! engine otherwise, just like [`Mvcc`] reads its in-memory versions.
+//!
+//! [`TICKS`] has a record per commit: its time, then the keys of the
+//! versions it wrote. Commits that fell out of the window lose their
+//! record and versions, oldest first and a couple at each new commit, so
+//! history never grows past the window by much. The horizon, kept next to
+//! the [`CLOCK`], is the tick of the oldest commit there is still a view
+//! of, and when that was; the window is kept there too, so that opening
+//! the database again keeps history for as long.
+use super::mvcc::{Mvcc, CLOCK};
+use super::{in_range, prefix_end, Batch, KeyRange, Snapshot, StorageEngine};
+use crate::codec::{self, Cursor};
+use crate::value::FunkValue;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// What each commit replaced, see the module documentation.
+pub const VERSIONS: &str = "__versions__";
+
+/// A record per commit: its time and the versions it wrote.
+pub const TICKS: &str = "__ticks__";
+
+/// The key next to the clock holding the horizon.
+pub(crate) const HORIZON: &[u8] = b"horizon";
+
+/// The key next to the clock holding the retention window.
+pub(crate) const WINDOW: &[u8] = b"window";
+
+/// How many expired commits a new commit drops at most.
+pub(crate) const PRUNE: usize = 2;
+
+/// Whether versions of `tree` are kept. The bookkeeping is not.
+pub(crate) fn is_kept(tree: &str) -> bool {
+    tree != VERSIONS && tree != TICKS && tree != CLOCK.0
+}
+
+fn escape_into(out: &mut Vec<u8>, bytes: &[u8]) {
+    for byte in bytes {
+        match byte {
+            0 => out.extend([0x00, 0xff]),
+            byte => out.push(*byte),
+        }
+    }
+    out.extend([0x00, 0x01]);
+}
+
+fn unescape(cursor: &mut Cursor) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    loop {
+        match cursor.byte()? {
+            0 => match cursor.byte()? {
+                0xff => bytes.push(0),
+                0x01 => return Ok(bytes),
+                byte => bail!("Bad escape 0x00 {byte:#04x} in a version key"),
+            },
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+fn tick_key(tick: u64) -> [u8; 8] {
+    tick.to_be_bytes()
+}
+
+/// The tree, key and former value of each write of a commit.
+pub(crate) type Replaced = [(String, Vec<u8>, Option<Vec<u8>>)];
+
+/// Writes the versions a commit at `tick` and `time` replaced.
+pub(crate) fn keep(batch: &mut Batch, tick: u64, time: i64, replaced: &Replaced) {
+    let mut record = time.to_be_bytes().to_vec();
+    for (tree, key, value) in replaced.iter().filter(|(tree, _, _)| is_kept(tree)) {
+        let mut version_key = vec![];
+        escape_into(&mut version_key, tree.as_bytes());
+        escape_into(&mut version_key, key);
+        version_key.extend(tick_key(tick));
+        let version = match value {
+            Some(value) => [&[0x01], value.as_slice()].concat(),
+            None => vec![0x00],
+        };
+        codec::put_varint(&mut record, version_key.len() as u128);
+        record.extend(&version_key);
+        batch.put(VERSIONS, version_key, version);
+    }
+    batch.put(TICKS, tick_key(tick).to_vec(), record);
+}
+
+/// The time of the commit at `tick` from its record.
+fn time_of(store: &dyn StorageEngine, tick: u64) -> Result<Option<i64>> {
+    match store.get(TICKS, &tick_key(tick))? {
+        Some(record) => Ok(Some(i64::from_be_bytes(Cursor::new(&record).take(8)?.try_into()?))),
+        None => Ok(None),
+    }
+}
+
+/// Drops up to `limit` of the oldest commits committed before `cutoff`,
+/// moving `horizon` past them, but never past `tick`, the commit being
+/// made.
+pub(crate) fn prune(
+    store: &dyn StorageEngine,
+    batch: &mut Batch,
+    horizon: &mut (u64, i64),
+    tick: u64,
+    cutoff: i64,
+    limit: usize,
+) -> Result<()> {
+    for _ in 0..limit {
+        let next = horizon.0 + 1;
+        if next >= tick {
+            break;
+        }
+        let Some(record) = store.get(TICKS, &tick_key(next))? else {
+            bail!("The record of commit {next} is missing from the history");
+        };
+        let mut cursor = Cursor::new(&record);
+        let time = i64::from_be_bytes(cursor.take(8)?.try_into()?);
+        if time >= cutoff {
+            break;
+        }
+        while !cursor.is_empty() {
+            let len = usize::try_from(cursor.varint()?)?;
+            batch.delete(VERSIONS, cursor.take(len)?.to_vec());
+        }
+        batch.delete(TICKS, tick_key(next).to_vec());
+        *horizon = (next, time);
+    }
+    batch.put(CLOCK.0, HORIZON.to_vec(), encode_horizon(*horizon));
+    Ok(())
+}
+
+/// Drops all history there is, for when it is no longer kept.
+pub(crate) fn forget(store: &dyn StorageEngine, batch: &mut Batch) -> Result<()> {
+    for tree in [VERSIONS, TICKS] {
+        for (key, _) in store.scan(tree, (Bound::Unbounded, Bound::Unbounded))? {
+            batch.delete(tree, key);
+        }
+    }
+    batch.delete(CLOCK.0, HORIZON.to_vec());
+    batch.delete(CLOCK.0, WINDOW.to_vec());
+    Ok(())
+}
+
+pub(crate) fn encode_horizon((tick, time): (u64, i64)) -> Vec<u8> {
+    [tick.to_be_bytes(), time.to_be_bytes()].concat()
+}
+
+pub(crate) fn encode_window(window: Duration) -> Vec<u8> {
+    u64::try_from(window.as_micros()).unwrap_or(u64::MAX).to_be_bytes().to_vec()
+}
+
+pub(crate) fn decode_window(bytes: &[u8]) -> Result<Duration> {
+    Ok(Duration::from_micros(u64::from_be_bytes(bytes.try_into()?)))
+}
+
+pub(crate) fn decode_horizon(bytes: &[u8]) -> Result<(u64, i64)> {
+    let mut cursor = Cursor::new(bytes);
+    let tick = u64::from_be_bytes(cursor.take(8)?.try_into()?);
+    let time = i64::from_be_bytes(cursor.take(8)?.try_into()?);
+    Ok((tick, time))
+}
+
+/// A read-only view of the database right after the commit at a tick, as
+/// the versions kept on disk allow. Everything that reads through a
+/// [`Snapshot`] reads through it, the catalog included.
+pub struct AsOf<'db> {
+    db: &'db Mvcc,
+    tick: u64,
+    time: i64,
+}
+
+impl<'db> AsOf<'db> {
+    /// The view right after the commit at `tick`.
+    pub(crate) fn at_tick(db: &'db Mvcc, tick: u64) -> Result<Self> {
+        let store = db.store();
+        let (horizon, horizon_time) = reachable(db)?;
+        let clock = db.clock();
+        if tick > clock {
+            bail!("Transaction {tick} has not happened yet, the last one is {clock}");
+        }
+        if tick < horizon {
+            bail!("History is only kept back to transaction {horizon}, not {tick}");
+        }
+        let time = match tick == horizon {
+            true => horizon_time,
+            false => match time_of(&**store, tick)? {
+                Some(time) => time,
+                None => bail!("The record of commit {tick} is missing from the history"),
+            },
+        };
+        Ok(Self { db, tick, time })
+    }
+
+    /// The view at `time`, in microseconds since the epoch: right after
+    /// the last commit made by then.
+    pub(crate) fn at_time(db: &'db Mvcc, time: i64) -> Result<Self> {
+        let store = db.store();
+        let (horizon, horizon_time) = reachable(db)?;
+        if time < horizon_time {
+            bail!(
+                "History is only kept back to {}, not {}",
+                FunkValue::datetime(horizon_time),
+                FunkValue::datetime(time)
+            );
+        }
+        // Commit times only go up, so the last commit made by `time` is
+        // found by bisection.
+        let (mut low, mut high) = (horizon, db.clock());
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            match time_of(&**store, mid)? {
+                Some(committed) if committed <= time => low = mid,
+                Some(_) => high = mid - 1,
+                None => bail!("The record of commit {mid} is missing from the history"),
+            }
+        }
+        Ok(Self { db, tick: low, time })
+    }
+
+    /// The tick of the last commit the view sees, which is also that
+    /// transaction's id.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The time the view is as of, in microseconds since the epoch.
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+
+    /// Fails if the commits after the view were pruned since it was made.
+    fn check(&self) -> Result<()> {
+        let (horizon, _) = reachable(self.db)?;
+        if self.tick < horizon {
+            bail!("Transaction {} fell out of the history while it was being read", self.tick);
+        }
+        Ok(())
+    }
+}
+
+fn reachable(db: &Mvcc) -> Result<(u64, i64)> {
+    match db.horizon() {
+        Some(horizon) => Ok(horizon),
+        None => bail!("History is not kept, open the database with a retention window to read as of a past commit"),
+    }
+}
+
+impl Snapshot for AsOf<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let store = self.db.store();
+        self.check()?;
+        let mut prefix = vec![];
+        escape_into(&mut prefix, tree.as_bytes());
+        escape_into(&mut prefix, key);
+        let start = [prefix.as_slice(), &tick_key(self.tick + 1)].concat();
+        let end = prefix_end(&prefix).unwrap();
+        let versions = store.scan(VERSIONS, (Bound::Included(&start), Bound::Excluded(&end)))?;
+        match versions.into_iter().next() {
+            Some((_, version)) => decode_version(&version),
+            None => store.get(tree, key),
+        }
+    }
+
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let store = self.db.store();
+        self.check()?;
+        let mut pairs: BTreeMap<_, _> = store.scan(tree, range)?.into_iter().collect();
+        let mut prefix = vec![];
+        escape_into(&mut prefix, tree.as_bytes());
+        let end = prefix_end(&prefix).unwrap();
+        let mut done: Option<Vec<u8>> = None;
+        for (version_key, version) in store.scan(VERSIONS, (Bound::Included(&prefix), Bound::Excluded(&end)))? {
+            let mut cursor = Cursor::new(&version_key[prefix.len()..]);
+            let key = unescape(&mut cursor)?;
+            let tick = u64::from_be_bytes(cursor.take(8)?.try_into()?);
+            // Only the first version after the view counts for each key.
+            if tick <= self.tick || done.as_ref() == Some(&key) || !in_range(&key, &range) {
+                continue;
+            }
+            match decode_version(&version)? {
+                Some(value) => pairs.insert(key.clone(), value),
+                None => pairs.remove(&key),
+            };
+            done = Some(key);
+        }
+        Ok(pairs.into_iter().collect())
+    }
+
+    fn time(&self) -> Option<i64> {
+        Some(self.time)
+    }
+}
+
+fn decode_version(version: &[u8]) -> Result<Option<Vec<u8>>> {
+    match version.split_first() {
+        Some((0x00, [])) => Ok(None),
+        Some((0x01, value)) => Ok(Some(value.to_vec())),
+        _ => bail!("A malformed version in the history"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{self, Object};
+    use crate::{FunkDb, FunkDbOptions};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    const SCHEMA: &str = "module default { type Person { required name: str; friend: Person; } }";
+    const LATER: &str = "module default { type Person { required name: str; age: int32; friend: Person; } type Pet { } }";
+
+    fn last_txn(db: &FunkDb) -> Result<u64> {
+        Ok(db.changes("Person", None, usize::MAX)?.last().unwrap().txn())
+    }
+
+    #[test]
+    fn reads_as_of_a_past_transaction_or_time() -> Result<()> {
+        let options = FunkDbOptions::builder().retain(Duration::from_secs(3600)).build();
+        let db = FunkDb::open_with("mem://", options)?;
+        db.set_catalog(SCHEMA)?;
+        let ann = db.insert(Object::new("Person").set("name", "ann"))?;
+        let bob = db.insert(Object::new("Person").set("name", "bob").link("friend", ann))?;
+        let before = last_txn(&db)?;
+        thread::sleep(Duration::from_millis(5));
+        let then = SystemTime::now();
+        thread::sleep(Duration::from_millis(5));
+        db.set_catalog(LATER)?;
+        db.update(db.object(ann)?.unwrap().set("name", "anne").set("age", 40))?;
+        db.update(db.object(bob)?.unwrap().unset("friend"))?;
+        db.insert(Object::new("Pet"))?;
+        db.delete(ann)?;
+
+        for view in [db.as_of(before)?, db.as_of_time(then)?] {
+            assert_eq!(view.tick(), before);
+            // Links resolve, and the schema is the one of the time.
+            let friend = object::get(&view, bob)?.unwrap().targets("friend")[0];
+            let ann = object::get(&view, friend)?.unwrap();
+            assert_eq!(ann.get("name").unwrap().to_string(), "ann");
+            assert_eq!(ann.get("age"), None);
+            let schema = FunkDb::schema_in(&view)?;
+            assert_eq!(object::scan(&view, &schema, "Person")?.len(), 2);
+            assert!(object::scan(&view, &schema, "Pet").is_err());
+        }
+        let now = db.as_of(db.db.clock())?;
+        assert_eq!(object::scan(&now, &FunkDb::schema_in(&now)?, "Person")?.len(), 1);
+        assert!(db.as_of(db.db.clock() + 1).is_err());
+        assert!(db.as_of_time(SystemTime::UNIX_EPOCH).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn history_falls_out_of_the_window() -> Result<()> {
+        let options = FunkDbOptions::builder().retain(Duration::from_millis(50)).build();
+        let db = FunkDb::open_with("mem://", options)?;
+        db.set_catalog(SCHEMA)?;
+        let id = db.insert(Object::new("Person").set("name", "ann"))?;
+        let early = last_txn(&db)?;
+        thread::sleep(Duration::from_millis(60));
+        for name in ["b", "c", "d", "e"] {
+            db.update(db.object(id)?.unwrap().set("name", name))?;
+        }
+        // What `early` committed lasted until the first update, which is
+        // within the window, so only the commits before it are gone.
+        assert!(db.as_of(early - 1).is_err());
+        assert_eq!(db.as_of(early)?.tick(), early);
+        assert_eq!(db.db.horizon().unwrap().0, early);
+        let store = db.db.store();
+        assert!(store.get(TICKS, &tick_key(early))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn the_window_holds_until_set_again() -> Result<()> {
+        let path = crate::storage::scratch_path("history.funk");
+        let options = FunkDbOptions::builder().retain(Duration::from_secs(3600)).build();
+        let db = FunkDb::open_with(&path, options)?;
+        db.set_catalog(SCHEMA)?;
+        let id = db.insert(Object::new("Person").set("name", "ann"))?;
+        let early = last_txn(&db)?;
+        drop(db);
+
+        let db = FunkDb::open(&path)?;
+        db.update(db.object(id)?.unwrap().set("name", "anne"))?;
+        let view = db.as_of(early)?;
+        assert_eq!(object::get(&view, id)?.unwrap().get("name").unwrap().to_string(), "ann");
+        drop(db);
+
+        let db = FunkDb::open_with(&path, FunkDbOptions::builder().retain(Duration::ZERO).build())?;
+        assert!(db.as_of(early).is_err());
+        assert!(db.db.store().scan(VERSIONS, (Bound::Unbounded, Bound::Unbounded))?.is_empty());
+        drop(db);
+        assert!(FunkDb::open(&path)?.as_of(early).is_err());
+        crate::storage::remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_only_kept_when_asked() -> Result<()> {
+        let db = FunkDb::open("mem://")?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Person").set("name", "ann"))?;
+        let err = db.as_of(1).err().unwrap();
+        assert!(err.to_string().contains("retention"), "{err}");
+        assert!(db.db.store().scan(VERSIONS, (Bound::Unbounded, Bound::Unbounded))?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Advisory locking, so that two processes never write one database.
+//!
+//! The lock is taken on `<db>-lock` rather than on the database itself:
+//! a `.funk` checkpoint renames a new file over the old one, and a lock on
+//! the old file would go with it. Writers lock exclusively and leave their
+//! pid in the lock file, so whoever comes second can say who is in the way.
+//! Read-only opens share the lock with each other, and clear the pid a
+//! writer that crashed left behind. Engines that only one process can open
+//! at all, like sled, lock exclusively for reading too, see
+//! [`FileLock::acquire_alone`].
+use anyhow::{bail, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Access {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+}
+
+pub fn path_for(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push("-lock");
+    PathBuf::from(path)
+}
+
+/// What an exclusive holder writes after its pid when it only reads.
+const READING: &str = "read-only";
+
+/// Held for as long as a database is open.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+    access: Access,
+    exclusive: bool,
+}
+
+impl FileLock {
+    /// Locks the database at `db`, or fails right away naming whoever
+    /// holds it.
+    pub fn acquire(db: &Path, access: Access) -> Result<Self> {
+        Self::lock(db, access, access == Access::ReadWrite)
+    }
+
+    /// Locks the database at `db` exclusively even to read it, for engines
+    /// that cannot be open in two places at once.
+    pub fn acquire_alone(db: &Path, access: Access) -> Result<Self> {
+        Self::lock(db, access, true)
+    }
+
+    fn lock(db: &Path, access: Access, exclusive: bool) -> Result<Self> {
+        let path = path_for(db);
+        loop {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+            let locked = match exclusive {
+                true => file.try_lock(),
+                false => file.try_lock_shared(),
+            };
+            match locked {
+                Ok(()) => {}
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+                Err(TryLockError::WouldBlock) => {
+                    let mut holder = String::new();
+                    file.read_to_string(&mut holder)?;
+                    let mut holder = holder.split_whitespace();
+                    match (holder.next().map(str::parse::<u32>), holder.next()) {
+                        (Some(Ok(pid)), Some(READING)) => {
+                            bail!("{} is locked by process {pid}, which has it open read-only and cannot share it", db.display())
+                        }
+                        (Some(Ok(pid)), _) => bail!("{} is locked by process {pid}, which has it open for writing", db.display()),
+                        _ if exclusive => bail!("{} is open read-only in another process", db.display()),
+                        _ => bail!("{} is locked by another process", db.display()),
+                    }
+                }
+            }
+            // A writer removes the lock file when it is done. If that
+            // happened between our open and our lock, we hold a lock on a
+            // file nobody else will ever see, so start over.
+            let current = match fs::metadata(&path) {
+                Ok(metadata) => Some((metadata.dev(), metadata.ino())),
+                Err(_) => None,
+            };
+            let metadata = file.metadata()?;
+            if current != Some((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            // Shared or not, nobody else holds the lock now, so a pid left
+            // in the file is that of a writer that crashed.
+            file.set_len(0)?;
+            file.rewind()?;
+            if exclusive {
+                write!(file, "{}", std::process::id())?;
+                if access == Access::ReadOnly {
+                    write!(file, " {READING}")?;
+                }
+                file.sync_all()?;
+            }
+            return Ok(Self {
+                file,
+                path,
+                access,
+                exclusive,
+            });
+        }
+    }
+
+    pub fn access(&self) -> Access {
+        self.access
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Readers sharing the lock cannot tell whether they are the last
+        // one, so only a sole holder cleans up.
+        if self.exclusive {
+            let _ = fs::remove_file(&self.path);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+
+    #[test]
+    fn writers_exclude_everyone() -> Result<()> {
+        let db = scratch_path("locked.funk");
+        let writer = FileLock::acquire(&db, Access::ReadWrite)?;
+        let pid = std::process::id();
+        for access in [Access::ReadWrite, Access::ReadOnly] {
+            let err = FileLock::acquire(&db, access).unwrap_err().to_string();
+            assert!(err.contains(&format!("locked by process {pid}")), "{err}");
+        }
+        drop(writer);
+        assert!(!path_for(&db).exists());
+
+        let reader = FileLock::acquire(&db, Access::ReadOnly)?;
+        let other = FileLock::acquire(&db, Access::ReadOnly)?;
+        let err = FileLock::acquire(&db, Access::ReadWrite).unwrap_err().to_string();
+        assert!(err.contains("open read-only in another process"), "{err}");
+        drop((reader, other));
+        drop(FileLock::acquire(&db, Access::ReadWrite)?);
+
+        // A writer that crashed leaves its pid behind, which a reader
+        // clears, so it is not named as the holder.
+        fs::write(path_for(&db), "4242424")?;
+        let reader = FileLock::acquire(&db, Access::ReadOnly)?;
+        let err = FileLock::acquire(&db, Access::ReadWrite).unwrap_err().to_string();
+        assert!(err.contains("open read-only in another process"), "{err}");
+        drop(reader);
+        let _ = fs::remove_file(path_for(&db));
+        Ok(())
+    }
+
+    #[test]
+    fn lone_readers_exclude_everyone() -> Result<()> {
+        let db = scratch_path("alone.funk");
+        let reader = FileLock::acquire_alone(&db, Access::ReadOnly)?;
+        let pid = std::process::id();
+        for access in [Access::ReadWrite, Access::ReadOnly] {
+            let err = FileLock::acquire_alone(&db, access).unwrap_err().to_string();
+            assert!(err.contains(&format!("locked by process {pid}, which has it open read-only")), "{err}");
+        }
+        drop(reader);
+        assert!(!path_for(&db).exists());
+        Ok(())
+    }
+}
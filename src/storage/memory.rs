@@ -0,0 +1,932 @@
+use super::compress::{Compression, Ratio};
+use super::crypt::{Cipher, Key};
+use super::wal::{self, Pending, Wal};
+use super::{in_range, Backend, Batch, KeyRange, Op, Rewrite, StorageEngine};
+use anyhow::{bail, Context, Result};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"FUNKDB\0\0";
+const FORMAT_VERSION: u32 = 4;
+
+/// Bytes of the trees per page, before compressing and sealing it.
+const PAGE: usize = 64 << 10;
+
+const PLAIN: u8 = 0;
+const CHACHA20_POLY1305: u8 = 1;
+
+/// Checkpoint once the log grows past this.
+pub const CHECKPOINT_AFTER: u64 = 16 << 20;
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Keeps every tree in memory.
+///
+/// Opened on a path, this is the `.funk` file backend: commits go through
+/// the [`Wal`] first, and a checkpoint (every [`StorageEngine::flush`], or
+/// once the log passes [`CHECKPOINT_AFTER`]) writes the whole store to the
+/// file and drops the log. The file is a checksummed snapshot:
+///
+/// ```text
+/// magic | version: u32 | checkpoint lsn: u64 | encryption: u8 | compression: u8
+///       | key id: [u8; 8], if encrypted | pages: u32 | (length: u32 | page)... | crc32: u32
+/// ```
+///
+/// The trees are split into pages of [`PAGE`] bytes, each compressed, see
+/// [`compress`](super::compress), then, given a [`Key`], sealed and bound
+/// to the checkpoint and its place among the others, see
+/// [`crypt`](super::crypt).
+#[derive(Debug)]
+pub struct MemoryStore {
+    trees: BTreeMap<String, Tree>,
+    path: Option<PathBuf>,
+    wal: Option<Arc<Wal>>,
+    cipher: Option<Cipher>,
+    compression: Compression,
+    // The pages of the last checkpoint read or written.
+    pages: Ratio,
+    checkpoint_after: u64,
+    read_only: bool,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            trees: BTreeMap::new(),
+            path: None,
+            wal: None,
+            cipher: None,
+            compression: Compression::None,
+            pages: Ratio::default(),
+            checkpoint_after: CHECKPOINT_AFTER,
+            read_only: false,
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the last checkpoint of `path` and replays the log on top. A
+    /// new file is encrypted with `key`, if given; an existing one has to
+    /// be opened with the key it was encrypted with, or none if it is not.
+    /// Given a `compression`, checkpoints use it from now on, otherwise the
+    /// one the file has.
+    pub fn open(path: impl AsRef<Path>, key: Option<&Key>, compression: Option<Compression>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = Self {
+            path: Some(path.clone()),
+            cipher: key.map(Cipher::new),
+            ..Self::default()
+        };
+        let existing = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len() > 0,
+            Err(_) => false,
+        };
+        let mut checkpoint = 0;
+        if existing {
+            let mut bytes = vec![];
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            let decoded = decode(&bytes, store.cipher.as_ref()).with_context(|| format!("Reading {}", path.display()))?;
+            (checkpoint, store.trees, store.compression, store.pages) = (decoded.lsn, decoded.trees, decoded.compression, decoded.pages);
+        }
+        // A crash between writing a checkpoint and dropping the log leaves
+        // records the checkpoint already has, perhaps sealed with the key
+        // it replaced.
+        wal::settle(&wal::path_for(&path), checkpoint)?;
+        let (wal, replayed) = Wal::open(wal::path_for(&path), store.cipher.clone(), checkpoint)?;
+        for (_, batch) in replayed {
+            store.apply_in_memory(batch);
+        }
+        wal.skip_to(checkpoint);
+        store.wal = Some(Arc::new(wal));
+        let recompress = compression.is_some_and(|compression| compression != store.compression);
+        store.compression = compression.unwrap_or(store.compression);
+        if !existing || recompress {
+            store.flush()?;
+        }
+        Ok(store)
+    }
+
+    /// Loads `path` and its log without writing to either.
+    pub fn open_read_only(path: impl AsRef<Path>, key: Option<&Key>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let cipher = key.map(Cipher::new);
+        let bytes = fs::read(&path).with_context(|| format!("Opening {} read-only", path.display()))?;
+        let decoded = decode(&bytes, cipher.as_ref()).with_context(|| format!("Reading {}", path.display()))?;
+        let records = wal::read(&wal::path_for(&path), cipher.as_ref(), decoded.lsn)?;
+        let mut store = Self {
+            trees: decoded.trees,
+            path: Some(path.clone()),
+            cipher,
+            compression: decoded.compression,
+            pages: decoded.pages,
+            read_only: true,
+            ..Self::default()
+        };
+        for (_, batch) in records {
+            store.apply_in_memory(batch);
+        }
+        Ok(store)
+    }
+
+    /// A read-only copy of the database at `path` as of the last commit on
+    /// disk, the checkpoint its log was read after, and the log sequence
+    /// number of that commit. Nothing is locked, so another process may go
+    /// on writing meanwhile; should it checkpoint while the log is being
+    /// read, reading starts over.
+    pub fn read_consistent(path: impl AsRef<Path>, key: Option<&Key>) -> Result<(Self, u64, u64)> {
+        let path = path.as_ref().to_path_buf();
+        let (trees, checkpoint, lsn) = snapshot(&path, key.map(Cipher::new).as_ref())?;
+        let store = Self {
+            trees,
+            path: Some(path),
+            read_only: true,
+            ..Self::default()
+        };
+        Ok((store, checkpoint, lsn))
+    }
+
+    /// Checkpoints once the log holds more than `bytes`.
+    pub fn checkpoint_after(mut self, bytes: u64) -> Self {
+        self.checkpoint_after = bytes;
+        self
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_deref()
+    }
+
+    /// Writes every tree to `path` as the checkpoint of a `.funk` file,
+    /// sealed with `cipher` if given, as of log sequence number `lsn`,
+    /// leaving the store as it is.
+    pub fn save_as(&self, path: impl AsRef<Path>, lsn: u64, cipher: Option<&Cipher>) -> Result<()> {
+        write_checkpoint(path.as_ref(), lsn, &self.trees, cipher, Compression::None).map(drop)
+    }
+
+    fn checkpoint_if_due(&mut self) -> Result<()> {
+        match self.wal.as_ref().is_some_and(|wal| wal.size() > self.checkpoint_after) {
+            true => self.flush(),
+            false => Ok(()),
+        }
+    }
+
+    fn begin_rewrite(&mut self, to: Option<Cipher>) -> Result<Option<Box<dyn Rewrite>>> {
+        let (Some(path), Some(wal)) = (&self.path, &self.wal) else {
+            return Ok(None);
+        };
+        if self.read_only {
+            return Ok(None);
+        }
+        // The rewrite has to be of a later commit than the file, so that
+        // its log can be told from the one that goes with the file, see
+        // `wal::settle`.
+        let base = checkpoint_of(path)?;
+        if synced(wal)? == base {
+            wal.commit(&Batch::new())?;
+        }
+        Ok(Some(Box::new(CheckpointRewrite {
+            path: path.clone(),
+            base,
+            from: self.cipher.clone(),
+            to,
+            compression: self.compression,
+            built: None,
+        })))
+    }
+
+    fn apply_in_memory(&mut self, batch: Batch) {
+        for op in batch {
+            match op {
+                Op::Put { tree, key, value } => {
+                    self.trees.entry(tree).or_default().insert(key, value);
+                }
+                Op::Delete { tree, key } => {
+                    if let Some(pairs) = self.trees.get_mut(&tree) {
+                        pairs.remove(&key);
+                        if pairs.is_empty() {
+                            self.trees.remove(&tree);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl StorageEngine for MemoryStore {
+    fn backend(&self) -> Backend {
+        match self.path {
+            Some(_) => Backend::File,
+            None => Backend::Memory,
+        }
+    }
+
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.trees.get(tree).and_then(|tree| tree.get(key)).cloned())
+    }
+
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let Some(tree) = self.trees.get(tree) else {
+            return Ok(vec![]);
+        };
+        let pairs = tree
+            .range::<[u8], _>(range)
+            .filter(|(key, _)| in_range(key, &range))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Ok(pairs)
+    }
+
+    fn apply(&mut self, batch: Batch) -> Result<()> {
+        if self.read_only {
+            bail!("Cannot write to a database opened read-only");
+        }
+        if let Some(wal) = &self.wal {
+            wal.commit(&batch)?;
+        }
+        self.apply_in_memory(batch);
+        self.checkpoint_if_due()
+    }
+
+    // The trees take the batch before it is on disk: readers are kept from
+    // it by the caller until the sync is through, see `Mvcc`.
+    fn apply_deferred(&mut self, batch: Batch) -> Result<Option<Pending>> {
+        if self.read_only {
+            bail!("Cannot write to a database opened read-only");
+        }
+        let pending = match &self.wal {
+            Some(wal) => Some(wal.defer(&batch)?),
+            None => None,
+        };
+        self.apply_in_memory(batch);
+        self.checkpoint_if_due()?;
+        Ok(pending)
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self.trees.keys().cloned().collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| !self.read_only) else {
+            return Ok(());
+        };
+        let checkpoint = self.wal.as_deref().map(synced).transpose()?.unwrap_or(0);
+        self.pages = write_checkpoint(path, checkpoint, &self.trees, self.cipher.as_ref(), self.compression)?;
+        if let Some(wal) = &self.wal {
+            wal.reset()?;
+        }
+        Ok(())
+    }
+
+    fn begin_compact(&mut self) -> Result<Option<Box<dyn Rewrite>>> {
+        self.begin_rewrite(self.cipher.clone())
+    }
+
+    // Moves the records committed since the snapshot next to the log,
+    // sealed the way the new file is, then renames the file and then the
+    // log in place. A crash in between is settled on open.
+    fn finish_rewrite(&mut self, rewrite: Box<dyn Rewrite>) -> Result<bool> {
+        let rewrite: Box<dyn Any> = rewrite;
+        let Ok(rewrite) = rewrite.downcast::<CheckpointRewrite>() else {
+            bail!("Not a rewrite of a .funk file");
+        };
+        let (Some(path), Some(wal)) = (self.path.clone(), self.wal.clone()) else {
+            bail!("Not a rewrite of this store");
+        };
+        let Some((lsn, pages)) = rewrite.built else {
+            bail!("The rewrite of {} was never built", path.display());
+        };
+        synced(&wal)?;
+        // A checkpoint since may have dropped records the rewrite missed.
+        if checkpoint_of(&path)? != rewrite.base {
+            return Ok(false);
+        }
+        let tail = wal::read(wal.path(), self.cipher.as_ref(), lsn)?;
+        let next = wal::next_path_for(wal.path());
+        if !tail.is_empty() {
+            wal::write(&next, rewrite.to.as_ref(), &tail)?;
+        }
+        fs::rename(rewrite_path(&path), &path)?;
+        match tail.is_empty() {
+            true => fs::remove_file(wal.path())?,
+            false => fs::rename(&next, wal.path())?,
+        }
+        wal.reopen(rewrite.to.clone())?;
+        self.cipher = rewrite.to.clone();
+        self.pages = pages;
+        Ok(true)
+    }
+
+    // Reads the file and the log back from disk: it is what is there that
+    // has to be intact, and the engine is held, so no checkpoint runs.
+    fn verify(&self) -> Result<Vec<String>> {
+        let Some(path) = &self.path else {
+            return Ok(vec![]);
+        };
+        let mut problems = vec![];
+        let cipher = self.cipher.as_ref();
+        if let Err(err) = fs::read(path).map_err(anyhow::Error::from).and_then(|bytes| decode(&bytes, cipher)) {
+            problems.push(format!("{}: {err:#}", path.display()));
+        }
+        let checkpoint = checkpoint_of(path).unwrap_or(0);
+        problems.extend(wal::verify(&wal::path_for(path), cipher, checkpoint)?);
+        Ok(problems)
+    }
+
+    // Checkpoints under the new key, then starts the log afresh with it. A
+    // crash in between leaves records under the old key that the new
+    // checkpoint already has, which opening skips.
+    fn rekey(&mut self, key: Option<&Key>) -> Result<()> {
+        let (Some(path), Some(wal)) = (&self.path, &self.wal) else {
+            bail!("Only .funk files can be encrypted");
+        };
+        if self.read_only {
+            bail!("Cannot encrypt a database opened read-only");
+        }
+        let cipher = key.map(Cipher::new);
+        self.pages = write_checkpoint(path, synced(wal)?, &self.trees, cipher.as_ref(), self.compression)?;
+        wal.reset()?;
+        wal.set_cipher(cipher.clone())?;
+        self.cipher = cipher;
+        Ok(())
+    }
+
+    // The rewrite reads the snapshot with the old key and seals the file,
+    // and the records committed meanwhile, with the new one.
+    fn begin_rekey(&mut self, key: Option<&Key>) -> Result<Option<Box<dyn Rewrite>>> {
+        if self.path.is_none() {
+            bail!("Only .funk files can be encrypted");
+        }
+        if self.read_only {
+            bail!("Cannot encrypt a database opened read-only");
+        }
+        self.begin_rewrite(key.map(Cipher::new))
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn page_compression(&self) -> (Compression, Ratio) {
+        (self.compression, self.pages)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let log = self.wal.as_deref().map(Wal::size).unwrap_or(0);
+        Ok(fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0) + log)
+    }
+}
+
+/// The last record of `wal`, once it is on disk. Commits that have not
+/// waited for their sync yet are in the trees already, and the log cannot
+/// be reset under them.
+fn synced(wal: &Wal) -> Result<u64> {
+    let lsn = wal.last_lsn();
+    wal.sync(lsn)?;
+    Ok(lsn)
+}
+
+/// The trees of the database at `path` as of the last commit on disk, the
+/// checkpoint they were read from and the log sequence number of that
+/// commit, see
+/// [`MemoryStore::read_consistent`].
+fn snapshot(path: &Path, cipher: Option<&Cipher>) -> Result<(BTreeMap<String, Tree>, u64, u64)> {
+    loop {
+        let bytes = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        let Checkpoint { lsn: checkpoint, trees, .. } = decode(&bytes, cipher).with_context(|| format!("Reading {}", path.display()))?;
+        let log = wal::path_for(path);
+        let records = match wal::read(&log, cipher, checkpoint) {
+            Ok(records) => records,
+            // A checkpoint meanwhile may have started the log afresh
+            // under another key, or a rewrite be about to.
+            Err(_) if checkpoint_of(path)? != checkpoint || wal::next_path_for(&log).exists() => continue,
+            Err(err) => return Err(err),
+        };
+        // The log is only dropped after a new checkpoint replaced the
+        // file, so as long as the file is the same the log went with it.
+        if checkpoint_of(path)? != checkpoint {
+            continue;
+        }
+        let mut store = MemoryStore {
+            trees,
+            ..MemoryStore::default()
+        };
+        let mut lsn = checkpoint;
+        for (record, batch) in records {
+            store.apply_in_memory(batch);
+            lsn = record;
+        }
+        return Ok((store.trees, checkpoint, lsn));
+    }
+}
+
+/// A checkpoint written without holding the store, see [`Rewrite`].
+struct CheckpointRewrite {
+    path: PathBuf,
+    /// The checkpoint in the file when the rewrite began.
+    base: u64,
+    from: Option<Cipher>,
+    to: Option<Cipher>,
+    compression: Compression,
+    /// The checkpoint written to [`rewrite_path`], and its pages.
+    built: Option<(u64, Ratio)>,
+}
+
+impl Rewrite for CheckpointRewrite {
+    fn build(&mut self) -> Result<()> {
+        let (trees, _, lsn) = snapshot(&self.path, self.from.as_ref())?;
+        let pages = write_file(&rewrite_path(&self.path), lsn, &trees, self.to.as_ref(), self.compression)?;
+        self.built = Some((lsn, pages));
+        Ok(())
+    }
+}
+
+// Once in place there is nothing left to remove.
+impl Drop for CheckpointRewrite {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(rewrite_path(&self.path));
+    }
+}
+
+/// Where a [`CheckpointRewrite`] writes, apart from checkpoints taken
+/// meanwhile.
+fn rewrite_path(path: &Path) -> PathBuf {
+    let mut scratch = path.to_path_buf().into_os_string();
+    scratch.push(".rewrite");
+    PathBuf::from(scratch)
+}
+
+fn write_checkpoint(
+    path: &Path,
+    checkpoint: u64,
+    trees: &BTreeMap<String, Tree>,
+    cipher: Option<&Cipher>,
+    compression: Compression,
+) -> Result<Ratio> {
+    // Write next to the file and rename over it, so a crash halfway
+    // through leaves the previous snapshot intact.
+    let mut scratch = path.to_path_buf().into_os_string();
+    scratch.push(".tmp");
+    let scratch = PathBuf::from(scratch);
+    let pages = write_file(&scratch, checkpoint, trees, cipher, compression)?;
+    fs::rename(&scratch, path)?;
+    Ok(pages)
+}
+
+/// Writes `trees` to `path` as the file of a checkpoint, durably.
+fn write_file(
+    path: &Path,
+    checkpoint: u64,
+    trees: &BTreeMap<String, Tree>,
+    cipher: Option<&Cipher>,
+    compression: Compression,
+) -> Result<Ratio> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let (bytes, pages) = encode(checkpoint, trees, cipher, compression);
+    out.write_all(&bytes)?;
+    out.into_inner()?.sync_all()?;
+    Ok(pages)
+}
+
+/// The file holding `trees` as of `checkpoint`, and how much its pages
+/// were compressed.
+fn encode(checkpoint: u64, trees: &BTreeMap<String, Tree>, cipher: Option<&Cipher>, compression: Compression) -> (Vec<u8>, Ratio) {
+    let mut out = MAGIC.to_vec();
+    out.extend(FORMAT_VERSION.to_le_bytes());
+    out.extend(checkpoint.to_le_bytes());
+    let mut body = vec![];
+    body.extend((trees.len() as u32).to_le_bytes());
+    for (name, pairs) in trees {
+        put_bytes(&mut body, name.as_bytes());
+        body.extend((pairs.len() as u64).to_le_bytes());
+        for (key, value) in pairs {
+            put_bytes(&mut body, key);
+            put_bytes(&mut body, value);
+        }
+    }
+    out.push(if cipher.is_some() { CHACHA20_POLY1305 } else { PLAIN });
+    out.push(compression.tag());
+    if let Some(cipher) = cipher {
+        out.extend(cipher.id());
+    }
+    let pages: Vec<&[u8]> = body.chunks(PAGE).collect();
+    let mut ratio = Ratio::default();
+    out.extend((pages.len() as u32).to_le_bytes());
+    for (n, page) in pages.iter().enumerate() {
+        let compressed = compression.compress(page);
+        let stored = match cipher {
+            Some(cipher) => cipher.seal(&page_aad(checkpoint, n, pages.len()), &compressed),
+            None => compressed,
+        };
+        ratio.add(page.len(), stored.len());
+        put_bytes(&mut out, &stored);
+    }
+    out.extend(crc32fast::hash(&out).to_le_bytes());
+    (out, ratio)
+}
+
+/// Binds a page to its checkpoint and place, so pages cannot be swapped,
+/// dropped or carried over from another checkpoint.
+fn page_aad(checkpoint: u64, page: usize, pages: usize) -> Vec<u8> {
+    [checkpoint.to_le_bytes().as_slice(), &(page as u32).to_le_bytes(), &(pages as u32).to_le_bytes()].concat()
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            bail!("Unexpected end of file");
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// The log sequence number the checkpoint in the file at `path` is as of,
+/// read from its header alone.
+pub fn checkpoint_of(path: &Path) -> Result<u64> {
+    let mut header = [0; MAGIC.len() + 12];
+    File::open(path)?.read_exact(&mut header)?;
+    if !header.starts_with(MAGIC) {
+        bail!("{} is not a FunkDB file", path.display());
+    }
+    Ok(u64::from_le_bytes(header[MAGIC.len() + 4..].try_into()?))
+}
+
+/// What [`decode`] read from a file.
+#[derive(Debug)]
+struct Checkpoint {
+    lsn: u64,
+    trees: BTreeMap<String, Tree>,
+    compression: Compression,
+    pages: Ratio,
+}
+
+/// The checkpoint in `bytes`, opened with `cipher`. The checksum comes
+/// first, so damage is told from a wrong key.
+fn decode(bytes: &[u8], cipher: Option<&Cipher>) -> Result<Checkpoint> {
+    if !bytes.starts_with(MAGIC) {
+        bail!("Not a FunkDB file");
+    }
+    let Some((body, crc)) = bytes.split_last_chunk::<4>() else {
+        bail!("Unexpected end of file");
+    };
+    if crc32fast::hash(body) != u32::from_le_bytes(*crc) {
+        bail!("Checksum mismatch, the file is corrupt");
+    }
+    let mut reader = Reader { bytes: &body[MAGIC.len()..] };
+    let version = reader.u32()?;
+    if version != FORMAT_VERSION {
+        bail!("Unsupported file format version {version}");
+    }
+    let checkpoint = reader.u64()?;
+    let encryption = reader.u8()?;
+    let compression = Compression::from_tag(reader.u8()?)?;
+    match (encryption, cipher) {
+        (PLAIN, None) => {}
+        (PLAIN, Some(_)) => bail!("The file is not encrypted; open it without a key, then encrypt it with `FunkDb::rekey`"),
+        (CHACHA20_POLY1305, None) => bail!("The file is encrypted, open it with its key"),
+        (CHACHA20_POLY1305, Some(cipher)) => {
+            if reader.take(8)? != cipher.id() {
+                bail!("The file is encrypted with another key");
+            }
+        }
+        (other, _) => bail!("Unknown encryption {other}"),
+    }
+    let mut pages = Ratio::default();
+    let count = reader.u32()? as usize;
+    let mut opened = vec![];
+    for n in 0..count {
+        let stored = reader.bytes()?;
+        let page = match cipher {
+            Some(cipher) => cipher
+                .open(&page_aad(checkpoint, n, count), &stored)
+                .with_context(|| format!("Page {n} fails authentication, the file is damaged"))?,
+            None => stored.clone(),
+        };
+        let page = compression.decompress(&page).with_context(|| format!("Page {n}"))?;
+        pages.add(page.len(), stored.len());
+        opened.extend(page);
+    }
+    let mut reader = Reader { bytes: &opened };
+    let mut trees = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let name = String::from_utf8(reader.bytes()?)?;
+        let mut pairs = Tree::new();
+        for _ in 0..reader.u64()? {
+            let key = reader.bytes()?;
+            pairs.insert(key, reader.bytes()?);
+        }
+        trees.insert(name, pairs);
+    }
+    Ok(Checkpoint { lsn: checkpoint, trees, compression, pages })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{remove_scratch, scratch_path};
+
+    fn put(store: &mut MemoryStore, key: &str, value: &str) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.put("t", key.as_bytes().to_vec(), value.as_bytes().to_vec());
+        store.apply(batch)
+    }
+
+    fn contents(store: &MemoryStore) -> Result<Vec<(String, String)>> {
+        let pairs = store.scan("t", (std::ops::Bound::Unbounded, std::ops::Bound::Unbounded))?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap()))
+            .collect())
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn commits_survive_a_crash() -> Result<()> {
+        let path = scratch_path("crash.funk");
+        let mut store = MemoryStore::open(&path, None, None)?;
+        put(&mut store, "a", "1")?;
+        store.flush()?;
+        put(&mut store, "b", "2")?;
+        put(&mut store, "a", "3")?;
+        // Dropping without a flush is as good as pulling the plug: only the
+        // log has the last two commits.
+        drop(store);
+        let store = MemoryStore::open(&path, None, None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "3"), ("b", "2")]));
+        assert_eq!(store.wal().unwrap().last_lsn(), 3);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_commits_are_rolled_back() -> Result<()> {
+        let path = scratch_path("torn.funk");
+        let mut store = MemoryStore::open(&path, None, None)?;
+        put(&mut store, "a", "1")?;
+        store.wal().unwrap().tear_next_write(12);
+        let mut batch = Batch::new();
+        batch.put("t", b"b".to_vec(), b"2".to_vec());
+        batch.delete("t", b"a".to_vec());
+        assert!(store.apply(batch).is_err());
+        assert_eq!(contents(&store)?, pairs(&[("a", "1")]));
+        drop(store);
+        let store = MemoryStore::open(&path, None, None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "1")]));
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_checkpoints() -> Result<()> {
+        let path = scratch_path("checkpoint.funk");
+        let wal_path = wal::path_for(&path);
+        let mut store = MemoryStore::open(&path, None, None)?;
+        put(&mut store, "a", "1")?;
+        put(&mut store, "b", "2")?;
+        let log = fs::read(&wal_path)?;
+        store.flush()?;
+        assert!(!wal_path.exists());
+        drop(store);
+
+        // Crashing halfway through writing a checkpoint leaves the scratch
+        // file behind, and the previous snapshot in place.
+        let mut scratch = path.clone().into_os_string();
+        scratch.push(".tmp");
+        fs::write(&scratch, b"FUNKDB\0\0garbage")?;
+        // Crashing after the rename but before the log is dropped leaves
+        // records the snapshot already holds.
+        fs::write(&wal_path, &log)?;
+        let mut store = MemoryStore::open(&path, None, None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "1"), ("b", "2")]));
+        put(&mut store, "a", "3")?;
+        assert_eq!(store.wal().unwrap().last_lsn(), 3);
+        drop(store);
+        let store = MemoryStore::open(&path, None, None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "3"), ("b", "2")]));
+        remove_scratch(&path);
+        let _ = fs::remove_file(scratch);
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites_catch_up_with_commits_made_meanwhile() -> Result<()> {
+        let path = scratch_path("rewrite.funk");
+        let wal_path = wal::path_for(&path);
+        let mut store = MemoryStore::open(&path, None, None)?;
+        put(&mut store, "a", "1")?;
+        let mut rewrite = store.begin_compact()?.unwrap();
+        rewrite.build()?;
+        put(&mut store, "b", "2")?;
+        assert!(store.finish_rewrite(rewrite)?);
+        assert!(!rewrite_path(&path).exists());
+        // The log is down to the commit the rewrite missed.
+        let lsn = checkpoint_of(&path)?;
+        assert_eq!(wal::read(&wal_path, None, 0)?.iter().map(|(lsn, _)| *lsn).collect::<Vec<_>>(), [lsn + 1]);
+        put(&mut store, "c", "3")?;
+        drop(store);
+        let mut store = MemoryStore::open(&path, None, None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+        // A checkpoint meanwhile may have dropped what the rewrite missed.
+        let mut rewrite = store.begin_compact()?.unwrap();
+        rewrite.build()?;
+        put(&mut store, "d", "4")?;
+        store.flush()?;
+        assert!(!store.finish_rewrite(rewrite)?);
+        assert!(!rewrite_path(&path).exists());
+
+        // Crashing with the missed commits written out for the new file:
+        // they go if the file never made it in place, and take over the
+        // log if it did.
+        for renamed in [false, true] {
+            let mut rewrite = store.begin_compact()?.unwrap();
+            rewrite.build()?;
+            put(&mut store, "e", if renamed { "6" } else { "5" })?;
+            let built = checkpoint_of(&rewrite_path(&path))?;
+            wal::write(&wal::next_path_for(&wal_path), None, &wal::read(&wal_path, None, built)?)?;
+            if renamed {
+                fs::rename(rewrite_path(&path), &path)?;
+            }
+            drop((rewrite, store));
+            store = MemoryStore::open(&path, None, None)?;
+            assert!(!wal::next_path_for(&wal_path).exists());
+            let e = if renamed { "6" } else { "5" };
+            assert_eq!(contents(&store)?, pairs(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", e)]));
+        }
+        drop(store);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn rekeys_without_holding_the_store() -> Result<()> {
+        let path = scratch_path("rekeyed.funk");
+        let wal_path = wal::path_for(&path);
+        let (key, other) = (Key::generate(), Key::generate());
+        let mut store = MemoryStore::open(&path, Some(&key), None)?;
+        put(&mut store, "a", "1")?;
+        let mut rewrite = store.begin_rekey(Some(&other))?.unwrap();
+        rewrite.build()?;
+        put(&mut store, "b", "2")?;
+        assert!(store.finish_rewrite(rewrite)?);
+        assert!(store.is_encrypted());
+        put(&mut store, "c", "3")?;
+        drop(store);
+        let mut store = MemoryStore::open(&path, Some(&other), None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+        // Crashing with the file in place but not the log: the commits the
+        // rewrite missed, sealed with the new key, take over the log.
+        let mut rewrite = store.begin_rekey(Some(&key))?.unwrap();
+        rewrite.build()?;
+        put(&mut store, "d", "4")?;
+        let built = checkpoint_of(&rewrite_path(&path))?;
+        let missed = wal::read(&wal_path, Some(&Cipher::new(&other)), built)?;
+        wal::write(&wal::next_path_for(&wal_path), Some(&Cipher::new(&key)), &missed)?;
+        fs::rename(rewrite_path(&path), &path)?;
+        drop((rewrite, store));
+        let store = MemoryStore::open(&path, Some(&key), None)?;
+        assert!(!wal::next_path_for(&wal_path).exists());
+        assert_eq!(contents(&store)?, pairs(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]));
+        drop(store);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn encrypts_and_rekeys() -> Result<()> {
+        let path = scratch_path("crypt.funk");
+        let wal_path = wal::path_for(&path);
+        let (key, other) = (Key::generate(), Key::generate());
+        let mut store = MemoryStore::open(&path, Some(&key), None)?.checkpoint_after(u64::MAX);
+        put(&mut store, "a", "secret checkpointed")?;
+        store.flush()?;
+        put(&mut store, "b", "secret logged")?;
+        for file in [&path, &wal_path] {
+            let bytes = fs::read(file)?;
+            assert!(!bytes.windows(6).any(|window| window == b"secret"), "{} is in the clear", file.display());
+        }
+        drop(store);
+
+        let refused = |key: Option<&Key>| format!("{:#}", MemoryStore::open(&path, key, None).unwrap_err());
+        assert!(refused(None).contains("open it with its key"));
+        assert!(refused(Some(&other)).contains("another key"));
+        let mut store = MemoryStore::open(&path, Some(&key), None)?;
+        assert_eq!(contents(&store)?, pairs(&[("a", "secret checkpointed"), ("b", "secret logged")]));
+
+        // Crashing after the checkpoint under the new key but before the
+        // log is dropped leaves records under the old key behind.
+        let log = fs::read(&wal_path)?;
+        store.rekey(Some(&other))?;
+        drop(store);
+        fs::write(&wal_path, &log)?;
+        let mut store = MemoryStore::open_read_only(&path, Some(&other))?;
+        assert_eq!(contents(&store)?.len(), 2);
+        drop(store);
+        store = MemoryStore::open(&path, Some(&other), None)?;
+        put(&mut store, "c", "3")?;
+        store.rekey(None)?;
+        drop(store);
+        assert!(refused(Some(&other)).contains("not encrypted"));
+        assert_eq!(contents(&MemoryStore::open(&path, None, None)?)?.len(), 3);
+
+        // Damage to a sealed page is caught by the checksum first, and by
+        // the tag should the checksum be forged as well.
+        let store = MemoryStore::open(&path, None, None)?;
+        let checkpoint = checkpoint_of(&path)?;
+        let (mut bytes, _) = encode(checkpoint, &store.trees, Some(&Cipher::new(&key)), Compression::None);
+        let end = bytes.len() - 4;
+        bytes[end - 1] ^= 1;
+        let crc = crc32fast::hash(&bytes[..end]).to_le_bytes();
+        bytes[end..].copy_from_slice(&crc);
+        let err = decode(&bytes, Some(&Cipher::new(&key))).unwrap_err();
+        assert!(format!("{err:#}").contains("Page 0 fails authentication"), "{err:#}");
+        drop(store);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_pages() -> Result<()> {
+        let path = scratch_path("compressed.funk");
+        let key = Key::generate();
+        let mut store = MemoryStore::open(&path, Some(&key), Some(Compression::Zstd))?;
+        for n in 0..100 {
+            put(&mut store, &n.to_string(), &"funk ".repeat(1000))?;
+        }
+        store.flush()?;
+        let (compression, pages) = store.page_compression();
+        assert_eq!(compression, Compression::Zstd);
+        assert!(pages.raw > 2 * PAGE as u64 && pages.ratio() > 10.0, "{pages}");
+        assert!(fs::metadata(&path)?.len() < pages.raw / 10);
+        drop(store);
+        let store = MemoryStore::open(&path, Some(&key), None)?;
+        assert_eq!(store.page_compression(), (Compression::Zstd, pages));
+        assert_eq!(contents(&store)?.len(), 100);
+        drop(store);
+        let store = MemoryStore::open(&path, Some(&key), Some(Compression::None))?;
+        assert_eq!(store.page_compression().0, Compression::None);
+        assert!(fs::metadata(&path)?.len() > pages.raw);
+        drop(store);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_refused() -> Result<()> {
+        let path = scratch_path("corrupt.funk");
+        let mut store = MemoryStore::open(&path, None, None)?.checkpoint_after(64);
+        for n in 0..10 {
+            put(&mut store, &n.to_string(), "value")?;
+        }
+        // The log was checkpointed along the way.
+        assert!(store.wal().unwrap().size() <= 64);
+        drop(store);
+        let mut bytes = fs::read(&path)?;
+        let last = bytes.len() - 6;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes)?;
+        let err = MemoryStore::open(&path, None, None).unwrap_err();
+        assert!(format!("{err:#}").contains("Checksum mismatch"));
+        remove_scratch(&path);
+        Ok(())
+    }
+}
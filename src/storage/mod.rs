@@ -0,0 +1,477 @@
+//! Where the bytes go.
+//!
+//! Everything FunkDB persists, objects and the schema catalog alike, is
+//! written as key/value pairs into named trees of a [`StorageEngine`].
+//! Keys within a tree are ordered bytewise, so range scans are the one
+//! primitive every higher layer builds on.
+//!
+//! Which engine a database uses is picked when it is opened, either by
+//! the URL scheme of its location or through [`FunkDbOptions`](crate::FunkDbOptions):
+//!
+//! | location              | backend            |
+//! |-----------------------|--------------------|
+//! | `mem://`              | [`MemoryStore`]    |
+//! | `path/to/db.funk`     | [`MemoryStore`] checkpointed to the file, with a [`wal`] |
+//! | `sled://path/to/dir`  | [`SledStore`]      |
+//! | `sqlite://path/to.db` | [`SqliteStore`]    |
+//!
+//! The SQLite backend also keeps relational tables per type, see
+//! [`relational`]. Whatever the engine, reads and writes go through the
+//! snapshot-isolated transactions of [`mvcc`].
+use crate::Interner;
+use anyhow::{bail, Result};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use strum::{Display, EnumIter, EnumString};
+
+pub mod compress;
+pub mod crypt;
+pub mod history;
+pub mod lock;
+mod memory;
+pub mod mvcc;
+pub mod relational;
+mod sled_store;
+mod sqlite_store;
+pub mod wal;
+
+pub use compress::{Compression, Ratio};
+pub use crypt::{Cipher, Key};
+pub use history::{AsOf, TICKS, VERSIONS};
+pub use lock::{Access, FileLock};
+pub use memory::{checkpoint_of, MemoryStore};
+pub use mvcc::{decode_clock, is_retryable, Conflict, Isolation, Mvcc, Transaction, CLOCK};
+pub use sled_store::SledStore;
+pub use sqlite_store::SqliteStore;
+pub use wal::Pending;
+
+/// The tree holding the schema catalog.
+pub const CATALOG: &str = "__catalog__";
+
+/// The SDL source of the schema committed as of `snapshot`, if any.
+pub fn catalog(snapshot: &(impl Snapshot + ?Sized)) -> Result<Option<String>> {
+    match snapshot.get(CATALOG, b"schema")? {
+        Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter)]
+pub enum Backend {
+    #[strum(serialize = "mem")]
+    Memory,
+    #[strum(serialize = "file")]
+    File,
+    #[strum(serialize = "sled")]
+    Sled,
+    #[strum(serialize = "sqlite")]
+    Sqlite,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Put {
+        tree: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        tree: String,
+        key: Vec<u8>,
+    },
+}
+
+impl Op {
+    pub fn tree(&self) -> &str {
+        match self {
+            Op::Put { tree, .. } | Op::Delete { tree, .. } => tree,
+        }
+    }
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Put { key, .. } | Op::Delete { key, .. } => key,
+        }
+    }
+}
+
+/// Writes that are applied all together or not at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn put(&mut self, tree: &str, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(Op::Put {
+            tree: tree.to_string(),
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+    pub fn delete(&mut self, tree: &str, key: impl Into<Vec<u8>>) {
+        self.ops.push(Op::Delete {
+            tree: tree.to_string(),
+            key: key.into(),
+        });
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+}
+
+impl IntoIterator for Batch {
+    type Item = Op;
+    type IntoIter = std::vec::IntoIter<Op>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+pub type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+/// A rewrite of what an engine keeps on disk, built without holding the
+/// engine so that readers and writers carry on meanwhile. The engine
+/// starts it, see [`StorageEngine::begin_compact`], [`Rewrite::build`]
+/// does the bulk of the work from a snapshot, and the engine takes the
+/// result in, catching up with what was committed since, see
+/// [`StorageEngine::finish_rewrite`]. Dropping a rewrite unfinished
+/// cleans up after it.
+pub trait Rewrite: Any + Send {
+    fn build(&mut self) -> Result<()>;
+}
+
+pub trait StorageEngine: Send {
+    fn backend(&self) -> Backend;
+
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Every pair of `tree` whose key falls in `range`, in key order.
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Applies every write of `batch` atomically.
+    fn apply(&mut self, batch: Batch) -> Result<()>;
+
+    /// Applies `batch` like [`StorageEngine::apply`], but may leave the
+    /// wait for it to reach disk to the caller, who does that without
+    /// holding the engine, so that readers go on and commits share syncs.
+    fn apply_deferred(&mut self, batch: Batch) -> Result<Option<Pending>> {
+        self.apply(batch)?;
+        Ok(None)
+    }
+
+    /// The names of all trees holding at least one key.
+    fn trees(&self) -> Result<Vec<String>>;
+
+    /// Makes everything applied so far durable.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Rewrites what the engine keeps on disk to hold only live data, as
+    /// far as it can. Durable afterwards, like [`flush`](Self::flush).
+    fn compact(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Starts compacting without holding the engine, see [`Rewrite`];
+    /// `None` if the engine only compacts held.
+    fn begin_compact(&mut self) -> Result<Option<Box<dyn Rewrite>>> {
+        Ok(None)
+    }
+
+    /// Puts a built `rewrite` of this engine in place. `false` if the
+    /// engine moved on in a way the rewrite cannot catch up with, so that
+    /// it has to start over.
+    fn finish_rewrite(&mut self, _rewrite: Box<dyn Rewrite>) -> Result<bool> {
+        bail!("The {} backend does not rewrite its files", self.backend())
+    }
+
+    /// Checks what the engine keeps on disk against its checksums, and
+    /// returns what is wrong with it. Engines that check on every read, as
+    /// sled does, have nothing to add.
+    fn verify(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// How many bytes the engine takes up on disk, 0 in memory.
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Re-encrypts everything on disk with `key`, or decrypts it given
+    /// none. Durable afterwards, like [`flush`](Self::flush).
+    fn rekey(&mut self, _key: Option<&Key>) -> Result<()> {
+        bail!("Only .funk files can be encrypted")
+    }
+
+    /// Starts rekeying without holding the engine, see [`Rewrite`];
+    /// `None` if the engine only rekeys held.
+    fn begin_rekey(&mut self, _key: Option<&Key>) -> Result<Option<Box<dyn Rewrite>>> {
+        Ok(None)
+    }
+
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    /// How the engine compresses its pages, and by how much, as of the
+    /// last time it wrote them.
+    fn page_compression(&self) -> (Compression, Ratio) {
+        (Compression::None, Ratio::default())
+    }
+
+    /// Called with a new schema before it replaces the catalog, for
+    /// backends that lay their data out by type.
+    fn migrate(&mut self, _schema: &Interner) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match prefix_end(prefix) {
+            Some(end) => self.scan(tree, (Bound::Included(prefix), Bound::Excluded(&end))),
+            None => self.scan(tree, (Bound::Included(prefix), Bound::Unbounded)),
+        }
+    }
+}
+
+/// Something to read consistent keys from: an engine or a transaction.
+pub trait Snapshot {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// The time reads are as of, in microseconds since the epoch, or
+    /// `None` for now.
+    fn time(&self) -> Option<i64> {
+        None
+    }
+}
+
+impl<T: StorageEngine + ?Sized> Snapshot for T {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        StorageEngine::get(self, tree, key)
+    }
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        StorageEngine::scan(self, tree, range)
+    }
+}
+
+impl Snapshot for Transaction<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Transaction::get(self, tree, key)
+    }
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Transaction::scan(self, tree, range)
+    }
+}
+
+/// The smallest key greater than every key starting with `prefix`, if
+/// there is one.
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+pub(crate) fn in_range(key: &[u8], range: &KeyRange) -> bool {
+    let after_start = match range.0 {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.1 {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Splits `location` into the backend it asks for and the path that
+/// backend should use.
+pub fn parse_location(location: &str) -> Result<(Backend, PathBuf)> {
+    let Some((scheme, rest)) = location.split_once("://") else {
+        return Ok((Backend::File, PathBuf::from(location)));
+    };
+    let backend = match scheme.parse::<Backend>() {
+        Ok(backend) if backend != Backend::File => backend,
+        _ => bail!("Unknown storage scheme `{scheme}://`, expected one of: mem://, sled://, sqlite://"),
+    };
+    if backend == Backend::Memory && !rest.is_empty() {
+        bail!("`mem://` databases have no path, found `{rest}`");
+    }
+    Ok((backend, PathBuf::from(rest)))
+}
+
+/// Opens the engine for `location`. An explicit `backend` wins over a
+/// plain path, but must agree with a URL scheme.
+///
+/// Read-only engines refuse writes. sled has no such mode, so there it is
+/// up to [`Mvcc`] to refuse them. Only `.funk` files take a `key`, or a
+/// `compression` for their pages.
+pub fn open(
+    location: &str,
+    backend: Option<Backend>,
+    access: Access,
+    key: Option<&Key>,
+    compression: Option<Compression>,
+) -> Result<(PathBuf, Box<dyn StorageEngine>)> {
+    let (parsed, path) = parse_location(location)?;
+    let has_scheme = location.contains("://");
+    let backend = match backend {
+        Some(backend) if has_scheme && backend != parsed => {
+            bail!("`{location}` asks for the {parsed} backend, but {backend} was requested")
+        }
+        Some(backend) => backend,
+        None => parsed,
+    };
+    if key.is_some() && backend != Backend::File {
+        bail!("Only .funk files can be encrypted, not {backend} databases");
+    }
+    if compression.is_some() && backend != Backend::File {
+        bail!("Only .funk files compress their pages, not {backend} databases; compress types in the schema instead");
+    }
+    let store: Box<dyn StorageEngine> = match (backend, access) {
+        (Backend::Memory, _) => Box::new(MemoryStore::new()),
+        (Backend::File, Access::ReadWrite) => Box::new(MemoryStore::open(&path, key, compression)?),
+        (Backend::File, Access::ReadOnly) => Box::new(MemoryStore::open_read_only(&path, key)?),
+        (Backend::Sled, _) => Box::new(SledStore::open(&path)?),
+        (Backend::Sqlite, Access::ReadWrite) => Box::new(SqliteStore::open(&path)?),
+        (Backend::Sqlite, Access::ReadOnly) => Box::new(SqliteStore::open_read_only(&path)?),
+    };
+    Ok((path, store))
+}
+
+#[cfg(test)]
+pub(crate) fn scratch_path(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("funk-{}-{n}-{name}", std::process::id()))
+}
+
+/// Removes a scratch database and whatever lies beside it: its log, the
+/// log a rewrite left, its lock, and SQLite's own journal.
+#[cfg(test)]
+pub(crate) fn remove_scratch(path: &std::path::Path) {
+    let log = wal::path_for(path);
+    let mut shm = path.as_os_str().to_owned();
+    shm.push("-shm");
+    for path in [path.to_path_buf(), wal::next_path_for(&log), log, lock::path_for(path), PathBuf::from(shm)] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn exercise(mut store: Box<dyn StorageEngine>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.put("people", b"b".to_vec(), b"2".to_vec());
+        batch.put("people", b"a".to_vec(), b"1".to_vec());
+        batch.put("people", b"c".to_vec(), b"3".to_vec());
+        batch.put("pets", b"a".to_vec(), b"dog".to_vec());
+        store.apply(batch)?;
+
+        assert_eq!(store.get("people", b"a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("pets", b"b")?, None);
+        assert_eq!(store.get("nobody", b"a")?, None);
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| pairs.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(
+            keys(store.scan("people", (Bound::Excluded(b"a"), Bound::Unbounded))?),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(keys(store.scan_prefix("people", b"c")?), vec![b"c".to_vec()]);
+        assert_eq!(store.trees()?, vec!["people".to_string(), "pets".to_string()]);
+
+        let db = Mvcc::new(store)?;
+        let mut txn = db.begin(Isolation::Snapshot);
+        txn.delete("people", b"a".to_vec());
+        txn.put("people", b"d".to_vec(), b"4".to_vec());
+        assert_eq!(txn.get("people", b"a")?, None);
+        assert_eq!(
+            keys(txn.scan("people", (Bound::Unbounded, Bound::Unbounded))?),
+            vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+        txn.rollback();
+        assert_eq!(db.store().get("people", b"a")?, Some(b"1".to_vec()));
+
+        let mut txn = db.begin(Isolation::Snapshot);
+        txn.delete("people", b"a".to_vec());
+        txn.delete("pets", b"a".to_vec());
+        txn.commit()?;
+
+        // Compacting catches up with what was committed while it ran.
+        let mut rewrite = db.store().begin_compact()?;
+        if let Some(rewrite) = &mut rewrite {
+            rewrite.build()?;
+        }
+        let mut txn = db.begin(Isolation::Snapshot);
+        txn.put("people", b"e".to_vec(), b"5".to_vec());
+        txn.commit()?;
+        if let Some(rewrite) = rewrite {
+            assert!(db.store().finish_rewrite(rewrite)?);
+        }
+        db.compact()?;
+        assert_eq!(db.begin(Isolation::Snapshot).get("people", b"e")?, Some(b"5".to_vec()));
+        let mut store = db.into_inner();
+        assert_eq!(store.get("people", b"a")?, None);
+        assert_eq!(store.trees()?, vec![CLOCK.0.to_string(), "people".to_string()]);
+        store.flush()
+    }
+
+    #[test]
+    fn every_backend_behaves_the_same() -> Result<()> {
+        exercise(Box::new(MemoryStore::new()))?;
+        for scheme in ["file", "sled", "sqlite"] {
+            let path = scratch_path(scheme);
+            let location = match scheme {
+                "file" => path.display().to_string(),
+                _ => format!("{scheme}://{}", path.display()),
+            };
+            let (_, store) = open(&location, None, Access::ReadWrite, None, None)?;
+            assert_eq!(store.backend().to_string(), scheme);
+            exercise(store)?;
+
+            let (_, store) = open(&location, None, Access::ReadWrite, None, None)?;
+            assert_eq!(store.get("people", b"c")?, Some(b"3".to_vec()), "{scheme}");
+            drop(store);
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn locations() -> Result<()> {
+        assert_eq!(parse_location("db.funk")?, (Backend::File, PathBuf::from("db.funk")));
+        assert_eq!(parse_location("mem://")?, (Backend::Memory, PathBuf::new()));
+        assert_eq!(parse_location("sled://data/db")?, (Backend::Sled, PathBuf::from("data/db")));
+        assert!(parse_location("postgres://db").is_err());
+        assert!(open("sled://x", Some(Backend::Sqlite), Access::ReadWrite, None, None).is_err());
+        assert_eq!(open("mem://", Some(Backend::Memory), Access::ReadWrite, None, None)?.1.backend(), Backend::Memory);
+        assert!(open("mem://", None, Access::ReadWrite, Some(&Key::generate()), None).is_err());
+        assert_eq!(prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,720 @@
+//! Multi-version concurrency control on top of any [`StorageEngine`].
+//!
+//! The engine always holds the latest committed value of every key. Each
+//! commit is stamped with the next tick of a logical clock, and the values
+//! it overwrote are kept in memory as versions stamped with that tick. A
+//! [`Transaction`] reads as of the tick it began at: whatever was committed
+//! after that is looked through, back to the version it replaced. So each
+//! transaction sees one consistent snapshot of objects and schema alike,
+//! and readers hold the engine only for the length of one `get` or `scan`,
+//! never for the length of a transaction.
+//!
+//! Commits are validated optimistically. Under [`Isolation::Snapshot`] a
+//! transaction fails if a key it writes was committed by someone else
+//! since it began (first committer wins). [`Isolation::Serializable`] also
+//! fails it if anything it read, point or range, was written since. Either
+//! failure is a [`Conflict`], which is safe to retry from the start.
+//!
+//! Versions are dropped as soon as no open transaction began before the
+//! commit that replaced them.
+//!
+//! A commit holds the engine only to validate and to apply its batch. It
+//! waits for the log to reach disk after letting go, see
+//! [`StorageEngine::apply_deferred`], so readers never wait on a sync and
+//! the commits behind it can join the next one. Until the sync is through
+//! the clock does not show the commit, so no transaction sees its writes.
+//!
+//! The clock itself is stored with every commit, under [`CLOCK`], so ticks
+//! keep counting up across restarts and can order things for good, like
+//! the keys [`Transaction::append`] makes. The commit's time goes along
+//! with it, never earlier than the last one's.
+//!
+//! Given a retention window, commits also keep what they replaced on disk
+//! for that long, so the database can be read as of any commit since, see
+//! [`history`](super::history). The window is stored, and holds until it
+//! is set again.
+use super::history::{self, AsOf};
+use super::{in_range, Batch, Key, KeyRange, Rewrite, StorageEngine};
+use crate::expiry;
+use anyhow::{bail, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The tree and key holding the tick of the last commit and its time,
+/// see [`decode_clock`].
+pub const CLOCK: (&str, &[u8]) = ("__mvcc__", b"clock");
+
+/// The tick and time, in microseconds since the epoch, a [`CLOCK`] value
+/// holds.
+pub fn decode_clock(bytes: &[u8]) -> Result<(u64, i64)> {
+    if bytes.len() != 16 {
+        bail!("A clock has 16 bytes, not {}", bytes.len());
+    }
+    Ok((u64::from_be_bytes(bytes[..8].try_into()?), i64::from_be_bytes(bytes[8..].try_into()?)))
+}
+
+type History = BTreeMap<String, BTreeMap<Vec<u8>, BTreeMap<u64, Option<Vec<u8>>>>>;
+
+/// A tree and the range of it a transaction read.
+type Read = (String, Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Isolation {
+    /// Reads see the snapshot the transaction began at, and concurrent
+    /// writes to the same key conflict.
+    #[default]
+    Snapshot,
+    /// Like [`Isolation::Snapshot`], but reads conflict with concurrent
+    /// writes too, so committed transactions behave as if run one by one.
+    Serializable,
+}
+
+/// A commit lost a race with another one. Nothing was written, and running
+/// the transaction again may well succeed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub tree: String,
+    pub write: bool,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.write {
+            true => "wrote",
+            false => "read",
+        };
+        write!(
+            f,
+            "Transaction {what} `{}` while a concurrent transaction committed to it; retry the transaction",
+            self.tree
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// Whether `err` is a [`Conflict`], so the transaction can be retried.
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Conflict>().is_some()
+}
+
+#[derive(Default)]
+struct State {
+    /// The tick of the last durable commit, which transactions begin at.
+    clock: u64,
+    /// The tick of the last commit applied to the engine, ahead of `clock`
+    /// while commits wait for their sync.
+    applied: u64,
+    /// The time of the last applied commit.
+    time: i64,
+    /// How many open transactions began at each tick.
+    active: BTreeMap<u64, usize>,
+    /// The values commits replaced, by tree, key and the commit's tick.
+    history: History,
+    /// The oldest tick the history on disk reaches back to and its time,
+    /// if history is kept.
+    horizon: Option<(u64, i64)>,
+}
+
+/// When history kept for `window` starts, for a commit at `time`.
+fn cutoff(time: i64, window: Duration) -> i64 {
+    time.saturating_sub(window.as_micros().try_into().unwrap_or(i64::MAX))
+}
+
+/// A [`StorageEngine`] shared by concurrent [`Transaction`]s.
+pub struct Mvcc {
+    store: Mutex<Box<dyn StorageEngine>>,
+    state: Mutex<State>,
+    // Held for the length of a rewrite, which gets the engine's files to
+    // itself.
+    rewriting: Mutex<()>,
+    ended: Condvar,
+    read_only: bool,
+    retention: Option<Duration>,
+}
+
+impl Mvcc {
+    pub fn new(store: Box<dyn StorageEngine>) -> Result<Self> {
+        let (clock, time) = match store.get(CLOCK.0, CLOCK.1)? {
+            Some(bytes) => decode_clock(&bytes)?,
+            None => (0, 0),
+        };
+        let horizon = match store.get(CLOCK.0, history::HORIZON)? {
+            Some(bytes) => Some(history::decode_horizon(&bytes)?),
+            None => None,
+        };
+        let retention = match store.get(CLOCK.0, history::WINDOW)? {
+            Some(bytes) => Some(history::decode_window(&bytes)?),
+            None => None,
+        };
+        Ok(Self {
+            store: Mutex::new(store),
+            state: Mutex::new(State {
+                clock,
+                applied: clock,
+                time,
+                horizon,
+                ..State::default()
+            }),
+            rewriting: Mutex::new(()),
+            ended: Condvar::new(),
+            read_only: false,
+            retention,
+        })
+    }
+
+    /// Keeps what commits replace for `window` from now on, or stops
+    /// keeping it, until set again: the window is stored, and opening the
+    /// engine again picks it up. The history on disk reaches back to where
+    /// it was last kept without a break: stopping drops what there is,
+    /// since later commits would not add to it.
+    pub fn set_retention(&mut self, window: Option<Duration>) -> Result<()> {
+        if window == self.retention {
+            return Ok(());
+        }
+        self.retention = window;
+        let state = self.state.get_mut().unwrap();
+        let store = self.store.get_mut().unwrap();
+        let mut batch = Batch::new();
+        match window {
+            Some(window) => {
+                if state.horizon.is_none() {
+                    let horizon = (state.clock, expiry::now());
+                    batch.put(CLOCK.0, history::HORIZON.to_vec(), history::encode_horizon(horizon));
+                    state.horizon = Some(horizon);
+                }
+                batch.put(CLOCK.0, history::WINDOW.to_vec(), history::encode_window(window));
+            }
+            None => {
+                history::forget(&**store, &mut batch)?;
+                state.horizon = None;
+            }
+        }
+        store.apply(batch)
+    }
+
+    /// Drops the history that fell out of the retention window, `limit`
+    /// commits' worth at a time, and returns how many commits went. Commits
+    /// drop a few as they go, so this only has much to do after a quiet
+    /// spell.
+    pub fn prune_history(&self, limit: usize) -> Result<u64> {
+        let Some(window) = self.retention.filter(|_| !self.read_only) else {
+            return Ok(0);
+        };
+        let mut pruned = 0;
+        loop {
+            let mut store = self.store();
+            let (tick, time, horizon) = {
+                let state = self.state.lock().unwrap();
+                (state.applied + 1, expiry::now().max(state.time), state.horizon)
+            };
+            let Some(mut horizon) = horizon else {
+                return Ok(pruned);
+            };
+            let from = horizon.0;
+            let mut batch = Batch::new();
+            history::prune(&**store, &mut batch, &mut horizon, tick, cutoff(time, window), limit)?;
+            if horizon.0 == from {
+                return Ok(pruned);
+            }
+            store.apply(batch)?;
+            self.state.lock().unwrap().horizon = Some(horizon);
+            pruned += horizon.0 - from;
+        }
+    }
+
+    /// The oldest tick the database can be read as of, and its time.
+    pub fn horizon(&self) -> Option<(u64, i64)> {
+        self.state.lock().unwrap().horizon
+    }
+
+    /// The database right after the commit at `tick`, see [`AsOf`].
+    pub fn as_of(&self, tick: u64) -> Result<AsOf<'_>> {
+        AsOf::at_tick(self, tick)
+    }
+
+    /// The database at `time`, in microseconds since the epoch.
+    pub fn as_of_time(&self, time: i64) -> Result<AsOf<'_>> {
+        AsOf::at_time(self, time)
+    }
+
+    /// Refuses every commit that writes something from now on.
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Direct access to the engine, bypassing versioning. Holding it
+    /// blocks every reader and writer.
+    pub fn store(&self) -> MutexGuard<'_, Box<dyn StorageEngine>> {
+        self.store.lock().unwrap()
+    }
+
+    /// Compacts the engine, see [`StorageEngine::compact`], holding it only
+    /// to start and to finish where the engine can, see [`Rewrite`].
+    pub fn compact(&self) -> Result<()> {
+        self.rewrite(|store| store.begin_compact(), |store| store.compact())
+    }
+
+    /// Rekeys the engine, see [`StorageEngine::rekey`], holding it only to
+    /// start and to finish where the engine can, see [`Rewrite`].
+    pub fn rekey(&self, key: Option<&Key>) -> Result<()> {
+        self.rewrite(|store| store.begin_rekey(key), |store| store.rekey(key))
+    }
+
+    fn rewrite(
+        &self,
+        begin: impl Fn(&mut dyn StorageEngine) -> Result<Option<Box<dyn Rewrite>>>,
+        held: impl FnOnce(&mut dyn StorageEngine) -> Result<()>,
+    ) -> Result<()> {
+        let _rewriting = self.rewriting.lock().unwrap();
+        loop {
+            let mut store = self.store();
+            let Some(mut rewrite) = begin(&mut **store)? else {
+                return held(&mut **store);
+            };
+            drop(store);
+            rewrite.build()?;
+            if self.store().finish_rewrite(rewrite)? {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> Box<dyn StorageEngine> {
+        self.store.into_inner().unwrap()
+    }
+
+    pub fn begin(&self, isolation: Isolation) -> Transaction<'_> {
+        let mut state = self.state.lock().unwrap();
+        let start = state.clock;
+        *state.active.entry(start).or_default() += 1;
+        Transaction {
+            db: self,
+            start,
+            isolation,
+            reads: RefCell::default(),
+            writes: BTreeMap::new(),
+            appends: vec![],
+        }
+    }
+
+    /// The tick of the last durable commit.
+    pub fn clock(&self) -> u64 {
+        self.state.lock().unwrap().clock
+    }
+
+    /// Blocks until every open transaction began at `tick` or later. Must
+    /// not be called with a transaction open on this thread.
+    pub fn wait_for_older(&self, tick: u64) {
+        let mut state = self.state.lock().unwrap();
+        while state.active.keys().next().is_some_and(|start| *start < tick) {
+            state = self.ended.wait(state).unwrap();
+        }
+    }
+
+    /// How many replaced values are still kept for open transactions.
+    pub fn versions(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.history.values().flat_map(BTreeMap::values).map(BTreeMap::len).sum()
+    }
+
+    fn get(&self, start: u64, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let store = self.store();
+        let state = self.state.lock().unwrap();
+        let replaced = state.history.get(tree).and_then(|keys| keys.get(key));
+        match replaced.and_then(|versions| versions.range(start + 1..).next()) {
+            Some((_, value)) => Ok(value.clone()),
+            None => store.get(tree, key),
+        }
+    }
+
+    fn scan(&self, start: u64, tree: &str, range: KeyRange) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let store = self.store();
+        let mut pairs: BTreeMap<_, _> = store.scan(tree, range)?.into_iter().collect();
+        let state = self.state.lock().unwrap();
+        let Some(keys) = state.history.get(tree) else {
+            return Ok(pairs);
+        };
+        for (key, versions) in keys.range::<[u8], _>(range).filter(|(key, _)| in_range(key, &range)) {
+            match versions.range(start + 1..).next() {
+                Some((_, Some(value))) => pairs.insert(key.clone(), value.clone()),
+                Some((_, None)) => pairs.remove(key),
+                None => None,
+            };
+        }
+        Ok(pairs)
+    }
+
+    fn commit(&self, txn: &Transaction, mut batch: Batch, appends: Vec<(String, Vec<u8>)>) -> Result<()> {
+        if self.read_only {
+            bail!("Cannot write to a database opened read-only");
+        }
+        let mut store = self.store();
+        // Holding the engine keeps every other commit out, so this is the
+        // tick the commit gets.
+        let tick = self.state.lock().unwrap().applied + 1;
+        {
+            let state = self.state.lock().unwrap();
+            let written_since = |tree: &str, range: KeyRange| {
+                let Some(keys) = state.history.get(tree) else {
+                    return false;
+                };
+                keys.range::<[u8], _>(range)
+                    .filter(|(key, _)| in_range(key, &range))
+                    .any(|(_, versions)| versions.range(txn.start + 1..).next().is_some())
+            };
+            for op in batch.ops() {
+                let key = op.key();
+                if written_since(op.tree(), (Bound::Included(key), Bound::Included(key))) {
+                    return Err(Conflict {
+                        tree: op.tree().to_string(),
+                        write: true,
+                    }
+                    .into());
+                }
+            }
+            if txn.isolation == Isolation::Serializable {
+                for (tree, start, end) in txn.reads.borrow().iter() {
+                    if written_since(tree, (as_ref(start), as_ref(end))) {
+                        return Err(Conflict {
+                            tree: tree.clone(),
+                            write: false,
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+        // Keys of their own never conflict, so neither do appends.
+        for (n, (tree, value)) in appends.into_iter().enumerate() {
+            let key = [tick.to_be_bytes().as_slice(), &(n as u32).to_be_bytes()].concat();
+            batch.put(&tree, key, value);
+        }
+        let mut replaced = vec![];
+        for op in batch.ops() {
+            replaced.push((op.tree().to_string(), op.key().to_vec(), store.get(op.tree(), op.key())?));
+        }
+        let (time, mut horizon) = {
+            let state = self.state.lock().unwrap();
+            (expiry::now().max(state.time), state.horizon)
+        };
+        // Nothing reads the clock through a snapshot, so it keeps no versions.
+        batch.put(CLOCK.0, CLOCK.1.to_vec(), [tick.to_be_bytes(), time.to_be_bytes()].concat());
+        if let Some(window) = self.retention {
+            history::keep(&mut batch, tick, time, &replaced);
+            let horizon = horizon.get_or_insert((tick - 1, time));
+            history::prune(&**store, &mut batch, horizon, tick, cutoff(time, window), history::PRUNE)?;
+        } else if horizon.take().is_some() {
+            history::forget(&**store, &mut batch)?;
+        }
+        let pending = store.apply_deferred(batch)?;
+        // Only commits touch the history, and the engine is still held, so
+        // no reader can see the new values without their versions.
+        {
+            let mut state = self.state.lock().unwrap();
+            state.applied = tick;
+            state.time = time;
+            state.horizon = horizon;
+            for (tree, key, value) in replaced {
+                state.history.entry(tree).or_default().entry(key).or_default().insert(tick, value);
+            }
+        }
+        drop(store);
+        if let Some(pending) = pending {
+            pending.wait()?;
+        }
+        // The log syncs in order, so every commit before this one is
+        // durable too, whether or not its own thread got here yet.
+        let mut state = self.state.lock().unwrap();
+        state.clock = state.clock.max(tick);
+        Ok(())
+    }
+
+    fn end(&self, start: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.active.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 {
+                state.active.remove(&start);
+            }
+        }
+        collect(&mut state);
+        self.ended.notify_all();
+    }
+}
+
+/// Drops every version no open transaction can see anymore: those replaced
+/// by a commit no later than the oldest transaction's start.
+fn collect(state: &mut State) {
+    let horizon = state.active.keys().next().copied().unwrap_or(state.clock);
+    state.history.retain(|_, keys| {
+        keys.retain(|_, versions| {
+            *versions = versions.split_off(&(horizon + 1));
+            !versions.is_empty()
+        });
+        !keys.is_empty()
+    });
+}
+
+fn as_ref(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn to_owned(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// A unit of work against an [`Mvcc`] engine. Reads see the snapshot the
+/// transaction began at, plus its own writes. Writes are buffered and reach
+/// the engine in one atomic [`Batch`] on [`Transaction::commit`]. Dropping
+/// a transaction without committing it rolls it back.
+pub struct Transaction<'db> {
+    db: &'db Mvcc,
+    start: u64,
+    isolation: Isolation,
+    reads: RefCell<Vec<Read>>,
+    writes: BTreeMap<(String, Vec<u8>), Option<Vec<u8>>>,
+    appends: Vec<(String, Vec<u8>)>,
+}
+
+impl<'db> Transaction<'db> {
+    /// The tick of the last commit this transaction sees.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    fn read(&self, tree: &str, range: KeyRange) {
+        if self.isolation == Isolation::Serializable {
+            let (start, end) = range;
+            self.reads.borrow_mut().push((tree.to_string(), to_owned(start), to_owned(end)));
+        }
+    }
+
+    pub fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(pending) = self.writes.get(&(tree.to_string(), key.to_vec())) {
+            return Ok(pending.clone());
+        }
+        self.read(tree, (Bound::Included(key), Bound::Included(key)));
+        self.db.get(self.start, tree, key)
+    }
+
+    pub fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.read(tree, range);
+        let mut pairs = self.db.scan(self.start, tree, range)?;
+        let pending = self
+            .writes
+            .iter()
+            .filter(|((pending_tree, key), _)| pending_tree == tree && in_range(key, &range));
+        for ((_, key), value) in pending {
+            match value {
+                Some(value) => pairs.insert(key.clone(), value.clone()),
+                None => pairs.remove(key),
+            };
+        }
+        Ok(pairs.into_iter().collect())
+    }
+
+    pub fn put(&mut self, tree: &str, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.insert((tree.to_string(), key.into()), Some(value.into()));
+    }
+
+    pub fn delete(&mut self, tree: &str, key: impl Into<Vec<u8>>) {
+        self.writes.insert((tree.to_string(), key.into()), None);
+    }
+
+    /// Adds `value` to the end of `tree` on commit, keyed by the commit's
+    /// tick and then by the order of the appends in the transaction, both
+    /// big-endian (8 and 4 bytes). The transaction's own reads do not see
+    /// it.
+    pub fn append(&mut self, tree: &str, value: impl Into<Vec<u8>>) {
+        self.appends.push((tree.to_string(), value.into()));
+    }
+
+    /// Applies every write, or fails with a [`Conflict`] and applies none.
+    pub fn commit(mut self) -> Result<()> {
+        let mut batch = Batch::new();
+        for ((tree, key), value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(value) => batch.put(&tree, key, value),
+                None => batch.delete(&tree, key),
+            }
+        }
+        let appends = std::mem::take(&mut self.appends);
+        if batch.is_empty() && appends.is_empty() {
+            return Ok(());
+        }
+        self.db.commit(&self, batch, appends)
+    }
+
+    pub fn rollback(self) {}
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.db.end(self.start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{remove_scratch, scratch_path, MemoryStore};
+    use std::thread;
+    use std::time::Instant;
+
+    fn all(txn: &Transaction) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        txn.scan("t", (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn pair(key: &[u8], value: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (key.to_vec(), value.to_vec())
+    }
+
+    #[test]
+    fn snapshots_stay_put() -> Result<()> {
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        let mut txn = db.begin(Isolation::Snapshot);
+        txn.put("t", b"a".to_vec(), b"1".to_vec());
+        txn.put("t", b"b".to_vec(), b"1".to_vec());
+        txn.commit()?;
+
+        let old = db.begin(Isolation::Snapshot);
+        let mut txn = db.begin(Isolation::Snapshot);
+        txn.put("t", b"a".to_vec(), b"2".to_vec());
+        txn.delete("t", b"b".to_vec());
+        txn.put("t", b"c".to_vec(), b"2".to_vec());
+        txn.commit()?;
+
+        assert_eq!(old.get("t", b"a")?, Some(b"1".to_vec()));
+        assert_eq!(old.get("t", b"c")?, None);
+        assert_eq!(all(&old)?, vec![pair(b"a", b"1"), pair(b"b", b"1")]);
+        let new = db.begin(Isolation::Snapshot);
+        assert_eq!(all(&new)?, vec![pair(b"a", b"2"), pair(b"c", b"2")]);
+        assert_eq!(db.versions(), 3);
+
+        // Versions go once nobody began before the commit replacing them.
+        drop(old);
+        assert_eq!(db.versions(), 0);
+        drop(new);
+        assert_eq!(db.clock(), 2);
+        // The clock carries on where it stopped.
+        assert_eq!(Mvcc::new(db.into_inner())?.clock(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_writes_conflict() -> Result<()> {
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        for isolation in [Isolation::Snapshot, Isolation::Serializable] {
+            let mut first = db.begin(isolation);
+            let mut second = db.begin(isolation);
+            first.put("t", b"a".to_vec(), b"1".to_vec());
+            second.put("t", b"a".to_vec(), b"2".to_vec());
+            first.commit()?;
+            let err = second.commit().unwrap_err();
+            assert!(is_retryable(&err), "{err}");
+            assert_eq!(db.begin(isolation).get("t", b"a")?, Some(b"1".to_vec()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn serializable_rejects_write_skew() -> Result<()> {
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        // Each transaction reads the other's key and writes its own. Under
+        // snapshot isolation both commit, one after the other they could not.
+        for (isolation, allowed) in [(Isolation::Snapshot, true), (Isolation::Serializable, false)] {
+            let mut first = db.begin(isolation);
+            let mut second = db.begin(isolation);
+            assert!(all(&first)?.len() < 2);
+            first.put("t", b"a".to_vec(), b"on".to_vec());
+            second.get("t", b"a")?;
+            second.put("t", b"b".to_vec(), b"on".to_vec());
+            first.commit()?;
+            assert_eq!(second.commit().is_ok(), allowed);
+            let mut reset = db.begin(isolation);
+            reset.delete("t", b"a".to_vec());
+            reset.delete("t", b"b".to_vec());
+            reset.commit()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retried_increments_all_land() -> Result<()> {
+        let db = Mvcc::new(Box::new(MemoryStore::new()))?;
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                    for _ in 0..25 {
+                        loop {
+                            let mut txn = db.begin(Isolation::Serializable);
+                            let count = match txn.get("t", b"count")? {
+                                Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap()),
+                                None => 0,
+                            };
+                            txn.put("t", b"count".to_vec(), (count + 1).to_le_bytes().to_vec());
+                            match txn.commit() {
+                                Err(err) if is_retryable(&err) => continue,
+                                result => break result?,
+                            }
+                        }
+                    }
+                    Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+        })?;
+        let count = db.begin(Isolation::Snapshot).get("t", b"count")?.unwrap();
+        assert_eq!(u64::from_le_bytes(count.try_into().unwrap()), 100);
+        assert_eq!(db.versions(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn readers_do_not_wait_for_syncs() -> Result<()> {
+        let path = scratch_path("mvcc.funk");
+        let store = MemoryStore::open(&path, None, None)?;
+        store.wal().unwrap().slow_syncs_by(Duration::from_millis(300));
+        let db = Mvcc::new(Box::new(store))?;
+        thread::scope(|scope| -> Result<()> {
+            let writer = scope.spawn(|| {
+                let mut txn = db.begin(Isolation::Snapshot);
+                txn.put("t", b"a".to_vec(), b"1".to_vec());
+                txn.commit()
+            });
+            // By now the commit is in the engine but not on disk yet.
+            thread::sleep(Duration::from_millis(50));
+            let started = Instant::now();
+            assert_eq!(db.begin(Isolation::Snapshot).get("t", b"a")?, None);
+            assert!(started.elapsed() < Duration::from_millis(150), "{:?}", started.elapsed());
+            writer.join().unwrap()
+        })?;
+        assert_eq!(db.begin(Isolation::Snapshot).get("t", b"a")?, Some(b"1".to_vec()));
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+}
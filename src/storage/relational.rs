@@ -0,0 +1,490 @@
+//! Materializes the committed schema as ordinary SQLite tables, so a
+//! SQLite-backed database can be read with stock `sqlite3` tooling.
+//!
+//! Every `FunkTy` gets a table named after it, e.g. `"default::FunksGiven"`,
+//! keyed by the object `id`:
+//!
+//! * single properties become columns, `NOT NULL` when required and with a
+//!   `CHECK` for the range of the narrower integer kinds,
+//! * single links become foreign-key columns,
+//! * multi properties go into a side table `"module::Type.property"` of
+//!   `(id, ord, value)` rows,
+//! * multi links go into a junction table `"module::Type.link"` of
+//!   `(source, ord, target)` rows, unique per target.
+//!
+//! SQLite cannot express "at least one row elsewhere", so `required multi`
+//! is left to FunkDB itself. Foreign keys are deferred to the end of the
+//! transaction, since one batch may write an object and what it links to
+//! in either order.
+//!
+//! Rows are written by [`write_object`] and [`delete_object`] in the same
+//! SQLite transaction as the object records themselves.
+//!
+//! The column definitions applied last are recorded in `funk_relational`,
+//! and [`migrate`] diffs the new schema against that record. Added
+//! optional columns and dropped plain columns are `ALTER TABLE`
+//! statements; anything SQLite cannot alter in place rebuilds the table
+//! and copies the rows over, which fails cleanly when existing rows do
+//! not fit the new constraints.
+use crate::object::Object;
+use crate::value::FunkValue;
+use crate::{funkstd, Interner, Named};
+use anyhow::{bail, Context, Result};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use serde_json::json;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub name: String,
+    // `(column, definition)` in declaration order.
+    pub columns: Vec<(String, String)>,
+    pub constraints: Vec<String>,
+}
+
+impl Table {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            columns: vec![],
+            constraints: vec![],
+        }
+    }
+
+    fn column(&mut self, name: &str, definition: String) -> Result<()> {
+        if self.columns.iter().any(|(existing, _)| existing == name) {
+            bail!("`{}` would get the column `{name}` twice", self.name);
+        }
+        self.columns.push((name.to_string(), definition));
+        Ok(())
+    }
+
+    pub fn create_sql(&self) -> String {
+        Self::create_sql_as(self, &self.name)
+    }
+
+    fn create_sql_as(&self, name: &str) -> String {
+        let mut lines: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(column, definition)| format!("{} {definition}", quote(column)))
+            .collect();
+        lines.extend(self.constraints.iter().cloned());
+        format!("CREATE TABLE {} (\n    {}\n)", quote(name), lines.join(",\n    "))
+    }
+
+    fn record(&self) -> String {
+        json!({ "columns": self.columns, "constraints": self.constraints }).to_string()
+    }
+
+    fn from_record(name: String, record: &str) -> Result<Self> {
+        let record: serde_json::Value = serde_json::from_str(record)?;
+        let columns = serde_json::from_value(record["columns"].clone())?;
+        let constraints = serde_json::from_value(record["constraints"].clone())?;
+        Ok(Self {
+            name,
+            columns,
+            constraints,
+        })
+    }
+}
+
+pub fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The SQL type of a `funkstd` kind, with any constraint it needs on
+/// `column`. Integers that do not fit SQLite's 64-bit `INTEGER` are kept
+/// as decimal `TEXT`.
+pub fn column_type(kind: funkstd, column: &str) -> String {
+    let column = quote(column);
+    let between = |min: i64, max: i64| format!("INTEGER CHECK ({column} BETWEEN {min} AND {max})");
+    match kind {
+        funkstd::bool => format!("INTEGER CHECK ({column} IN (0, 1))"),
+        funkstd::str => "TEXT".to_string(),
+        funkstd::int8 => between(i8::MIN.into(), i8::MAX.into()),
+        funkstd::int16 => between(i16::MIN.into(), i16::MAX.into()),
+        funkstd::int32 => between(i32::MIN.into(), i32::MAX.into()),
+        funkstd::uint8 => between(0, u8::MAX.into()),
+        funkstd::uint16 => between(0, u16::MAX.into()),
+        funkstd::uint32 => between(0, u32::MAX.into()),
+        funkstd::int64 => "INTEGER".to_string(),
+        funkstd::uint64 | funkstd::int128 | funkstd::uint128 => "TEXT".to_string(),
+        funkstd::vector => "BLOB".to_string(),
+        funkstd::datetime => "TEXT".to_string(),
+    }
+}
+
+const DEFERRED: &str = "DEFERRABLE INITIALLY DEFERRED";
+
+fn not_null(required: bool) -> &'static str {
+    match required {
+        true => " NOT NULL",
+        false => "",
+    }
+}
+
+/// Every table the committed types of `interner` map to, in a stable order.
+pub fn tables(interner: &Interner) -> Result<Vec<Table>> {
+    let mut tables = vec![];
+    for (module, funk_ty) in interner.types() {
+        let owner = format!("{module}::{}", funk_ty.get_name().unwrap());
+        let mut table = Table::new(owner.clone());
+        table.column("id", "TEXT NOT NULL PRIMARY KEY".to_string())?;
+        for (name, (kind, required, is_multi)) in funk_ty.properties.iter() {
+            if !is_multi {
+                let definition = column_type(*kind, name) + not_null(*required);
+                table.column(name, definition)?;
+                continue;
+            }
+            let mut side = Table::new(format!("{owner}.{name}"));
+            side.column(
+                "id",
+                format!("TEXT NOT NULL REFERENCES {}(id) ON DELETE CASCADE {DEFERRED}", quote(&owner)),
+            )?;
+            side.column("ord", "INTEGER NOT NULL".to_string())?;
+            side.column("value", column_type(*kind, "value") + " NOT NULL")?;
+            side.constraints.push("PRIMARY KEY (id, ord)".to_string());
+            tables.push(side);
+        }
+        for (name, (target, required, is_multi)) in funk_ty.links.iter() {
+            let target = match interner.resolve_type(module, target.get_name().unwrap()) {
+                Some((target_module, target_ty)) => format!("{target_module}::{}", target_ty.get_name().unwrap()),
+                None => bail!("`{owner}.{name}` links to an unknown type"),
+            };
+            if !is_multi {
+                let definition = format!("TEXT{} REFERENCES {}(id) {DEFERRED}", not_null(*required), quote(&target));
+                table.column(name, definition)?;
+                continue;
+            }
+            let mut junction = Table::new(format!("{owner}.{name}"));
+            junction.column(
+                "source",
+                format!("TEXT NOT NULL REFERENCES {}(id) ON DELETE CASCADE {DEFERRED}", quote(&owner)),
+            )?;
+            junction.column("ord", "INTEGER NOT NULL".to_string())?;
+            junction.column("target", format!("TEXT NOT NULL REFERENCES {}(id) {DEFERRED}", quote(&target)))?;
+            junction.constraints.push("PRIMARY KEY (source, ord)".to_string());
+            junction.constraints.push("UNIQUE (source, target)".to_string());
+            tables.push(junction);
+        }
+        tables.push(table);
+    }
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tables)
+}
+
+pub(crate) fn recorded(conn: &Connection) -> Result<BTreeMap<String, Table>> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS funk_relational (
+            name TEXT NOT NULL PRIMARY KEY,
+            record TEXT NOT NULL
+        );",
+    )?;
+    let mut statement = conn.prepare("SELECT name, record FROM funk_relational")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut tables = BTreeMap::new();
+    for row in rows {
+        let (name, record) = row?;
+        let table = Table::from_record(name.clone(), &record).with_context(|| format!("Reading the record of {name}"))?;
+        tables.insert(name, table);
+    }
+    Ok(tables)
+}
+
+/// The statements turning `old` into `new`.
+fn alter(old: &Table, new: &Table) -> Vec<String> {
+    let find = |table: &Table, column: &str| {
+        table
+            .columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, definition)| definition.clone())
+    };
+    let added: Vec<&(String, String)> = new.columns.iter().filter(|(name, _)| find(old, name).is_none()).collect();
+    let dropped: Vec<&(String, String)> = old.columns.iter().filter(|(name, _)| find(new, name).is_none()).collect();
+    let changed = new
+        .columns
+        .iter()
+        .any(|(name, definition)| find(old, name).is_some_and(|old| &old != definition));
+
+    let in_place = !changed
+        && old.constraints == new.constraints
+        && added.iter().all(|(_, definition)| !definition.contains("NOT NULL"))
+        && dropped.iter().all(|(_, definition)| !definition.contains("REFERENCES"));
+    if in_place {
+        let table = quote(&new.name);
+        let adds = added
+            .iter()
+            .map(|(name, definition)| format!("ALTER TABLE {table} ADD COLUMN {} {definition}", quote(name)));
+        let drops = dropped
+            .iter()
+            .map(|(name, _)| format!("ALTER TABLE {table} DROP COLUMN {}", quote(name)));
+        return adds.chain(drops).collect();
+    }
+
+    let scratch = format!("{}.__rebuild__", new.name);
+    let kept: Vec<String> = new
+        .columns
+        .iter()
+        .filter(|(name, _)| find(old, name).is_some())
+        .map(|(name, _)| quote(name))
+        .collect();
+    let kept = kept.join(", ");
+    vec![
+        new.create_sql_as(&scratch),
+        format!("INSERT INTO {} ({kept}) SELECT {kept} FROM {}", quote(&scratch), quote(&new.name)),
+        format!("DROP TABLE {}", quote(&new.name)),
+        format!("ALTER TABLE {} RENAME TO {}", quote(&scratch), quote(&new.name)),
+    ]
+}
+
+/// Brings the tables of `conn` in line with `interner` and returns the
+/// statements that took. Nothing changes when any of them fails.
+pub fn migrate(conn: &mut Connection, interner: &Interner) -> Result<Vec<String>> {
+    let desired = tables(interner)?;
+    let recorded = recorded(conn)?;
+
+    let mut statements = vec![];
+    for table in desired.iter() {
+        match recorded.get(&table.name) {
+            None => statements.push(table.create_sql()),
+            Some(old) if old != table => statements.extend(alter(old, table)),
+            Some(_) => {}
+        }
+    }
+    for name in recorded.keys() {
+        if !desired.iter().any(|table| &table.name == name) {
+            statements.push(format!("DROP TABLE {}", quote(name)));
+        }
+    }
+    if statements.is_empty() {
+        return Ok(statements);
+    }
+
+    // Rebuilding a table means dropping it while others still point at
+    // it, so foreign keys are checked once, after the fact.
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let migrated = (|| -> Result<()> {
+        let txn = conn.transaction()?;
+        for statement in statements.iter() {
+            txn.execute_batch(statement)
+                .with_context(|| format!("Migration failed at:\n{statement}"))?;
+        }
+        txn.execute("DELETE FROM funk_relational", [])?;
+        for table in desired.iter() {
+            txn.execute(
+                "INSERT INTO funk_relational (name, record) VALUES (?1, ?2)",
+                params![table.name, table.record()],
+            )?;
+        }
+        let dangling: Option<String> = txn
+            .query_row("PRAGMA foreign_key_check", [], |row| row.get(0))
+            .ok();
+        if let Some(table) = dangling {
+            bail!("Migration would leave `{table}` pointing at objects that do not exist");
+        }
+        txn.commit()?;
+        Ok(())
+    })();
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    migrated?;
+    Ok(statements)
+}
+
+fn sql_value(value: &FunkValue) -> Value {
+    match value {
+        FunkValue::bool(v) => Value::Integer((*v).into()),
+        FunkValue::int8(v) => Value::Integer((*v).into()),
+        FunkValue::int16(v) => Value::Integer((*v).into()),
+        FunkValue::int32(v) => Value::Integer((*v).into()),
+        FunkValue::int64(v) => Value::Integer(*v),
+        FunkValue::uint8(v) => Value::Integer((*v).into()),
+        FunkValue::uint16(v) => Value::Integer((*v).into()),
+        FunkValue::uint32(v) => Value::Integer((*v).into()),
+        FunkValue::str(v) => Value::Text(v.clone()),
+        FunkValue::int128(_) | FunkValue::uint64(_) | FunkValue::uint128(_) | FunkValue::datetime(_) => {
+            Value::Text(value.to_string())
+        }
+        FunkValue::vector(v) => Value::Blob(v.iter().flat_map(|x| x.to_le_bytes()).collect()),
+    }
+}
+
+fn id_value(id: &Uuid) -> Value {
+    Value::Text(id.hyphenated().to_string())
+}
+
+/// Writes the rows of `object` into the tables it maps to, replacing any
+/// it had before.
+pub fn write_object(conn: &Connection, tables: &[Table], object: &Object) -> Result<()> {
+    let owner = object.type_name.as_str();
+    let Some(main) = tables.iter().find(|table| table.name == owner) else {
+        bail!("`{owner}` has no table, the schema was not migrated");
+    };
+    let mut columns = vec![];
+    let mut values = vec![];
+    for (column, _) in main.columns.iter() {
+        let value = match column.as_str() {
+            "id" => id_value(&object.id),
+            name => match (object.get(name), object.targets(name).first()) {
+                (Some(value), _) => sql_value(value),
+                (None, Some(target)) => id_value(target),
+                (None, None) => Value::Null,
+            },
+        };
+        columns.push(quote(column));
+        values.push(value);
+    }
+    let updates: Vec<String> = columns[1..]
+        .iter()
+        .map(|column| format!("{column} = excluded.{column}"))
+        .collect();
+    let on_conflict = match updates.is_empty() {
+        true => "DO NOTHING".to_string(),
+        false => format!("DO UPDATE SET {}", updates.join(", ")),
+    };
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    conn.execute(
+        &format!(
+            "INSERT INTO {} ({}) VALUES ({placeholders}) ON CONFLICT (id) {on_conflict}",
+            quote(owner),
+            columns.join(", ")
+        ),
+        params_from_iter(values),
+    )?;
+
+    let prefix = format!("{owner}.");
+    for side in tables.iter().filter(|table| table.name.starts_with(&prefix)) {
+        let field = &side.name[prefix.len()..];
+        let (owner_column, rows): (&str, Vec<Value>) = match side.columns[0].0.as_str() {
+            "id" => ("id", object.get_all(field).iter().map(sql_value).collect()),
+            _ => ("source", object.targets(field).iter().map(id_value).collect()),
+        };
+        conn.execute(
+            &format!("DELETE FROM {} WHERE {owner_column} = ?1", quote(&side.name)),
+            [id_value(&object.id)],
+        )?;
+        let insert = format!("INSERT INTO {} VALUES (?1, ?2, ?3)", quote(&side.name));
+        for (ord, row) in rows.into_iter().enumerate() {
+            conn.execute(&insert, params![id_value(&object.id), ord as i64, row])?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the rows of an object; its side table rows go with them.
+pub fn delete_object(conn: &Connection, type_name: &str, id: &Uuid) -> Result<()> {
+    conn.execute(&format!("DELETE FROM {} WHERE id = ?1", quote(type_name)), [id_value(id)])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdl;
+
+    const V1: &str = "
+        module default {
+            type FunksGiven {
+                required expires: int32;
+                significance: str;
+                multi tags: str;
+            }
+            type ReasonForLiving {
+                required online: bool;
+                best: FunksGiven;
+                multi funks: FunksGiven;
+            }
+        }
+    ";
+
+    fn columns(conn: &Connection, table: &str) -> Vec<(String, bool)> {
+        let mut statement = conn.prepare(&format!("PRAGMA table_info({})", quote(table))).unwrap();
+        statement
+            .query_map([], |row| Ok((row.get(1)?, row.get(3)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn tables_columns_and_constraints() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        let statements = migrate(&mut conn, &sdl::parse(V1)?)?;
+        assert_eq!(statements.len(), 4);
+        assert_eq!(
+            columns(&conn, "default::FunksGiven"),
+            vec![
+                ("id".to_string(), true),
+                ("expires".to_string(), true),
+                ("significance".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            columns(&conn, "default::ReasonForLiving.funks"),
+            vec![
+                ("source".to_string(), true),
+                ("ord".to_string(), true),
+                ("target".to_string(), true)
+            ]
+        );
+
+        conn.execute("INSERT INTO \"default::FunksGiven\" (id, expires) VALUES ('a', 1)", [])?;
+        assert!(conn
+            .execute("INSERT INTO \"default::FunksGiven\" (id) VALUES ('b')", [])
+            .is_err());
+        assert!(conn
+            .execute("INSERT INTO \"default::FunksGiven\" (id, expires) VALUES ('b', 1e10)", [])
+            .is_err());
+        assert!(conn
+            .execute("INSERT INTO \"default::ReasonForLiving\" (id, online, best) VALUES ('r', 1, 'nope')", [])
+            .is_err());
+        conn.execute("INSERT INTO \"default::ReasonForLiving\" (id, online, best) VALUES ('r', 1, 'a')", [])?;
+        conn.execute("INSERT INTO \"default::ReasonForLiving.funks\" VALUES ('r', 0, 'a')", [])?;
+        assert!(conn
+            .execute("INSERT INTO \"default::ReasonForLiving.funks\" VALUES ('r', 1, 'a')", [])
+            .is_err());
+
+        assert!(migrate(&mut conn, &sdl::parse(V1)?)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn migrations() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn, &sdl::parse(V1)?)?;
+        conn.execute("INSERT INTO \"default::FunksGiven\" (id, expires) VALUES ('a', 1)", [])?;
+
+        let v2 = V1.replace("significance: str;", "note: str;");
+        assert_eq!(
+            migrate(&mut conn, &sdl::parse(&v2)?)?,
+            vec![
+                "ALTER TABLE \"default::FunksGiven\" ADD COLUMN \"note\" TEXT",
+                "ALTER TABLE \"default::FunksGiven\" DROP COLUMN \"significance\"",
+            ]
+        );
+
+        // A new required column cannot be filled in for the existing row.
+        let v3 = v2.replace("note: str;", "note: str; required owner: str;");
+        let err = migrate(&mut conn, &sdl::parse(&v3)?).unwrap_err();
+        assert!(format!("{err:#}").contains("NOT NULL"), "{err:#}");
+        assert_eq!(columns(&conn, "default::FunksGiven").len(), 3);
+
+        // Relaxing `required` keeps the rows.
+        let v4 = v2.replace("required expires", "expires");
+        let statements = migrate(&mut conn, &sdl::parse(&v4)?)?;
+        assert!(statements[0].starts_with("CREATE TABLE \"default::FunksGiven.__rebuild__\""));
+        let expires: i64 = conn.query_row("SELECT expires FROM \"default::FunksGiven\"", [], |row| row.get(0))?;
+        assert_eq!(expires, 1);
+
+        let v5 = v4.replace("multi tags: str;", "");
+        assert_eq!(
+            migrate(&mut conn, &sdl::parse(&v5)?)?,
+            vec!["DROP TABLE \"default::FunksGiven.tags\""]
+        );
+        Ok(())
+    }
+}
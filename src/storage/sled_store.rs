@@ -0,0 +1,145 @@
+use super::{Backend, Batch, KeyRange, Op, Rewrite, StorageEngine};
+use anyhow::{anyhow, Result};
+use sled::transaction::ConflictableTransactionError;
+use sled::Transactional;
+use std::fs::{OpenOptions, TryLockError};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a `sled::Db` dropped in this process gets to let go of its
+/// directory before `sled::open` reports it as locked.
+const RELEASE: Duration = Duration::from_secs(1);
+
+/// Every tree is a sled tree of the same name. Sled locks its directory
+/// for one process at a time, for reading too, so [`FunkDb`](crate::FunkDb)
+/// locks it with [`FileLock::acquire_alone`](super::FileLock::acquire_alone).
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        wait_for_release(path.as_ref());
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    fn existing_tree(&self, tree: &str) -> Result<Option<sled::Tree>> {
+        let exists = self.db.tree_names().iter().any(|name| name.as_ref() == tree.as_bytes());
+        match exists {
+            true => Ok(Some(self.db.open_tree(tree)?)),
+            false => Ok(None),
+        }
+    }
+}
+
+/// A dropped `sled::Db` unlocks `<dir>/db` only once its flusher thread
+/// has wound down, so reopening right away can race it. sled reports a
+/// held lock as `ErrorKind::Other`, so take the same lock here and wait
+/// while it would block.
+fn wait_for_release(dir: &Path) {
+    let Ok(file) = OpenOptions::new().read(true).write(true).open(dir.join("db")) else {
+        return;
+    };
+    let start = Instant::now();
+    while let Err(TryLockError::WouldBlock) = file.try_lock() {
+        if start.elapsed() > RELEASE {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+impl StorageEngine for SledStore {
+    fn backend(&self) -> Backend {
+        Backend::Sled
+    }
+
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(tree) = self.existing_tree(tree)? else {
+            return Ok(None);
+        };
+        Ok(tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let Some(tree) = self.existing_tree(tree)? else {
+            return Ok(vec![]);
+        };
+        tree.range::<&[u8], _>(range)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn apply(&mut self, batch: Batch) -> Result<()> {
+        let mut names: Vec<&str> = batch.ops().iter().map(Op::tree).collect();
+        names.sort();
+        names.dedup();
+        let trees = names
+            .iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<sled::Result<Vec<_>>>()?;
+        trees
+            .as_slice()
+            .transaction(|views| {
+                for op in batch.ops() {
+                    let view = &views[names.binary_search(&op.tree()).unwrap()];
+                    match op {
+                        Op::Put { key, value, .. } => view.insert(key.as_slice(), value.as_slice())?,
+                        Op::Delete { key, .. } => view.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| anyhow!("sled transaction failed: {err:?}"))
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for name in self.db.tree_names() {
+            // The default tree always exists and is never written to.
+            if name.as_ref() == b"__sled__default" {
+                continue;
+            }
+            if !self.db.open_tree(&name)?.is_empty() {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Sled reclaims the space of its log segments on its own, so all that
+    // compacting does is flush, and sled needs no holding for that.
+    fn begin_compact(&mut self) -> Result<Option<Box<dyn Rewrite>>> {
+        Ok(Some(Box::new(Flush(self.db.clone()))))
+    }
+
+    fn finish_rewrite(&mut self, _rewrite: Box<dyn Rewrite>) -> Result<bool> {
+        Ok(true)
+    }
+
+    // Sled reclaims the space of its log segments on its own.
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+}
+
+struct Flush(sled::Db);
+
+impl Rewrite for Flush {
+    fn build(&mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
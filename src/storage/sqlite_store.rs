@@ -0,0 +1,266 @@
+use super::{relational, Backend, Batch, KeyRange, Op, Rewrite, StorageEngine};
+use crate::object::Object;
+use crate::Interner;
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use std::any::Any;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// The batches applied while a [`VacuumInto`] is built, `None` when none
+/// is or a migration left it behind.
+type Tail = Arc<Mutex<Option<Vec<Batch>>>>;
+
+/// Every tree lives in the single `funk_kv` table. SQLite compares blobs
+/// with `memcmp`, so its key order is the same as everyone else's.
+///
+/// Objects are also mirrored into the tables of [`relational`].
+///
+/// Files are kept in write-ahead log mode, so that compacting can read
+/// them from a connection of its own while this one goes on writing.
+pub struct SqliteStore {
+    conn: Connection,
+    tables: Vec<relational::Table>,
+    path: Option<PathBuf>,
+    tail: Tail,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path.as_ref())?;
+        let _: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        let mut store = Self::with_connection(conn)?;
+        store.path = Some(path.as_ref().to_path_buf());
+        Ok(store)
+    }
+
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags(path, flags)?;
+        let tables = relational::recorded(&conn)?.into_values().collect();
+        Ok(Self {
+            conn,
+            tables,
+            path: None,
+            tail: Tail::default(),
+        })
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE IF NOT EXISTS funk_kv (
+                tree TEXT NOT NULL,
+                key BLOB NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (tree, key)
+            ) WITHOUT ROWID;",
+        )?;
+        let tables = relational::recorded(&conn)?.into_values().collect();
+        Ok(Self {
+            conn,
+            tables,
+            path: None,
+            tail: Tail::default(),
+        })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl StorageEngine for SqliteStore {
+    fn backend(&self) -> Backend {
+        Backend::Sqlite
+    }
+
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self
+            .conn
+            .query_row(
+                "SELECT value FROM funk_kv WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn scan(&self, tree: &str, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut sql = String::from("SELECT key, value FROM funk_kv WHERE tree = ?");
+        let mut bounds: Vec<&[u8]> = vec![];
+        let conditions = [(range.0, ">=", ">"), (range.1, "<=", "<")];
+        for (bound, inclusive, exclusive) in conditions {
+            match bound {
+                Bound::Included(key) => {
+                    sql.push_str(&format!(" AND key {inclusive} ?"));
+                    bounds.push(key);
+                }
+                Bound::Excluded(key) => {
+                    sql.push_str(&format!(" AND key {exclusive} ?"));
+                    bounds.push(key);
+                }
+                Bound::Unbounded => {}
+            }
+        }
+        sql.push_str(" ORDER BY key");
+        let mut statement = self.conn.prepare_cached(&sql)?;
+        let params = std::iter::once(rusqlite::types::Value::from(tree.to_string()))
+            .chain(bounds.into_iter().map(|key| rusqlite::types::Value::from(key.to_vec())));
+        let pairs = statement
+            .query_map(params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(pairs)
+    }
+
+    fn apply(&mut self, batch: Batch) -> Result<()> {
+        let mut tail = self.tail.lock().unwrap();
+        let replay = tail.as_ref().map(|_| batch.clone());
+        let txn = self.conn.transaction()?;
+        for op in batch {
+            let type_name = op.tree().strip_prefix("objects/").map(str::to_string);
+            match op {
+                Op::Put { tree, key, value } => {
+                    if type_name.is_some() {
+                        let object = Object::decode(Uuid::from_slice(&key)?, &value)?;
+                        relational::write_object(&txn, &self.tables, &object)
+                            .with_context(|| format!("Writing the rows of {}", object.id))?;
+                    }
+                    txn.execute(
+                        "INSERT OR REPLACE INTO funk_kv (tree, key, value) VALUES (?1, ?2, ?3)",
+                        params![tree, key, value],
+                    )?;
+                }
+                Op::Delete { tree, key } => {
+                    if let Some(type_name) = type_name {
+                        relational::delete_object(&txn, &type_name, &Uuid::from_slice(&key)?)?;
+                    }
+                    txn.execute("DELETE FROM funk_kv WHERE tree = ?1 AND key = ?2", params![tree, key])?;
+                }
+            };
+        }
+        txn.commit()?;
+        if let (Some(tail), Some(batch)) = (tail.as_mut(), replay) {
+            tail.push(batch);
+        }
+        Ok(())
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare("SELECT DISTINCT tree FROM funk_kv ORDER BY tree")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Every batch is its own SQLite transaction and durable on commit.
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    fn begin_compact(&mut self) -> Result<Option<Box<dyn Rewrite>>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        *self.tail.lock().unwrap() = Some(vec![]);
+        Ok(Some(Box::new(VacuumInto {
+            path: path.clone(),
+            tail: Arc::clone(&self.tail),
+        })))
+    }
+
+    // Replays what was applied since the copy began on top of it, then
+    // closes the connection, so that it checkpoints its log, and reopens
+    // on the copy.
+    fn finish_rewrite(&mut self, rewrite: Box<dyn Rewrite>) -> Result<bool> {
+        let rewrite: Box<dyn Any> = rewrite;
+        let Ok(rewrite) = rewrite.downcast::<VacuumInto>() else {
+            bail!("Not a rewrite of a SQLite database");
+        };
+        // A migration since leaves the copy with the tables it had before.
+        let Some(tail) = rewrite.tail.lock().unwrap().take() else {
+            return Ok(false);
+        };
+        let copy = rewrite_path(&rewrite.path);
+        {
+            let mut copy = Self::open(&copy)?;
+            for batch in tail {
+                copy.apply(batch)?;
+            }
+        }
+        let conn = std::mem::replace(&mut self.conn, Connection::open_in_memory()?);
+        conn.close().map_err(|(_, err)| err)?;
+        let renamed = fs::rename(&copy, &rewrite.path);
+        *self = Self::open(&rewrite.path)?;
+        renamed?;
+        Ok(true)
+    }
+
+    fn verify(&self) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare("PRAGMA integrity_check")?;
+        let mut problems: Vec<String> = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        problems.retain(|problem| problem != "ok");
+        Ok(problems)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let pages: u64 = self.conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = self.conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(pages * page_size)
+    }
+
+    fn migrate(&mut self, schema: &Interner) -> Result<()> {
+        relational::migrate(&mut self.conn, schema)?;
+        self.tables = relational::tables(schema)?;
+        *self.tail.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+/// `VACUUM INTO` a copy next to the database, read from a connection of
+/// its own, see [`Rewrite`].
+struct VacuumInto {
+    path: PathBuf,
+    tail: Tail,
+}
+
+impl Rewrite for VacuumInto {
+    fn build(&mut self) -> Result<()> {
+        let copy = rewrite_path(&self.path);
+        let _ = fs::remove_file(&copy);
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.execute("VACUUM INTO ?1", [copy.to_string_lossy()])?;
+        Ok(())
+    }
+}
+
+// Stops recording the tail, and takes the copy along unless it is in
+// place.
+impl Drop for VacuumInto {
+    fn drop(&mut self) {
+        *self.tail.lock().unwrap() = None;
+        let _ = fs::remove_file(rewrite_path(&self.path));
+    }
+}
+
+fn rewrite_path(path: &Path) -> PathBuf {
+    let mut copy = path.to_path_buf().into_os_string();
+    copy.push(".vacuum");
+    PathBuf::from(copy)
+}
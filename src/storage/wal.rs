@@ -0,0 +1,587 @@
+//! The write-ahead log of a `.funk` file.
+//!
+//! Every committed [`Batch`] is appended to `<db>.funk-wal` as one record
+//! and fsynced before it is applied, so a commit that returned survives a
+//! crash. A record is framed as
+//!
+//! ```text
+//! length: u32 LE | crc32: u32 LE | lsn: u64 LE | batch
+//! ```
+//!
+//! with the checksum covering the log sequence number and the batch. On
+//! open the log is replayed up to the first record that is short or fails
+//! its checksum, which is where a crash interrupted a write; everything
+//! from there on is cut off, so a half-written commit never becomes
+//! visible.
+//!
+//! Commits from several threads are fsynced together: whoever finds no
+//! sync in flight writes out everything appended so far and syncs once,
+//! and everyone whose record made it in returns (group commit). A commit
+//! can also [`Wal::defer`] the wait, to sync without holding anything the
+//! next commit needs.
+//!
+//! A checkpoint writes the whole store to the `.funk` file and removes the
+//! log, see [`super::MemoryStore`]. One written without holding the store
+//! leaves the records committed meanwhile at [`next_path_for`] until the
+//! file is in place, then moves them over the log; [`settle`] finishes
+//! or undoes that after a crash.
+//!
+//! The log of an encrypted file seals every batch with the file's key,
+//! bound to its log sequence number, see [`crypt`](super::crypt). The
+//! checksum then covers the sealed batch, so a torn write is still told
+//! from a wrong key.
+use super::crypt::Cipher;
+use super::{Batch, Op};
+use crate::codec::{self, Cursor};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Bytes in front of every record's payload.
+pub const HEADER: usize = 16;
+
+pub fn path_for(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push("-wal");
+    PathBuf::from(path)
+}
+
+/// Where the records that follow a rewritten checkpoint wait for it to be
+/// in place.
+pub fn next_path_for(log: &Path) -> PathBuf {
+    let mut path = log.as_os_str().to_owned();
+    path.push(".next");
+    PathBuf::from(path)
+}
+
+/// Writes `records` to `path` as a log of their own, sealed with `cipher`.
+pub fn write(path: &Path, cipher: Option<&Cipher>, records: &[(u64, Batch)]) -> Result<()> {
+    let mut bytes = vec![];
+    for (lsn, batch) in records {
+        bytes.extend(frame(*lsn, &seal(cipher, *lsn, batch)));
+    }
+    let mut file = File::create(path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Finishes what a crash cut short after a rewrite wrote the records at
+/// [`next_path_for`] of `log`: they take over the log if they pick up
+/// right after `checkpoint`, the one in the file, so the rewritten file
+/// made it in place; otherwise they go.
+pub fn settle(log: &Path, checkpoint: u64) -> Result<()> {
+    let next = next_path_for(log);
+    let bytes = match fs::read(&next) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    match unframe(&bytes).0.first() {
+        Some((lsn, _)) if *lsn == checkpoint + 1 => fs::rename(&next, log)?,
+        _ => fs::remove_file(&next)?,
+    }
+    Ok(())
+}
+
+pub fn encode_batch(batch: &Batch) -> Vec<u8> {
+    let mut out = vec![];
+    codec::put_varint(&mut out, batch.len() as u128);
+    for op in batch.ops() {
+        let (tag, tree, key, value) = match op {
+            Op::Put { tree, key, value } => (0, tree, key, Some(value)),
+            Op::Delete { tree, key } => (1, tree, key, None),
+        };
+        out.push(tag);
+        for bytes in [Some(tree.as_bytes()), Some(key.as_slice()), value.map(Vec::as_slice)]
+            .into_iter()
+            .flatten()
+        {
+            codec::put_varint(&mut out, bytes.len() as u128);
+            out.extend(bytes);
+        }
+    }
+    out
+}
+
+pub fn decode_batch(bytes: &[u8]) -> Result<Batch> {
+    let mut cursor = Cursor::new(bytes);
+    let take = |cursor: &mut Cursor| -> Result<Vec<u8>> {
+        let len = usize::try_from(cursor.varint()?)?;
+        Ok(cursor.take(len)?.to_vec())
+    };
+    let mut batch = Batch::new();
+    for _ in 0..cursor.varint()? {
+        let tag = cursor.byte()?;
+        let tree = String::from_utf8(take(&mut cursor)?)?;
+        let key = take(&mut cursor)?;
+        match tag {
+            0 => batch.put(&tree, key, take(&mut cursor)?),
+            1 => batch.delete(&tree, key),
+            tag => bail!("Unknown operation {tag}"),
+        }
+    }
+    if !cursor.is_empty() {
+        bail!("Trailing bytes after the batch");
+    }
+    Ok(batch)
+}
+
+/// The checksum of a record, covering its sequence number and payload.
+pub fn checksum(lsn: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&lsn.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+pub fn frame(lsn: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER + payload.len());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(checksum(lsn, payload).to_le_bytes());
+    record.extend(lsn.to_le_bytes());
+    record.extend(payload);
+    record
+}
+
+/// Every intact record at the front of `bytes`, and how many bytes they
+/// take up.
+pub fn unframe(bytes: &[u8]) -> (Vec<(u64, Vec<u8>)>, usize) {
+    let mut records = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= HEADER {
+        let header = &bytes[offset..offset + HEADER];
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let lsn = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let Some(payload) = bytes.get(offset + HEADER..offset + HEADER + len) else {
+            break;
+        };
+        if checksum(lsn, payload) != crc {
+            break;
+        }
+        records.push((lsn, payload.to_vec()));
+        offset += HEADER + len;
+    }
+    (records, offset)
+}
+
+#[derive(Debug)]
+struct State {
+    file: Option<File>,
+    cipher: Option<Cipher>,
+    // Framed records appended but not yet written out.
+    pending: Vec<u8>,
+    last_lsn: u64,
+    synced_lsn: u64,
+    syncing: bool,
+    failed: bool,
+    size: u64,
+    // Fault injection: the next write stops after this many bytes.
+    tear_after: Option<usize>,
+    // Fault injection: every sync takes this much longer.
+    slow_sync: Duration,
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    state: Mutex<State>,
+    synced: Condvar,
+    syncs: AtomicU64,
+}
+
+/// A record in the log that may not be on disk yet, see [`Wal::defer`].
+#[must_use = "the record is not durable until `wait` returns"]
+#[derive(Debug)]
+pub struct Pending {
+    wal: Arc<Wal>,
+    lsn: u64,
+}
+
+impl Pending {
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// Blocks until the record is on disk.
+    pub fn wait(self) -> Result<()> {
+        self.wal.sync(self.lsn)
+    }
+}
+
+/// The payload of the record of `batch` at `lsn`.
+fn seal(cipher: Option<&Cipher>, lsn: u64, batch: &Batch) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal(&lsn.to_le_bytes(), &encode_batch(batch)),
+        None => encode_batch(batch),
+    }
+}
+
+/// The batch in the `payload` of the record at `lsn`.
+fn open_record(cipher: Option<&Cipher>, lsn: u64, payload: &[u8]) -> Result<Batch> {
+    match cipher {
+        Some(cipher) => {
+            let batch = cipher.open(&lsn.to_le_bytes(), payload);
+            decode_batch(&batch.with_context(|| format!("Record {lsn} of the log"))?)
+        }
+        None => decode_batch(payload),
+    }
+}
+
+/// The intact records of the log at `path` after `after`, leaving the
+/// file as it is.
+pub fn read(path: &Path, cipher: Option<&Cipher>, after: u64) -> Result<Vec<(u64, Batch)>> {
+    match File::open(path) {
+        Ok(mut file) => Ok(read_from(&mut file, cipher, after)?.0),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// What is wrong with the log at `path`: records that fail their checksum
+/// or do not decode, and the bytes after them, which opening would cut
+/// off. A write cut short by a crash shows up here too.
+pub fn verify(path: &Path, cipher: Option<&Cipher>, after: u64) -> Result<Vec<String>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let (records, valid) = unframe(&bytes);
+    let mut problems = vec![];
+    for (lsn, payload) in records.into_iter().filter(|(lsn, _)| *lsn > after) {
+        if let Err(err) = open_record(cipher, lsn, &payload) {
+            problems.push(format!("{}: record {lsn} does not decode: {err:#}", path.display()));
+        }
+    }
+    if valid < bytes.len() {
+        problems.push(format!(
+            "{}: the {} bytes after byte {valid} are not an intact record",
+            path.display(),
+            bytes.len() - valid
+        ));
+    }
+    Ok(problems)
+}
+
+/// Batches by log sequence number.
+type Records = Vec<(u64, Batch)>;
+
+/// The batches of the intact records after `after`, the last log sequence
+/// number of any, and how many bytes the intact records take up. Records
+/// up to `after` are not even opened: a checkpoint has them, perhaps under
+/// another key.
+fn read_from(file: &mut File, cipher: Option<&Cipher>, after: u64) -> Result<(Records, u64, u64)> {
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let (records, valid) = unframe(&bytes);
+    let last = records.last().map(|(lsn, _)| *lsn).unwrap_or(0);
+    let batches = records
+        .into_iter()
+        .filter(|(lsn, _)| *lsn > after)
+        .map(|(lsn, payload)| Ok((lsn, open_record(cipher, lsn, &payload)?)))
+        .collect::<Result<_>>()?;
+    Ok((batches, last, valid as u64))
+}
+
+impl Wal {
+    /// Opens the log at `path` and returns it with every intact batch it
+    /// holds after `after`, cutting off whatever follows the last of them.
+    /// Records are sealed with `cipher`, if given.
+    pub fn open(path: impl AsRef<Path>, cipher: Option<Cipher>, after: u64) -> Result<(Self, Vec<(u64, Batch)>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut batches = vec![];
+        let mut last_lsn = 0;
+        let mut file = None;
+        let mut size = 0;
+        if path.exists() {
+            let mut handle = OpenOptions::new().read(true).write(true).open(&path)?;
+            let valid;
+            (batches, last_lsn, valid) = read_from(&mut handle, cipher.as_ref(), after)?;
+            if valid < handle.metadata()?.len() {
+                handle.set_len(valid)?;
+                handle.sync_all()?;
+            }
+            size = valid;
+            file = Some(handle);
+        }
+        let wal = Self {
+            path,
+            state: Mutex::new(State {
+                file,
+                cipher,
+                pending: vec![],
+                last_lsn,
+                synced_lsn: last_lsn,
+                syncing: false,
+                failed: false,
+                size,
+                tear_after: None,
+                slow_sync: Duration::ZERO,
+            }),
+            synced: Condvar::new(),
+            syncs: AtomicU64::new(0),
+        };
+        Ok((wal, batches))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Numbers from here on continue after `lsn`.
+    pub fn skip_to(&self, lsn: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.last_lsn = state.last_lsn.max(lsn);
+        state.synced_lsn = state.synced_lsn.max(lsn);
+    }
+
+    pub fn last_lsn(&self) -> u64 {
+        self.state.lock().unwrap().last_lsn
+    }
+
+    /// Bytes in the log, including what is not yet written out.
+    pub fn size(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.size + state.pending.len() as u64
+    }
+
+    /// How many times the log was fsynced.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Queues `batch` and returns its log sequence number. It is durable
+    /// once [`Wal::sync`] returns for that number.
+    pub fn append(&self, batch: &Batch) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            bail!("An earlier write to {} failed, reopen the database", self.path.display());
+        }
+        state.last_lsn += 1;
+        let lsn = state.last_lsn;
+        let record = frame(lsn, &seal(state.cipher.as_ref(), lsn, batch));
+        state.pending.extend(record);
+        Ok(lsn)
+    }
+
+    /// Waits until every record up to `lsn` is on disk, writing them out
+    /// itself unless another thread already is.
+    pub fn sync(&self, lsn: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.failed {
+                bail!("An earlier write to {} failed, reopen the database", self.path.display());
+            }
+            if state.synced_lsn >= lsn {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            if state.file.is_none() {
+                state.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+            }
+            let mut file = state.file.as_ref().unwrap().try_clone()?;
+            state.syncing = true;
+            let pending = std::mem::take(&mut state.pending);
+            let upto = state.last_lsn;
+            let tear_after = state.tear_after.take();
+            let slow_sync = state.slow_sync;
+            drop(state);
+
+            let written = (|| -> Result<()> {
+                match tear_after {
+                    Some(n) if n < pending.len() => {
+                        file.write_all(&pending[..n])?;
+                        Err(anyhow!("Injected crash after {n} bytes"))
+                    }
+                    _ => {
+                        file.write_all(&pending)?;
+                        file.sync_data()?;
+                        thread::sleep(slow_sync);
+                        Ok(())
+                    }
+                }
+            })();
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            match written {
+                Ok(()) => {
+                    state.synced_lsn = upto;
+                    state.size += pending.len() as u64;
+                }
+                // What made it to the file is garbage to replay; nothing
+                // may follow it.
+                Err(_) => state.failed = true,
+            }
+            self.synced.notify_all();
+            written?;
+        }
+    }
+
+    pub fn commit(&self, batch: &Batch) -> Result<u64> {
+        let lsn = self.append(batch)?;
+        self.sync(lsn)?;
+        Ok(lsn)
+    }
+
+    /// Appends `batch` like [`Wal::commit`], but leaves waiting for the
+    /// sync to the caller.
+    pub fn defer(self: &Arc<Self>, batch: &Batch) -> Result<Pending> {
+        let lsn = self.append(batch)?;
+        Ok(Pending {
+            wal: Arc::clone(self),
+            lsn,
+        })
+    }
+
+    /// Drops the log once a checkpoint holds everything in it.
+    pub fn reset(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.pending.is_empty() {
+            bail!("Cannot reset the log with unsynced records");
+        }
+        state.file = None;
+        state.size = 0;
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Takes up what a rewrite left at the log's path, sealed with
+    /// `cipher`, in place of what it held.
+    pub fn reopen(&self, cipher: Option<Cipher>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.pending.is_empty() {
+            bail!("Cannot reopen the log with unsynced records");
+        }
+        state.file = None;
+        state.size = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        state.cipher = cipher;
+        Ok(())
+    }
+
+    /// Seals records from here on with `cipher`, or leaves them in the
+    /// clear. Only an empty log may change, so that it is all one way.
+    pub fn set_cipher(&self, cipher: Option<Cipher>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.size > 0 || !state.pending.is_empty() {
+            bail!("Cannot change the key of a log with records in it");
+        }
+        state.cipher = cipher;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn tear_next_write(&self, after: usize) {
+        self.state.lock().unwrap().tear_after = Some(after);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn slow_syncs_by(&self, by: Duration) {
+        self.state.lock().unwrap().slow_sync = by;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn batch(n: u8) -> Batch {
+        let mut batch = Batch::new();
+        batch.put("t", vec![n], vec![n; 3]);
+        batch.delete("t", vec![n, n]);
+        batch
+    }
+
+    #[test]
+    fn replays_intact_records_only() -> Result<()> {
+        let path = scratch_path("wal");
+        let (wal, replayed) = Wal::open(&path, None, 0)?;
+        assert!(replayed.is_empty());
+        for n in 1..=3 {
+            assert_eq!(wal.commit(&batch(n))?, u64::from(n));
+        }
+        drop(wal);
+
+        // A torn last record and a flipped bit are both cut off on replay.
+        let bytes = fs::read(&path)?;
+        let record = bytes.len() / 3;
+        fs::write(&path, &bytes[..bytes.len() - 5])?;
+        let (wal, replayed) = Wal::open(&path, None, 0)?;
+        assert_eq!(replayed.iter().map(|(lsn, _)| *lsn).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(replayed[1].1, batch(2));
+        assert_eq!(fs::metadata(&path)?.len() as usize, 2 * record);
+        wal.commit(&batch(3))?;
+        drop(wal);
+
+        let mut bytes = fs::read(&path)?;
+        bytes[record + HEADER] ^= 1;
+        fs::write(&path, &bytes)?;
+        let (wal, replayed) = Wal::open(&path, None, 0)?;
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(wal.last_lsn(), 1);
+        wal.reset()?;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_writes_poison_the_log() -> Result<()> {
+        let path = scratch_path("wal");
+        let (wal, _) = Wal::open(&path, None, 0)?;
+        wal.commit(&batch(1))?;
+        wal.tear_next_write(10);
+        assert!(wal.commit(&batch(2)).is_err());
+        assert!(wal.commit(&batch(3)).is_err());
+        drop(wal);
+        let (_, replayed) = Wal::open(&path, None, 0)?;
+        assert_eq!(replayed.len(), 1);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn group_commit() -> Result<()> {
+        let path = scratch_path("wal");
+        let wal = Arc::new(Wal::open(&path, None, 0)?.0);
+        // Syncs slow enough that the other threads always queue up behind
+        // one, and ride along with the next.
+        wal.slow_syncs_by(Duration::from_millis(5));
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let wal = Arc::clone(&wal);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..25 {
+                        wal.commit(&batch(n))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!(wal.last_lsn(), 200);
+        assert!(wal.syncs() < 100, "{} syncs for 200 commits", wal.syncs());
+        let (_, replayed) = Wal::open(&path, None, 0)?;
+        let lsns: Vec<u64> = replayed.iter().map(|(lsn, _)| *lsn).collect();
+        assert_eq!(lsns, (1..=200).collect::<Vec<_>>());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! "Did you mean" hints for names that failed to resolve.
+//!
+//! Distances are optimal string alignment distances, so a swapped pair of
+//! letters (`relp` for `repl`) costs one edit rather than two, and names
+//! are compared case-insensitively.
+
+/// The number of single-character insertions, deletions, substitutions
+/// and adjacent transpositions needed to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The candidate closest to `name`, if any is close enough to be a typo
+/// rather than a different name altogether. Ties go to a candidate that
+/// starts with the same letter, since first letters are rarely mistyped.
+pub fn closest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let budget = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= budget)
+        .min_by_key(|(distance, candidate)| (*distance, !same_initial(name, candidate)))
+        .map(|(_, candidate)| candidate)
+}
+
+fn same_initial(a: &str, b: &str) -> bool {
+    let initial = |s: &str| s.chars().next().map(|c| c.to_ascii_lowercase());
+    initial(a) == initial(b)
+}
+
+/// `" Did you mean `candidate`?"`, ready to be appended to an error
+/// message, or an empty string when nothing is close.
+pub fn did_you_mean<'a, I>(name: &str, candidates: I) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    match closest(name, candidates) {
+        Some(candidate) => format!(" Did you mean `{candidate}`?"),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances() {
+        assert_eq!(edit_distance("repl", "repl"), 0);
+        assert_eq!(edit_distance("relp", "repl"), 1);
+        assert_eq!(edit_distance("int23", "int32"), 1);
+        assert_eq!(edit_distance("FunksGivn", "FunksGiven"), 1);
+        assert_eq!(edit_distance("funksgiven", "FunksGiven"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn suggestions() {
+        let commands = ["help", "open", "create", "repl", "schema", "lint"];
+        assert_eq!(closest("relp", commands), Some("repl"));
+        assert_eq!(closest("crate", commands), Some("create"));
+        assert_eq!(closest("helpzzzzzzzzzzz", commands), None);
+        assert_eq!(closest("new", commands), None);
+        assert_eq!(did_you_mean("strr", ["str", "bool"]), " Did you mean `str`?");
+        assert_eq!(did_you_mean("xyzzy", ["str", "bool"]), "");
+    }
+}
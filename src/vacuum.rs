@@ -0,0 +1,267 @@
+//! Reclaiming space. Deleted objects, expired objects not yet swept,
+//! history past its retention window and index entries that lead nowhere
+//! all take up room until something clears them out, and a `.funk` file
+//! only grows until a checkpoint rewrites it. [`vacuum`]:
+//!
+//! 1. sweeps expired objects, see [`expiry::sweep`];
+//! 2. drops the history that fell out of the retention window, see
+//!    [`Mvcc::prune_history`];
+//! 3. goes through every ready index for entries no stored object has
+//!    any more, and links in nearest neighbour graphs to objects removed
+//!    since, see [`index::tidy`], and rebuilds those of them that were
+//!    [`FRAGMENTED`], see [`index::rebuild`];
+//! 4. compacts the engine, see [`Mvcc::compact`]: a checkpoint for a
+//!    `.funk` file, which writes out live data only and empties the log,
+//!    and `VACUUM INTO` a copy for SQLite.
+//!
+//! The first three go a small chunk at a time, a transaction each, so
+//! reads and writes carry on in between, and a vacuum stopped halfway
+//! loses nothing. The last builds the compacted copy from a snapshot and
+//! holds the engine only to catch it up with the commits since and put it
+//! in place, see [`Rewrite`].
+//! [`FunkDbOptions::vacuum_every`](crate::FunkDbOptions) vacuums in the
+//! background.
+//!
+//! [`Rewrite`]: crate::storage::Rewrite
+use crate::expiry;
+use crate::index::{self, State};
+use crate::storage::{Isolation, Mvcc};
+use anyhow::Result;
+use std::fmt;
+
+/// How many commits of history go per step.
+const PRUNE_CHUNK: usize = 256;
+
+/// The share of an index's entries, or of a graph's links, that tidying
+/// dropped above which the index is rebuilt rather than left patched: a
+/// nearest neighbour graph that lost that many links finds less.
+pub const FRAGMENTED: f64 = 0.25;
+
+/// What a [`vacuum`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Bytes on disk before and after, 0 in memory.
+    pub before: u64,
+    pub after: u64,
+    /// Expired objects deleted.
+    pub swept: usize,
+    /// Commits whose history was dropped.
+    pub pruned: u64,
+    /// Index entries and graph links dropped.
+    pub tidied: usize,
+    /// Indexes rebuilt for being [`FRAGMENTED`].
+    pub rebuilt: usize,
+}
+
+impl Report {
+    /// Bytes given back, none if the database grew meanwhile.
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reclaimed {} bytes ({} -> {}), swept {} expired objects, pruned the history of {} commits, tidied {} index entries and rebuilt {} indexes",
+            self.reclaimed(),
+            self.before,
+            self.after,
+            self.swept,
+            self.pruned,
+            self.tidied,
+            self.rebuilt
+        )
+    }
+}
+
+/// Reclaims what space it can, see the [module](self).
+pub fn vacuum(db: &Mvcc) -> Result<Report> {
+    let before = db.store().size_on_disk()?;
+    let swept = expiry::sweep(db)?;
+    let pruned = db.prune_history(PRUNE_CHUNK)?;
+    let ready: Vec<_> = {
+        let txn = db.begin(Isolation::Snapshot);
+        index::states(&txn)?
+            .into_iter()
+            .filter(|(_, state)| *state == State::Ready)
+            .collect()
+    };
+    let (mut tidied, mut rebuilt) = (0, 0);
+    for (index, _) in ready {
+        let tidy = index::tidy(db, &index)?;
+        tidied += tidy.dropped;
+        if tidy.fragmentation() > FRAGMENTED {
+            index::rebuild(db, &index)?;
+            rebuilt += 1;
+        }
+    }
+    db.compact()?;
+    Ok(Report {
+        before,
+        after: db.store().size_on_disk()?,
+        swept,
+        pruned,
+        tidied,
+        rebuilt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{self, Object};
+    use crate::storage::{remove_scratch, scratch_path, wal, Batch, StorageEngine};
+    use crate::value::FunkValue;
+    use crate::{FunkDb, FunkDbOptions};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    const SCHEMA: &str = "
+        module default {
+            type Doc {
+                required title: str;
+                embedding: vector<2>;
+                index on (.title);
+                index hnsw::l2 on (.embedding);
+            }
+        }
+    ";
+
+    #[test]
+    fn shrinks_the_file_and_tidies_indexes() -> Result<()> {
+        let path = scratch_path("vacuum.funk");
+        let options = FunkDbOptions::builder().retain(Duration::from_millis(50)).build();
+        let db = FunkDb::open_with(&path, options.clone())?;
+        db.set_catalog(SCHEMA)?;
+        let mut ids = vec![];
+        for n in 0..200 {
+            let doc = Object::new("Doc")
+                .set("title", format!("doc {n}"))
+                .set("embedding", FunkValue::vector(vec![n as f32, (n % 7) as f32]));
+            ids.push(db.insert(doc)?);
+        }
+        let reader = db.begin();
+        for id in ids.iter().skip(10) {
+            db.delete(*id)?;
+        }
+        // Entries a crash could have left behind: one for an object that
+        // never was, and a link to a node that is gone.
+        let (nearest, ordered): (Vec<_>, Vec<_>) = index::states(&db.begin())?
+            .into_iter()
+            .map(|(index, _)| index)
+            .partition(|index| matches!(index.kind, index::Kind::Nearest(_)));
+        let (ordered, nearest) = (ordered[0].tree(), nearest[0].tree());
+        let neighbours = [0x02, 0].iter().copied().chain(*ids[0].as_bytes()).collect::<Vec<u8>>();
+        {
+            let mut store = db.db.store();
+            let links = store.get(&nearest, &neighbours)?.unwrap();
+            let mut batch = Batch::new();
+            batch.put(&ordered, [&[0x01, 0x00][..], Uuid::new_v4().as_bytes()].concat(), vec![]);
+            batch.put(&nearest, neighbours.clone(), [links, ids[199].as_bytes().to_vec()].concat());
+            store.apply(batch)?;
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        let report = db.vacuum()?;
+        assert_eq!((report.tidied, report.rebuilt), (2, 0), "{report}");
+        assert!(report.pruned > 0, "{report}");
+        assert!(report.reclaimed() > 0, "{report}");
+        assert!(!wal::path_for(&path).exists());
+        // Readers keep the snapshot they began with.
+        assert!(object::get(&reader, ids[100])?.is_some());
+        drop(reader);
+        assert_eq!(db.vacuum()?.tidied, 0);
+        assert_eq!(db.select("Doc", &[crate::query::Filter::eq("title", "doc 3")])?.len(), 1);
+        assert_eq!(db.nearest("Doc", "embedding", &[3.0, 3.0], 1, &[])?[0].0.id, ids[3]);
+        drop(db);
+
+        let db = FunkDb::open_with(&path, options)?;
+        assert_eq!(db.objects("Doc")?.len(), 10);
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn rebuilds_fragmented_indexes() -> Result<()> {
+        let path = scratch_path("fragmented.funk");
+        let db = FunkDb::open(&path)?;
+        db.set_catalog(SCHEMA)?;
+        for n in 0..10 {
+            db.insert(Object::new("Doc").set("title", format!("doc {n}")))?;
+        }
+        let ordered = index::states(&db.begin())?
+            .into_iter()
+            .map(|(index, _)| index)
+            .find(|index| !matches!(index.kind, index::Kind::Nearest(_)))
+            .unwrap();
+        {
+            let mut store = db.db.store();
+            let mut batch = Batch::new();
+            for _ in 0..5 {
+                batch.put(&ordered.tree(), [&[0x01, 0x00][..], Uuid::new_v4().as_bytes()].concat(), vec![]);
+            }
+            store.apply(batch)?;
+        }
+
+        let report = db.vacuum()?;
+        assert_eq!((report.tidied, report.rebuilt), (5, 1), "{report}");
+        let states = index::states(&db.begin())?;
+        assert!(states.contains(&(ordered, index::State::Ready)));
+        assert_eq!(db.select("Doc", &[crate::query::Filter::eq("title", "doc 3")])?.len(), 1);
+        assert_eq!(db.vacuum()?.rebuilt, 0);
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuums_sqlite_into_a_copy() -> Result<()> {
+        let path = scratch_path("vacuum.sqlite");
+        let location = format!("sqlite://{}", path.display());
+        let db = FunkDb::open(&location)?;
+        db.set_catalog(SCHEMA)?;
+        let ids = (0..300)
+            .map(|n| db.insert(Object::new("Doc").set("title", format!("doc {n} {}", "funk ".repeat(20)))))
+            .collect::<Result<Vec<_>>>()?;
+        for id in ids.iter().skip(10) {
+            db.delete(*id)?;
+        }
+        let report = db.vacuum()?;
+        assert!(report.reclaimed() > 0, "{report}");
+        assert!(!path.with_extension("sqlite.vacuum").exists());
+        db.insert(Object::new("Doc").set("title", "after"))?;
+        drop(db);
+        let db = FunkDb::open(&location)?;
+        assert_eq!(db.objects("Doc")?.len(), 11);
+        assert_eq!(db.select("Doc", &[crate::query::Filter::eq("title", "after")])?.len(), 1);
+        drop(db);
+        remove_scratch(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuums_in_the_background() -> Result<()> {
+        let path = scratch_path("vacuumed.funk");
+        let options = FunkDbOptions::builder().vacuum_every(Duration::from_millis(10)).build();
+        let db = FunkDb::open_with(&path, options)?;
+        db.set_catalog(SCHEMA)?;
+        db.insert(Object::new("Doc").set("title", "a"))?;
+        let mut checkpointed = false;
+        for _ in 0..500 {
+            // Only a checkpoint empties the log of a database this small.
+            if !wal::path_for(&path).exists() {
+                checkpointed = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(db.background_error().is_none());
+        drop(db);
+        remove_scratch(&path);
+        assert!(checkpointed, "never vacuumed");
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+//! Values of the `funkstd` scalars, one variant per kind.
+use crate::{funkstd, Named};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum FunkValue {
+    r#bool(bool),
+    int8(i8),
+    int16(i16),
+    int32(i32),
+    int64(i64),
+    int128(i128),
+    r#str(String),
+    uint8(u8),
+    uint16(u16),
+    uint32(u32),
+    uint64(u64),
+    uint128(u128),
+    vector(Vec<f32>),
+    /// Microseconds since 1970-01-01T00:00:00Z.
+    datetime(i64),
+}
+
+impl FunkValue {
+    pub fn kind(&self) -> funkstd {
+        match self {
+            Self::bool(_) => funkstd::bool,
+            Self::int8(_) => funkstd::int8,
+            Self::int16(_) => funkstd::int16,
+            Self::int32(_) => funkstd::int32,
+            Self::int64(_) => funkstd::int64,
+            Self::int128(_) => funkstd::int128,
+            Self::str(_) => funkstd::str,
+            Self::uint8(_) => funkstd::uint8,
+            Self::uint16(_) => funkstd::uint16,
+            Self::uint32(_) => funkstd::uint32,
+            Self::uint64(_) => funkstd::uint64,
+            Self::uint128(_) => funkstd::uint128,
+            Self::vector(_) => funkstd::vector,
+            Self::datetime(_) => funkstd::datetime,
+        }
+    }
+
+    /// Reads `text` as a value of `kind`, the way it would be written in
+    /// a query or a CSV cell.
+    pub fn parse(kind: funkstd, text: &str) -> Result<Self> {
+        let invalid = || anyhow!("`{text}` is not a valid {}", kind.get_name().unwrap());
+        let value = match kind {
+            funkstd::bool => match text {
+                "true" => Self::bool(true),
+                "false" => Self::bool(false),
+                _ => return Err(invalid()),
+            },
+            funkstd::int8 => Self::int8(text.parse().map_err(|_| invalid())?),
+            funkstd::int16 => Self::int16(text.parse().map_err(|_| invalid())?),
+            funkstd::int32 => Self::int32(text.parse().map_err(|_| invalid())?),
+            funkstd::int64 => Self::int64(text.parse().map_err(|_| invalid())?),
+            funkstd::int128 => Self::int128(text.parse().map_err(|_| invalid())?),
+            funkstd::str => Self::str(text.to_string()),
+            funkstd::uint8 => Self::uint8(text.parse().map_err(|_| invalid())?),
+            funkstd::uint16 => Self::uint16(text.parse().map_err(|_| invalid())?),
+            funkstd::uint32 => Self::uint32(text.parse().map_err(|_| invalid())?),
+            funkstd::uint64 => Self::uint64(text.parse().map_err(|_| invalid())?),
+            funkstd::uint128 => Self::uint128(text.parse().map_err(|_| invalid())?),
+            funkstd::vector => {
+                let Some(items) = text.trim().strip_prefix('[').and_then(|text| text.strip_suffix(']')) else {
+                    return Err(invalid());
+                };
+                let items = items.split(',').map(str::trim).filter(|item| !item.is_empty());
+                let finite = |item: &str| item.parse::<f32>().ok().filter(|x| x.is_finite()).ok_or_else(invalid);
+                Self::vector(items.map(finite).collect::<Result<_>>()?)
+            }
+            funkstd::datetime => Self::datetime(parse_datetime(text).map_err(|e| anyhow!("{}: {e}", invalid()))?),
+        };
+        Ok(value)
+    }
+}
+
+impl fmt::Display for FunkValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::bool(v) => write!(f, "{v}"),
+            Self::int8(v) => write!(f, "{v}"),
+            Self::int16(v) => write!(f, "{v}"),
+            Self::int32(v) => write!(f, "{v}"),
+            Self::int64(v) => write!(f, "{v}"),
+            Self::int128(v) => write!(f, "{v}"),
+            Self::str(v) => write!(f, "{v}"),
+            Self::uint8(v) => write!(f, "{v}"),
+            Self::uint16(v) => write!(f, "{v}"),
+            Self::uint32(v) => write!(f, "{v}"),
+            Self::uint64(v) => write!(f, "{v}"),
+            Self::uint128(v) => write!(f, "{v}"),
+            Self::vector(v) => {
+                let items: Vec<String> = v.iter().map(f32::to_string).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Self::datetime(micros) => {
+                let (days, micros) = (micros.div_euclid(86_400_000_000), micros.rem_euclid(86_400_000_000));
+                let (year, month, day) = civil_from_days(days);
+                let seconds = micros / 1_000_000;
+                let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+                match year {
+                    0..=9999 => write!(f, "{year:04}")?,
+                    _ => write!(f, "{year:+05}")?,
+                }
+                write!(f, "-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}")?;
+                match micros % 1_000_000 {
+                    0 => write!(f, "Z"),
+                    fraction => write!(f, ".{fraction:06}Z"),
+                }
+            }
+        }
+    }
+}
+
+macro_rules! from_primitive {
+    ($($primitive:ty => $variant:ident),+ $(,)?) => {
+        $(
+            impl From<$primitive> for FunkValue {
+                fn from(value: $primitive) -> Self {
+                    Self::$variant(value)
+                }
+            }
+        )+
+    };
+}
+
+from_primitive! {
+    bool => bool,
+    i8 => int8,
+    i16 => int16,
+    i32 => int32,
+    i64 => int64,
+    i128 => int128,
+    String => str,
+    u8 => uint8,
+    u16 => uint16,
+    u32 => uint32,
+    u64 => uint64,
+    u128 => uint128,
+    Vec<f32> => vector,
+}
+
+impl From<SystemTime> for FunkValue {
+    fn from(time: SystemTime) -> Self {
+        let micros = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_micros() as i64,
+            Err(before) => -(before.duration().as_micros() as i64),
+        };
+        Self::datetime(micros)
+    }
+}
+
+impl FunkValue {
+    /// The value as JSON. 128-bit integers, which JSON numbers cannot
+    /// carry exactly, and datetimes become strings.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::bool(v) => json!(v),
+            Self::int8(v) => json!(v),
+            Self::int16(v) => json!(v),
+            Self::int32(v) => json!(v),
+            Self::int64(v) => json!(v),
+            Self::uint8(v) => json!(v),
+            Self::uint16(v) => json!(v),
+            Self::uint32(v) => json!(v),
+            Self::uint64(v) => json!(v),
+            Self::str(v) => json!(v),
+            Self::vector(v) => json!(v),
+            Self::int128(_) | Self::uint128(_) | Self::datetime(_) => json!(self.to_string()),
+        }
+    }
+
+    /// Reads a value of `kind` back from what [`FunkValue::to_json`] made
+    /// of it.
+    pub fn from_json(kind: funkstd, json: &Value) -> Result<Self> {
+        let invalid = || anyhow!("`{json}` is not a valid {}", kind.get_name().unwrap());
+        let int = || json.as_i64().ok_or_else(invalid);
+        let uint = || json.as_u64().ok_or_else(invalid);
+        let value = match kind {
+            funkstd::bool => Self::bool(json.as_bool().ok_or_else(invalid)?),
+            funkstd::int8 => Self::int8(int()?.try_into().map_err(|_| invalid())?),
+            funkstd::int16 => Self::int16(int()?.try_into().map_err(|_| invalid())?),
+            funkstd::int32 => Self::int32(int()?.try_into().map_err(|_| invalid())?),
+            funkstd::int64 => Self::int64(int()?),
+            funkstd::uint8 => Self::uint8(uint()?.try_into().map_err(|_| invalid())?),
+            funkstd::uint16 => Self::uint16(uint()?.try_into().map_err(|_| invalid())?),
+            funkstd::uint32 => Self::uint32(uint()?.try_into().map_err(|_| invalid())?),
+            funkstd::uint64 => Self::uint64(uint()?),
+            funkstd::str => Self::str(json.as_str().ok_or_else(invalid)?.to_string()),
+            funkstd::vector => {
+                let items = json.as_array().ok_or_else(invalid)?;
+                let finite = |item: &Value| item.as_f64().map(|x| x as f32).filter(|x| x.is_finite()).ok_or_else(invalid);
+                Self::vector(items.iter().map(finite).collect::<Result<_>>()?)
+            }
+            funkstd::int128 | funkstd::uint128 | funkstd::datetime => Self::parse(kind, json.as_str().ok_or_else(invalid)?)?,
+        };
+        Ok(value)
+    }
+
+    /// The time a `datetime` stands for.
+    pub fn as_time(&self) -> Option<SystemTime> {
+        match self {
+            Self::datetime(micros) if *micros >= 0 => Some(UNIX_EPOCH + Duration::from_micros(*micros as u64)),
+            Self::datetime(micros) => Some(UNIX_EPOCH - Duration::from_micros(micros.unsigned_abs())),
+            _ => None,
+        }
+    }
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from((month + 9) % 12);
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Reads an RFC 3339 timestamp, `2023-11-05T09:30:00Z` or with a
+/// fraction and an offset like `2023-11-05T10:30:00.25+01:00`. Years
+/// outside 0000-9999 take a sign and at least 4 digits, as ISO 8601
+/// expands them and as they print: `-0001-12-31T23:00:00Z`.
+fn parse_datetime(text: &str) -> Result<i64> {
+    let text = text.trim();
+    let Some((date, time)) = text.split_once(['T', 't', ' ']) else {
+        bail!("expected a date and a time");
+    };
+    let number = |part: &str, digits: usize| -> Result<u32> {
+        if part.len() != digits || !part.bytes().all(|byte| byte.is_ascii_digit()) {
+            bail!("expected {digits} digits, found `{part}`");
+        }
+        Ok(part.parse()?)
+    };
+    let (sign, date) = match date.strip_prefix(['+', '-']) {
+        Some(rest) => (if date.starts_with('-') { -1 } else { 1 }, rest),
+        None => (0, date),
+    };
+    let [year, month, day] = date.splitn(3, '-').collect::<Vec<_>>()[..] else {
+        bail!("expected a date like 2023-11-05");
+    };
+    let year = match sign {
+        0 => i64::from(number(year, 4)?),
+        sign => sign * i64::from(number(year, year.len().clamp(4, 6))?),
+    };
+    let (month, day) = (number(month, 2)?, number(day, 2)?);
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(at) => time.split_at(at),
+        None => bail!("expected `Z` or an offset like +01:00"),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        offset => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let Some((hours, minutes)) = offset[1..].split_once(':') else {
+                bail!("expected an offset like +01:00");
+            };
+            sign * i64::from(number(hours, 2)? * 60 + number(minutes, 2)?) * 60_000_000
+        }
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let [hour, minute, second] = time.splitn(3, ':').collect::<Vec<_>>()[..] else {
+        bail!("expected a time like 09:30:00");
+    };
+    let (hour, minute, second) = (number(hour, 2)?, number(minute, 2)?, number(second, 2)?);
+    let days_in_month = match month {
+        2 if year.rem_euclid(4) == 0 && (year.rem_euclid(100) != 0 || year.rem_euclid(400) == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        bail!("out of range");
+    }
+    let mut micros = 0;
+    if !fraction.is_empty() {
+        if fraction.len() > 6 {
+            bail!("more precise than a microsecond");
+        }
+        micros = number(fraction, fraction.len())? * 10u32.pow(6 - fraction.len() as u32);
+    }
+    let seconds = days_from_civil(year, month, day) * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    // An i64 of microseconds ends some 292,000 years out.
+    let micros = i128::from(seconds) * 1_000_000 + i128::from(micros) - i128::from(offset);
+    i64::try_from(micros).map_err(|_| anyhow!("out of range"))
+}
+
+impl From<&str> for FunkValue {
+    fn from(value: &str) -> Self {
+        Self::str(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_hold_finite_numbers() -> Result<()> {
+        assert_eq!(FunkValue::parse(funkstd::vector, "[0.5, -2]")?, FunkValue::vector(vec![0.5, -2.0]));
+        for bad in ["[NaN, 1]", "[inf]", "[1, -inf]", "[1e39]"] {
+            assert!(FunkValue::parse(funkstd::vector, bad).is_err(), "{bad}");
+        }
+        assert!(FunkValue::from_json(funkstd::vector, &serde_json::json!([1e39, 1.0])).is_err());
+        assert!(FunkValue::from_json(funkstd::vector, &serde_json::json!([null, 1.0])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn datetimes_read_and_print_as_rfc3339() -> Result<()> {
+        for (text, expected) in [
+            ("1970-01-01T00:00:00Z", "1970-01-01T00:00:00Z"),
+            ("2024-02-29T23:59:59.5Z", "2024-02-29T23:59:59.500000Z"),
+            ("2023-11-05T10:30:00+01:00", "2023-11-05T09:30:00Z"),
+            ("1969-12-31 23:59:59.000001-00:30", "1970-01-01T00:29:59.000001Z"),
+            ("0001-01-01T00:00:00Z", "0001-01-01T00:00:00Z"),
+            ("0000-01-01T00:00:00+01:00", "-0001-12-31T23:00:00Z"),
+            ("9999-12-31T23:00:00-01:00", "+10000-01-01T00:00:00Z"),
+            ("-0001-03-01T00:00:00Z", "-0001-03-01T00:00:00Z"),
+            ("-0004-02-29T00:00:00Z", "-0004-02-29T00:00:00Z"),
+        ] {
+            assert_eq!(FunkValue::parse(funkstd::datetime, text)?.to_string(), expected);
+        }
+        // Whatever a datetime holds prints as something that reads back.
+        for micros in [i64::MIN, -62_167_219_200_000_001, -1, 253_402_300_800_000_000, i64::MAX] {
+            let text = FunkValue::datetime(micros).to_string();
+            assert_eq!(FunkValue::parse(funkstd::datetime, &text)?, FunkValue::datetime(micros), "{text}");
+        }
+        for bad in [
+            "2023-02-29T00:00:00Z",
+            "2023-11-05T09:30:00",
+            "2023-11-05",
+            "23-11-05T09:30:00Z",
+            "-0003-02-29T00:00:00Z",
+            "+999999-01-01T00:00:00Z",
+        ] {
+            assert!(FunkValue::parse(funkstd::datetime, bad).is_err(), "{bad}");
+        }
+        let now = SystemTime::now();
+        let value = FunkValue::from(now);
+        let micros = now.duration_since(UNIX_EPOCH)?.as_micros();
+        assert_eq!(value.as_time().unwrap().duration_since(UNIX_EPOCH)?.as_micros(), micros);
+        Ok(())
+    }
+}